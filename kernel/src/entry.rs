@@ -1,6 +1,7 @@
 use alloc::{
     string::{String, ToString},
     sync::Arc,
+    vec,
 };
 
 use axfs::FS_CONTEXT;
@@ -11,15 +12,29 @@ use starry_process::{Pid, Process};
 
 use crate::{
     file::FD_TABLE,
+    fs::{flush_filesystems, mount_root, spawn_flusher_task},
     mm::{copy_from_kernel, load_user_app, new_user_aspace_empty},
     pseudofs::{self, dev::tty::N_TTY},
     task::{ProcessData, Thread, add_task_to_table, new_user_task, spawn_alarm_task},
 };
 
 /// Initialize and run initproc.
-pub fn init(args: &[String], envs: &[String]) {
+///
+/// `initramfs` is an initramfs image built into the kernel, which may be
+/// empty. If there is an initramfs, its init program is run instead of
+/// `args`.
+pub fn init(args: &[String], envs: &[String], initramfs: &[u8]) {
+    let rdinit_args;
+    let args = match mount_root(initramfs) {
+        Some(init) => {
+            rdinit_args = vec![init];
+            &rdinit_args[..]
+        }
+        None => args,
+    };
     pseudofs::mount_all().expect("Failed to mount pseudofs");
     spawn_alarm_task();
+    spawn_flusher_task();
 
     let loc = FS_CONTEXT
         .lock()
@@ -63,6 +78,10 @@ pub fn init(args: &[String], envs: &[String]) {
         let mut scope = proc.scope.write();
         crate::file::add_stdio(&mut FD_TABLE.scope_mut(&mut scope).write())
             .expect("Failed to add stdio");
+        FS_CONTEXT
+            .scope_mut(&mut scope)
+            .lock()
+            .clone_from(&FS_CONTEXT.lock());
     }
 
     let thr = Thread::new(pid, proc);
@@ -79,8 +98,5 @@ pub fn init(args: &[String], envs: &[String]) {
     cx.root_dir()
         .unmount_all()
         .expect("Failed to unmount all filesystems");
-    cx.root_dir()
-        .filesystem()
-        .flush()
-        .expect("Failed to flush rootfs");
+    flush_filesystems().expect("Failed to flush filesystems");
 }
@@ -0,0 +1,113 @@
+//! Directory notifications (dnotify).
+//!
+//! `F_NOTIFY` asks for a signal whenever an entry of a directory is
+//! accessed, modified, created, deleted, renamed or has its attributes
+//! changed. The signal goes to the owner of the file description, like with
+//! `O_ASYNC`.
+
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::ffi::c_int;
+
+use axerrno::{AxError, AxResult};
+use axfs_ng_vfs::Location;
+use kspin::SpinNoIrq;
+use linux_raw_sys::general::{
+    DN_ACCESS, DN_ATTRIB, DN_CREATE, DN_DELETE, DN_MODIFY, DN_MULTISHOT, DN_RENAME, IN_ACCESS,
+    IN_ATTRIB, IN_CREATE, IN_DELETE, IN_MODIFY, IN_MOVED_FROM, IN_MOVED_TO,
+};
+
+use super::{FileLike, fasync::AsyncFile};
+
+/// All the events that can be watched.
+const DN_ALL_EVENTS: u32 = DN_ACCESS | DN_MODIFY | DN_CREATE | DN_DELETE | DN_RENAME | DN_ATTRIB;
+
+struct Watch {
+    /// The open file description of the directory, by address.
+    owner: usize,
+    file: Weak<dyn FileLike>,
+    /// The descriptor the watch was set through, reported in `si_fd`.
+    fd: c_int,
+    mask: u32,
+}
+
+/// The watches on a directory, stored in the user data of its entry.
+#[derive(Default)]
+struct DirWatches(SpinNoIrq<Vec<Watch>>);
+
+fn file_key(file: &Arc<dyn FileLike>) -> usize {
+    Arc::as_ptr(file) as *const () as usize
+}
+
+/// Sets the events watched on the directory `dir` through `file` to `mask`,
+/// replacing the previous ones, or removes the watch if `mask` is empty.
+pub fn set_dnotify(dir: &Location, file: &Arc<dyn FileLike>, fd: c_int, mask: u32) -> AxResult<()> {
+    if mask & !(DN_ALL_EVENTS | DN_MULTISHOT) != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    let owner = file_key(file);
+    let watches = dir.user_data().get_or_insert_with(DirWatches::default);
+    let mut watches = watches.0.lock();
+    watches.retain(|it| it.owner != owner && it.file.strong_count() > 0);
+    if mask & DN_ALL_EVENTS != 0 {
+        watches.push(Watch {
+            owner,
+            file: Arc::downgrade(file),
+            fd,
+            mask,
+        });
+    }
+    Ok(())
+}
+
+/// Removes the watch set through the open file description at `addr`, which
+/// is being destroyed.
+pub(super) fn release_dnotify(dir: &Location, addr: usize) {
+    if let Some(watches) = dir.user_data().get::<DirWatches>() {
+        watches.0.lock().retain(|it| it.owner != addr);
+    }
+}
+
+/// Reports an inotify event on an entry of `dir` to the dnotify watches on
+/// it.
+pub fn report_dnotify(dir: &Location, mask: u32) {
+    let Some(watches) = dir.user_data().get::<DirWatches>() else {
+        return;
+    };
+    let mut events = 0;
+    for (from, to) in [
+        (IN_ACCESS, DN_ACCESS),
+        (IN_MODIFY, DN_MODIFY),
+        (IN_ATTRIB, DN_ATTRIB),
+        (IN_CREATE | IN_MOVED_TO, DN_CREATE),
+        (IN_DELETE | IN_MOVED_FROM, DN_DELETE),
+        (IN_MOVED_FROM | IN_MOVED_TO, DN_RENAME),
+    ] {
+        if mask & from != 0 {
+            events |= to;
+        }
+    }
+    if events == 0 {
+        return;
+    }
+
+    let mut targets = Vec::new();
+    watches.0.lock().retain_mut(|watch| {
+        if watch.mask & events == 0 {
+            return true;
+        }
+        targets.push((watch.file.clone(), watch.fd));
+        // Without `DN_MULTISHOT`, a watch only fires once.
+        if watch.mask & DN_MULTISHOT == 0 {
+            watch.mask = 0;
+        }
+        watch.mask & DN_ALL_EVENTS != 0
+    });
+    for (file, fd) in targets {
+        if let Some(file) = file.upgrade() {
+            AsyncFile::of(&file).send_message(fd);
+        }
+    }
+}
@@ -0,0 +1,313 @@
+//! Signal-driven I/O.
+//!
+//! With `O_ASYNC` set on an open file description, its owner is sent
+//! `SIGIO`, or the signal chosen with `F_SETSIG`, whenever the file becomes
+//! ready for I/O. This works for any [`FileLike`] by keeping a waker
+//! registered on it, which sends the signal and registers itself again.
+
+use alloc::{
+    collections::btree_map::BTreeMap,
+    sync::{Arc, Weak},
+    task::Wake,
+};
+use core::{
+    ffi::c_int,
+    task::{Context, Waker},
+};
+
+use axerrno::{AxError, AxResult};
+use axpoll::IoEvents;
+use axtask::current;
+use kspin::SpinNoIrq;
+use linux_raw_sys::general::{
+    __sifields__bindgen_ty_6, F_OWNER_PGRP, F_OWNER_PID, F_OWNER_TID, POLL_ERR, POLL_HUP, POLL_IN,
+    POLL_MSG, POLL_OUT, f_owner_ex,
+};
+use starry_process::Pid;
+use starry_signal::{SignalInfo, Signo};
+
+use super::FileLike;
+use crate::task::{
+    AsThread, get_process_data, get_process_group, get_task, send_signal_to_process,
+    send_signal_to_process_group, send_signal_to_thread,
+};
+
+/// The receiver of the signals of a file, set with `F_SETOWN(_EX)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsyncOwner {
+    Thread(Pid),
+    Process(Pid),
+    ProcessGroup(Pid),
+}
+
+impl AsyncOwner {
+    /// Returns the current process, which leases and directory notifications
+    /// signal by default.
+    pub fn current_process() -> Self {
+        Self::Process(current().as_thread().proc_data.proc.pid())
+    }
+
+    /// Parses the argument of `F_SETOWN`, where a negative value is a
+    /// process group. Returns `None` for 0, which removes the owner.
+    pub fn from_pid(pid: i32) -> AxResult<Option<Self>> {
+        Ok(match pid {
+            0 => None,
+            pid if pid > 0 => Some(Self::Process(pid as _)),
+            pid => Some(Self::ProcessGroup(
+                pid.checked_neg().ok_or(AxError::InvalidInput)? as _,
+            )),
+        })
+    }
+
+    /// Parses the argument of `F_SETOWN_EX`.
+    pub fn from_owner_ex(owner: &f_owner_ex) -> AxResult<Option<Self>> {
+        if owner.pid < 0 {
+            return Err(AxError::InvalidInput);
+        }
+        if owner.pid == 0 {
+            return Ok(None);
+        }
+        let pid = owner.pid as Pid;
+        Ok(Some(match owner.type_ as u32 {
+            F_OWNER_TID => Self::Thread(pid),
+            F_OWNER_PID => Self::Process(pid),
+            F_OWNER_PGRP => Self::ProcessGroup(pid),
+            _ => return Err(AxError::InvalidInput),
+        }))
+    }
+
+    /// Returns the value reported by `F_GETOWN`.
+    pub fn to_pid(owner: Option<Self>) -> i32 {
+        match owner {
+            None => 0,
+            Some(Self::Thread(pid) | Self::Process(pid)) => pid as _,
+            Some(Self::ProcessGroup(pgid)) => -(pgid as i32),
+        }
+    }
+
+    /// Returns the value reported by `F_GETOWN_EX`.
+    pub fn to_owner_ex(owner: Option<Self>) -> f_owner_ex {
+        let (type_, pid) = match owner {
+            None => (F_OWNER_PID, 0),
+            Some(Self::Thread(pid)) => (F_OWNER_TID, pid),
+            Some(Self::Process(pid)) => (F_OWNER_PID, pid),
+            Some(Self::ProcessGroup(pgid)) => (F_OWNER_PGRP, pgid),
+        };
+        f_owner_ex {
+            type_: type_ as _,
+            pid: pid as _,
+        }
+    }
+
+    fn check(&self) -> AxResult<()> {
+        match *self {
+            Self::Thread(tid) => get_task(tid).map(drop),
+            Self::Process(pid) => get_process_data(pid).map(drop),
+            Self::ProcessGroup(pgid) => get_process_group(pgid).map(drop),
+        }
+    }
+
+    fn send(&self, sig: SignalInfo) {
+        let _ = match *self {
+            Self::Thread(tid) => send_signal_to_thread(None, tid, Some(sig)),
+            Self::Process(pid) => send_signal_to_process(pid, Some(sig)),
+            Self::ProcessGroup(pgid) => send_signal_to_process_group(pgid, Some(sig)),
+        };
+    }
+}
+
+struct AsyncState {
+    enabled: bool,
+    /// The descriptor `O_ASYNC` was set through, reported in `si_fd`.
+    fd: c_int,
+    owner: Option<AsyncOwner>,
+    /// The signal set with `F_SETSIG`, where 0 means `SIGIO`.
+    signal: u32,
+    /// Whether the waker is registered on the file.
+    registered: bool,
+    /// The events that were ready when the file was last checked.
+    ready: IoEvents,
+}
+
+/// The signal-driven I/O settings of an open file description.
+pub struct AsyncFile {
+    file: Weak<dyn FileLike>,
+    state: SpinNoIrq<AsyncState>,
+}
+
+/// The settings of all open file descriptions that have any, by address.
+static ASYNC_FILES: SpinNoIrq<BTreeMap<usize, Arc<AsyncFile>>> = SpinNoIrq::new(BTreeMap::new());
+
+fn file_key(file: &Arc<dyn FileLike>) -> usize {
+    Arc::as_ptr(file) as *const () as usize
+}
+
+impl AsyncFile {
+    /// Returns the settings of `file`, creating them if needed.
+    pub fn of(file: &Arc<dyn FileLike>) -> Arc<Self> {
+        let mut files = ASYNC_FILES.lock();
+        // The weak reference keeps the address of a closed file from being
+        // reused, so stale entries can be dropped lazily.
+        files.retain(|_, it| it.file.strong_count() > 0);
+        files
+            .entry(file_key(file))
+            .or_insert_with(|| {
+                Arc::new(Self {
+                    file: Arc::downgrade(file),
+                    state: SpinNoIrq::new(AsyncState {
+                        enabled: false,
+                        fd: -1,
+                        owner: None,
+                        signal: 0,
+                        registered: false,
+                        ready: IoEvents::empty(),
+                    }),
+                })
+            })
+            .clone()
+    }
+
+    /// Returns the settings of `file`, if it has any.
+    pub fn get(file: &Arc<dyn FileLike>) -> Option<Arc<Self>> {
+        ASYNC_FILES.lock().get(&file_key(file)).cloned()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.state.lock().enabled
+    }
+
+    /// Sets or clears `O_ASYNC` through the descriptor `fd`.
+    pub fn set_enabled(self: &Arc<Self>, fd: c_int, enabled: bool) {
+        {
+            let mut state = self.state.lock();
+            state.enabled = enabled;
+            state.fd = fd;
+        }
+        if enabled {
+            self.arm();
+        }
+    }
+
+    pub fn owner(&self) -> Option<AsyncOwner> {
+        self.state.lock().owner
+    }
+
+    pub fn set_owner(&self, owner: Option<AsyncOwner>) -> AxResult<()> {
+        if let Some(owner) = &owner {
+            owner.check()?;
+        }
+        self.state.lock().owner = owner;
+        Ok(())
+    }
+
+    pub fn signal(&self) -> u32 {
+        self.state.lock().signal
+    }
+
+    /// Sets the signal sent instead of `SIGIO`, with `si_fd` and `si_band`
+    /// filled in.
+    pub fn set_signal(&self, signal: u32) -> AxResult<()> {
+        if signal > 64 || (signal != 0 && Signo::from_repr(signal as u8).is_none()) {
+            return Err(AxError::InvalidInput);
+        }
+        self.state.lock().signal = signal;
+        Ok(())
+    }
+
+    /// Registers the waker on the file unless it is already.
+    fn arm(self: &Arc<Self>) {
+        let Some(file) = self.file.upgrade() else {
+            return;
+        };
+        let ready = file.poll();
+        {
+            let mut state = self.state.lock();
+            if state.registered || !state.enabled {
+                return;
+            }
+            state.registered = true;
+            state.ready = ready;
+        }
+        let waker = Waker::from(self.clone());
+        let mut context = Context::from_waker(&waker);
+        file.register(&mut context, IoEvents::IN | IoEvents::OUT);
+    }
+
+    /// Signals the owner about the events of the file that are ready.
+    ///
+    /// Readable data is reported on every wakeup, since more data may have
+    /// arrived, while the other events only when they become ready.
+    fn notify(&self, file: &Arc<dyn FileLike>) {
+        let events = file.poll();
+        let (owner, signal, fd, reason) = {
+            let mut state = self.state.lock();
+            let new = events - state.ready;
+            state.ready = events;
+            let reason = if events.contains(IoEvents::IN) {
+                POLL_IN
+            } else if new.contains(IoEvents::ERR) {
+                POLL_ERR
+            } else if new.contains(IoEvents::HUP) {
+                POLL_HUP
+            } else if new.contains(IoEvents::OUT) {
+                POLL_OUT
+            } else {
+                return;
+            };
+            let Some(owner) = state.owner.filter(|_| state.enabled) else {
+                return;
+            };
+            (owner, state.signal, state.fd, reason)
+        };
+        owner.send(sigio(signal, fd, reason, events));
+    }
+
+    /// Signals the owner on behalf of the descriptor `fd`, for leases and
+    /// directory notifications, even if `O_ASYNC` is not set.
+    pub fn send_message(&self, fd: c_int) {
+        let (owner, signal) = {
+            let state = self.state.lock();
+            (state.owner, state.signal)
+        };
+        if let Some(owner) = owner {
+            let band = IoEvents::IN | IoEvents::RDNORM | IoEvents::MSG;
+            owner.send(sigio(signal, fd, POLL_MSG, band));
+        }
+    }
+}
+
+/// Builds the signal sent for `reason` on `fd`, where `signal` is the one
+/// set with `F_SETSIG`.
+fn sigio(signal: u32, fd: c_int, reason: u32, band: IoEvents) -> SignalInfo {
+    let mut sig = SignalInfo::new_kernel(if signal == 0 {
+        Signo::SIGIO
+    } else {
+        Signo::from_repr(signal as u8).unwrap()
+    });
+    sig.set_code(reason as _);
+    sig.0.__bindgen_anon_1.__bindgen_anon_1._sifields._sigpoll = __sifields__bindgen_ty_6 {
+        _band: band.bits() as _,
+        _fd: fd as _,
+    };
+    sig
+}
+
+impl Wake for AsyncFile {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        let Some(file) = self.file.upgrade() else {
+            return;
+        };
+        self.state.lock().registered = false;
+        self.notify(&file);
+        self.arm();
+    }
+}
+
+/// Returns whether `O_ASYNC` is set on `file`.
+pub fn is_async(file: &Arc<dyn FileLike>) -> bool {
+    AsyncFile::get(file).is_some_and(|it| it.is_enabled())
+}
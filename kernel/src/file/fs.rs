@@ -1,4 +1,4 @@
-use alloc::{borrow::Cow, string::ToString, sync::Arc};
+use alloc::{borrow::Cow, format, string::ToString, sync::Arc, vec::Vec};
 use core::{
     ffi::c_int,
     hint::likely,
@@ -7,15 +7,36 @@ use core::{
 };
 
 use axerrno::{AxError, AxResult};
-use axfs::{FS_CONTEXT, FsContext};
-use axfs_ng_vfs::{Location, Metadata, NodeFlags};
+use axfs::{FS_CONTEXT, FileFlags, FsContext, SYMLINKS_MAX};
+use axfs_ng_vfs::{
+    Location, Metadata, NodeFlags, NodeType,
+    path::{Component, Path},
+};
+use axio::{Seek, SeekFrom};
 use axpoll::{IoEvents, Pollable};
 use axsync::Mutex;
 use axtask::future::{block_on, poll_io};
-use linux_raw_sys::general::{AT_EMPTY_PATH, AT_FDCWD, AT_SYMLINK_NOFOLLOW};
+use bitflags::bitflags;
+use linux_raw_sys::general::{
+    AT_EMPTY_PATH, AT_FDCWD, AT_SYMLINK_NOFOLLOW, RESOLVE_BENEATH, RESOLVE_CACHED, RESOLVE_IN_ROOT,
+    RESOLVE_NO_MAGICLINKS, RESOLVE_NO_SYMLINKS, RESOLVE_NO_XDEV,
+};
 
-use super::{FileLike, Kstat, get_file_like};
-use crate::file::{IoDst, IoSrc};
+use super::{
+    FileLike, Kstat,
+    dnotify::release_dnotify,
+    get_file_like,
+    lease::{lease_close, lease_open},
+    lock::release_file_locks,
+};
+use crate::{
+    file::{IoDst, IoSrc, memfd::Memfd, tmpfile::TmpFile},
+    fs::{
+        MountUse, fuse_hold, fuse_put, mark_dirty, notify_access, notify_close, notify_hold,
+        notify_modify, notify_put, read as sparse_read,
+    },
+    pseudofs::{MemoryNode, charge_pages},
+};
 
 pub fn with_fs<R>(dirfd: c_int, f: impl FnOnce(&mut FsContext) -> AxResult<R>) -> AxResult<R> {
     let mut fs = FS_CONTEXT.lock();
@@ -75,6 +96,190 @@ pub fn resolve_at(dirfd: c_int, path: Option<&str>, flags: u32) -> AxResult<Reso
     }
 }
 
+bitflags! {
+    /// Restrictions on path resolution, see `openat2(2)`.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct ResolveFlags: u64 {
+        /// Do not cross mount points.
+        const NO_XDEV = RESOLVE_NO_XDEV as u64;
+        /// Do not follow magic links, like those in `/proc/[pid]/fd`.
+        const NO_MAGICLINKS = RESOLVE_NO_MAGICLINKS as u64;
+        /// Do not follow any symlinks.
+        const NO_SYMLINKS = RESOLVE_NO_SYMLINKS as u64;
+        /// Do not leave the starting directory.
+        const BENEATH = RESOLVE_BENEATH as u64;
+        /// Treat the starting directory as the root directory.
+        const IN_ROOT = RESOLVE_IN_ROOT as u64;
+        /// Only use cached directory entries.
+        const CACHED = RESOLVE_CACHED as u64;
+    }
+}
+
+/// Resolves paths from the current directory of a [`FsContext`] under the
+/// restrictions of [`ResolveFlags`].
+pub struct Resolver<'a> {
+    fs: &'a FsContext,
+    flags: ResolveFlags,
+    follow_count: usize,
+}
+
+impl<'a> Resolver<'a> {
+    pub fn new(fs: &'a FsContext, flags: ResolveFlags) -> Self {
+        Self {
+            fs,
+            flags,
+            follow_count: 0,
+        }
+    }
+
+    fn is_scoped(&self) -> bool {
+        self.flags
+            .intersects(ResolveFlags::BENEATH | ResolveFlags::IN_ROOT)
+    }
+
+    /// Returns the directory absolute paths start from.
+    fn root(&self) -> AxResult<&Location> {
+        if self.flags.contains(ResolveFlags::BENEATH) {
+            Err(AxError::CrossesDevices)
+        } else if self.flags.contains(ResolveFlags::IN_ROOT) {
+            Ok(self.fs.current_dir())
+        } else {
+            Ok(self.fs.root_dir())
+        }
+    }
+
+    fn check_xdev(&self, from: &Location, to: &Location) -> AxResult {
+        if self.flags.contains(ResolveFlags::NO_XDEV)
+            && !Arc::ptr_eq(from.mountpoint(), to.mountpoint())
+        {
+            return Err(AxError::CrossesDevices);
+        }
+        Ok(())
+    }
+
+    /// Moves from `dir` to `comp`, without following symlinks.
+    fn step(&self, dir: Location, comp: Component) -> AxResult<Location> {
+        let next = match comp {
+            Component::CurDir => return Ok(dir),
+            Component::RootDir => self.root()?.clone(),
+            Component::ParentDir => {
+                let top = if self.is_scoped() {
+                    self.fs.current_dir()
+                } else {
+                    self.fs.root_dir()
+                };
+                if dir.ptr_eq(top) {
+                    if self.flags.contains(ResolveFlags::BENEATH) {
+                        return Err(AxError::CrossesDevices);
+                    }
+                    return Ok(dir);
+                }
+                dir.parent().unwrap_or_else(|| dir.clone())
+            }
+            Component::Normal(name) => {
+                if self.flags.contains(ResolveFlags::CACHED)
+                    && !dir
+                        .entry()
+                        .as_dir()
+                        .is_ok_and(|it| it.lookup_cache(name).is_some())
+                {
+                    return Err(AxError::WouldBlock);
+                }
+                dir.lookup_no_follow(name)?
+            }
+        };
+        self.check_xdev(&dir, &next)?;
+        Ok(next)
+    }
+
+    /// Follows the symlink `link` found in `dir`.
+    pub fn follow(&mut self, dir: &Location, link: Location) -> AxResult<Location> {
+        if self.flags.contains(ResolveFlags::NO_SYMLINKS) {
+            return Err(AxError::FilesystemLoop);
+        }
+        // Magic links in procfs refer to open files and such, which are
+        // reached regardless of where they are.
+        if is_magic_link(&link) {
+            if self.flags.contains(ResolveFlags::NO_MAGICLINKS) {
+                return Err(AxError::FilesystemLoop);
+            }
+            if self.is_scoped() {
+                return Err(AxError::CrossesDevices);
+            }
+        }
+        if self.follow_count >= SYMLINKS_MAX {
+            return Err(AxError::FilesystemLoop);
+        }
+        self.follow_count += 1;
+        let target = link.read_link()?;
+        if target.is_empty() {
+            return Err(AxError::NotFound);
+        }
+        let (dir, name) = self.resolve_parent_from(dir.clone(), &target)?;
+        match name {
+            Some(name) => self.lookup(&dir, name, true),
+            None => Ok(dir),
+        }
+    }
+
+    /// Looks up `name` in `dir`, following it if it is a symlink and `follow`
+    /// is set.
+    pub fn lookup(&mut self, dir: &Location, name: &str, follow: bool) -> AxResult<Location> {
+        let loc = self.step(dir.clone(), Component::Normal(name))?;
+        if follow && loc.node_type() == NodeType::Symlink {
+            self.follow(dir, loc)
+        } else {
+            Ok(loc)
+        }
+    }
+
+    fn resolve_parent_from<'p>(
+        &mut self,
+        mut dir: Location,
+        path: &'p str,
+    ) -> AxResult<(Location, Option<&'p str>)> {
+        let mut components = Path::new(path).components().collect::<Vec<_>>();
+        let name = match components.last() {
+            Some(Component::Normal(name)) if !path.ends_with('/') => {
+                let name = *name;
+                components.pop();
+                Some(name)
+            }
+            _ => None,
+        };
+        for comp in components {
+            dir = match comp {
+                Component::Normal(name) => self.lookup(&dir, name, true)?,
+                comp => self.step(dir, comp)?,
+            };
+        }
+        if name.is_some() || path.ends_with('/') {
+            dir.check_is_dir()?;
+        }
+        Ok((dir, name))
+    }
+
+    /// Resolves all but the last component of `path`.
+    ///
+    /// Returns the directory containing the last component and its name, or
+    /// the location of the whole path and `None` if it does not end with a
+    /// name (like `/`, `..` or `dir/`).
+    pub fn resolve_parent<'p>(&mut self, path: &'p str) -> AxResult<(Location, Option<&'p str>)> {
+        if path.is_empty() {
+            return Err(AxError::NotFound);
+        }
+        self.resolve_parent_from(self.fs.current_dir().clone(), path)
+    }
+}
+
+/// Returns whether `link` is a procfs magic link: `/proc/<pid>/fd/*`,
+/// `exe`, `cwd` or `root`. Others, like `/proc/self`, are plain symlinks.
+fn is_magic_link(link: &Location) -> bool {
+    link.filesystem().name() == "proc"
+        && (matches!(link.name(), "exe" | "cwd" | "root")
+            || link.parent().is_some_and(|parent| parent.name() == "fd"))
+}
+
 pub fn metadata_to_kstat(metadata: &Metadata) -> Kstat {
     let ty = metadata.node_type as u8;
     let perm = metadata.mode.bits() as u32;
@@ -100,11 +305,19 @@ pub fn metadata_to_kstat(metadata: &Metadata) -> Kstat {
 pub struct File {
     inner: axfs::File,
     nonblock: AtomicBool,
+    /// Keeps the mount of the file busy.
+    _mount: MountUse,
 }
 
 impl File {
     pub fn new(inner: axfs::File) -> Self {
+        if !inner.is_path() {
+            lease_open(inner.location(), inner.flags());
+            fuse_hold(inner.location());
+        }
+        notify_hold(inner.location());
         Self {
+            _mount: MountUse::new(inner.location()),
             inner,
             nonblock: AtomicBool::new(false),
         }
@@ -114,7 +327,8 @@ impl File {
         &self.inner
     }
 
-    fn is_blocking(&self) -> bool {
+    /// Returns whether I/O on the file may block, e.g. on a disk.
+    pub fn is_blocking(&self) -> bool {
         self.inner.location().flags().contains(NodeFlags::BLOCKING)
     }
 }
@@ -127,24 +341,48 @@ fn path_for(loc: &Location) -> Cow<'static, str> {
 impl FileLike for File {
     fn read(&self, dst: &mut IoDst) -> AxResult<usize> {
         let inner = self.inner();
-        if likely(self.is_blocking()) {
-            inner.read(dst)
+        let read = if likely(self.is_blocking()) {
+            sparse_read(inner, dst)?
         } else {
             block_on(poll_io(self, IoEvents::IN, self.nonblocking(), || {
-                inner.read(&mut *dst)
-            }))
+                sparse_read(inner, &mut *dst)
+            }))?
+        };
+        if read > 0 {
+            notify_access(inner.location());
         }
+        Ok(read)
     }
 
     fn write(&self, src: &mut IoSrc) -> AxResult<usize> {
         let inner = self.inner();
-        if likely(self.is_blocking()) {
-            inner.write(src)
+        let loc = inner.location();
+        if loc.entry().downcast::<MemoryNode>().is_ok() {
+            let size = loc.len()?;
+            let offset = if inner.flags().contains(FileFlags::APPEND) {
+                size
+            } else {
+                self.inner().seek(SeekFrom::Current(0))?
+            };
+            if let Some(memfd) = Memfd::of(loc) {
+                memfd.check_write(offset, src.remaining(), size)?;
+            }
+            charge_pages(loc, offset, src.remaining() as u64)?;
+        }
+        let written = if likely(self.is_blocking()) {
+            inner.write(src)?
         } else {
             block_on(poll_io(self, IoEvents::OUT, self.nonblocking(), || {
                 inner.write(&mut *src)
-            }))
+            }))?
+        };
+        if written > 0 {
+            if let Ok(end) = self.inner().seek(SeekFrom::Current(0)) {
+                mark_dirty(inner, end - written as u64, written);
+            }
+            notify_modify(inner.location());
         }
+        Ok(written)
     }
 
     fn stat(&self) -> AxResult<Kstat> {
@@ -165,7 +403,17 @@ impl FileLike for File {
     }
 
     fn path(&self) -> Cow<'_, str> {
-        path_for(self.inner.location())
+        let loc = self.inner.location();
+        if let Some(memfd) = Memfd::of(loc) {
+            return format!("/{} (deleted)", memfd.name()).into();
+        }
+        if TmpFile::of(loc).is_some_and(|tmp| tmp.is_unnamed(loc))
+            && let Some(dir) = loc.parent()
+        {
+            let ino = loc.inode();
+            return format!("{}/#{ino} (deleted)", path_for(&dir).trim_end_matches('/')).into();
+        }
+        path_for(loc)
     }
 
     fn from_fd(fd: c_int) -> AxResult<Arc<Self>>
@@ -181,6 +429,19 @@ impl FileLike for File {
         })
     }
 }
+impl Drop for File {
+    fn drop(&mut self) {
+        let loc = self.inner.location();
+        release_file_locks(loc, self as *const Self as usize);
+        if !self.inner.is_path() {
+            lease_close(loc, self.inner.flags(), self as *const Self as usize);
+            fuse_put(loc);
+            notify_close(loc, self.inner.flags().contains(FileFlags::WRITE));
+        }
+        notify_put(loc);
+    }
+}
+
 impl Pollable for File {
     fn poll(&self) -> IoEvents {
         self.inner().location().poll()
@@ -195,11 +456,15 @@ impl Pollable for File {
 pub struct Directory {
     inner: Location,
     pub offset: Mutex<u64>,
+    /// Keeps the mount of the directory busy.
+    _mount: MountUse,
 }
 
 impl Directory {
     pub fn new(inner: Location) -> Self {
+        notify_hold(&inner);
         Self {
+            _mount: MountUse::new(&inner),
             inner,
             offset: Mutex::new(0),
         }
@@ -234,6 +499,15 @@ impl FileLike for Directory {
             .map_err(|_| AxError::NotADirectory)
     }
 }
+impl Drop for Directory {
+    fn drop(&mut self) {
+        release_file_locks(&self.inner, self as *const Self as usize);
+        release_dnotify(&self.inner, self as *const Self as usize);
+        notify_close(&self.inner, false);
+        notify_put(&self.inner);
+    }
+}
+
 impl Pollable for Directory {
     fn poll(&self) -> IoEvents {
         IoEvents::IN | IoEvents::OUT
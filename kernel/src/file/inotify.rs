@@ -0,0 +1,325 @@
+//! inotify file descriptors.
+
+use alloc::{
+    borrow::Cow,
+    collections::{btree_map::BTreeMap, vec_deque::VecDeque},
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    mem, ptr,
+    sync::atomic::{AtomicBool, Ordering},
+    task::Context,
+};
+
+use axerrno::{AxError, AxResult};
+use axfs_ng_vfs::Location;
+use axpoll::{IoEvents, PollSet, Pollable};
+use axtask::future::{block_on, poll_io};
+use kspin::SpinNoIrq;
+use linux_raw_sys::general::{
+    IN_ALL_EVENTS, IN_DELETE_SELF, IN_IGNORED, IN_ISDIR, IN_MASK_ADD, IN_MASK_CREATE, IN_ONESHOT,
+    IN_Q_OVERFLOW, inotify_event,
+};
+
+use crate::file::{FileLike, IoDst};
+
+/// Maximum number of events queued on an inotify instance, as the default
+/// of `/proc/sys/fs/inotify/max_queued_events`.
+const MAX_QUEUED_EVENTS: usize = 16384;
+
+/// Identifies an inode across the entries that may refer to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct InodeKey {
+    fs: usize,
+    ino: u64,
+}
+
+impl InodeKey {
+    pub(crate) fn of(loc: &Location) -> Self {
+        Self {
+            fs: loc.filesystem() as *const _ as *const () as usize,
+            ino: loc.inode(),
+        }
+    }
+}
+
+/// A watch registered on an inode: the instance and the watch descriptor.
+type Watcher = (Weak<Inotify>, i32);
+
+/// All watches, indexed by the inode they are on.
+static WATCHES: SpinNoIrq<BTreeMap<InodeKey, Vec<Watcher>>> = SpinNoIrq::new(BTreeMap::new());
+
+struct Watch {
+    /// Keeps the watched entry alive.
+    loc: Location,
+    mask: u32,
+}
+
+struct Event {
+    wd: i32,
+    mask: u32,
+    cookie: u32,
+    name: Option<String>,
+}
+
+impl Event {
+    /// Length of the name field, including the terminating NUL and padding.
+    fn name_len(&self) -> usize {
+        self.name.as_ref().map_or(0, |name| {
+            (name.len() + 1).next_multiple_of(size_of::<inotify_event>())
+        })
+    }
+
+    fn size(&self) -> usize {
+        size_of::<inotify_event>() + self.name_len()
+    }
+}
+
+#[derive(Default)]
+struct InotifyInner {
+    watches: BTreeMap<i32, Watch>,
+    next_wd: i32,
+    events: VecDeque<Event>,
+    overflowed: bool,
+}
+
+impl InotifyInner {
+    fn push(&mut self, event: Event) {
+        if let Some(last) = self.events.back()
+            && last.wd == event.wd
+            && last.mask == event.mask
+            && last.cookie == event.cookie
+            && last.name == event.name
+        {
+            return;
+        }
+        if self.events.len() >= MAX_QUEUED_EVENTS {
+            if !self.overflowed {
+                self.overflowed = true;
+                self.events.push_back(Event {
+                    wd: -1,
+                    mask: IN_Q_OVERFLOW,
+                    cookie: 0,
+                    name: None,
+                });
+            }
+            return;
+        }
+        self.events.push_back(event);
+    }
+
+    /// Removes the watch `wd` and queues `IN_IGNORED` for it.
+    fn remove_watch(&mut self, wd: i32) -> Option<Watch> {
+        let watch = self.watches.remove(&wd)?;
+        self.push(Event {
+            wd,
+            mask: IN_IGNORED,
+            cookie: 0,
+            name: None,
+        });
+        Some(watch)
+    }
+}
+
+/// An inotify instance.
+pub struct Inotify {
+    inner: SpinNoIrq<InotifyInner>,
+    non_blocking: AtomicBool,
+    poll_rx: PollSet,
+}
+
+impl Inotify {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            inner: SpinNoIrq::new(InotifyInner {
+                next_wd: 1,
+                ..Default::default()
+            }),
+            non_blocking: AtomicBool::new(false),
+            poll_rx: PollSet::new(),
+        })
+    }
+
+    /// Adds a watch for `loc`, or modifies the existing one, and returns its
+    /// watch descriptor.
+    pub fn add_watch(self: &Arc<Self>, loc: Location, mask: u32) -> AxResult<i32> {
+        let key = InodeKey::of(&loc);
+        let mut watches = WATCHES.lock();
+        let mut inner = self.inner.lock();
+        let existing = inner
+            .watches
+            .iter()
+            .find(|(_, it)| InodeKey::of(&it.loc) == key)
+            .map(|(wd, _)| *wd);
+        if let Some(wd) = existing {
+            if mask & IN_MASK_CREATE != 0 {
+                return Err(AxError::AlreadyExists);
+            }
+            let watch = inner.watches.get_mut(&wd).unwrap();
+            if mask & IN_MASK_ADD != 0 {
+                watch.mask |= mask;
+            } else {
+                watch.mask = mask;
+            }
+            return Ok(wd);
+        }
+
+        let wd = inner.next_wd;
+        inner.next_wd += 1;
+        inner.watches.insert(wd, Watch { loc, mask });
+        watches
+            .entry(key)
+            .or_default()
+            .push((Arc::downgrade(self), wd));
+        Ok(wd)
+    }
+
+    /// Removes the watch `wd`.
+    pub fn rm_watch(&self, wd: i32) -> AxResult<()> {
+        let watch = self
+            .inner
+            .lock()
+            .remove_watch(wd)
+            .ok_or(AxError::InvalidInput)?;
+        unregister(InodeKey::of(&watch.loc), self, wd);
+        self.poll_rx.wake();
+        Ok(())
+    }
+
+    /// Queues an event for the watch `wd` if it is interested in `mask`.
+    fn handle(&self, wd: i32, mask: u32, cookie: u32, name: Option<&str>) {
+        let mut inner = self.inner.lock();
+        let Some(watch) = inner.watches.get(&wd) else {
+            return;
+        };
+        let interested = mask & watch.mask & IN_ALL_EVENTS != 0;
+        // The watch ends with the watched inode, or after its only event.
+        let remove = mask & IN_DELETE_SELF != 0 || (interested && watch.mask & IN_ONESHOT != 0);
+        if interested {
+            inner.push(Event {
+                wd,
+                mask,
+                cookie,
+                name: name.map(ToString::to_string),
+            });
+        }
+        let removed = if remove { inner.remove_watch(wd) } else { None };
+        drop(inner);
+        if let Some(watch) = removed {
+            unregister(InodeKey::of(&watch.loc), self, wd);
+        }
+        if interested || remove {
+            self.poll_rx.wake();
+        }
+    }
+}
+
+fn unregister(key: InodeKey, inotify: &Inotify, wd: i32) {
+    let mut watches = WATCHES.lock();
+    if let Some(list) = watches.get_mut(&key) {
+        list.retain(|(it, it_wd)| !(ptr::eq(it.as_ptr(), inotify) && *it_wd == wd));
+        if list.is_empty() {
+            watches.remove(&key);
+        }
+    }
+}
+
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        let inner = self.inner.get_mut();
+        for (wd, watch) in mem::take(&mut inner.watches) {
+            unregister(InodeKey::of(&watch.loc), self, wd);
+        }
+    }
+}
+
+/// Reports an event on `loc` to the inotify watches on it.
+///
+/// `name` is the name of the child the event happened to, if `loc` is a
+/// directory reporting an event of one of its entries.
+pub fn report_event(loc: &Location, mut mask: u32, cookie: u32, name: Option<&str>) {
+    let watchers = {
+        let watches = WATCHES.lock();
+        if watches.is_empty() {
+            return;
+        }
+        match watches.get(&InodeKey::of(loc)) {
+            Some(list) => list.clone(),
+            None => return,
+        }
+    };
+    if name.is_none() && loc.is_dir() {
+        mask |= IN_ISDIR;
+    }
+    for (inotify, wd) in watchers {
+        if let Some(inotify) = inotify.upgrade() {
+            inotify.handle(wd, mask, cookie, name);
+        }
+    }
+}
+
+impl FileLike for Inotify {
+    fn read(&self, dst: &mut IoDst) -> AxResult<usize> {
+        block_on(poll_io(self, IoEvents::IN, self.nonblocking(), || {
+            let mut inner = self.inner.lock();
+            let Some(first) = inner.events.front() else {
+                return Err(AxError::WouldBlock);
+            };
+            if first.size() > dst.remaining_mut() {
+                return Err(AxError::InvalidInput);
+            }
+            let mut read = 0;
+            while let Some(event) = inner.events.front() {
+                let name_len = event.name_len();
+                if event.size() > dst.remaining_mut() {
+                    break;
+                }
+                let mut buf = Vec::with_capacity(event.size());
+                buf.extend_from_slice(&event.wd.to_ne_bytes());
+                buf.extend_from_slice(&event.mask.to_ne_bytes());
+                buf.extend_from_slice(&event.cookie.to_ne_bytes());
+                buf.extend_from_slice(&(name_len as u32).to_ne_bytes());
+                if let Some(name) = &event.name {
+                    buf.extend_from_slice(name.as_bytes());
+                }
+                buf.resize(event.size(), 0);
+                dst.write(&buf)?;
+                read += event.size();
+                if event.mask == IN_Q_OVERFLOW {
+                    inner.overflowed = false;
+                }
+                inner.events.pop_front();
+            }
+            Ok(read)
+        }))
+    }
+
+    fn nonblocking(&self) -> bool {
+        self.non_blocking.load(Ordering::Acquire)
+    }
+
+    fn set_nonblocking(&self, non_blocking: bool) -> AxResult {
+        self.non_blocking.store(non_blocking, Ordering::Release);
+        Ok(())
+    }
+
+    fn path(&self) -> Cow<'_, str> {
+        "anon_inode:inotify".into()
+    }
+}
+
+impl Pollable for Inotify {
+    fn poll(&self) -> IoEvents {
+        let mut events = IoEvents::empty();
+        events.set(IoEvents::IN, !self.inner.lock().events.is_empty());
+        events
+    }
+
+    fn register(&self, context: &mut Context<'_>, events: IoEvents) {
+        if events.contains(IoEvents::IN) {
+            self.poll_rx.register(context.waker());
+        }
+    }
+}
@@ -0,0 +1,687 @@
+//! io_uring instances.
+
+use alloc::{
+    borrow::Cow,
+    boxed::Box,
+    collections::vec_deque::VecDeque,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    future::{Future, poll_fn},
+    pin::Pin,
+    ptr,
+    sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axhal::{mem::phys_to_virt, paging::PageSize};
+use axpoll::{IoEvents, PollSet, Pollable};
+use axtask::{
+    AxTaskExt, AxTaskRef, TaskInner, current,
+    future::{block_on, interruptible},
+    spawn_task,
+};
+use kspin::SpinNoIrq;
+use linux_raw_sys::io_uring::{
+    IORING_FEAT_CQE_SKIP, IORING_FEAT_NODROP, IORING_FEAT_RW_CUR_POS, IORING_FEAT_SINGLE_MMAP,
+    IORING_FEAT_SUBMIT_STABLE, IORING_OFF_CQ_RING, IORING_OFF_SQ_RING, IORING_OFF_SQES,
+    IORING_SQ_CQ_OVERFLOW, io_cqring_offsets, io_sqring_offsets, io_uring_params,
+};
+use memory_addr::PAGE_SIZE_4K;
+use spin::Mutex;
+
+use crate::{
+    file::FileLike,
+    mm::SharedPages,
+    pseudofs::DeviceMmap,
+    task::{AsThread, Thread},
+};
+
+// Layout of the ring region, which holds both the submission and the
+// completion queue.
+const SQ_HEAD: usize = 0;
+const SQ_TAIL: usize = 4;
+const SQ_RING_MASK: usize = 8;
+const SQ_RING_ENTRIES: usize = 12;
+const SQ_FLAGS: usize = 16;
+const SQ_DROPPED: usize = 20;
+const CQ_HEAD: usize = 64;
+const CQ_TAIL: usize = 68;
+const CQ_RING_MASK: usize = 72;
+const CQ_RING_ENTRIES: usize = 76;
+const CQ_OVERFLOW: usize = 80;
+const CQ_FLAGS: usize = 84;
+const SQ_ARRAY: usize = 128;
+
+const SQE_SIZE: usize = 64;
+const CQE_SIZE: usize = 16;
+
+/// Maximum number of submission queue entries.
+pub const MAX_SQ_ENTRIES: u32 = 32768;
+/// Maximum number of completion queue entries.
+pub const MAX_CQ_ENTRIES: u32 = 2 * MAX_SQ_ENTRIES;
+
+/// Maximum number of worker tasks of a ring.
+const MAX_WORKERS: usize = 4;
+
+/// Memory shared with user space, accessed through the kernel mapping of
+/// its pages.
+struct RingMemory(Arc<SharedPages>);
+
+impl RingMemory {
+    fn new(size: usize) -> AxResult<Self> {
+        Ok(Self(Arc::new(SharedPages::new(size, PageSize::Size4K)?)))
+    }
+
+    /// Returns a pointer to `offset`. Accesses through it must not cross a
+    /// page boundary.
+    fn ptr<T>(&self, offset: usize) -> *mut T {
+        let page = self.0[offset / PAGE_SIZE_4K];
+        (phys_to_virt(page).as_usize() + offset % PAGE_SIZE_4K) as *mut T
+    }
+
+    fn atomic(&self, offset: usize) -> &AtomicU32 {
+        // SAFETY: the offset is aligned and within the pages, which live as
+        // long as `self`.
+        unsafe { AtomicU32::from_ptr(self.ptr(offset)) }
+    }
+}
+
+/// A submission queue entry.
+#[derive(Debug, Clone, Copy)]
+pub struct Sqe {
+    pub opcode: u8,
+    pub flags: u8,
+    /// The I/O priority, or flags for some operations.
+    pub ioprio: u16,
+    pub fd: i32,
+    /// The file offset, or a second address for some operations.
+    pub off: u64,
+    pub addr: u64,
+    pub len: u32,
+    /// The flags specific to the operation.
+    pub op_flags: u32,
+    pub user_data: u64,
+    pub buf_index: u16,
+    pub file_index: u32,
+}
+
+impl Sqe {
+    fn parse(buf: &[u8; SQE_SIZE]) -> Self {
+        let u16_at = |off: usize| u16::from_ne_bytes(buf[off..off + 2].try_into().unwrap());
+        let u32_at = |off: usize| u32::from_ne_bytes(buf[off..off + 4].try_into().unwrap());
+        let u64_at = |off: usize| u64::from_ne_bytes(buf[off..off + 8].try_into().unwrap());
+        Self {
+            opcode: buf[0],
+            flags: buf[1],
+            ioprio: u16_at(2),
+            fd: u32_at(4) as i32,
+            off: u64_at(8),
+            addr: u64_at(16),
+            len: u32_at(24),
+            op_flags: u32_at(28),
+            user_data: u64_at(32),
+            buf_index: u16_at(40),
+            file_index: u32_at(44),
+        }
+    }
+}
+
+/// The kind of a request, which determines the operations that can cancel
+/// it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestKind {
+    Poll,
+    Timeout,
+    Other,
+}
+
+/// The future of a request, which outputs the result of its completion.
+pub type RequestFuture = Pin<Box<dyn Future<Output = i32> + Send>>;
+
+/// A request in flight.
+struct Request {
+    id: u64,
+    user_data: u64,
+    kind: RequestKind,
+    /// Whether a successful completion posts no completion queue entry.
+    skip_success: bool,
+    /// Whether a completion with `-ETIME` counts as successful.
+    etime_success: bool,
+    /// Whether the request may block while it runs, so it is left to the
+    /// workers.
+    blocking: bool,
+    /// The future of the request, unless it is being run.
+    future: Option<RequestFuture>,
+    /// Whether the request was woken while it was being run.
+    woken: bool,
+    /// The worker running the blocking request, if any.
+    worker: Option<AxTaskRef>,
+}
+
+/// Queues a request to be run again when woken.
+struct RequestWaker {
+    ring: Weak<IoUring>,
+    id: u64,
+}
+
+impl alloc::task::Wake for RequestWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if let Some(ring) = self.ring.upgrade() {
+            ring.schedule(self.id);
+        }
+    }
+}
+
+/// An io_uring instance.
+///
+/// Requests are first run by the task submitting them, and those that have
+/// to wait are run again by the worker tasks of the ring once woken. Those
+/// that may block while they run, e.g. on disk I/O, are left to the workers
+/// from the start, so that they do not hold up the others. The number of
+/// workers is bounded, and they serve the process that set the ring up.
+pub struct IoUring {
+    rings: RingMemory,
+    sqes: RingMemory,
+    sq_entries: u32,
+    cq_entries: u32,
+    cqes: usize,
+    /// Serializes consumers of the submission queue.
+    sq_lock: Mutex<()>,
+    /// Completions that did not fit in the completion queue. Also serializes
+    /// producers of the completion queue.
+    overflow: Mutex<VecDeque<(u64, i32)>>,
+    requests: SpinNoIrq<Vec<Request>>,
+    next_id: AtomicU64,
+    /// The requests to be run by the workers.
+    runnable: SpinNoIrq<VecDeque<u64>>,
+    /// Woken when requests become runnable.
+    work_rx: Arc<PollSet>,
+    /// Number of worker tasks spawned.
+    workers: AtomicUsize,
+    /// Number of completions posted.
+    completed: AtomicU64,
+    /// Buffers registered for fixed reads and writes, as address and length.
+    buffers: Mutex<Option<Vec<(usize, usize)>>>,
+    /// Woken on completions.
+    poll_rx: Arc<PollSet>,
+}
+
+impl IoUring {
+    /// Creates an instance with the given queue sizes, which must be powers
+    /// of two within the limits.
+    pub fn new(sq_entries: u32, cq_entries: u32) -> AxResult<Arc<Self>> {
+        debug_assert!(sq_entries.is_power_of_two() && sq_entries <= MAX_SQ_ENTRIES);
+        debug_assert!(cq_entries.is_power_of_two() && cq_entries <= MAX_CQ_ENTRIES);
+
+        let cqes = (SQ_ARRAY + sq_entries as usize * size_of::<u32>()).next_multiple_of(SQE_SIZE);
+        let rings = RingMemory::new(cqes + cq_entries as usize * CQE_SIZE)?;
+        let sqes = RingMemory::new(sq_entries as usize * SQE_SIZE)?;
+        rings
+            .atomic(SQ_RING_MASK)
+            .store(sq_entries - 1, Ordering::Relaxed);
+        rings
+            .atomic(SQ_RING_ENTRIES)
+            .store(sq_entries, Ordering::Relaxed);
+        rings
+            .atomic(CQ_RING_MASK)
+            .store(cq_entries - 1, Ordering::Relaxed);
+        rings
+            .atomic(CQ_RING_ENTRIES)
+            .store(cq_entries, Ordering::Relaxed);
+
+        Ok(Arc::new(Self {
+            rings,
+            sqes,
+            sq_entries,
+            cq_entries,
+            cqes,
+            sq_lock: Mutex::new(()),
+            overflow: Mutex::new(VecDeque::new()),
+            requests: SpinNoIrq::new(Vec::new()),
+            next_id: AtomicU64::new(0),
+            runnable: SpinNoIrq::new(VecDeque::new()),
+            work_rx: Arc::new(PollSet::new()),
+            workers: AtomicUsize::new(0),
+            completed: AtomicU64::new(0),
+            buffers: Mutex::new(None),
+            poll_rx: Arc::new(PollSet::new()),
+        }))
+    }
+
+    /// Fills in the queue sizes, the features and the layout of the rings.
+    pub fn fill_params(&self, params: &mut io_uring_params) {
+        params.sq_entries = self.sq_entries;
+        params.cq_entries = self.cq_entries;
+        params.features = IORING_FEAT_SINGLE_MMAP
+            | IORING_FEAT_NODROP
+            | IORING_FEAT_SUBMIT_STABLE
+            | IORING_FEAT_RW_CUR_POS
+            | IORING_FEAT_CQE_SKIP;
+        params.sq_off = io_sqring_offsets {
+            head: SQ_HEAD as _,
+            tail: SQ_TAIL as _,
+            ring_mask: SQ_RING_MASK as _,
+            ring_entries: SQ_RING_ENTRIES as _,
+            flags: SQ_FLAGS as _,
+            dropped: SQ_DROPPED as _,
+            array: SQ_ARRAY as _,
+            resv1: 0,
+            user_addr: 0,
+        };
+        params.cq_off = io_cqring_offsets {
+            head: CQ_HEAD as _,
+            tail: CQ_TAIL as _,
+            ring_mask: CQ_RING_MASK as _,
+            ring_entries: CQ_RING_ENTRIES as _,
+            overflow: CQ_OVERFLOW as _,
+            cqes: self.cqes as _,
+            flags: CQ_FLAGS as _,
+            resv1: 0,
+            user_addr: 0,
+        };
+    }
+
+    /// Takes up to `max` entries from the submission queue.
+    ///
+    /// Entries with an invalid index are dropped.
+    pub fn take_sqes(&self, max: u32) -> Vec<Sqe> {
+        let _guard = self.sq_lock.lock();
+        let head = self.rings.atomic(SQ_HEAD).load(Ordering::Relaxed);
+        let tail = self.rings.atomic(SQ_TAIL).load(Ordering::Acquire);
+        let count = tail.wrapping_sub(head).min(self.sq_entries).min(max);
+
+        let mut sqes = Vec::with_capacity(count as usize);
+        for i in 0..count {
+            let slot = head.wrapping_add(i) & (self.sq_entries - 1);
+            let index = self
+                .rings
+                .atomic(SQ_ARRAY + slot as usize * size_of::<u32>())
+                .load(Ordering::Relaxed);
+            if index >= self.sq_entries {
+                self.rings
+                    .atomic(SQ_DROPPED)
+                    .fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            let mut buf = [0; SQE_SIZE];
+            // SAFETY: entries are aligned to their size, so none crosses a page
+            // boundary.
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    self.sqes.ptr::<u8>(index as usize * SQE_SIZE),
+                    buf.as_mut_ptr(),
+                    SQE_SIZE,
+                );
+            }
+            sqes.push(Sqe::parse(&buf));
+        }
+        self.rings
+            .atomic(SQ_HEAD)
+            .store(head.wrapping_add(count), Ordering::Release);
+        sqes
+    }
+
+    /// Writes an entry to the completion queue, returning `false` if it is
+    /// full. Must be called with `overflow` locked.
+    fn push_cqe(&self, user_data: u64, res: i32) -> bool {
+        let head = self.rings.atomic(CQ_HEAD).load(Ordering::Acquire);
+        let tail = self.rings.atomic(CQ_TAIL).load(Ordering::Relaxed);
+        if tail.wrapping_sub(head) >= self.cq_entries {
+            return false;
+        }
+        let offset = self.cqes + (tail & (self.cq_entries - 1)) as usize * CQE_SIZE;
+        // SAFETY: entries are aligned to their size, so none crosses a page
+        // boundary.
+        unsafe {
+            self.rings.ptr::<u64>(offset).write(user_data);
+            self.rings.ptr::<i32>(offset + 8).write(res);
+            self.rings.ptr::<u32>(offset + 12).write(0);
+        }
+        self.rings
+            .atomic(CQ_TAIL)
+            .store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Moves overflowed completions to the completion queue as space allows.
+    fn flush_overflow(&self, overflow: &mut VecDeque<(u64, i32)>) {
+        while let Some(&(user_data, res)) = overflow.front() {
+            if !self.push_cqe(user_data, res) {
+                return;
+            }
+            overflow.pop_front();
+        }
+        self.rings
+            .atomic(SQ_FLAGS)
+            .fetch_and(!IORING_SQ_CQ_OVERFLOW, Ordering::Relaxed);
+    }
+
+    /// Posts a completion with the result `res`.
+    pub fn post(&self, user_data: u64, res: i32) {
+        let mut overflow = self.overflow.lock();
+        self.flush_overflow(&mut overflow);
+        if !overflow.is_empty() || !self.push_cqe(user_data, res) {
+            overflow.push_back((user_data, res));
+            self.rings
+                .atomic(SQ_FLAGS)
+                .fetch_or(IORING_SQ_CQ_OVERFLOW, Ordering::Relaxed);
+        }
+        drop(overflow);
+        self.completed.fetch_add(1, Ordering::Release);
+        self.poll_rx.wake();
+    }
+
+    /// Returns the number of completions posted so far.
+    pub fn completed(&self) -> u64 {
+        self.completed.load(Ordering::Acquire)
+    }
+
+    /// Returns the number of completions not yet consumed by user space.
+    fn pending_completions(&self) -> usize {
+        let mut overflow = self.overflow.lock();
+        self.flush_overflow(&mut overflow);
+        let tail = self.rings.atomic(CQ_TAIL).load(Ordering::Relaxed);
+        let head = self.rings.atomic(CQ_HEAD).load(Ordering::Acquire);
+        tail.wrapping_sub(head) as usize + overflow.len()
+    }
+
+    /// Starts a request that completes with the output of `future`.
+    ///
+    /// The request is run right away unless it is `blocking`, in which case
+    /// it is left to the workers. With `etime_success`, a completion with
+    /// `-ETIME` counts as successful and is skipped along with the others.
+    pub fn submit(
+        self: &Arc<Self>,
+        user_data: u64,
+        kind: RequestKind,
+        skip_success: bool,
+        etime_success: bool,
+        blocking: bool,
+        future: RequestFuture,
+    ) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.requests.lock().push(Request {
+            id,
+            user_data,
+            kind,
+            skip_success,
+            etime_success,
+            blocking,
+            future: Some(future),
+            woken: false,
+            worker: None,
+        });
+        if blocking {
+            self.schedule(id);
+        } else {
+            self.run(id);
+        }
+
+        // Requests left pending need a worker, and each blocking one may hold
+        // up a worker of its own.
+        let requests = self.requests.lock();
+        let blocking = requests.iter().filter(|it| it.blocking).count();
+        let wanted = (blocking + 1).min(MAX_WORKERS);
+        let pending = !requests.is_empty();
+        drop(requests);
+        while pending && self.workers.load(Ordering::Relaxed) < wanted {
+            self.workers.fetch_add(1, Ordering::Relaxed);
+            self.spawn_worker();
+        }
+    }
+
+    /// Queues the request `id` to be run by the workers.
+    fn schedule(&self, id: u64) {
+        let mut requests = self.requests.lock();
+        let Some(request) = requests.iter_mut().find(|it| it.id == id) else {
+            return;
+        };
+        request.woken = true;
+        drop(requests);
+        let mut runnable = self.runnable.lock();
+        if !runnable.contains(&id) {
+            runnable.push_back(id);
+        }
+        drop(runnable);
+        self.work_rx.wake();
+    }
+
+    /// Runs the request `id` on the current task, until it completes or, if
+    /// it does not block, until it has to wait.
+    fn run(self: &Arc<Self>, id: u64) {
+        let mut requests = self.requests.lock();
+        let Some(request) = requests.iter_mut().find(|it| it.id == id) else {
+            return;
+        };
+        // Already being run, which is told to run it again.
+        let Some(mut future) = request.future.take() else {
+            return;
+        };
+        request.woken = false;
+        let blocking = request.blocking;
+        if blocking {
+            request.worker = Some(current().clone());
+        }
+        drop(requests);
+
+        let res = if blocking {
+            block_on(interruptible(future)).unwrap_or_else(|_| -LinuxError::ECANCELED.code())
+        } else {
+            let waker = Waker::from(Arc::new(RequestWaker {
+                ring: Arc::downgrade(self),
+                id,
+            }));
+            match future.as_mut().poll(&mut Context::from_waker(&waker)) {
+                Poll::Ready(res) => res,
+                Poll::Pending => {
+                    let mut requests = self.requests.lock();
+                    // Dropped here if canceled meanwhile.
+                    let Some(request) = requests.iter_mut().find(|it| it.id == id) else {
+                        return;
+                    };
+                    request.future = Some(future);
+                    let woken = request.woken;
+                    drop(requests);
+                    if woken {
+                        self.schedule(id);
+                    }
+                    return;
+                }
+            }
+        };
+        self.complete(id, res);
+    }
+
+    /// Spawns a worker task running the requests queued on the ring, in the
+    /// context of the current process.
+    ///
+    /// Like io-wq workers in Linux, it shares the file table and the address
+    /// space of the process without being one of its threads. It stops once
+    /// the ring is gone or the process has exited.
+    fn spawn_worker(self: &Arc<Self>) {
+        let curr = current();
+        let proc_data = curr.as_thread().proc_data.clone();
+
+        let ring = Arc::downgrade(self);
+        let work_rx = self.work_rx.clone();
+        let exit_event = proc_data.exit_event.clone();
+        let mut task = TaskInner::new(
+            move || {
+                loop {
+                    let mut next = None;
+                    block_on(poll_fn(|cx| {
+                        work_rx.register(cx.waker());
+                        exit_event.register(cx.waker());
+                        let Some(ring) = ring.upgrade() else {
+                            return Poll::Ready(());
+                        };
+                        if current().as_thread().proc_data.proc.is_zombie() {
+                            return Poll::Ready(());
+                        }
+                        next = ring
+                            .runnable
+                            .lock()
+                            .pop_front()
+                            .map(|id| (ring.clone(), id));
+                        if next.is_some() {
+                            Poll::Ready(())
+                        } else {
+                            Poll::Pending
+                        }
+                    }));
+                    let Some((ring, id)) = next else {
+                        break;
+                    };
+                    ring.run(id);
+                }
+            },
+            "io_uring-worker".into(),
+            crate::config::KERNEL_STACK_SIZE,
+        );
+        let id = task.id().as_u64();
+        task.ctx_mut()
+            .set_page_table_root(proc_data.aspace.lock().page_table_root());
+        *task.task_ext_mut() = Some(AxTaskExt::from_impl(Thread::new(id as _, proc_data)));
+        spawn_task(task);
+    }
+
+    /// Records the completion of the request `id`, unless it was canceled.
+    fn complete(&self, id: u64, res: i32) {
+        let mut requests = self.requests.lock();
+        let Some(index) = requests.iter().position(|it| it.id == id) else {
+            return;
+        };
+        let request = requests.remove(index);
+        drop(requests);
+
+        let success = res >= 0 || (request.etime_success && res == -LinuxError::ETIME.code());
+        if request.skip_success && success {
+            self.completed.fetch_add(1, Ordering::Release);
+            self.poll_rx.wake();
+        } else {
+            self.post(request.user_data, res);
+        }
+    }
+
+    /// Cancels the pending request `user_data`, if it is of `kind`.
+    ///
+    /// Returns whether a request was canceled.
+    pub fn cancel(&self, user_data: u64, kind: Option<RequestKind>) -> bool {
+        let mut requests = self.requests.lock();
+        let Some(index) = requests
+            .iter()
+            .position(|it| it.user_data == user_data && kind.is_none_or(|kind| it.kind == kind))
+        else {
+            return false;
+        };
+        let request = requests.remove(index);
+        drop(requests);
+        if let Some(worker) = &request.worker {
+            worker.interrupt();
+        }
+        drop(request);
+        self.post(user_data, -LinuxError::ECANCELED.code());
+        true
+    }
+
+    /// Waits until at least `min_complete` completions are available to user
+    /// space.
+    pub fn wait(&self, min_complete: u32) -> AxResult<()> {
+        block_on(interruptible(poll_fn(|cx| {
+            self.poll_rx.register(cx.waker());
+            if self.pending_completions() >= min_complete as usize {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })))?;
+        Ok(())
+    }
+
+    /// Registers buffers for fixed reads and writes.
+    pub fn register_buffers(&self, buffers: Vec<(usize, usize)>) -> AxResult<()> {
+        let mut current = self.buffers.lock();
+        if current.is_some() {
+            return Err(AxError::ResourceBusy);
+        }
+        *current = Some(buffers);
+        Ok(())
+    }
+
+    /// Unregisters the buffers for fixed reads and writes.
+    pub fn unregister_buffers(&self) -> AxResult<()> {
+        self.buffers
+            .lock()
+            .take()
+            .map(drop)
+            .ok_or(AxError::from(LinuxError::ENXIO))
+    }
+
+    /// Checks that `[addr, addr + len)` lies within the registered buffer
+    /// `index`.
+    pub fn check_fixed_buffer(&self, index: u16, addr: usize, len: usize) -> AxResult<()> {
+        let buffers = self.buffers.lock();
+        let &(start, size) = buffers
+            .as_ref()
+            .and_then(|it| it.get(index as usize))
+            .ok_or(AxError::BadAddress)?;
+        let end = addr.checked_add(len).ok_or(AxError::BadAddress)?;
+        if addr < start || end > start + size {
+            return Err(AxError::BadAddress);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for IoUring {
+    fn drop(&mut self) {
+        // Requests are canceled when the ring goes away, and the workers stop.
+        for request in self.requests.get_mut().drain(..) {
+            if let Some(worker) = request.worker {
+                worker.interrupt();
+            }
+        }
+        self.work_rx.wake();
+    }
+}
+
+impl FileLike for IoUring {
+    fn path(&self) -> Cow<'_, str> {
+        "anon_inode:[io_uring]".into()
+    }
+
+    fn mmap(&self, offset: u64) -> AxResult<DeviceMmap> {
+        // With `IORING_FEAT_SINGLE_MMAP`, both queues live in the same region.
+        match u32::try_from(offset) {
+            Ok(IORING_OFF_SQ_RING | IORING_OFF_CQ_RING) => {
+                Ok(DeviceMmap::Shared(self.rings.0.clone()))
+            }
+            Ok(IORING_OFF_SQES) => Ok(DeviceMmap::Shared(self.sqes.0.clone())),
+            _ => Err(AxError::InvalidInput),
+        }
+    }
+}
+
+impl Pollable for IoUring {
+    fn poll(&self) -> IoEvents {
+        let mut events = IoEvents::empty();
+        events.set(IoEvents::IN, self.pending_completions() > 0);
+        let head = self.rings.atomic(SQ_HEAD).load(Ordering::Relaxed);
+        let tail = self.rings.atomic(SQ_TAIL).load(Ordering::Acquire);
+        events.set(IoEvents::OUT, tail.wrapping_sub(head) < self.sq_entries);
+        events
+    }
+
+    fn register(&self, context: &mut Context<'_>, events: IoEvents) {
+        if events.intersects(IoEvents::IN | IoEvents::OUT) {
+            self.poll_rx.register(context.waker());
+        }
+    }
+}
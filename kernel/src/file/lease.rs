@@ -0,0 +1,286 @@
+//! File leases.
+//!
+//! A lease lets the holder of an open file description learn about other
+//! opens of the file before they happen: a conflicting `open` or `truncate`
+//! signals the holder and waits until it gives the lease up (or downgrades a
+//! write lease to a read lease), but at most for
+//! `/proc/sys/fs/lease-break-time` seconds.
+
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    ffi::c_int,
+    future::poll_fn,
+    sync::atomic::{AtomicU64, Ordering},
+    task::Poll,
+    time::Duration,
+};
+
+use axerrno::{AxError, AxResult};
+use axfs::FileFlags;
+use axfs_ng_vfs::Location;
+use axhal::time::wall_time;
+use axpoll::PollSet;
+use axtask::future::{block_on, interruptible, timeout_at};
+use kspin::SpinNoIrq;
+
+use super::{File, FileLike, fasync::AsyncFile, lock::LockKind};
+
+/// Seconds a lease holder has to release the lease after being signaled,
+/// exposed as `/proc/sys/fs/lease-break-time`.
+pub static LEASE_BREAK_TIME: AtomicU64 = AtomicU64::new(45);
+
+struct Lease {
+    /// The open file description holding the lease, by address.
+    owner: usize,
+    file: Weak<dyn FileLike>,
+    /// The descriptor the lease was set through, reported in `si_fd`.
+    fd: c_int,
+    kind: LockKind,
+    /// While the lease is being broken, the type it has to be downgraded to
+    /// (`None` to remove it) and when that happens anyway.
+    breaking: Option<(Option<LockKind>, Duration)>,
+}
+
+impl Lease {
+    fn conflicts_with_open(&self, write: bool) -> bool {
+        write || self.kind == LockKind::Exclusive
+    }
+}
+
+#[derive(Default)]
+struct LeaseState {
+    /// Number of open file descriptions.
+    opens: usize,
+    /// Number of open file descriptions that can write.
+    writers: usize,
+    leases: Vec<Lease>,
+}
+
+impl LeaseState {
+    /// Forcibly downgrades the leases whose break time is over.
+    fn expire(&mut self, now: Duration) -> bool {
+        let mut changed = false;
+        self.leases.retain_mut(|lease| match lease.breaking {
+            Some((target, deadline)) if deadline <= now => {
+                changed = true;
+                lease.breaking = None;
+                match target {
+                    Some(kind) => {
+                        lease.kind = kind;
+                        true
+                    }
+                    None => false,
+                }
+            }
+            _ => true,
+        });
+        changed
+    }
+
+    fn has_conflict(&self, write: bool) -> bool {
+        self.leases.iter().any(|it| it.conflicts_with_open(write))
+    }
+}
+
+/// The leases of a single inode, stored in the user data of its entry.
+#[derive(Default)]
+struct FileLeases {
+    state: SpinNoIrq<LeaseState>,
+    /// Woken whenever a lease is released or downgraded.
+    event: PollSet,
+}
+
+impl FileLeases {
+    fn of(loc: &Location) -> Arc<Self> {
+        loc.user_data().get_or_insert_with(Self::default)
+    }
+
+    fn try_of(loc: &Location) -> Option<Arc<Self>> {
+        loc.user_data().get::<Self>()
+    }
+}
+
+fn file_key(file: &Arc<dyn FileLike>) -> usize {
+    Arc::as_ptr(file) as *const () as usize
+}
+
+/// Counts an open file description of `loc`, which leases conflict with.
+pub(super) fn lease_open(loc: &Location, flags: FileFlags) {
+    let leases = FileLeases::of(loc);
+    let mut state = leases.state.lock();
+    state.opens += 1;
+    if flags.contains(FileFlags::WRITE) {
+        state.writers += 1;
+    }
+}
+
+/// Undoes [`lease_open`] when the open file description at `addr` is
+/// destroyed, releasing its lease.
+pub(super) fn lease_close(loc: &Location, flags: FileFlags, addr: usize) {
+    let Some(leases) = FileLeases::try_of(loc) else {
+        return;
+    };
+    let mut state = leases.state.lock();
+    state.opens -= 1;
+    if flags.contains(FileFlags::WRITE) {
+        state.writers -= 1;
+    }
+    let len = state.leases.len();
+    state.leases.retain(|it| it.owner != addr);
+    if state.leases.len() != len {
+        leases.event.wake();
+    }
+}
+
+/// Places a lease of `kind` on `loc` for `file`, set through the descriptor
+/// `fd`, or removes its lease if `kind` is `None`.
+pub fn set_lease(
+    loc: &Location,
+    file: &Arc<dyn FileLike>,
+    fd: c_int,
+    kind: Option<LockKind>,
+) -> AxResult<()> {
+    let leases = FileLeases::of(loc);
+    let owner = file_key(file);
+    let mut state = leases.state.lock();
+    state.expire(wall_time());
+    let current = state.leases.iter().position(|it| it.owner == owner);
+
+    let Some(kind) = kind else {
+        if let Some(index) = current {
+            state.leases.remove(index);
+            leases.event.wake();
+        }
+        return Ok(());
+    };
+
+    if let Some(index) = current {
+        let lease = &mut state.leases[index];
+        if let Some((target, _)) = lease.breaking {
+            // A lease being broken can only be downgraded.
+            if kind == LockKind::Exclusive {
+                return Err(AxError::WouldBlock);
+            }
+            if target == Some(kind) {
+                lease.breaking = None;
+            }
+        }
+    }
+    // The holder's own open does not conflict, so that a write lease can be
+    // downgraded through a writable descriptor.
+    let own_writer = file
+        .downcast_ref::<File>()
+        .is_some_and(|it| it.inner().flags().contains(FileFlags::WRITE));
+    let mut others = state.leases.iter().filter(|it| it.owner != owner);
+    let conflict = match kind {
+        LockKind::Shared => {
+            state.writers > own_writer as usize || others.any(|it| it.kind == LockKind::Exclusive)
+        }
+        LockKind::Exclusive => state.opens > 1 || others.next().is_some(),
+    };
+    if conflict {
+        return Err(AxError::WouldBlock);
+    }
+    match current {
+        Some(index) => state.leases[index].kind = kind,
+        None => state.leases.push(Lease {
+            owner,
+            file: Arc::downgrade(file),
+            fd,
+            kind,
+            breaking: None,
+        }),
+    }
+    leases.event.wake();
+    Ok(())
+}
+
+/// Returns the type of the lease `file` holds on `loc`, or the type it has
+/// to be downgraded to while it is being broken.
+pub fn get_lease(loc: &Location, file: &Arc<dyn FileLike>) -> Option<LockKind> {
+    let leases = FileLeases::try_of(loc)?;
+    let owner = file_key(file);
+    let mut state = leases.state.lock();
+    state.expire(wall_time());
+    let lease = state.leases.iter().find(|it| it.owner == owner)?;
+    match lease.breaking {
+        Some((target, _)) => target,
+        None => Some(lease.kind),
+    }
+}
+
+/// Breaks the leases on `loc` that conflict with opening it, for writing if
+/// `write` is set, or truncating it.
+///
+/// The holders are signaled, and unless `nonblocking` is set, this waits
+/// until they have given up their leases or their break time is over.
+pub fn break_lease(loc: &Location, write: bool, nonblocking: bool) -> AxResult<()> {
+    let Some(leases) = FileLeases::try_of(loc) else {
+        return Ok(());
+    };
+    let target = if write { None } else { Some(LockKind::Shared) };
+    let now = wall_time();
+    let break_time = Duration::from_secs(LEASE_BREAK_TIME.load(Ordering::Relaxed));
+
+    let mut holders = Vec::new();
+    let mut deadline = None;
+    {
+        let mut state = leases.state.lock();
+        if state.expire(now) {
+            leases.event.wake();
+        }
+        for lease in state
+            .leases
+            .iter_mut()
+            .filter(|it| it.conflicts_with_open(write))
+        {
+            let end = match &mut lease.breaking {
+                Some((current, end)) => {
+                    if target.is_none() {
+                        *current = None;
+                    }
+                    *end
+                }
+                None => {
+                    let end = now + break_time;
+                    lease.breaking = Some((target, end));
+                    holders.push((lease.file.clone(), lease.fd));
+                    end
+                }
+            };
+            deadline = Some(deadline.map_or(end, |it: Duration| it.max(end)));
+        }
+    }
+    for (file, fd) in holders {
+        if let Some(file) = file.upgrade() {
+            AsyncFile::of(&file).send_message(fd);
+        }
+    }
+
+    let Some(deadline) = deadline else {
+        return Ok(());
+    };
+    if nonblocking {
+        return Err(AxError::WouldBlock);
+    }
+    let wait = poll_fn(|cx| {
+        let mut state = leases.state.lock();
+        state.expire(wall_time());
+        if state.has_conflict(write) {
+            leases.event.register(cx.waker());
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    });
+    if block_on(interruptible(timeout_at(Some(deadline), wait)))?.is_err() {
+        let mut state = leases.state.lock();
+        if state.expire(wall_time()) {
+            leases.event.wake();
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,333 @@
+//! Advisory file locks.
+//!
+//! Two independent kinds of locks are kept per inode, as on Linux:
+//! - `flock` locks, which cover the whole file and belong to an open file
+//!   description;
+//! - record locks, which cover a byte range and belong either to a process
+//!   (traditional POSIX locks) or to an open file description (OFD locks).
+//!   Both kinds of record locks conflict with each other.
+
+use alloc::{collections::btree_map::BTreeMap, sync::Arc, vec, vec::Vec};
+use core::{future::poll_fn, task::Poll};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axfs_ng_vfs::Location;
+use axpoll::PollSet;
+use axtask::{
+    current,
+    future::{block_on, interruptible},
+};
+use kspin::SpinNoIrq;
+use starry_process::Pid;
+
+use super::{Directory, File, FileLike};
+use crate::task::AsThread;
+
+/// The owner of a lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockOwner {
+    /// A process, for POSIX record locks.
+    Process(Pid),
+    /// An open file description, identified by its address, for `flock` and
+    /// OFD locks.
+    File(usize),
+}
+
+impl LockOwner {
+    /// Returns the owner of POSIX record locks taken by the current process.
+    pub fn current_process() -> Self {
+        Self::Process(current().as_thread().proc_data.proc.pid())
+    }
+
+    /// Returns the owner of `flock` and OFD locks taken through `file`.
+    pub fn file(file: &Arc<dyn FileLike>) -> Self {
+        Self::File(Arc::as_ptr(file) as *const () as usize)
+    }
+
+    /// Returns the PID reported by `F_GETLK`.
+    pub fn pid(&self) -> i32 {
+        match self {
+            Self::Process(pid) => *pid as _,
+            Self::File(_) => -1,
+        }
+    }
+}
+
+/// The type of a lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockKind {
+    Shared,
+    Exclusive,
+}
+
+impl LockKind {
+    fn conflicts_with(self, other: Self) -> bool {
+        self == Self::Exclusive || other == Self::Exclusive
+    }
+}
+
+/// A lock on the byte range `start..end` of a file.
+#[derive(Debug, Clone, Copy)]
+pub struct RecordLock {
+    pub owner: LockOwner,
+    pub kind: LockKind,
+    pub start: u64,
+    /// The end of the range (exclusive), where `u64::MAX` extends the range to
+    /// the end of the file however large it grows.
+    pub end: u64,
+}
+
+impl RecordLock {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start < end && start < self.end
+    }
+}
+
+#[derive(Default)]
+struct LockState {
+    flocks: Vec<(LockOwner, LockKind)>,
+    records: Vec<RecordLock>,
+}
+
+impl LockState {
+    fn flock_conflict(&self, owner: LockOwner, kind: LockKind) -> bool {
+        self.flocks
+            .iter()
+            .any(|(it, other)| *it != owner && kind.conflicts_with(*other))
+    }
+
+    fn record_conflict(&self, lock: &RecordLock) -> Option<RecordLock> {
+        self.records
+            .iter()
+            .find(|it| {
+                it.owner != lock.owner
+                    && it.overlaps(lock.start, lock.end)
+                    && lock.kind.conflicts_with(it.kind)
+            })
+            .copied()
+    }
+
+    /// Sets the type of the range `start..end` owned by `owner` to `kind`, or
+    /// unlocks it if `kind` is `None`, splitting and merging existing locks
+    /// as needed.
+    fn apply(&mut self, owner: LockOwner, kind: Option<LockKind>, mut start: u64, mut end: u64) {
+        let mut result = Vec::with_capacity(self.records.len() + 2);
+        for lock in self.records.drain(..) {
+            if lock.owner != owner {
+                result.push(lock);
+            } else if Some(lock.kind) == kind && lock.start <= end && start <= lock.end {
+                // Overlapping or adjacent locks of the same type are merged.
+                start = start.min(lock.start);
+                end = end.max(lock.end);
+            } else if !lock.overlaps(start, end) {
+                result.push(lock);
+            } else {
+                if lock.start < start {
+                    result.push(RecordLock { end: start, ..lock });
+                }
+                if lock.end > end {
+                    result.push(RecordLock { start: end, ..lock });
+                }
+            }
+        }
+        if let Some(kind) = kind {
+            result.push(RecordLock {
+                owner,
+                kind,
+                start,
+                end,
+            });
+        }
+        self.records = result;
+    }
+}
+
+/// The locks of a single inode, stored in the user data of its entry.
+#[derive(Default)]
+struct FileLocks {
+    state: SpinNoIrq<LockState>,
+    /// Woken whenever a lock is released.
+    event: PollSet,
+}
+
+impl FileLocks {
+    fn of(loc: &Location) -> Arc<Self> {
+        loc.user_data().get_or_insert_with(Self::default)
+    }
+
+    /// Returns the locks of `loc` only if some were ever taken.
+    fn try_of(loc: &Location) -> Option<Arc<Self>> {
+        loc.user_data().get::<Self>()
+    }
+
+    /// Runs `f` until it succeeds, waiting for locks to be released in
+    /// between if `wait` is set.
+    fn wait_until(
+        &self,
+        wait: bool,
+        mut f: impl FnMut(&mut LockState) -> AxResult<bool>,
+    ) -> AxResult<()> {
+        block_on(interruptible(poll_fn(|cx| {
+            let mut state = self.state.lock();
+            match f(&mut state) {
+                Ok(true) => Poll::Ready(Ok(())),
+                Ok(false) if wait => {
+                    self.event.register(cx.waker());
+                    Poll::Pending
+                }
+                Ok(false) => Poll::Ready(Err(AxError::WouldBlock)),
+                Err(err) => Poll::Ready(Err(err)),
+            }
+        })))?
+    }
+}
+
+/// The process each blocked thread is waiting for, along with the process
+/// of that thread, for deadlock detection of POSIX record locks.
+///
+/// The values form the multiset of edges between processes: several threads
+/// of a process may be blocked at once, each on its own lock.
+static BLOCKED_ON: SpinNoIrq<BTreeMap<Pid, (Pid, Pid)>> = SpinNoIrq::new(BTreeMap::new());
+
+/// Maximum length of a chain of blocked processes that is followed when
+/// detecting deadlocks, as on Linux.
+const MAX_DEADLOCK_DEPTH: usize = 10;
+
+fn would_deadlock(waiter: Pid, blocker: Pid) -> bool {
+    let blocked_on = BLOCKED_ON.lock();
+    let mut blockers = vec![blocker];
+    for _ in 0..MAX_DEADLOCK_DEPTH {
+        if blockers.contains(&waiter) {
+            return true;
+        }
+        blockers = blocked_on
+            .values()
+            .filter(|(it, _)| blockers.contains(it))
+            .map(|(_, it)| *it)
+            .collect();
+        blockers.sort_unstable();
+        blockers.dedup();
+        if blockers.is_empty() {
+            return false;
+        }
+    }
+    false
+}
+
+/// Returns the inode a lock on `file` applies to.
+pub fn lock_location(file: &Arc<dyn FileLike>) -> AxResult<Location> {
+    if let Some(file) = file.downcast_ref::<File>() {
+        Ok(file.inner().location().clone())
+    } else if let Some(dir) = file.downcast_ref::<Directory>() {
+        Ok(dir.inner().clone())
+    } else {
+        Err(AxError::InvalidInput)
+    }
+}
+
+/// Places a `flock` lock of `kind` on `loc` for the open file description
+/// `owner`, replacing any lock it already holds.
+pub fn flock(loc: &Location, owner: LockOwner, kind: LockKind, wait: bool) -> AxResult<()> {
+    let locks = FileLocks::of(loc);
+    // Converting a lock is not atomic, as on Linux.
+    funlock(loc, owner);
+    locks.wait_until(wait, |state| {
+        if state.flock_conflict(owner, kind) {
+            return Ok(false);
+        }
+        state.flocks.push((owner, kind));
+        Ok(true)
+    })
+}
+
+/// Removes the `flock` lock held by `owner` on `loc`.
+pub fn funlock(loc: &Location, owner: LockOwner) {
+    let Some(locks) = FileLocks::try_of(loc) else {
+        return;
+    };
+    let mut state = locks.state.lock();
+    let len = state.flocks.len();
+    state.flocks.retain(|(it, _)| *it != owner);
+    if state.flocks.len() != len {
+        locks.event.wake();
+    }
+}
+
+/// Returns the first record lock on `loc` that would prevent `lock` from
+/// being placed.
+pub fn test_record_lock(loc: &Location, lock: &RecordLock) -> Option<RecordLock> {
+    FileLocks::try_of(loc)?.state.lock().record_conflict(lock)
+}
+
+/// Places the record lock `lock` on `loc`, or releases the range if `kind`
+/// of `lock` is `None`.
+pub fn set_record_lock(
+    loc: &Location,
+    owner: LockOwner,
+    kind: Option<LockKind>,
+    start: u64,
+    end: u64,
+    wait: bool,
+) -> AxResult<()> {
+    let locks = FileLocks::of(loc);
+    let Some(kind) = kind else {
+        locks.state.lock().apply(owner, None, start, end);
+        locks.event.wake();
+        return Ok(());
+    };
+
+    let lock = RecordLock {
+        owner,
+        kind,
+        start,
+        end,
+    };
+    let tid = current().id().as_u64() as Pid;
+    let result = locks.wait_until(wait, |state| {
+        if let Some(blocker) = state.record_conflict(&lock) {
+            if let (LockOwner::Process(waiter), LockOwner::Process(blocker)) =
+                (owner, blocker.owner)
+                && wait
+            {
+                if would_deadlock(waiter, blocker) {
+                    return Err(AxError::from(LinuxError::EDEADLK));
+                }
+                BLOCKED_ON.lock().insert(tid, (waiter, blocker));
+            }
+            return Ok(false);
+        }
+        // Downgrading a lock may let others in.
+        state.apply(owner, Some(kind), start, end);
+        Ok(true)
+    });
+    if let LockOwner::Process(_) = owner {
+        BLOCKED_ON.lock().remove(&tid);
+    }
+    locks.event.wake();
+    result
+}
+
+/// Releases all record locks held by `owner` on `loc`.
+pub fn release_record_locks(loc: &Location, owner: LockOwner) {
+    if let Some(locks) = FileLocks::try_of(loc) {
+        locks.state.lock().apply(owner, None, 0, u64::MAX);
+        locks.event.wake();
+    }
+}
+
+/// Releases the POSIX record locks of the current process on the file that
+/// `file` refers to, which happens whenever the process closes any file
+/// descriptor of that file.
+pub fn release_posix_locks(file: &Arc<dyn FileLike>) {
+    if let Ok(loc) = lock_location(file) {
+        release_record_locks(&loc, LockOwner::current_process());
+    }
+}
+
+/// Releases all locks held by an open file description that is being
+/// destroyed.
+pub(super) fn release_file_locks(loc: &Location, addr: usize) {
+    let owner = LockOwner::File(addr);
+    funlock(loc, owner);
+    release_record_locks(loc, owner);
+}
@@ -0,0 +1,198 @@
+//! Anonymous memory-backed files created by `memfd_create`, and their seals.
+
+use alloc::{format, string::String, sync::Arc};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use axerrno::{AxError, AxResult};
+use axfs::OpenOptions;
+use axfs_ng_vfs::{Location, Mountpoint, NodePermission, NodeType};
+use kspin::SpinNoIrq;
+use lazy_static::lazy_static;
+use linux_raw_sys::general::{
+    F_SEAL_EXEC, F_SEAL_FUTURE_WRITE, F_SEAL_GROW, F_SEAL_SEAL, F_SEAL_SHRINK, F_SEAL_WRITE,
+};
+
+use crate::pseudofs::MemoryFs;
+
+/// All seals that can be added with `F_ADD_SEALS`.
+const ALL_SEALS: u32 =
+    F_SEAL_SEAL | F_SEAL_SHRINK | F_SEAL_GROW | F_SEAL_WRITE | F_SEAL_FUTURE_WRITE | F_SEAL_EXEC;
+
+lazy_static! {
+    /// The directory memfds are created in. Its filesystem is never mounted,
+    /// and the files are unlinked right away, so they can only be reached
+    /// through their descriptors.
+    static ref MEMFD_ROOT: Location = Mountpoint::new_root(&MemoryFs::new()).root_location();
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The state of a memfd, stored in the user data of its entry.
+pub struct Memfd {
+    name: String,
+    seals: SpinNoIrq<u32>,
+    /// Held by the shared mappings the file can be written through.
+    writers: Arc<()>,
+}
+
+impl Memfd {
+    /// Creates an anonymous file named `name` with the initial `seals`.
+    pub fn create(name: &str, mode: u32, seals: u32) -> AxResult<axfs::File> {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let id = format!("{id}");
+        let loc = MEMFD_ROOT.create(
+            &id,
+            NodeType::RegularFile,
+            NodePermission::from_bits_truncate(mode as _),
+        )?;
+        MEMFD_ROOT.unlink(&id, false)?;
+        loc.user_data().insert(Self {
+            name: format!("memfd:{name}"),
+            seals: SpinNoIrq::new(seals),
+            writers: Arc::new(()),
+        });
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open_loc(loc)?
+            .into_file()
+    }
+
+    /// Returns the memfd state of `loc`, if it is a memfd.
+    pub fn of(loc: &Location) -> Option<Arc<Self>> {
+        loc.user_data().get::<Self>()
+    }
+
+    /// Returns the name shown for the file in `/proc/self/fd`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the current `F_SEAL_*` seals.
+    pub fn seals(&self) -> u32 {
+        *self.seals.lock()
+    }
+
+    /// Adds `seals` to the file, whose permission bits are `mode`.
+    fn add_seals(&self, mut seals: u32, mode: u32) -> AxResult {
+        if seals & !ALL_SEALS != 0 {
+            return Err(AxError::InvalidInput);
+        }
+        let mut current = self.seals.lock();
+        if *current & F_SEAL_SEAL != 0 {
+            return Err(AxError::OperationNotPermitted);
+        }
+        // An executable file can no longer be changed once it cannot be made
+        // non-executable.
+        if seals & F_SEAL_EXEC != 0 && mode & 0o111 != 0 {
+            seals |= F_SEAL_SHRINK | F_SEAL_GROW | F_SEAL_WRITE | F_SEAL_FUTURE_WRITE;
+        }
+        if seals & F_SEAL_WRITE != 0
+            && *current & F_SEAL_WRITE == 0
+            && Arc::strong_count(&self.writers) > 1
+        {
+            return Err(AxError::ResourceBusy);
+        }
+        *current |= seals;
+        Ok(())
+    }
+
+    /// Checks whether `len` bytes can be written at `offset` of the file of
+    /// `size` bytes.
+    pub fn check_write(&self, offset: u64, len: usize, size: u64) -> AxResult {
+        let seals = self.seals();
+        if seals & (F_SEAL_WRITE | F_SEAL_FUTURE_WRITE) != 0 {
+            return Err(AxError::OperationNotPermitted);
+        }
+        if seals & F_SEAL_GROW != 0 && offset.saturating_add(len as u64) > size {
+            return Err(AxError::OperationNotPermitted);
+        }
+        Ok(())
+    }
+
+    fn check_modify(&self) -> AxResult {
+        if self.seals() & (F_SEAL_WRITE | F_SEAL_FUTURE_WRITE) != 0 {
+            return Err(AxError::OperationNotPermitted);
+        }
+        Ok(())
+    }
+
+    fn check_resize(&self, size: u64, new_size: u64) -> AxResult {
+        let seals = self.seals();
+        if (seals & F_SEAL_SHRINK != 0 && new_size < size)
+            || (seals & F_SEAL_GROW != 0 && new_size > size)
+        {
+            return Err(AxError::OperationNotPermitted);
+        }
+        Ok(())
+    }
+
+    /// Checks whether the file can be mapped shared, and returns the handle
+    /// the mapping holds if it can be written through.
+    ///
+    /// `writable` tells whether the file was opened for writing, and `write`
+    /// whether the mapping is requested with write access.
+    pub fn map_shared(&self, writable: bool, write: bool) -> AxResult<Option<Arc<()>>> {
+        if self.seals() & (F_SEAL_WRITE | F_SEAL_FUTURE_WRITE) != 0 {
+            if write {
+                return Err(AxError::OperationNotPermitted);
+            }
+            return Ok(None);
+        }
+        Ok(writable.then(|| self.writers.clone()))
+    }
+
+    /// Checks whether the permission bits may be changed to `mode`.
+    fn check_chmod(&self, old_mode: u32, mode: u32) -> AxResult {
+        if self.seals() & F_SEAL_EXEC != 0 && (old_mode ^ mode) & 0o111 != 0 {
+            return Err(AxError::OperationNotPermitted);
+        }
+        Ok(())
+    }
+}
+
+/// Checks the seals of `loc` before writing `len` bytes at `offset`.
+pub fn check_write_seals(loc: &Location, offset: u64, len: usize) -> AxResult {
+    match Memfd::of(loc) {
+        Some(memfd) => memfd.check_write(offset, len, loc.len()?),
+        None => Ok(()),
+    }
+}
+
+/// Checks the seals of `loc` before changing its content other than by
+/// writing to it, e.g. by punching a hole.
+pub fn check_modify_seals(loc: &Location) -> AxResult {
+    match Memfd::of(loc) {
+        Some(memfd) => memfd.check_modify(),
+        None => Ok(()),
+    }
+}
+
+/// Checks the seals of `loc` before changing its size to `new_size`.
+pub fn check_resize_seals(loc: &Location, new_size: u64) -> AxResult {
+    match Memfd::of(loc) {
+        Some(memfd) => memfd.check_resize(loc.len()?, new_size),
+        None => Ok(()),
+    }
+}
+
+/// Checks the seals of `loc` before changing its permission bits to `mode`.
+pub fn check_chmod_seals(loc: &Location, mode: u32) -> AxResult {
+    match Memfd::of(loc) {
+        Some(memfd) => memfd.check_chmod(loc.metadata()?.mode.bits() as _, mode),
+        None => Ok(()),
+    }
+}
+
+/// Adds the `F_SEAL_*` `seals` to `loc`.
+pub fn add_seals(loc: &Location, seals: u32) -> AxResult {
+    let memfd = Memfd::of(loc).ok_or(AxError::InvalidInput)?;
+    memfd.add_seals(seals, loc.metadata()?.mode.bits() as _)
+}
+
+/// Returns the `F_SEAL_*` seals of `loc`.
+pub fn get_seals(loc: &Location) -> AxResult<u32> {
+    Memfd::of(loc)
+        .map(|it| it.seals())
+        .ok_or(AxError::InvalidInput)
+}
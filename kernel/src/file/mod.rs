@@ -1,10 +1,20 @@
+pub mod dnotify;
 pub mod epoll;
 pub mod event;
+pub mod fasync;
 mod fs;
+pub mod inotify;
+pub mod io_uring;
+pub mod lease;
+pub mod lock;
+pub mod memfd;
 mod net;
 mod pidfd;
 mod pipe;
 pub mod signalfd;
+pub mod timerfd;
+pub mod tmpfile;
+pub mod userfaultfd;
 
 use alloc::{borrow::Cow, sync::Arc};
 use core::{ffi::c_int, time::Duration};
@@ -21,12 +31,15 @@ use linux_raw_sys::general::{RLIMIT_NOFILE, stat, statx, statx_timestamp};
 use spin::RwLock;
 
 pub use self::{
-    fs::{Directory, File, resolve_at, with_fs},
+    fs::{Directory, File, ResolveAtResult, ResolveFlags, Resolver, resolve_at, with_fs},
     net::Socket,
     pidfd::PidFd,
     pipe::Pipe,
 };
-use crate::task::{AX_FILE_LIMIT, AsThread};
+use crate::{
+    pseudofs::DeviceMmap,
+    task::{AX_FILE_LIMIT, AsThread},
+};
 
 #[derive(Debug, Clone, Copy)]
 pub struct Kstat {
@@ -153,6 +166,12 @@ pub trait FileLike: Pollable + DowncastSync {
         Err(AxError::NotATty)
     }
 
+    /// Returns the memory mapping behavior at `offset`, for files that are
+    /// not backed by the filesystem.
+    fn mmap(&self, _offset: u64) -> AxResult<DeviceMmap> {
+        Err(AxError::NoSuchDevice)
+    }
+
     fn nonblocking(&self) -> bool {
         false
     }
@@ -217,6 +236,7 @@ pub fn close_file_like(fd: c_int) -> AxResult {
         .remove(fd as usize)
         .ok_or(AxError::BadFileDescriptor)?;
     debug!("close_file_like <= count: {}", Arc::strong_count(&f.inner));
+    lock::release_posix_locks(&f.inner);
     Ok(())
 }
 
@@ -1,71 +1,532 @@
-use alloc::{borrow::Cow, format, sync::Arc};
+//! Pipes and FIFOs.
+//!
+//! The data in a pipe is kept in pages, which `tee` shares with other pipes
+//! and `splice` moves between pipes without copying. Splicing a file into a
+//! pipe adds the pages of its page cache to the pipe, and `vmsplice` with
+//! `SPLICE_F_GIFT` adds the pages of private user memory, see [`PipePage`].
+//! Splicing a pipe into a file copies the data once, straight from the pages
+//! of the pipe into the page cache.
+
+use alloc::{
+    borrow::Cow, collections::vec_deque::VecDeque, format, string::ToString, sync::Arc, vec::Vec,
+};
 use core::{
-    mem,
-    sync::atomic::{AtomicBool, Ordering},
+    ptr, slice,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
     task::Context,
 };
 
-use axerrno::{AxError, AxResult};
+use axerrno::{AxError, AxResult, LinuxError};
+use axfs::CachedFile;
+use axfs_ng_vfs::Location;
+use axhal::{mem::phys_to_virt, paging::PageSize};
+use axio::Read;
 use axpoll::{IoEvents, PollSet, Pollable};
-use axsync::Mutex;
+use axsync::{Mutex, MutexGuard};
 use axtask::{
     current,
     future::{block_on, poll_io},
 };
-use linux_raw_sys::{general::S_IFIFO, ioctl::FIONREAD};
-use memory_addr::PAGE_SIZE_4K;
-use ringbuf::{
-    HeapRb,
-    traits::{Consumer, Observer, Producer},
+use linux_raw_sys::{
+    general::{O_ACCMODE, O_NONBLOCK, O_RDONLY, O_WRONLY, S_IFIFO},
+    ioctl::FIONREAD,
 };
+use memory_addr::{PAGE_SIZE_4K, PhysAddr, VirtAddr};
 use starry_signal::{SignalInfo, Signo};
 use starry_vm::VmMutPtr;
 
 use super::{FileLike, Kstat};
 use crate::{
-    file::{IoDst, IoSrc},
+    file::{IoDst, IoSrc, fs::metadata_to_kstat},
+    mm::{GiftedPage, IoVectorBuf, SharedPages, VmBytes},
+    pseudofs::{TruncateListener, add_truncate_listener},
     task::{AsThread, send_signal_to_process},
 };
 
-const RING_BUFFER_INIT_SIZE: usize = 65536; // 64 KiB
+/// Default number of buffers of a pipe.
+const PIPE_DEF_BUFFERS: usize = 16; // 64 KiB
+
+/// The frame of a [`CachePage`].
+enum CacheFrame {
+    /// The page is in the page cache.
+    Cached(PhysAddr),
+    /// The page left the page cache, and its data was copied here.
+    Copied(SharedPages),
+    /// The page left the page cache, but could not be copied.
+    Lost,
+}
+
+impl CacheFrame {
+    /// Copies the page out of the page cache before it is freed.
+    fn detach(&mut self) {
+        let CacheFrame::Cached(paddr) = *self else {
+            return;
+        };
+        *self = match SharedPages::new(PAGE_SIZE_4K, PageSize::Size4K) {
+            Ok(page) => {
+                // SAFETY: The page cache frees the page only after this.
+                unsafe {
+                    ptr::copy_nonoverlapping(
+                        phys_to_virt(paddr).as_ptr(),
+                        phys_to_virt(page[0]).as_mut_ptr(),
+                        PAGE_SIZE_4K,
+                    )
+                };
+                CacheFrame::Copied(page)
+            }
+            Err(_) => {
+                warn!("Failed to copy a page spliced into a pipe");
+                CacheFrame::Lost
+            }
+        };
+    }
+}
+
+/// A page of the page cache spliced into a pipe.
+///
+/// The pipe sees writes to the file as long as the page is in the page cache,
+/// like on Linux. The page cache frees the pages it evicts or truncates, so
+/// the page is copied out when that happens while it is still in the pipe.
+struct CachePage {
+    cache: CachedFile,
+    frame: Arc<Mutex<CacheFrame>>,
+    /// The handle of the evict listener.
+    handle: usize,
+    /// Keeps the truncate listener of tmpfs files registered.
+    _truncate: Arc<TruncateListener>,
+}
+
+impl CachePage {
+    /// Refers to page `pn` of `cache`, loading it if needed. Returns `None`
+    /// if the page is past the end of the file.
+    fn new(cache: &CachedFile, pn: u32) -> AxResult<Option<Self>> {
+        let frame = Arc::new(Mutex::new(CacheFrame::Lost));
+        let truncate: Arc<TruncateListener> = Arc::new({
+            let frame = frame.clone();
+            move |first| {
+                if pn >= first {
+                    frame.lock().detach();
+                }
+            }
+        });
+        // A tmpfs file shrinking drops the pages before the page cache is
+        // locked, which is why the length is checked with the page locked.
+        add_truncate_listener(cache.location(), &truncate)?;
+        let handle = cache.with_page_or_insert(pn, |page, _| {
+            if pn as u64 * PAGE_SIZE_4K as u64 >= cache.location().len()? {
+                return Ok(None);
+            }
+            *frame.lock() = CacheFrame::Cached(page.paddr());
+            let weak = Arc::downgrade(&frame);
+            Ok(Some(cache.add_evict_listener(move |evicted, _| {
+                if evicted == pn
+                    && let Some(frame) = weak.upgrade()
+                {
+                    frame.lock().detach();
+                }
+            })))
+        })?;
+        Ok(handle.map(|handle| Self {
+            cache: cache.clone(),
+            frame,
+            handle,
+            _truncate: truncate,
+        }))
+    }
+
+    /// Copies `len` bytes at `offset` out of the page. The page may leave the
+    /// page cache as soon as the frame is unlocked.
+    fn read(&self, offset: usize, len: usize) -> AxResult<Vec<u8>> {
+        let frame = self.frame.lock();
+        let paddr = match &*frame {
+            CacheFrame::Cached(paddr) => *paddr,
+            CacheFrame::Copied(page) => page[0],
+            CacheFrame::Lost => return Err(AxError::Io),
+        };
+        // SAFETY: The page is not freed while the frame is locked.
+        let data = unsafe { slice::from_raw_parts(phys_to_virt(paddr).as_ptr().add(offset), len) };
+        Ok(data.to_vec())
+    }
+}
+
+impl Drop for CachePage {
+    fn drop(&mut self) {
+        // SAFETY: The handle was returned by `add_evict_listener` of the
+        // cache.
+        unsafe { self.cache.remove_evict_listener(self.handle) };
+    }
+}
+
+/// A page holding pipe data.
+enum PipePage {
+    /// A page of the pipe itself.
+    Owned(SharedPages),
+    /// A page of user memory given to the pipe by `vmsplice`.
+    Gift(GiftedPage),
+    /// A page of the page cache spliced into the pipe.
+    Cache(CachePage),
+}
+
+/// A range of a page holding pipe data.
+#[derive(Clone)]
+struct PipeBuffer {
+    page: Arc<PipePage>,
+    offset: usize,
+    len: usize,
+}
+
+impl PipeBuffer {
+    fn alloc() -> AxResult<Self> {
+        Ok(Self::new(
+            PipePage::Owned(SharedPages::new(PAGE_SIZE_4K, PageSize::Size4K)?),
+            0,
+            0,
+        ))
+    }
+
+    fn new(page: PipePage, offset: usize, len: usize) -> Self {
+        Self {
+            page: Arc::new(page),
+            offset,
+            len,
+        }
+    }
+
+    /// Calls `f` with the data of the buffer.
+    fn with_data<R>(&self, f: impl FnOnce(&[u8]) -> R) -> AxResult<R> {
+        let paddr = match &*self.page {
+            PipePage::Owned(page) => page[0],
+            PipePage::Gift(page) => page.paddr(),
+            // `f` may evict the page, which waits for the frame to be
+            // unlocked, so the data is passed on as a copy.
+            PipePage::Cache(page) => return Ok(f(&page.read(self.offset, self.len)?)),
+        };
+        // SAFETY: The page lives as long as `self`, and is only written to
+        // through `spare` while no other buffer refers to it.
+        Ok(f(unsafe {
+            slice::from_raw_parts(phys_to_virt(paddr).as_ptr().add(self.offset), self.len)
+        }))
+    }
+
+    /// Returns whether data can be appended to the buffer, i.e. it is a page
+    /// of the pipe with space after the data, and no other buffer refers to
+    /// it.
+    fn has_spare(&self) -> bool {
+        matches!(*self.page, PipePage::Owned(_))
+            && self.offset + self.len < PAGE_SIZE_4K
+            && Arc::strong_count(&self.page) == 1
+    }
+
+    /// Returns the space after the data, see [`Self::has_spare`].
+    fn spare(&mut self) -> &mut [u8] {
+        let Some(PipePage::Owned(page)) = Arc::get_mut(&mut self.page) else {
+            panic!("appending to a shared pipe page");
+        };
+        let ptr = phys_to_virt(page[0]).as_mut_ptr();
+        let end = self.offset + self.len;
+        // SAFETY: No other buffer refers to the page.
+        unsafe { slice::from_raw_parts_mut(ptr.add(end), PAGE_SIZE_4K - end) }
+    }
+
+    /// Splits off the first `len` bytes, which then share the page.
+    fn split_front(&mut self, len: usize) -> Self {
+        let front = Self {
+            page: self.page.clone(),
+            offset: self.offset,
+            len,
+        };
+        self.offset += len;
+        self.len -= len;
+        front
+    }
+}
+
+/// The buffers of a pipe, none of them empty.
+struct PipeRing {
+    buffers: VecDeque<PipeBuffer>,
+    /// Maximum number of buffers.
+    slots: usize,
+}
+
+impl PipeRing {
+    fn new() -> Self {
+        Self {
+            buffers: VecDeque::new(),
+            slots: PIPE_DEF_BUFFERS,
+        }
+    }
+
+    /// Returns the number of bytes in the pipe.
+    fn len(&self) -> usize {
+        self.buffers.iter().map(|it| it.len).sum()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buffers.is_empty()
+    }
+
+    fn is_full(&self) -> bool {
+        self.buffers.len() >= self.slots
+    }
+
+    /// Returns whether more data can be written.
+    fn has_room(&self) -> bool {
+        !self.is_full() || self.buffers.back().is_some_and(PipeBuffer::has_spare)
+    }
+
+    fn clear(&mut self) {
+        self.buffers.clear();
+    }
+
+    /// Copies data from `src` into the pipe, appending to the last page
+    /// first.
+    fn push_from(&mut self, src: &mut IoSrc) -> AxResult<usize> {
+        let mut count = 0;
+        while !src.is_empty() {
+            if !self.buffers.back().is_some_and(PipeBuffer::has_spare) {
+                if self.is_full() {
+                    break;
+                }
+                self.buffers.push_back(PipeBuffer::alloc()?);
+            }
+            let last = self.buffers.back_mut().unwrap();
+            let read = src.read(last.spare());
+            last.len += *read.as_ref().unwrap_or(&0);
+            if last.len == 0 {
+                self.buffers.pop_back();
+            }
+            match read {
+                Ok(0) => break,
+                Ok(read) => count += read,
+                Err(_) if count > 0 => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(count)
+    }
+
+    /// Copies data out of the pipe into `dst`.
+    fn pop_into(&mut self, dst: &mut IoDst) -> AxResult<usize> {
+        let mut count = 0;
+        while let Some(front) = self.buffers.front_mut() {
+            let written = match front.with_data(|data| dst.write(data)).and_then(|it| it) {
+                Ok(written) => written,
+                Err(_) if count > 0 => break,
+                Err(err) => return Err(err),
+            };
+            front.offset += written;
+            front.len -= written;
+            count += written;
+            if front.len > 0 {
+                break;
+            }
+            self.buffers.pop_front();
+        }
+        Ok(count)
+    }
+
+    /// Returns buffers sharing the pages of the first `len` bytes of the pipe,
+    /// which stay in the pipe.
+    fn peek(&self, len: usize) -> Vec<PipeBuffer> {
+        let mut peeked = Vec::new();
+        let mut count = 0;
+        for buf in self.buffers.iter() {
+            if count >= len {
+                break;
+            }
+            let n = buf.len.min(len - count);
+            peeked.push(PipeBuffer {
+                len: n,
+                ..buf.clone()
+            });
+            count += n;
+        }
+        peeked
+    }
+
+    /// Drops the first `len` bytes of the pipe.
+    fn consume(&mut self, mut len: usize) {
+        while len > 0
+            && let Some(front) = self.buffers.front_mut()
+        {
+            if front.len > len {
+                front.offset += len;
+                front.len -= len;
+                break;
+            }
+            len -= front.len;
+            self.buffers.pop_front();
+        }
+    }
+
+    /// Moves up to `len` bytes to `dst` as long as it has free slots, or
+    /// only shares them with `dst` if `keep` is set.
+    fn transfer(&mut self, dst: &mut PipeRing, len: usize, keep: bool) -> usize {
+        let mut count = 0;
+        let mut index = 0;
+        while count < len
+            && !dst.is_full()
+            && let Some(buf) = self.buffers.get_mut(index)
+        {
+            let n = buf.len.min(len - count);
+            let part = if keep {
+                index += 1;
+                PipeBuffer {
+                    len: n,
+                    ..buf.clone()
+                }
+            } else if n == buf.len {
+                self.buffers.pop_front().unwrap()
+            } else {
+                buf.split_front(n)
+            };
+            dst.buffers.push_back(part);
+            count += n;
+        }
+        count
+    }
+}
 
 struct Shared {
-    buffer: Mutex<HeapRb<u8>>,
+    buffer: Mutex<PipeRing>,
+    /// Held while data is taken out of the pipe, so that the data `splice`
+    /// passes on stays in the pipe until it is consumed.
+    read_lock: Mutex<()>,
     poll_rx: PollSet,
     poll_tx: PollSet,
     poll_close: PollSet,
+    /// Number of open ends that can read.
+    readers: AtomicUsize,
+    /// Number of open ends that can write.
+    writers: AtomicUsize,
+    /// Number of times an end that can read was opened, so that opening a
+    /// FIFO for writing notices readers that are gone again already.
+    read_opens: AtomicUsize,
+    /// Like `read_opens`, for the ends that can write.
+    write_opens: AtomicUsize,
+}
+
+impl Shared {
+    fn new() -> Self {
+        Self {
+            buffer: Mutex::new(PipeRing::new()),
+            read_lock: Mutex::new(()),
+            poll_rx: PollSet::new(),
+            poll_tx: PollSet::new(),
+            poll_close: PollSet::new(),
+            readers: AtomicUsize::new(0),
+            writers: AtomicUsize::new(0),
+            read_opens: AtomicUsize::new(0),
+            write_opens: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// Locks the buffers of two different pipes, in a fixed order.
+fn lock_both<'a>(
+    a: &'a Shared,
+    b: &'a Shared,
+) -> (MutexGuard<'a, PipeRing>, MutexGuard<'a, PipeRing>) {
+    if (a as *const Shared) < (b as *const Shared) {
+        let a = a.buffer.lock();
+        (a, b.buffer.lock())
+    } else {
+        let b = b.buffer.lock();
+        (a.buffer.lock(), b)
+    }
 }
 
 pub struct Pipe {
     read_side: bool,
+    write_side: bool,
     shared: Arc<Shared>,
     non_blocking: AtomicBool,
+    /// The FIFO the pipe was opened from, if it is not an anonymous pipe.
+    fifo: Option<Location>,
 }
 impl Drop for Pipe {
     fn drop(&mut self) {
+        let readers = if self.read_side {
+            self.shared.readers.fetch_sub(1, Ordering::AcqRel) - 1
+        } else {
+            self.shared.readers.load(Ordering::Acquire)
+        };
+        let writers = if self.write_side {
+            self.shared.writers.fetch_sub(1, Ordering::AcqRel) - 1
+        } else {
+            self.shared.writers.load(Ordering::Acquire)
+        };
+        // The data in a FIFO is gone once nobody has it open.
+        if readers == 0 && writers == 0 {
+            self.shared.buffer.lock().clear();
+        }
         self.shared.poll_close.wake();
     }
 }
 
 impl Pipe {
     pub fn new() -> (Pipe, Pipe) {
-        let shared = Arc::new(Shared {
-            buffer: Mutex::new(HeapRb::new(RING_BUFFER_INIT_SIZE)),
-            poll_rx: PollSet::new(),
-            poll_tx: PollSet::new(),
-            poll_close: PollSet::new(),
-        });
-        let read_end = Pipe {
-            read_side: true,
-            shared: shared.clone(),
-            non_blocking: AtomicBool::new(false),
-        };
-        let write_end = Pipe {
-            read_side: false,
+        let shared = Arc::new(Shared::new());
+        let read_end = Pipe::new_end(shared.clone(), true, false, None);
+        let write_end = Pipe::new_end(shared, false, true, None);
+        (read_end, write_end)
+    }
+
+    fn new_end(shared: Arc<Shared>, read: bool, write: bool, fifo: Option<Location>) -> Pipe {
+        if read {
+            shared.readers.fetch_add(1, Ordering::AcqRel);
+            shared.read_opens.fetch_add(1, Ordering::AcqRel);
+        }
+        if write {
+            shared.writers.fetch_add(1, Ordering::AcqRel);
+            shared.write_opens.fetch_add(1, Ordering::AcqRel);
+        }
+        shared.poll_close.wake();
+        Pipe {
+            read_side: read,
+            write_side: write,
             shared,
             non_blocking: AtomicBool::new(false),
+            fifo,
+        }
+    }
+
+    /// Opens the FIFO `loc` with the access mode in `flags`.
+    ///
+    /// Opening only one side blocks until the other side is opened as well,
+    /// unless `O_NONBLOCK` is given. Then opening for writing fails with
+    /// `ENXIO` if there is no reader.
+    pub fn open_fifo(loc: Location, flags: u32) -> AxResult<Pipe> {
+        let shared = loc.user_data().get_or_insert_with(Shared::new);
+        let (read, write) = match flags & O_ACCMODE {
+            O_RDONLY => (true, false),
+            O_WRONLY => (false, true),
+            _ => (true, true),
         };
-        (read_end, write_end)
+        let non_blocking = flags & O_NONBLOCK != 0;
+        if write && !read && non_blocking && shared.readers.load(Ordering::Acquire) == 0 {
+            return Err(AxError::from(LinuxError::ENXIO));
+        }
+        let (others, other_opens) = if read {
+            (&shared.writers, &shared.write_opens)
+        } else {
+            (&shared.readers, &shared.read_opens)
+        };
+        let opens = other_opens.load(Ordering::Acquire);
+        let pipe = Pipe::new_end(shared.clone(), read, write, Some(loc));
+        pipe.set_nonblocking(non_blocking)?;
+        if read != write && !non_blocking {
+            block_on(poll_io(&pipe, IoEvents::empty(), false, || {
+                if others.load(Ordering::Acquire) > 0
+                    || other_opens.load(Ordering::Acquire) != opens
+                {
+                    Ok(())
+                } else {
+                    Err(AxError::WouldBlock)
+                }
+            }))?;
+        }
+        Ok(pipe)
     }
 
     pub const fn is_read(&self) -> bool {
@@ -73,46 +534,36 @@ impl Pipe {
     }
 
     pub const fn is_write(&self) -> bool {
-        !self.read_side
+        self.write_side
+    }
+
+    /// Returns whether there is no end left that can write.
+    fn no_writers(&self) -> bool {
+        self.shared.writers.load(Ordering::Acquire) == 0
     }
 
-    pub fn closed(&self) -> bool {
-        Arc::strong_count(&self.shared) == 1
+    /// Returns whether there is no end left that can read.
+    fn no_readers(&self) -> bool {
+        self.shared.readers.load(Ordering::Acquire) == 0
     }
 
     pub fn capacity(&self) -> usize {
-        self.shared.buffer.lock().capacity().get()
+        self.shared.buffer.lock().slots * PAGE_SIZE_4K
     }
 
     pub fn resize(&self, new_size: usize) -> AxResult<()> {
-        let new_size = new_size.div_ceil(PAGE_SIZE_4K).max(1) * PAGE_SIZE_4K;
-
+        let slots = new_size.div_ceil(PAGE_SIZE_4K).max(1);
         let mut buffer = self.shared.buffer.lock();
-        if new_size == buffer.capacity().get() {
-            return Ok(());
-        }
-        if new_size < buffer.occupied_len() {
+        if slots < buffer.buffers.len() {
             return Err(AxError::ResourceBusy);
         }
-        let old_buffer = mem::replace(&mut *buffer, HeapRb::new(new_size));
-        let (left, right) = old_buffer.as_slices();
-        buffer.push_slice(left);
-        buffer.push_slice(right);
+        buffer.slots = slots;
         Ok(())
     }
-}
 
-fn raise_pipe() {
-    let curr = current();
-    send_signal_to_process(
-        curr.as_thread().proc_data.proc.pid(),
-        Some(SignalInfo::new_kernel(Signo::SIGPIPE)),
-    )
-    .expect("Failed to send SIGPIPE");
-}
-
-impl FileLike for Pipe {
-    fn read(&self, dst: &mut IoDst) -> AxResult<usize> {
+    /// Reads from the pipe into `dst`, like [`FileLike::read`] with the given
+    /// blocking mode.
+    pub fn read_to(&self, dst: &mut IoDst, nonblocking: bool) -> AxResult<usize> {
         if !self.is_read() {
             return Err(AxError::BadFileDescriptor);
         }
@@ -120,21 +571,13 @@ impl FileLike for Pipe {
             return Ok(0);
         }
 
-        block_on(poll_io(self, IoEvents::IN, self.nonblocking(), || {
-            let read = {
-                let cons = self.shared.buffer.lock();
-                let (left, right) = cons.as_slices();
-                let mut count = dst.write(left)?;
-                if count >= left.len() {
-                    count += dst.write(right)?;
-                }
-                unsafe { cons.advance_read_index(count) };
-                count
-            };
+        block_on(poll_io(self, IoEvents::IN, nonblocking, || {
+            let _read_lock = self.shared.read_lock.lock();
+            let read = self.shared.buffer.lock().pop_into(dst)?;
             if read > 0 {
                 self.shared.poll_tx.wake();
                 Ok(read)
-            } else if self.closed() {
+            } else if self.no_writers() {
                 Ok(0)
             } else {
                 Err(AxError::WouldBlock)
@@ -142,7 +585,9 @@ impl FileLike for Pipe {
         }))
     }
 
-    fn write(&self, src: &mut IoSrc) -> AxResult<usize> {
+    /// Writes `src` to the pipe, like [`FileLike::write`] with the given
+    /// blocking mode.
+    pub fn write_from(&self, src: &mut IoSrc, nonblocking: bool) -> AxResult<usize> {
         if !self.is_write() {
             return Err(AxError::BadFileDescriptor);
         }
@@ -153,26 +598,17 @@ impl FileLike for Pipe {
 
         let mut total_written = 0;
 
-        block_on(poll_io(self, IoEvents::OUT, self.nonblocking(), || {
-            if self.closed() {
+        block_on(poll_io(self, IoEvents::OUT, nonblocking, || {
+            if self.no_readers() {
                 raise_pipe();
                 return Err(AxError::BrokenPipe);
             }
 
-            let written = {
-                let mut prod = self.shared.buffer.lock();
-                let (left, right) = prod.vacant_slices_mut();
-                let mut count = src.read(unsafe { left.assume_init_mut() })?;
-                if count >= left.len() {
-                    count += src.read(unsafe { right.assume_init_mut() })?;
-                }
-                unsafe { prod.advance_write_index(count) };
-                count
-            };
+            let written = self.shared.buffer.lock().push_from(src)?;
             if written > 0 {
                 self.shared.poll_rx.wake();
                 total_written += written;
-                if total_written == size || self.nonblocking() {
+                if total_written == size || nonblocking {
                     return Ok(total_written);
                 }
             }
@@ -180,7 +616,291 @@ impl FileLike for Pipe {
         }))
     }
 
+    /// Waits for data in the pipe, returning `false` at the end of file.
+    fn wait_for_data(&self, nonblocking: bool) -> AxResult<bool> {
+        block_on(poll_io(self, IoEvents::IN, nonblocking, || {
+            if !self.shared.buffer.lock().is_empty() {
+                Ok(true)
+            } else if self.no_writers() {
+                Ok(false)
+            } else {
+                Err(AxError::WouldBlock)
+            }
+        }))
+    }
+
+    /// Waits until data can be written to the pipe, or with `new_buffer`, a
+    /// new buffer can be added.
+    fn wait_for_room(&self, nonblocking: bool, new_buffer: bool) -> AxResult {
+        block_on(poll_io(self, IoEvents::OUT, nonblocking, || {
+            if self.no_readers() {
+                raise_pipe();
+                return Err(AxError::BrokenPipe);
+            }
+            let buffer = self.shared.buffer.lock();
+            if (new_buffer && !buffer.is_full()) || (!new_buffer && buffer.has_room()) {
+                Ok(())
+            } else {
+                Err(AxError::WouldBlock)
+            }
+        }))
+    }
+
+    /// Moves up to `len` bytes from this pipe to `dst` without copying them,
+    /// for `splice`. With `keep`, the data stays in this pipe as well, for
+    /// `tee`.
+    pub fn splice_to(
+        &self,
+        dst: &Pipe,
+        len: usize,
+        keep: bool,
+        nonblocking: bool,
+    ) -> AxResult<usize> {
+        if !self.is_read() || !dst.is_write() {
+            return Err(AxError::BadFileDescriptor);
+        }
+        if Arc::ptr_eq(&self.shared, &dst.shared) {
+            return Err(AxError::InvalidInput);
+        }
+        if len == 0 {
+            return Ok(0);
+        }
+        loop {
+            if !self.wait_for_data(nonblocking)? {
+                return Ok(0);
+            }
+            dst.wait_for_room(nonblocking, true)?;
+            let moved = {
+                let _read_lock = (!keep).then(|| self.shared.read_lock.lock());
+                let (mut src, mut dst) = lock_both(&self.shared, &dst.shared);
+                src.transfer(&mut dst, len, keep)
+            };
+            if moved > 0 {
+                dst.shared.poll_rx.wake();
+                if !keep {
+                    self.shared.poll_tx.wake();
+                }
+                return Ok(moved);
+            }
+        }
+    }
+
+    /// Passes up to `len` bytes of the pipe to `write` straight from its
+    /// pages, for `splice`. The data `write` does not take stays in the
+    /// pipe.
+    pub fn splice_out(
+        &self,
+        len: usize,
+        nonblocking: bool,
+        mut write: impl FnMut(&[u8]) -> AxResult<usize>,
+    ) -> AxResult<usize> {
+        if !self.is_read() {
+            return Err(AxError::BadFileDescriptor);
+        }
+        if len == 0 {
+            return Ok(0);
+        }
+        loop {
+            if !self.wait_for_data(nonblocking)? {
+                return Ok(0);
+            }
+            let _read_lock = self.shared.read_lock.lock();
+            let buffers = self.shared.buffer.lock().peek(len);
+            if buffers.is_empty() {
+                // Another reader took the data.
+                continue;
+            }
+            let mut count = 0;
+            let mut result = Ok(());
+            for buf in &buffers {
+                match buf.with_data(&mut write).and_then(|it| it) {
+                    Ok(written) => {
+                        count += written;
+                        if written < buf.len {
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        result = Err(err);
+                        break;
+                    }
+                }
+            }
+            drop(buffers);
+            if count > 0 {
+                self.shared.buffer.lock().consume(count);
+                self.shared.poll_tx.wake();
+                return Ok(count);
+            }
+            return result.map(|_| 0);
+        }
+    }
+
+    /// Fills new pages of the pipe with up to `len` bytes from `read`, for
+    /// `splice`.
+    pub fn splice_in(
+        &self,
+        len: usize,
+        nonblocking: bool,
+        mut read: impl FnMut(&mut [u8]) -> AxResult<usize>,
+    ) -> AxResult<usize> {
+        if !self.is_write() {
+            return Err(AxError::BadFileDescriptor);
+        }
+        let mut count = 0;
+        while count < len {
+            if count == 0 {
+                self.wait_for_room(nonblocking, true)?;
+            } else if self.shared.buffer.lock().is_full() {
+                break;
+            }
+            let mut buf = PipeBuffer::alloc()?;
+            let chunk = (len - count).min(PAGE_SIZE_4K);
+            let read = match read(&mut buf.spare()[..chunk]) {
+                Ok(read) => read,
+                Err(_) if count > 0 => break,
+                Err(err) => return Err(err),
+            };
+            if read == 0 {
+                break;
+            }
+            buf.len = read;
+            self.shared.buffer.lock().buffers.push_back(buf);
+            self.shared.poll_rx.wake();
+            count += read;
+            if read < chunk {
+                break;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Adds up to `len` bytes of `cache` at `offset` to the pipe as pages of
+    /// the page cache, for `splice`. Holes of in-memory files are added as
+    /// new zeroed pages instead of populating them.
+    pub fn splice_cache(
+        &self,
+        cache: &CachedFile,
+        offset: u64,
+        len: usize,
+        nonblocking: bool,
+    ) -> AxResult<usize> {
+        if !self.is_write() {
+            return Err(AxError::BadFileDescriptor);
+        }
+        let end = offset
+            .saturating_add(len as u64)
+            .min(cache.location().len()?);
+        let mut pos = offset;
+        while pos < end {
+            if pos == offset {
+                self.wait_for_room(nonblocking, true)?;
+            } else if self.shared.buffer.lock().is_full() {
+                break;
+            }
+            let pn = (pos / PAGE_SIZE_4K as u64) as u32;
+            let start = (pos % PAGE_SIZE_4K as u64) as usize;
+            let n = ((end - pos) as usize).min(PAGE_SIZE_4K - start);
+            let page = if cache.in_memory() && cache.with_page(pn, |page| page.is_none()) {
+                PipePage::Owned(SharedPages::new(PAGE_SIZE_4K, PageSize::Size4K)?)
+            } else {
+                match CachePage::new(cache, pn)? {
+                    Some(page) => PipePage::Cache(page),
+                    None => break,
+                }
+            };
+            self.shared
+                .buffer
+                .lock()
+                .buffers
+                .push_back(PipeBuffer::new(page, start, n));
+            self.shared.poll_rx.wake();
+            pos += n as u64;
+        }
+        Ok((pos - offset) as usize)
+    }
+
+    /// Adds the user memory in `iov` to the pipe, for `vmsplice` with
+    /// `SPLICE_F_GIFT`. The pages of private mappings are given to the pipe,
+    /// see [`AddrSpace::gift_page`](crate::mm::AddrSpace::gift_page), and
+    /// other memory is copied.
+    pub fn splice_gift(&self, iov: IoVectorBuf, nonblocking: bool) -> AxResult<usize> {
+        if !self.is_write() {
+            return Err(AxError::BadFileDescriptor);
+        }
+        let mut count = 0;
+        let mut stopped = false;
+        let result = iov.read_with(|ptr, len| {
+            if stopped {
+                return Ok(0);
+            }
+            let mut done = 0;
+            while done < len {
+                let addr = ptr.wrapping_add(done);
+                let offset = addr as usize % PAGE_SIZE_4K;
+                let n = (len - done).min(PAGE_SIZE_4K - offset);
+                match self.gift_page(addr, n, nonblocking) {
+                    Ok(()) => done += n,
+                    Err(_) if count + done > 0 => {
+                        stopped = true;
+                        break;
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            count += done;
+            Ok(done)
+        });
+        if count > 0 { Ok(count) } else { result }
+    }
+
+    /// Adds the `len` bytes at `addr`, all in one page, to the pipe, see
+    /// [`Self::splice_gift`].
+    fn gift_page(&self, addr: *const u8, len: usize, nonblocking: bool) -> AxResult {
+        self.wait_for_room(nonblocking, true)?;
+        let gifted = current()
+            .as_thread()
+            .proc_data
+            .aspace
+            .lock()
+            .gift_page(VirtAddr::from_ptr_of(addr))?;
+        let buf = match gifted {
+            Some(page) => PipeBuffer::new(PipePage::Gift(page), addr as usize % PAGE_SIZE_4K, len),
+            None => {
+                let mut buf = PipeBuffer::alloc()?;
+                VmBytes::new(addr, len).read_exact(&mut buf.spare()[..len])?;
+                buf.len = len;
+                buf
+            }
+        };
+        self.shared.buffer.lock().buffers.push_back(buf);
+        self.shared.poll_rx.wake();
+        Ok(())
+    }
+}
+
+fn raise_pipe() {
+    let curr = current();
+    send_signal_to_process(
+        curr.as_thread().proc_data.proc.pid(),
+        Some(SignalInfo::new_kernel(Signo::SIGPIPE)),
+    )
+    .expect("Failed to send SIGPIPE");
+}
+
+impl FileLike for Pipe {
+    fn read(&self, dst: &mut IoDst) -> AxResult<usize> {
+        self.read_to(dst, self.nonblocking())
+    }
+
+    fn write(&self, src: &mut IoSrc) -> AxResult<usize> {
+        self.write_from(src, self.nonblocking())
+    }
+
     fn stat(&self) -> AxResult<Kstat> {
+        if let Some(fifo) = &self.fifo {
+            return Ok(metadata_to_kstat(&fifo.metadata()?));
+        }
         Ok(Kstat {
             mode: S_IFIFO | if self.is_read() { 0o444 } else { 0o222 },
             ..Default::default()
@@ -188,6 +908,9 @@ impl FileLike for Pipe {
     }
 
     fn path(&self) -> Cow<'_, str> {
+        if let Some(path) = self.fifo.as_ref().and_then(|it| it.absolute_path().ok()) {
+            return path.to_string().into();
+        }
         format!("pipe:[{}]", self as *const _ as usize).into()
     }
 
@@ -203,7 +926,7 @@ impl FileLike for Pipe {
     fn ioctl(&self, cmd: u32, arg: usize) -> AxResult<usize> {
         match cmd {
             FIONREAD => {
-                (arg as *mut u32).vm_write(self.shared.buffer.lock().occupied_len() as u32)?;
+                (arg as *mut u32).vm_write(self.shared.buffer.lock().len() as u32)?;
                 Ok(0)
             }
             _ => Err(AxError::NotATty),
@@ -216,10 +939,11 @@ impl Pollable for Pipe {
         let mut events = IoEvents::empty();
         let buf = self.shared.buffer.lock();
         if self.read_side {
-            events.set(IoEvents::IN, buf.occupied_len() > 0);
-            events.set(IoEvents::HUP, self.closed());
-        } else {
-            events.set(IoEvents::OUT, buf.vacant_len() > 0);
+            events.set(IoEvents::IN, !buf.is_empty());
+            events.set(IoEvents::HUP, self.no_writers());
+        }
+        if self.write_side {
+            events.set(IoEvents::OUT, buf.has_room());
         }
         events
     }
@@ -0,0 +1,200 @@
+//! timerfd file descriptors.
+
+use alloc::{borrow::Cow, sync::Arc};
+use core::{
+    mem,
+    sync::atomic::{AtomicBool, Ordering},
+    task::Context,
+    time::Duration,
+};
+
+use axerrno::{AxError, AxResult};
+use axhal::time::TimeValue;
+use axpoll::{IoEvents, PollSet, Pollable};
+use axtask::future::{block_on, poll_io};
+use spin::Mutex;
+
+use crate::{
+    file::{FileLike, IoDst},
+    task::{AlarmKey, call_at, cancel_alarm},
+    time::Clock,
+};
+
+#[derive(Default)]
+struct TimerState {
+    /// The next expiration, in the time of the clock of the timer.
+    deadline: Option<TimeValue>,
+    interval: Duration,
+    /// Number of expirations since the timer was last read or set.
+    expirations: u64,
+    /// The alarm that goes off at `deadline`.
+    alarm: Option<AlarmKey>,
+}
+
+struct Timer {
+    clock: Clock,
+    // Not a `SpinNoIrq`, since the alarm list is locked while holding it.
+    state: Mutex<TimerState>,
+    poll_rx: PollSet,
+}
+
+impl Timer {
+    /// Accounts for the expirations up to now, moving the deadline past the
+    /// current time for interval timers.
+    fn update(self: &Arc<Self>, state: &mut TimerState) {
+        let Some(deadline) = state.deadline else {
+            return;
+        };
+        let now = self.clock.now();
+        if deadline > now {
+            return;
+        }
+        if state.interval.is_zero() {
+            state.expirations = state.expirations.saturating_add(1);
+            state.deadline = None;
+        } else {
+            let interval = state.interval.as_nanos();
+            let count = (now - deadline).as_nanos() / interval + 1;
+            state.expirations = state.expirations.saturating_add(count as u64);
+            state.deadline = Some(deadline + Duration::from_nanos((count * interval) as u64));
+        }
+        self.arm(state);
+    }
+
+    /// Sets up the alarm for the current deadline, replacing the old one.
+    fn arm(self: &Arc<Self>, state: &mut TimerState) {
+        if let Some(alarm) = state.alarm.take() {
+            cancel_alarm(alarm);
+        }
+        if let Some(deadline) = state.deadline {
+            let timer = Arc::downgrade(self);
+            state.alarm = Some(call_at(self.clock.to_wall_time(deadline), move || {
+                if let Some(timer) = timer.upgrade() {
+                    timer.expire();
+                }
+            }));
+        }
+    }
+
+    fn expire(self: &Arc<Self>) {
+        let mut state = self.state.lock();
+        self.update(&mut state);
+        let ready = state.expirations > 0;
+        drop(state);
+        if ready {
+            self.poll_rx.wake();
+        }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        if let Some(alarm) = self.state.get_mut().alarm.take() {
+            cancel_alarm(alarm);
+        }
+    }
+}
+
+/// A timer that is read through a file descriptor.
+pub struct TimerFd {
+    timer: Arc<Timer>,
+    non_blocking: AtomicBool,
+}
+
+impl TimerFd {
+    pub fn new(clock: Clock) -> Arc<Self> {
+        Arc::new(Self {
+            timer: Arc::new(Timer {
+                clock,
+                state: Mutex::default(),
+                poll_rx: PollSet::new(),
+            }),
+            non_blocking: AtomicBool::new(false),
+        })
+    }
+
+    /// Returns the clock of the timer.
+    pub fn clock(&self) -> Clock {
+        self.timer.clock
+    }
+
+    /// Returns the interval of the timer and the time until its next
+    /// expiration, which is zero if the timer is disarmed.
+    pub fn get(&self) -> (Duration, Duration) {
+        let mut state = self.timer.state.lock();
+        self.timer.update(&mut state);
+        self.setting(&state)
+    }
+
+    /// Arms the timer to expire at `deadline`, given in the time of its
+    /// clock, and then every `interval` if it is not zero. The timer is
+    /// disarmed if `deadline` is `None`.
+    ///
+    /// Returns the previous setting as [`TimerFd::get`] does.
+    pub fn set(&self, deadline: Option<TimeValue>, interval: Duration) -> (Duration, Duration) {
+        let mut state = self.timer.state.lock();
+        self.timer.update(&mut state);
+        let old = self.setting(&state);
+        state.deadline = deadline;
+        state.interval = interval;
+        state.expirations = 0;
+        self.timer.arm(&mut state);
+        old
+    }
+
+    fn setting(&self, state: &TimerState) -> (Duration, Duration) {
+        let remaining = state.deadline.map_or(Duration::ZERO, |it| {
+            it.saturating_sub(self.timer.clock.now())
+        });
+        (state.interval, remaining)
+    }
+}
+
+impl FileLike for TimerFd {
+    fn read(&self, dst: &mut IoDst) -> AxResult<usize> {
+        if dst.remaining_mut() < size_of::<u64>() {
+            return Err(AxError::InvalidInput);
+        }
+
+        block_on(poll_io(self, IoEvents::IN, self.nonblocking(), || {
+            let mut state = self.timer.state.lock();
+            self.timer.update(&mut state);
+            if state.expirations == 0 {
+                return Err(AxError::WouldBlock);
+            }
+            let expirations = mem::take(&mut state.expirations);
+            drop(state);
+            dst.write(&expirations.to_ne_bytes())?;
+            Ok(size_of::<u64>())
+        }))
+    }
+
+    fn nonblocking(&self) -> bool {
+        self.non_blocking.load(Ordering::Acquire)
+    }
+
+    fn set_nonblocking(&self, non_blocking: bool) -> AxResult {
+        self.non_blocking.store(non_blocking, Ordering::Release);
+        Ok(())
+    }
+
+    fn path(&self) -> Cow<'_, str> {
+        "anon_inode:[timerfd]".into()
+    }
+}
+
+impl Pollable for TimerFd {
+    fn poll(&self) -> IoEvents {
+        let mut state = self.timer.state.lock();
+        self.timer.update(&mut state);
+        let mut events = IoEvents::empty();
+        events.set(IoEvents::IN, state.expirations > 0);
+        events
+    }
+
+    fn register(&self, context: &mut Context<'_>, events: IoEvents) {
+        if events.contains(IoEvents::IN) {
+            self.timer.poll_rx.register(context.waker());
+        }
+    }
+}
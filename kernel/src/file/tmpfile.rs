@@ -0,0 +1,61 @@
+//! Unnamed files created with `O_TMPFILE`.
+//!
+//! The file is created without a directory entry, so no other process can
+//! see it before it is linked into place. See [`create_unnamed`] for the
+//! filesystems supporting this.
+
+use alloc::sync::Arc;
+
+use axerrno::{AxError, AxResult};
+use axfs_ng_vfs::{Location, MetadataUpdate, NodePermission};
+
+use crate::fs::create_unnamed;
+
+/// The state of a tmpfile, stored in the user data of its entry.
+pub struct TmpFile {
+    /// Whether the file may be linked into place, i.e. it was not opened
+    /// with `O_EXCL`.
+    linkable: bool,
+}
+
+impl TmpFile {
+    /// Creates an unnamed regular file in `dir`.
+    pub fn create(
+        dir: &Location,
+        mode: u32,
+        user: (u32, u32),
+        linkable: bool,
+    ) -> AxResult<Location> {
+        dir.check_is_dir()?;
+        let loc = create_unnamed(dir, NodePermission::from_bits_truncate(mode as _))?;
+        loc.update_metadata(MetadataUpdate {
+            owner: Some(user),
+            ..Default::default()
+        })?;
+        loc.user_data().insert(Self { linkable });
+        Ok(loc)
+    }
+
+    /// Returns the tmpfile state of `loc`, if it is a tmpfile.
+    pub fn of(loc: &Location) -> Option<Arc<Self>> {
+        loc.user_data().get::<Self>()
+    }
+
+    /// Returns whether the file `loc` has not been linked into place yet.
+    pub fn is_unnamed(&self, loc: &Location) -> bool {
+        loc.metadata().is_ok_and(|it| it.nlink == 0)
+    }
+}
+
+/// Checks whether `loc` may be given a new link.
+///
+/// Files without links can't be linked again, unless they are tmpfiles
+/// opened without `O_EXCL`.
+pub fn check_link_source(loc: &Location) -> AxResult {
+    match TmpFile::of(loc) {
+        Some(tmp) if !tmp.linkable => Err(AxError::NotFound),
+        Some(_) => Ok(()),
+        None if loc.metadata()?.nlink == 0 => Err(AxError::NotFound),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,456 @@
+//! userfaultfd file descriptors.
+
+use alloc::{
+    borrow::Cow,
+    collections::btree_map::BTreeMap,
+    sync::{Arc, Weak},
+};
+use core::{
+    future::poll_fn,
+    mem::{self, MaybeUninit},
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axpoll::{IoEvents, PollSet, Pollable};
+use axsync::Mutex;
+use axtask::{
+    current,
+    future::{block_on, interruptible, poll_io},
+};
+use kspin::SpinNoIrq;
+use linux_raw_sys::{
+    general::{
+        _UFFDIO_API, _UFFDIO_COPY, _UFFDIO_REGISTER, _UFFDIO_UNREGISTER, _UFFDIO_WAKE,
+        _UFFDIO_WRITEPROTECT, _UFFDIO_ZEROPAGE, UFFD_API, UFFD_EVENT_PAGEFAULT,
+        UFFD_FEATURE_EXACT_ADDRESS, UFFD_FEATURE_PAGEFAULT_FLAG_WP, UFFD_FEATURE_THREAD_ID,
+        UFFDIO_COPY_MODE_DONTWAKE, UFFDIO_COPY_MODE_WP, UFFDIO_REGISTER_MODE_MISSING,
+        UFFDIO_REGISTER_MODE_WP, UFFDIO_ZEROPAGE_MODE_DONTWAKE, uffdio_api, uffdio_copy,
+        uffdio_range, uffdio_register, uffdio_writeprotect, uffdio_zeropage,
+    },
+    ioctl::{
+        UFFDIO_API, UFFDIO_COPY, UFFDIO_REGISTER, UFFDIO_UNREGISTER, UFFDIO_WAKE,
+        UFFDIO_WRITEPROTECT, UFFDIO_ZEROPAGE,
+    },
+};
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K, VirtAddr, VirtAddrRange};
+use starry_process::Pid;
+use starry_vm::{VmMutPtr, VmPtr, vm_read_slice};
+use zerocopy::{Immutable, IntoBytes};
+
+use crate::{
+    file::{FileLike, IoDst},
+    mm::AddrSpace,
+};
+
+const UFFDIO_WRITEPROTECT_MODE_WP: u64 = 1;
+const UFFDIO_WRITEPROTECT_MODE_DONTWAKE: u64 = 2;
+
+const SUPPORTED_FEATURES: u64 =
+    (UFFD_FEATURE_PAGEFAULT_FLAG_WP | UFFD_FEATURE_THREAD_ID | UFFD_FEATURE_EXACT_ADDRESS) as u64;
+const API_IOCTLS: u64 = 1 << _UFFDIO_REGISTER | 1 << _UFFDIO_UNREGISTER | 1 << _UFFDIO_API;
+const RANGE_IOCTLS: u64 = 1 << _UFFDIO_WAKE | 1 << _UFFDIO_COPY | 1 << _UFFDIO_ZEROPAGE;
+
+/// A `uffd_msg` for a page fault event.
+#[derive(IntoBytes, Immutable)]
+#[repr(C)]
+struct PageFaultMsg {
+    event: u8,
+    reserved: [u8; 7],
+    flags: u64,
+    address: u64,
+    ptid: u32,
+    padding: u32,
+}
+
+struct Fault {
+    address: VirtAddr,
+    /// The `UFFD_PAGEFAULT_FLAG_*` flags.
+    flags: u32,
+    tid: Pid,
+    /// Whether the fault has been read.
+    reported: bool,
+}
+
+#[derive(Default)]
+struct FaultQueue {
+    /// Unresolved faults, in the order they occurred.
+    faults: BTreeMap<u64, Fault>,
+    next_id: u64,
+    /// Whether the userfaultfd is closed, after which faults are handled
+    /// normally.
+    released: bool,
+}
+
+struct Faults {
+    queue: SpinNoIrq<FaultQueue>,
+    /// Woken up when faults are resolved.
+    fault_rx: PollSet,
+    /// Woken up when faults occur.
+    poll_rx: PollSet,
+}
+
+/// A userfaultfd, through which page faults in registered ranges are
+/// reported to and resolved by user space.
+pub struct UserFaultFd {
+    this: Weak<Self>,
+    faults: Arc<Faults>,
+    aspace: Weak<Mutex<AddrSpace>>,
+    /// The features enabled by `UFFDIO_API`, which must come first.
+    features: SpinNoIrq<Option<u64>>,
+    non_blocking: AtomicBool,
+}
+
+impl UserFaultFd {
+    pub fn new(aspace: &Arc<Mutex<AddrSpace>>) -> Arc<Self> {
+        Arc::new_cyclic(|this| Self {
+            this: this.clone(),
+            faults: Arc::new(Faults {
+                queue: SpinNoIrq::default(),
+                fault_rx: PollSet::new(),
+                poll_rx: PollSet::new(),
+            }),
+            aspace: Arc::downgrade(aspace),
+            features: SpinNoIrq::new(None),
+            non_blocking: AtomicBool::new(false),
+        })
+    }
+
+    /// Reports a fault at `address` with the `UFFD_PAGEFAULT_FLAG_*` `flags`,
+    /// and blocks the current thread until it is resolved.
+    ///
+    /// The fault may be resolved spuriously, so the access should be retried
+    /// afterwards. Returns `false` if the wait is interrupted by a signal.
+    pub fn handle_fault(self: Arc<Self>, address: VirtAddr, flags: u32) -> bool {
+        // The userfaultfd may be closed while the thread is blocked.
+        let faults = self.faults.clone();
+        drop(self);
+
+        let mut queue = faults.queue.lock();
+        if queue.released {
+            return true;
+        }
+        let id = queue.next_id;
+        queue.next_id += 1;
+        queue.faults.insert(
+            id,
+            Fault {
+                address,
+                flags,
+                tid: current().id().as_u64() as Pid,
+                reported: false,
+            },
+        );
+        drop(queue);
+        faults.poll_rx.wake();
+
+        let result = block_on(interruptible(poll_fn(|cx| {
+            faults.fault_rx.register(cx.waker());
+            let queue = faults.queue.lock();
+            if queue.released || !queue.faults.contains_key(&id) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })));
+        // An interrupted fault is reported again when the access is retried.
+        faults.queue.lock().faults.remove(&id);
+        result.is_ok()
+    }
+
+    /// Wakes up the threads blocked on faults in `range`.
+    fn wake(&self, range: VirtAddrRange) {
+        self.faults
+            .queue
+            .lock()
+            .faults
+            .retain(|_, it| !range.contains(it.address));
+        self.faults.fault_rx.wake();
+    }
+
+    fn aspace(&self) -> AxResult<Arc<Mutex<AddrSpace>>> {
+        self.aspace
+            .upgrade()
+            .ok_or(AxError::from(LinuxError::ESRCH))
+    }
+
+    fn features(&self) -> AxResult<u64> {
+        self.features.lock().ok_or(AxError::InvalidInput)
+    }
+
+    fn api(&self, arg: *mut uffdio_api) -> AxResult {
+        // FIXME: AnyBitPattern
+        let mut api = unsafe { arg.vm_read_uninit()?.assume_init() };
+        let mut features = self.features.lock();
+        if features.is_some()
+            || api.api != UFFD_API as u64
+            || api.features & !SUPPORTED_FEATURES != 0
+        {
+            return Err(AxError::InvalidInput);
+        }
+        *features = Some(api.features);
+        drop(features);
+
+        api.features = SUPPORTED_FEATURES;
+        api.ioctls = API_IOCTLS;
+        arg.vm_write(api)?;
+        Ok(())
+    }
+
+    fn register(&self, arg: *mut uffdio_register) -> AxResult {
+        // FIXME: AnyBitPattern
+        let mut reg = unsafe { arg.vm_read_uninit()?.assume_init() };
+        let range = check_range(&reg.range)?;
+        let supported = (UFFDIO_REGISTER_MODE_MISSING | UFFDIO_REGISTER_MODE_WP) as u64;
+        if reg.mode == 0 || reg.mode & !supported != 0 {
+            return Err(AxError::InvalidInput);
+        }
+        self.aspace()?
+            .lock()
+            .register_userfault(range, reg.mode, self.this.clone())?;
+
+        reg.ioctls = RANGE_IOCTLS;
+        if reg.mode & UFFDIO_REGISTER_MODE_WP as u64 != 0 {
+            reg.ioctls |= 1 << _UFFDIO_WRITEPROTECT;
+        }
+        arg.vm_write(reg)?;
+        Ok(())
+    }
+
+    /// Fills the missing pages in `range` with the data at `src`, or with
+    /// zeroes if it is `None`, stopping at the first error.
+    ///
+    /// Returns the number of bytes filled, or the error if none is.
+    fn fill(&self, range: VirtAddrRange, src: Option<usize>, wp: bool) -> AxResult<usize> {
+        let aspace = self.aspace()?;
+        let mut filled = 0;
+        while filled < range.size() {
+            let result = (|| {
+                let mut buf = [0; PAGE_SIZE_4K];
+                let data = match src {
+                    Some(src) => {
+                        vm_read_slice((src + filled) as *const u8, unsafe {
+                            mem::transmute::<&mut [u8], &mut [MaybeUninit<u8>]>(&mut buf)
+                        })?;
+                        Some(&buf)
+                    }
+                    None => None,
+                };
+                aspace
+                    .lock()
+                    .fill_userfault_page(range.start + filled, data, wp)
+            })();
+            match result {
+                Ok(()) => filled += PAGE_SIZE_4K,
+                Err(err) if filled == 0 => return Err(err),
+                Err(_) => break,
+            }
+        }
+        Ok(filled)
+    }
+
+    fn copy(&self, arg: *mut uffdio_copy) -> AxResult {
+        // FIXME: AnyBitPattern
+        let mut copy = unsafe { arg.vm_read_uninit()?.assume_init() };
+        let range = check_range(&uffdio_range {
+            start: copy.dst,
+            len: copy.len,
+        })?;
+        let supported = (UFFDIO_COPY_MODE_DONTWAKE | UFFDIO_COPY_MODE_WP) as u64;
+        if copy.mode & !supported != 0 || !(copy.src as usize).is_aligned_4k() {
+            return Err(AxError::InvalidInput);
+        }
+
+        let wp = copy.mode & UFFDIO_COPY_MODE_WP as u64 != 0;
+        let result = self.fill(range, Some(copy.src as usize), wp);
+        copy.copy = match result {
+            Ok(filled) => filled as i64,
+            Err(err) => -(LinuxError::from(err).code() as i64),
+        };
+        arg.vm_write(copy)?;
+        if copy.mode & UFFDIO_COPY_MODE_DONTWAKE as u64 == 0 {
+            self.wake(range);
+        }
+        result.and_then(|filled| {
+            if filled < range.size() {
+                Err(AxError::WouldBlock)
+            } else {
+                Ok(())
+            }
+        })
+    }
+
+    fn zeropage(&self, arg: *mut uffdio_zeropage) -> AxResult {
+        // FIXME: AnyBitPattern
+        let mut zeropage = unsafe { arg.vm_read_uninit()?.assume_init() };
+        let range = check_range(&zeropage.range)?;
+        if zeropage.mode & !UFFDIO_ZEROPAGE_MODE_DONTWAKE as u64 != 0 {
+            return Err(AxError::InvalidInput);
+        }
+
+        let result = self.fill(range, None, false);
+        zeropage.zeropage = match result {
+            Ok(filled) => filled as i64,
+            Err(err) => -(LinuxError::from(err).code() as i64),
+        };
+        arg.vm_write(zeropage)?;
+        if zeropage.mode & UFFDIO_ZEROPAGE_MODE_DONTWAKE as u64 == 0 {
+            self.wake(range);
+        }
+        result.and_then(|filled| {
+            if filled < range.size() {
+                Err(AxError::WouldBlock)
+            } else {
+                Ok(())
+            }
+        })
+    }
+
+    fn write_protect(&self, arg: *const uffdio_writeprotect) -> AxResult {
+        // FIXME: AnyBitPattern
+        let wp = unsafe { arg.vm_read_uninit()?.assume_init() };
+        let range = check_range(&wp.range)?;
+        if wp.mode & !(UFFDIO_WRITEPROTECT_MODE_WP | UFFDIO_WRITEPROTECT_MODE_DONTWAKE) != 0 {
+            return Err(AxError::InvalidInput);
+        }
+        let protect = wp.mode & UFFDIO_WRITEPROTECT_MODE_WP != 0;
+        // Waking up the faulting threads makes no sense when protecting.
+        if protect && wp.mode & UFFDIO_WRITEPROTECT_MODE_DONTWAKE != 0 {
+            return Err(AxError::InvalidInput);
+        }
+
+        self.aspace()?
+            .lock()
+            .write_protect_userfault(range, protect)?;
+        if !protect && wp.mode & UFFDIO_WRITEPROTECT_MODE_DONTWAKE == 0 {
+            self.wake(range);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for UserFaultFd {
+    fn drop(&mut self) {
+        self.faults.queue.lock().released = true;
+        self.faults.fault_rx.wake();
+        if let Some(aspace) = self.aspace.upgrade() {
+            aspace.lock().release_userfaults(self);
+        }
+    }
+}
+
+/// Checks a range given to an ioctl, which has to be page-aligned and
+/// non-empty.
+fn check_range(range: &uffdio_range) -> AxResult<VirtAddrRange> {
+    let start = VirtAddr::from(range.start as usize);
+    let len = range.len as usize;
+    if len == 0 || !start.is_aligned_4k() || !len.is_aligned_4k() {
+        return Err(AxError::InvalidInput);
+    }
+    VirtAddrRange::try_from_start_size(start, len).ok_or(AxError::InvalidInput)
+}
+
+impl FileLike for UserFaultFd {
+    fn read(&self, dst: &mut IoDst) -> AxResult<usize> {
+        const MSG_SIZE: usize = size_of::<PageFaultMsg>();
+        let features = self.features()?;
+        if dst.remaining_mut() < MSG_SIZE {
+            return Err(AxError::InvalidInput);
+        }
+
+        block_on(poll_io(self, IoEvents::IN, self.nonblocking(), || {
+            let mut queue = self.faults.queue.lock();
+            let mut read = 0;
+            for fault in queue.faults.values_mut().filter(|it| !it.reported) {
+                if dst.remaining_mut() < MSG_SIZE {
+                    break;
+                }
+                let address = if features & UFFD_FEATURE_EXACT_ADDRESS as u64 != 0 {
+                    fault.address
+                } else {
+                    fault.address.align_down_4k()
+                };
+                let ptid = if features & UFFD_FEATURE_THREAD_ID as u64 != 0 {
+                    fault.tid
+                } else {
+                    0
+                };
+                let msg = PageFaultMsg {
+                    event: UFFD_EVENT_PAGEFAULT as u8,
+                    reserved: [0; 7],
+                    flags: fault.flags as u64,
+                    address: address.as_usize() as u64,
+                    ptid,
+                    padding: 0,
+                };
+                dst.write(msg.as_bytes())?;
+                fault.reported = true;
+                read += MSG_SIZE;
+            }
+            if read == 0 {
+                Err(AxError::WouldBlock)
+            } else {
+                Ok(read)
+            }
+        }))
+    }
+
+    fn nonblocking(&self) -> bool {
+        self.non_blocking.load(Ordering::Acquire)
+    }
+
+    fn set_nonblocking(&self, non_blocking: bool) -> AxResult {
+        self.non_blocking.store(non_blocking, Ordering::Release);
+        Ok(())
+    }
+
+    fn path(&self) -> Cow<'_, str> {
+        "anon_inode:[userfaultfd]".into()
+    }
+
+    fn ioctl(&self, cmd: u32, arg: usize) -> AxResult<usize> {
+        if cmd == UFFDIO_API {
+            self.api(arg as _)?;
+            return Ok(0);
+        }
+        self.features()?;
+        match cmd {
+            UFFDIO_REGISTER => self.register(arg as _)?,
+            UFFDIO_UNREGISTER => {
+                // FIXME: AnyBitPattern
+                let range = unsafe { (arg as *const uffdio_range).vm_read_uninit()?.assume_init() };
+                let range = check_range(&range)?;
+                self.aspace()?
+                    .lock()
+                    .unregister_userfault(range, &self.this);
+                self.wake(range);
+            }
+            UFFDIO_WAKE => {
+                // FIXME: AnyBitPattern
+                let range = unsafe { (arg as *const uffdio_range).vm_read_uninit()?.assume_init() };
+                self.wake(check_range(&range)?);
+            }
+            UFFDIO_COPY => self.copy(arg as _)?,
+            UFFDIO_ZEROPAGE => self.zeropage(arg as _)?,
+            UFFDIO_WRITEPROTECT => self.write_protect(arg as _)?,
+            _ => return Err(AxError::InvalidInput),
+        }
+        Ok(0)
+    }
+}
+
+impl Pollable for UserFaultFd {
+    fn poll(&self) -> IoEvents {
+        let mut events = IoEvents::empty();
+        let queue = self.faults.queue.lock();
+        events.set(IoEvents::IN, queue.faults.values().any(|it| !it.reported));
+        events
+    }
+
+    fn register(&self, context: &mut Context<'_>, events: IoEvents) {
+        if events.contains(IoEvents::IN) {
+            self.faults.poll_rx.register(context.waker());
+        }
+    }
+}
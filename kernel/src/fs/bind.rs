@@ -0,0 +1,198 @@
+//! Bind mounts.
+//!
+//! A bind mount makes a directory tree visible at another place. Since a
+//! [`DirEntry`] remembers its parent, the source tree can't be mounted as-is
+//! (`..` and absolute paths would lead back into the source). Instead,
+//! directories are wrapped so that they form a tree rooted at the bind mount,
+//! while files are shared with the source so that they also share the page
+//! cache.
+
+use alloc::{string::String, sync::Arc};
+use core::{any::Any, task::Context};
+
+use axfs_ng_vfs::{
+    DirEntry, DirEntrySink, DirNode, DirNodeOps, Filesystem, FilesystemOps, Location, Metadata,
+    MetadataUpdate, NodeFlags, NodeOps, NodePermission, NodeType, Reference, StatFs, VfsError,
+    VfsResult, WeakDirEntry,
+};
+use axpoll::{IoEvents, Pollable};
+use axsync::Mutex;
+use hashbrown::HashMap;
+
+/// A directory in a bind mount, forwarding everything to the source
+/// directory.
+struct BindDir {
+    source: DirEntry,
+    this: WeakDirEntry,
+    /// Wrappers of subdirectories handed out so far, so that looking up the
+    /// same directory twice yields the same entry (mountpoints are attached
+    /// to entries).
+    children: Mutex<HashMap<String, WeakDirEntry>>,
+}
+
+impl BindDir {
+    fn new_entry(source: DirEntry, reference: Reference) -> DirEntry {
+        DirEntry::new_dir(
+            |this| {
+                DirNode::new(Arc::new(Self {
+                    source,
+                    this,
+                    children: Mutex::new(HashMap::new()),
+                }))
+            },
+            reference,
+        )
+    }
+
+    fn source_dir(&self) -> VfsResult<&DirNode> {
+        self.source.as_dir()
+    }
+
+    fn wrap(&self, name: &str, entry: DirEntry) -> DirEntry {
+        if !entry.is_dir() {
+            return entry;
+        }
+        let mut children = self.children.lock();
+        if let Some(wrapper) = children.get(name).and_then(WeakDirEntry::upgrade)
+            && let Ok(dir) = wrapper.downcast::<Self>()
+            && dir.source.ptr_eq(&entry)
+        {
+            return wrapper;
+        }
+        let wrapper = Self::new_entry(entry, Reference::new(self.this.upgrade(), name.into()));
+        children.insert(name.into(), wrapper.downgrade());
+        wrapper
+    }
+}
+
+impl NodeOps for BindDir {
+    fn inode(&self) -> u64 {
+        self.source.inode()
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        self.source.metadata()
+    }
+
+    fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+        self.source.update_metadata(update)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        self.source.filesystem()
+    }
+
+    fn len(&self) -> VfsResult<u64> {
+        self.source.len()
+    }
+
+    fn sync(&self, data_only: bool) -> VfsResult<()> {
+        self.source.sync(data_only)
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn flags(&self) -> NodeFlags {
+        self.source.flags()
+    }
+}
+
+impl Pollable for BindDir {
+    fn poll(&self) -> IoEvents {
+        IoEvents::IN | IoEvents::OUT
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
+
+impl DirNodeOps for BindDir {
+    fn read_dir(&self, offset: u64, sink: &mut dyn DirEntrySink) -> VfsResult<usize> {
+        self.source_dir()?.read_dir(offset, sink)
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<DirEntry> {
+        let entry = self.source_dir()?.lookup(name)?;
+        Ok(self.wrap(name, entry))
+    }
+
+    fn is_cacheable(&self) -> bool {
+        // The source directory does the caching, and may be modified through
+        // other paths.
+        false
+    }
+
+    fn create(
+        &self,
+        name: &str,
+        node_type: NodeType,
+        permission: NodePermission,
+    ) -> VfsResult<DirEntry> {
+        let entry = self.source_dir()?.create(name, node_type, permission)?;
+        Ok(self.wrap(name, entry))
+    }
+
+    fn link(&self, name: &str, node: &DirEntry) -> VfsResult<DirEntry> {
+        let entry = self.source_dir()?.link(name, node)?;
+        Ok(self.wrap(name, entry))
+    }
+
+    fn unlink(&self, name: &str) -> VfsResult<()> {
+        let dir = self.source_dir()?;
+        let is_dir = dir.lookup(name)?.is_dir();
+        dir.unlink(name, is_dir)?;
+        self.children.lock().remove(name);
+        Ok(())
+    }
+
+    fn rename(&self, src_name: &str, dst_dir: &DirNode, dst_name: &str) -> VfsResult<()> {
+        let dst: Arc<Self> = dst_dir.downcast().map_err(|_| VfsError::CrossesDevices)?;
+        self.source_dir()?
+            .rename(src_name, dst.source_dir()?, dst_name)?;
+        self.children.lock().remove(src_name);
+        dst.children.lock().remove(dst_name);
+        Ok(())
+    }
+}
+
+/// The filesystem of a bind mount.
+struct BindFs {
+    source: DirEntry,
+    root: DirEntry,
+}
+
+impl FilesystemOps for BindFs {
+    fn name(&self) -> &str {
+        self.source.filesystem().name()
+    }
+
+    fn root_dir(&self) -> DirEntry {
+        self.root.clone()
+    }
+
+    fn stat(&self) -> VfsResult<StatFs> {
+        self.source.filesystem().stat()
+    }
+
+    fn flush(&self) -> VfsResult<()> {
+        self.source.filesystem().flush()
+    }
+}
+
+/// Creates a filesystem exposing the directory tree at `source`.
+pub fn new_bind_fs(source: Location) -> VfsResult<Filesystem> {
+    source.check_is_dir()?;
+    let source = source.entry().clone();
+    let root = BindDir::new_entry(source.clone(), Reference::root());
+    Ok(Filesystem::new(Arc::new(BindFs { source, root })))
+}
+
+/// Returns the source directory of `entry` if it is a directory in a bind
+/// mount.
+pub(super) fn bind_source(entry: &DirEntry) -> Option<DirEntry> {
+    entry
+        .downcast::<BindDir>()
+        .ok()
+        .map(|dir| dir.source.clone())
+}
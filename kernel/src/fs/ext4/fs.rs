@@ -0,0 +1,88 @@
+use alloc::sync::Arc;
+use core::cell::OnceCell;
+
+use axfs_ng_vfs::{
+    DirEntry, DirNode, Filesystem, FilesystemOps, Reference, StatFs, VfsResult, path::MAX_NAME_LEN,
+};
+use axsync::{Mutex, MutexGuard};
+use lwext4_rust::{FsConfig, ffi::EXT4_ROOT_INO};
+
+use super::{Ext4Disk, LwExt4Filesystem, inode::Inode, into_vfs_err, orphan};
+use crate::pseudofs::DeviceOps;
+
+const EXT4_CONFIG: FsConfig = FsConfig { bcache_size: 256 };
+
+/// An ext4 filesystem backed by a block device.
+///
+/// Unlike the root filesystem set up by `axfs`, I/O here may end up in the
+/// page cache of another filesystem (e.g. the backing file of a loop device),
+/// so a sleeping lock is used instead of a spinlock.
+pub struct Ext4Filesystem {
+    inner: Mutex<LwExt4Filesystem>,
+    root_dir: OnceCell<DirEntry>,
+}
+
+impl Ext4Filesystem {
+    /// Opens the ext4 filesystem stored on `dev`.
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(dev: Arc<dyn DeviceOps>) -> VfsResult<Filesystem> {
+        let mut ext4 = LwExt4Filesystem::new(Ext4Disk(dev), EXT4_CONFIG).map_err(into_vfs_err)?;
+        orphan::release_all(&mut ext4).map_err(into_vfs_err)?;
+
+        let fs = Arc::new(Self {
+            inner: Mutex::new(ext4),
+            root_dir: OnceCell::new(),
+        });
+        let _ = fs.root_dir.set(DirEntry::new_dir(
+            |this| DirNode::new(Inode::new(fs.clone(), EXT4_ROOT_INO, Some(this))),
+            Reference::root(),
+        ));
+        Ok(Filesystem::new(fs))
+    }
+
+    pub(crate) fn lock(&self) -> MutexGuard<'_, LwExt4Filesystem> {
+        self.inner.lock()
+    }
+}
+
+// SAFETY: the raw pointers within `LwExt4Filesystem` only point into its own
+// boxed state, so it may move to another thread, and `OnceCell` is only set
+// in `new` before the filesystem is shared.
+unsafe impl Send for Ext4Filesystem {}
+
+// SAFETY: lwext4 is only reached through `inner`, whose mutex serializes all
+// accesses to it, and `root_dir` is never written after `new`.
+unsafe impl Sync for Ext4Filesystem {}
+
+impl FilesystemOps for Ext4Filesystem {
+    fn name(&self) -> &str {
+        "ext4"
+    }
+
+    fn root_dir(&self) -> DirEntry {
+        self.root_dir.get().unwrap().clone()
+    }
+
+    fn stat(&self) -> VfsResult<StatFs> {
+        let mut fs = self.lock();
+        let stat = fs.stat().map_err(into_vfs_err)?;
+        Ok(StatFs {
+            fs_type: 0xef53,
+            block_size: stat.block_size as _,
+            blocks: stat.blocks_count,
+            blocks_free: stat.free_blocks_count,
+            blocks_available: stat.free_blocks_count,
+
+            file_count: stat.inodes_count as _,
+            free_file_count: stat.free_inodes_count as _,
+
+            name_length: MAX_NAME_LEN as _,
+            fragment_size: 0,
+            mount_flags: 0,
+        })
+    }
+
+    fn flush(&self) -> VfsResult<()> {
+        self.lock().flush().map_err(into_vfs_err)
+    }
+}
@@ -0,0 +1,527 @@
+use alloc::{borrow::ToOwned, format, string::String, sync::Arc, vec::Vec};
+use core::{
+    any::Any,
+    mem::MaybeUninit,
+    ops::Range,
+    sync::atomic::{AtomicBool, Ordering},
+    task::Context,
+};
+
+use axerrno::AxResult;
+use axfs_ng_vfs::{
+    DeviceId, DirEntry, DirEntrySink, DirNode, DirNodeOps, FileNode, FileNodeOps, FilesystemOps,
+    Metadata, MetadataUpdate, NodeFlags, NodeOps, NodePermission, NodeType, Reference, VfsError,
+    VfsResult, WeakDirEntry,
+};
+use axpoll::{IoEvents, Pollable};
+use lwext4_rust::{
+    Ext4Error, Ext4Result, FileAttr, InodeType,
+    ffi::{
+        EIO, EXT4_INODE_FLAG_EXTENTS, ext4_block, ext4_block_get, ext4_block_set, ext4_blockdev,
+        ext4_fs_get_inode_dblk_idx, ext4_inode, ext4_inode_has_flag,
+    },
+};
+use zerocopy::IntoBytes;
+
+use super::{
+    Ext4Filesystem, LwExt4Filesystem, into_vfs_err, into_vfs_type, orphan, raw_inode, xattr,
+};
+use crate::fs::{XattrFlags, XattrOps, check_set_flags, no_data};
+
+unsafe extern "C" {
+    fn ext4_inode_get_dev(inode: *mut ext4_inode) -> u32;
+
+    fn ext4_inode_set_dev(inode: *mut ext4_inode, dev: u32);
+}
+
+/// Magic number in the header of extent tree nodes.
+const EXTENT_MAGIC: u16 = 0xf30a;
+
+/// Length of extent headers and entries.
+const EXTENT_ENTRY_LEN: usize = 12;
+
+/// Lengths above this mark unwritten extents.
+const EXTENT_MAX_WRITTEN_LEN: u16 = 32768;
+
+/// Appends `run` to `runs`, merging it with the last run if adjacent.
+fn push_run(runs: &mut Vec<Range<u64>>, run: Range<u64>) {
+    match runs.last_mut() {
+        Some(last) if last.end == run.start => last.end = run.end,
+        _ => runs.push(run),
+    }
+}
+
+/// Collects the written extents of the extent tree node `node` that end
+/// after block `from` into `runs`, as ranges of logical blocks.
+fn collect_extents(
+    bdev: *mut ext4_blockdev,
+    node: &[u8],
+    from: u64,
+    runs: &mut Vec<Range<u64>>,
+) -> Ext4Result<()> {
+    let u16_at = |offset: usize| u16::from_le_bytes([node[offset], node[offset + 1]]);
+    let u32_at = |offset: usize| u32::from_le_bytes(node[offset..offset + 4].try_into().unwrap());
+    if u16_at(0) != EXTENT_MAGIC {
+        return Err(Ext4Error::new(EIO as _, "bad extent header"));
+    }
+    let entries = (u16_at(2) as usize).min(node.len() / EXTENT_ENTRY_LEN - 1);
+    let depth = u16_at(6);
+    let entry = |i: usize| EXTENT_ENTRY_LEN * (i + 1);
+
+    for i in 0..entries {
+        let block = u32_at(entry(i)) as u64;
+        if depth == 0 {
+            let len = u16_at(entry(i) + 4);
+            if len <= EXTENT_MAX_WRITTEN_LEN && block + len as u64 > from {
+                push_run(runs, block..block + len as u64);
+            }
+            continue;
+        }
+        // An index covers the blocks up to where the next one starts.
+        if i + 1 < entries && u32_at(entry(i + 1)) as u64 <= from {
+            continue;
+        }
+        let leaf = u32_at(entry(i) + 4) as u64 | (u16_at(entry(i) + 8) as u64) << 32;
+        let mut child = MaybeUninit::<ext4_block>::zeroed();
+        let ret = unsafe { ext4_block_get(bdev, child.as_mut_ptr(), leaf) };
+        if ret != 0 {
+            return Err(Ext4Error::new(ret, "ext4_block_get"));
+        }
+        let mut child = unsafe { child.assume_init() };
+        let block_size = unsafe { (*bdev).lg_bsize } as usize;
+        let data = unsafe { core::slice::from_raw_parts(child.data, block_size) };
+        let result = collect_extents(bdev, data, from, runs);
+        unsafe { ext4_block_set(bdev, &mut child) };
+        result?;
+    }
+    Ok(())
+}
+
+/// An ext4 inode.
+pub struct Inode {
+    fs: Arc<Ext4Filesystem>,
+    ino: u32,
+    this: Option<WeakDirEntry>,
+    /// Whether the inode is on the orphan list, to be freed with this node.
+    orphan: AtomicBool,
+}
+
+impl Inode {
+    pub(crate) fn new(fs: Arc<Ext4Filesystem>, ino: u32, this: Option<WeakDirEntry>) -> Arc<Self> {
+        Arc::new(Self {
+            fs,
+            ino,
+            this,
+            orphan: AtomicBool::new(false),
+        })
+    }
+
+    /// Creates a regular file in this directory without giving it a name.
+    ///
+    /// The inode stays on the orphan list until it is linked into place, and
+    /// is freed once released otherwise.
+    pub fn create_unnamed(&self, permission: NodePermission) -> VfsResult<DirEntry> {
+        let mut fs = self.fs.lock();
+        if !fs
+            .with_inode_ref(self.ino, |inode| Ok(inode.is_dir()))
+            .map_err(into_vfs_err)?
+        {
+            return Err(VfsError::NotADirectory);
+        }
+        let ino = orphan::alloc_file(&mut fs, permission.bits() as _).map_err(into_vfs_err)?;
+        let inode = Arc::new(Self {
+            fs: self.fs.clone(),
+            ino,
+            this: None,
+            orphan: AtomicBool::new(true),
+        });
+        Ok(DirEntry::new_file(
+            FileNode::new(inode),
+            NodeType::RegularFile,
+            Reference::new(
+                self.this.as_ref().and_then(WeakDirEntry::upgrade),
+                format!("#{ino}"),
+            ),
+        ))
+    }
+
+    fn create_entry(&self, entry: &lwext4_rust::DirEntry, name: impl Into<String>) -> DirEntry {
+        let reference = Reference::new(
+            self.this.as_ref().and_then(WeakDirEntry::upgrade),
+            name.into(),
+        );
+        if entry.inode_type() == InodeType::Directory {
+            DirEntry::new_dir(
+                |this| DirNode::new(Inode::new(self.fs.clone(), entry.ino(), Some(this))),
+                reference,
+            )
+        } else {
+            DirEntry::new_file(
+                FileNode::new(Inode::new(self.fs.clone(), entry.ino(), None)),
+                into_vfs_type(entry.inode_type()),
+                reference,
+            )
+        }
+    }
+
+    fn lookup_locked(&self, fs: &mut LwExt4Filesystem, name: &str) -> VfsResult<DirEntry> {
+        let mut result = fs.lookup(self.ino, name).map_err(into_vfs_err)?;
+        let entry = result.entry();
+        Ok(self.create_entry(&entry, name))
+    }
+
+    /// Returns the ranges of the file holding data that end after `start`,
+    /// in order and in bytes, up to `end` for files without extents.
+    /// Unwritten extents read as zeros, so they count as holes.
+    pub(crate) fn data_ranges(&self, start: u64, end: u64) -> VfsResult<Vec<Range<u64>>> {
+        let block_size = self.metadata()?.block_size.max(1);
+        let from = start / block_size;
+        let mut blocks = Vec::new();
+        self.fs
+            .lock()
+            .with_inode_ref(self.ino, |inode| {
+                let inode = raw_inode(inode);
+                if unsafe { ext4_inode_has_flag((*inode).inode, EXT4_INODE_FLAG_EXTENTS) } {
+                    let root = unsafe { (*(*inode).inode).blocks };
+                    let bdev = unsafe { (*(*inode).fs).bdev };
+                    return collect_extents(bdev, root.as_bytes(), from, &mut blocks);
+                }
+                // Block maps have no extents to walk.
+                for block in from..end.div_ceil(block_size) {
+                    let mut fblock = 0;
+                    let ret = unsafe {
+                        ext4_fs_get_inode_dblk_idx(inode, block as _, &mut fblock, false)
+                    };
+                    if ret != 0 {
+                        return Err(Ext4Error::new(ret, None));
+                    }
+                    if fblock != 0 {
+                        push_run(&mut blocks, block..block + 1);
+                    }
+                }
+                Ok(())
+            })
+            .map_err(into_vfs_err)?;
+        Ok(blocks
+            .into_iter()
+            .map(|it| it.start * block_size..it.end * block_size)
+            .collect())
+    }
+
+    /// Returns the device number of a device node, which is kept in the
+    /// first block pointers.
+    fn rdev(&self) -> VfsResult<DeviceId> {
+        self.fs
+            .lock()
+            .with_inode_ref(self.ino, |inode| {
+                let inode = raw_inode(inode);
+                Ok(DeviceId(
+                    unsafe { ext4_inode_get_dev((*inode).inode) } as u64
+                ))
+            })
+            .map_err(into_vfs_err)
+    }
+
+    /// Sets the device number of a device node.
+    pub(crate) fn set_rdev(&self, rdev: DeviceId) -> VfsResult<()> {
+        let dev = u32::try_from(rdev.0).map_err(|_| VfsError::InvalidInput)?;
+        self.fs
+            .lock()
+            .with_inode_ref(self.ino, |inode| {
+                let inode = raw_inode(inode);
+                unsafe {
+                    ext4_inode_set_dev((*inode).inode, dev);
+                    (*inode).dirty = true;
+                }
+                Ok(())
+            })
+            .map_err(into_vfs_err)
+    }
+
+    fn update_ctime_locked(&self, fs: &mut LwExt4Filesystem, ino: u32) -> VfsResult<()> {
+        fs.with_inode_ref(ino, |ino| {
+            ino.update_ctime();
+            Ok(())
+        })
+        .map_err(into_vfs_err)
+    }
+}
+
+impl NodeOps for Inode {
+    fn inode(&self) -> u64 {
+        self.ino as _
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        let mut attr = FileAttr::default();
+        self.fs
+            .lock()
+            .get_attr(self.ino, &mut attr)
+            .map_err(into_vfs_err)?;
+        let rdev = match attr.node_type {
+            InodeType::CharacterDevice | InodeType::BlockDevice => self.rdev()?,
+            _ => DeviceId::default(),
+        };
+        Ok(Metadata {
+            inode: self.ino as _,
+            device: attr.device,
+            nlink: attr.nlink,
+            mode: NodePermission::from_bits_truncate(attr.mode as u16),
+            node_type: into_vfs_type(attr.node_type),
+            uid: attr.uid,
+            gid: attr.gid,
+            size: attr.size,
+            block_size: attr.block_size,
+            blocks: attr.blocks,
+            rdev,
+            atime: attr.atime,
+            mtime: attr.mtime,
+            ctime: attr.ctime,
+        })
+    }
+
+    fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+        let mut fs = self.fs.lock();
+        fs.with_inode_ref(self.ino, |inode| {
+            if let Some(mode) = update.mode {
+                inode.set_mode((inode.mode() & !0xfff) | (mode.bits() as u32));
+            }
+            if let Some((uid, gid)) = update.owner {
+                inode.set_owner(uid as _, gid as _);
+            }
+            if let Some(atime) = update.atime {
+                inode.set_atime(&atime);
+            }
+            if let Some(mtime) = update.mtime {
+                inode.set_mtime(&mtime);
+            }
+            inode.update_ctime();
+            Ok(())
+        })
+        .map_err(into_vfs_err)?;
+        Ok(())
+    }
+
+    fn len(&self) -> VfsResult<u64> {
+        self.fs
+            .lock()
+            .with_inode_ref(self.ino, |inode| Ok(inode.size()))
+            .map_err(into_vfs_err)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.fs
+    }
+
+    fn sync(&self, _data_only: bool) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn flags(&self) -> NodeFlags {
+        NodeFlags::BLOCKING
+    }
+}
+
+impl FileNodeOps for Inode {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        self.fs
+            .lock()
+            .read_at(self.ino, buf, offset)
+            .map_err(into_vfs_err)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> VfsResult<usize> {
+        self.fs
+            .lock()
+            .write_at(self.ino, buf, offset)
+            .map_err(into_vfs_err)
+    }
+
+    fn append(&self, buf: &[u8]) -> VfsResult<(usize, u64)> {
+        let mut fs = self.fs.lock();
+        let length = fs
+            .with_inode_ref(self.ino, |inode| Ok(inode.size()))
+            .map_err(into_vfs_err)?;
+        let written = fs.write_at(self.ino, buf, length).map_err(into_vfs_err)?;
+        Ok((written, length + written as u64))
+    }
+
+    fn set_len(&self, len: u64) -> VfsResult<()> {
+        self.fs.lock().set_len(self.ino, len).map_err(into_vfs_err)
+    }
+
+    fn set_symlink(&self, target: &str) -> VfsResult<()> {
+        self.fs
+            .lock()
+            .set_symlink(self.ino, target.as_bytes())
+            .map_err(into_vfs_err)
+    }
+}
+
+impl Pollable for Inode {
+    fn poll(&self) -> IoEvents {
+        IoEvents::IN | IoEvents::OUT
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
+
+impl DirNodeOps for Inode {
+    fn read_dir(&self, offset: u64, sink: &mut dyn DirEntrySink) -> VfsResult<usize> {
+        let mut fs = self.fs.lock();
+        let mut reader = fs.read_dir(self.ino, offset).map_err(into_vfs_err)?;
+        let mut count = 0;
+        while let Some(entry) = reader.current() {
+            let name = core::str::from_utf8(entry.name())
+                .map_err(|_| VfsError::InvalidData)?
+                .to_owned();
+            let ino = entry.ino() as u64;
+            let node_type = into_vfs_type(entry.inode_type());
+            reader.step().map_err(into_vfs_err)?;
+            if !sink.accept(&name, ino, node_type, reader.offset()) {
+                break;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<DirEntry> {
+        let mut fs = self.fs.lock();
+        self.lookup_locked(&mut fs, name)
+    }
+
+    fn create(
+        &self,
+        name: &str,
+        node_type: NodeType,
+        permission: NodePermission,
+    ) -> VfsResult<DirEntry> {
+        let inode_type = match node_type {
+            NodeType::Fifo => InodeType::Fifo,
+            NodeType::CharacterDevice => InodeType::CharacterDevice,
+            NodeType::Directory => InodeType::Directory,
+            NodeType::BlockDevice => InodeType::BlockDevice,
+            NodeType::RegularFile => InodeType::RegularFile,
+            NodeType::Symlink => InodeType::Symlink,
+            NodeType::Socket => InodeType::Socket,
+            NodeType::Unknown => {
+                return Err(VfsError::InvalidData);
+            }
+        };
+        let mut fs = self.fs.lock();
+        if fs.lookup(self.ino, name).is_ok() {
+            return Err(VfsError::AlreadyExists);
+        }
+        let ino = fs
+            .create(self.ino, name, inode_type, permission.bits() as _)
+            .map_err(into_vfs_err)?;
+        self.update_ctime_locked(&mut fs, ino)?;
+
+        let reference = Reference::new(
+            self.this.as_ref().and_then(WeakDirEntry::upgrade),
+            name.to_owned(),
+        );
+        Ok(if node_type == NodeType::Directory {
+            DirEntry::new_dir(
+                |this| DirNode::new(Inode::new(self.fs.clone(), ino, Some(this))),
+                reference,
+            )
+        } else {
+            DirEntry::new_file(
+                FileNode::new(Inode::new(self.fs.clone(), ino, None)),
+                node_type,
+                reference,
+            )
+        })
+    }
+
+    fn link(&self, name: &str, node: &DirEntry) -> VfsResult<DirEntry> {
+        let mut fs = self.fs.lock();
+        fs.link(self.ino, name, node.inode() as _)
+            .map_err(into_vfs_err)?;
+        if let Ok(node) = node.downcast::<Self>()
+            && node.orphan.swap(false, Ordering::AcqRel)
+        {
+            orphan::remove(&mut fs, node.ino).map_err(into_vfs_err)?;
+        }
+        self.update_ctime_locked(&mut fs, node.inode() as _)?;
+        self.lookup_locked(&mut fs, name)
+    }
+
+    fn unlink(&self, name: &str) -> VfsResult<()> {
+        self.fs.lock().unlink(self.ino, name).map_err(into_vfs_err)
+    }
+
+    fn rename(&self, src_name: &str, dst_dir: &DirNode, dst_name: &str) -> VfsResult<()> {
+        let dst_dir: Arc<Self> = dst_dir.downcast().map_err(|_| VfsError::InvalidInput)?;
+        let mut fs = self.fs.lock();
+        fs.rename(self.ino, src_name, dst_dir.ino, dst_name)
+            .map_err(into_vfs_err)
+    }
+}
+
+impl Drop for Inode {
+    fn drop(&mut self) {
+        if !*self.orphan.get_mut() {
+            return;
+        }
+        let mut fs = self.fs.lock();
+        if let Err(err) =
+            orphan::remove(&mut fs, self.ino).and_then(|_| orphan::free(&mut fs, self.ino))
+        {
+            warn!("Failed to free orphan inode {}: {err}", self.ino);
+        }
+    }
+}
+
+impl XattrOps for Inode {
+    fn get_xattr(&self, name: &str) -> AxResult<Vec<u8>> {
+        self.fs
+            .lock()
+            .with_inode_ref(self.ino, |inode| xattr::get(inode, name))
+            .map_err(into_vfs_err)?
+            .ok_or_else(no_data)
+    }
+
+    fn set_xattr(&self, name: &str, value: &[u8], flags: XattrFlags) -> AxResult {
+        let mut fs = self.fs.lock();
+        let exists = fs
+            .with_inode_ref(self.ino, |inode| xattr::exists(inode, name))
+            .map_err(into_vfs_err)?;
+        check_set_flags(flags, exists)?;
+        fs.with_inode_ref(self.ino, |inode| {
+            xattr::set(inode, name, value)?;
+            inode.update_ctime();
+            Ok(())
+        })
+        .map_err(into_vfs_err)
+    }
+
+    fn remove_xattr(&self, name: &str) -> AxResult {
+        let mut fs = self.fs.lock();
+        let exists = fs
+            .with_inode_ref(self.ino, |inode| xattr::exists(inode, name))
+            .map_err(into_vfs_err)?;
+        if !exists {
+            return Err(no_data());
+        }
+        fs.with_inode_ref(self.ino, |inode| {
+            xattr::remove(inode, name)?;
+            inode.update_ctime();
+            Ok(())
+        })
+        .map_err(into_vfs_err)
+    }
+
+    fn list_xattr(&self) -> AxResult<Vec<String>> {
+        self.fs
+            .lock()
+            .with_inode_ref(self.ino, xattr::list)
+            .map_err(into_vfs_err)
+    }
+}
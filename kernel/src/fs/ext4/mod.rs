@@ -0,0 +1,100 @@
+//! ext4 filesystem mounted from a block device node.
+
+mod fs;
+mod inode;
+mod orphan;
+mod xattr;
+
+use alloc::{boxed::Box, sync::Arc};
+
+use axerrno::LinuxError;
+use axfs_ng_vfs::{NodeType, VfsError};
+use lwext4_rust::{
+    BlockDevice, EXT4_DEV_BSIZE, Ext4Error, Ext4Result, InodeRef, InodeType, SystemHal,
+    ffi::{EIO, ext4_inode_ref},
+};
+
+pub use self::{fs::Ext4Filesystem, inode::Inode};
+use crate::pseudofs::DeviceOps;
+
+pub(crate) struct KernelHal;
+
+impl SystemHal for KernelHal {
+    fn now() -> Option<core::time::Duration> {
+        Some(axhal::time::wall_time())
+    }
+}
+
+/// A block device used as the backing storage of an ext4 filesystem.
+pub(crate) struct Ext4Disk(Arc<dyn DeviceOps>);
+
+impl BlockDevice for Ext4Disk {
+    fn read_blocks(&mut self, block_id: u64, mut buf: &mut [u8]) -> Ext4Result<usize> {
+        let len = buf.len();
+        let mut offset = block_id * EXT4_DEV_BSIZE as u64;
+        while !buf.is_empty() {
+            let read = self
+                .0
+                .read_at(buf, offset)
+                .map_err(|_| Ext4Error::new(EIO as _, None))?;
+            if read == 0 {
+                return Err(Ext4Error::new(EIO as _, None));
+            }
+            buf = &mut buf[read..];
+            offset += read as u64;
+        }
+        Ok(len)
+    }
+
+    fn write_blocks(&mut self, block_id: u64, mut buf: &[u8]) -> Ext4Result<usize> {
+        let len = buf.len();
+        let mut offset = block_id * EXT4_DEV_BSIZE as u64;
+        while !buf.is_empty() {
+            let written = self
+                .0
+                .write_at(buf, offset)
+                .map_err(|_| Ext4Error::new(EIO as _, None))?;
+            if written == 0 {
+                return Err(Ext4Error::new(EIO as _, None));
+            }
+            buf = &buf[written..];
+            offset += written as u64;
+        }
+        Ok(len)
+    }
+
+    fn num_blocks(&self) -> Ext4Result<u64> {
+        let size = self
+            .0
+            .capacity()
+            .map_err(|_| Ext4Error::new(EIO as _, None))?;
+        Ok(size / EXT4_DEV_BSIZE as u64)
+    }
+}
+
+pub(crate) type LwExt4Filesystem = lwext4_rust::Ext4Filesystem<KernelHal, Ext4Disk>;
+
+/// Returns the raw inode reference of `inode`, for the functions of lwext4
+/// that have no bindings.
+pub(crate) fn raw_inode(inode: &mut InodeRef<KernelHal>) -> *mut ext4_inode_ref {
+    // SAFETY: `InodeRef` is a transparent wrapper of a boxed `ext4_inode_ref`.
+    unsafe { &mut **(inode as *mut InodeRef<KernelHal> as *mut Box<ext4_inode_ref>) }
+}
+
+pub(crate) fn into_vfs_err(err: Ext4Error) -> VfsError {
+    let linux_error = LinuxError::try_from(err.code).unwrap_or(LinuxError::EIO);
+    VfsError::from(linux_error).canonicalize()
+}
+
+pub(crate) fn into_vfs_type(ty: InodeType) -> NodeType {
+    match ty {
+        InodeType::RegularFile => NodeType::RegularFile,
+        InodeType::Directory => NodeType::Directory,
+        InodeType::CharacterDevice => NodeType::CharacterDevice,
+        InodeType::BlockDevice => NodeType::BlockDevice,
+        InodeType::Fifo => NodeType::Fifo,
+        InodeType::Socket => NodeType::Socket,
+        InodeType::Symlink => NodeType::Symlink,
+        InodeType::Unknown => NodeType::Unknown,
+    }
+}
@@ -0,0 +1,151 @@
+//! The orphan list, which holds the inodes that are in use without any link.
+//!
+//! The list starts at `last_orphan` in the superblock and goes on through
+//! the deletion time of each inode, as in Linux. Inodes left on it are freed
+//! when the filesystem is mounted again, so an unclean unmount does not leak
+//! them.
+
+use core::{ffi::c_int, mem::MaybeUninit};
+
+use lwext4_rust::{
+    Ext4Error, Ext4Result,
+    ffi::{
+        EXT4_DE_REG_FILE, EXT4_ROOT_INO, ext4_blockdev, ext4_fs, ext4_fs_alloc_inode,
+        ext4_fs_free_inode, ext4_fs_inode_blocks_init, ext4_fs_put_inode_ref, ext4_inode_ref,
+        ext4_inode_set_del_time, ext4_sblock,
+    },
+};
+
+use super::{LwExt4Filesystem, raw_inode};
+
+unsafe extern "C" {
+    fn ext4_sb_write(bdev: *mut ext4_blockdev, s: *mut ext4_sblock) -> c_int;
+}
+
+fn check(ret: c_int, context: &'static str) -> Ext4Result<()> {
+    match ret {
+        0 => Ok(()),
+        ret => Err(Ext4Error::new(ret, context)),
+    }
+}
+
+/// Runs `f` on the raw filesystem, which is only reachable through an inode.
+fn with_raw_fs<R>(
+    fs: &mut LwExt4Filesystem,
+    f: impl FnOnce(*mut ext4_fs) -> Ext4Result<R>,
+) -> Ext4Result<R> {
+    fs.with_inode_ref(EXT4_ROOT_INO, |root| f(unsafe { (*raw_inode(root)).fs }))
+}
+
+/// Writes the superblock of `fs` back, so that the list survives a crash.
+fn write_superblock(fs: *mut ext4_fs) -> Ext4Result<()> {
+    check(
+        unsafe { ext4_sb_write((*fs).bdev, &mut (*fs).sb) },
+        "ext4_sb_write",
+    )
+}
+
+/// Returns the inode following `ino` on the list.
+fn next_of(fs: &mut LwExt4Filesystem, ino: u32) -> Ext4Result<u32> {
+    fs.with_inode_ref(ino, |inode| {
+        let inode = raw_inode(inode);
+        Ok(u32::from_le(unsafe { (*(*inode).inode).deletion_time }))
+    })
+}
+
+/// Sets the inode following `ino` on the list to `next`.
+fn set_next(fs: &mut LwExt4Filesystem, ino: u32, next: u32) -> Ext4Result<()> {
+    fs.with_inode_ref(ino, |inode| {
+        let inode = raw_inode(inode);
+        unsafe {
+            (*(*inode).inode).deletion_time = next.to_le();
+            (*inode).dirty = true;
+        }
+        Ok(())
+    })
+}
+
+/// Returns the first inode on the list.
+fn head(fs: &mut LwExt4Filesystem) -> Ext4Result<u32> {
+    with_raw_fs(fs, |fs| Ok(u32::from_le(unsafe { (*fs).sb.last_orphan })))
+}
+
+/// Makes `ino` the first inode on the list.
+fn set_head(fs: &mut LwExt4Filesystem, ino: u32) -> Ext4Result<()> {
+    with_raw_fs(fs, |fs| {
+        unsafe { (*fs).sb.last_orphan = ino.to_le() };
+        write_superblock(fs)
+    })
+}
+
+/// Allocates a regular file without links and puts it on the list.
+///
+/// Returns the number of the new inode.
+pub(crate) fn alloc_file(fs: &mut LwExt4Filesystem, mode: u32) -> Ext4Result<u32> {
+    let ino = with_raw_fs(fs, |fs| unsafe {
+        let mut inode = MaybeUninit::<ext4_inode_ref>::zeroed();
+        check(
+            ext4_fs_alloc_inode(fs, inode.as_mut_ptr(), EXT4_DE_REG_FILE as _),
+            "ext4_fs_alloc_inode",
+        )?;
+        let mut inode = inode.assume_init();
+        ext4_fs_inode_blocks_init(fs, &mut inode);
+        let ino = inode.index;
+        check(ext4_fs_put_inode_ref(&mut inode), "ext4_fs_put_inode_ref")?;
+        Ok(ino)
+    })?;
+    fs.with_inode_ref(ino, |inode| {
+        inode.set_mode((inode.mode() & !0o7777) | (mode & 0o7777));
+        Ok(())
+    })?;
+    let head = head(fs)?;
+    set_next(fs, ino, head)?;
+    set_head(fs, ino)?;
+    Ok(ino)
+}
+
+/// Takes the inode `ino` off the list, once it has been linked or before it
+/// is freed.
+pub(crate) fn remove(fs: &mut LwExt4Filesystem, ino: u32) -> Ext4Result<()> {
+    let next = next_of(fs, ino)?;
+    set_next(fs, ino, 0)?;
+    let mut prev = head(fs)?;
+    if prev == ino {
+        return set_head(fs, next);
+    }
+    while prev != 0 {
+        let after = next_of(fs, prev)?;
+        if after == ino {
+            return set_next(fs, prev, next);
+        }
+        prev = after;
+    }
+    Ok(())
+}
+
+/// Frees the inode `ino`, which has no links, along with its data.
+pub(crate) fn free(fs: &mut LwExt4Filesystem, ino: u32) -> Ext4Result<()> {
+    fs.with_inode_ref(ino, |inode| {
+        inode.truncate(0)?;
+        let inode = raw_inode(inode);
+        unsafe {
+            ext4_inode_set_del_time((*inode).inode, u32::MAX);
+            (*inode).dirty = true;
+            check(ext4_fs_free_inode(inode), "ext4_fs_free_inode")
+        }
+    })
+}
+
+/// Frees the inodes left on the list by the last mount.
+pub(crate) fn release_all(fs: &mut LwExt4Filesystem) -> Ext4Result<()> {
+    let mut ino = head(fs)?;
+    if ino == 0 {
+        return Ok(());
+    }
+    while ino != 0 {
+        let next = next_of(fs, ino)?;
+        free(fs, ino)?;
+        ino = next;
+    }
+    set_head(fs, 0)
+}
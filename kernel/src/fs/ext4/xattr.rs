@@ -0,0 +1,197 @@
+//! Extended attributes stored in the inode body and the xattr block.
+//!
+//! `lwext4_rust` has no bindings for these, so the C functions are declared
+//! here and called on the raw inode reference.
+
+use alloc::{string::String, vec, vec::Vec};
+use core::{
+    ffi::{CStr, c_char, c_int, c_void},
+    mem::size_of,
+    ptr, slice,
+};
+
+use axerrno::LinuxError;
+use lwext4_rust::{Ext4Error, Ext4Result, InodeRef, ffi::ext4_inode_ref};
+
+use super::{KernelHal, raw_inode};
+
+/// An entry in the list filled by `ext4_xattr_list`. Entries are packed one
+/// after another, each followed by its NUL-terminated name.
+#[repr(C)]
+struct XattrListEntry {
+    name_index: u8,
+    name: *mut c_char,
+    name_len: usize,
+    _next: *mut XattrListEntry,
+}
+
+unsafe extern "C" {
+    fn ext4_extract_xattr_name(
+        full_name: *const c_char,
+        full_name_len: usize,
+        name_index: *mut u8,
+        name_len: *mut usize,
+        found: *mut bool,
+    ) -> *const c_char;
+
+    fn ext4_get_xattr_name_prefix(name_index: u8, ret_prefix_len: *mut usize) -> *const c_char;
+
+    fn ext4_xattr_list(
+        inode_ref: *mut ext4_inode_ref,
+        list: *mut XattrListEntry,
+        list_len: *mut usize,
+    ) -> c_int;
+
+    fn ext4_xattr_get(
+        inode_ref: *mut ext4_inode_ref,
+        name_index: u8,
+        name: *const c_char,
+        name_len: usize,
+        buf: *mut c_void,
+        buf_len: usize,
+        data_len: *mut usize,
+    ) -> c_int;
+
+    fn ext4_xattr_remove(
+        inode_ref: *mut ext4_inode_ref,
+        name_index: u8,
+        name: *const c_char,
+        name_len: usize,
+    ) -> c_int;
+
+    fn ext4_xattr_set(
+        inode_ref: *mut ext4_inode_ref,
+        name_index: u8,
+        name: *const c_char,
+        name_len: usize,
+        value: *const c_void,
+        value_len: usize,
+    ) -> c_int;
+}
+
+fn check(ret: c_int) -> Ext4Result<()> {
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(Ext4Error::new(ret, None))
+    }
+}
+
+/// Splits `name` into the index of its namespace and the name within it.
+fn split_name(name: &str) -> Ext4Result<(u8, &[u8])> {
+    let mut name_index = 0;
+    let mut name_len = 0;
+    let mut found = false;
+    unsafe {
+        ext4_extract_xattr_name(
+            name.as_ptr().cast(),
+            name.len(),
+            &mut name_index,
+            &mut name_len,
+            &mut found,
+        );
+    }
+    if !found {
+        return Err(Ext4Error::new(LinuxError::EOPNOTSUPP as _, None));
+    }
+    Ok((name_index, &name.as_bytes()[name.len() - name_len..]))
+}
+
+/// Returns whether attribute `name` exists, and its value if `read` is set.
+fn lookup(inode: &mut InodeRef<KernelHal>, name: &str, read: bool) -> Ext4Result<Option<Vec<u8>>> {
+    let (index, name) = split_name(name)?;
+    let inode = raw_inode(inode);
+    let mut len = 0;
+    let ret = unsafe {
+        ext4_xattr_get(
+            inode,
+            index,
+            name.as_ptr().cast(),
+            name.len(),
+            ptr::null_mut(),
+            0,
+            &mut len,
+        )
+    };
+    if ret == LinuxError::ENODATA as c_int {
+        return Ok(None);
+    }
+    check(ret)?;
+    let mut value = vec![0; len];
+    if read && len > 0 {
+        check(unsafe {
+            ext4_xattr_get(
+                inode,
+                index,
+                name.as_ptr().cast(),
+                name.len(),
+                value.as_mut_ptr().cast(),
+                value.len(),
+                &mut len,
+            )
+        })?;
+        value.truncate(len);
+    }
+    Ok(Some(value))
+}
+
+/// Returns the value of attribute `name`, or `None` if it does not exist.
+pub(super) fn get(inode: &mut InodeRef<KernelHal>, name: &str) -> Ext4Result<Option<Vec<u8>>> {
+    lookup(inode, name, true)
+}
+
+/// Returns whether attribute `name` exists.
+pub(super) fn exists(inode: &mut InodeRef<KernelHal>, name: &str) -> Ext4Result<bool> {
+    lookup(inode, name, false).map(|it| it.is_some())
+}
+
+/// Sets attribute `name` to `value`.
+pub(super) fn set(inode: &mut InodeRef<KernelHal>, name: &str, value: &[u8]) -> Ext4Result<()> {
+    let (index, name) = split_name(name)?;
+    check(unsafe {
+        ext4_xattr_set(
+            raw_inode(inode),
+            index,
+            name.as_ptr().cast(),
+            name.len(),
+            value.as_ptr().cast(),
+            value.len(),
+        )
+    })
+}
+
+/// Removes attribute `name`.
+pub(super) fn remove(inode: &mut InodeRef<KernelHal>, name: &str) -> Ext4Result<()> {
+    let (index, name) = split_name(name)?;
+    check(unsafe { ext4_xattr_remove(raw_inode(inode), index, name.as_ptr().cast(), name.len()) })
+}
+
+/// Returns the full names of all attributes.
+pub(super) fn list(inode: &mut InodeRef<KernelHal>) -> Ext4Result<Vec<String>> {
+    let inode = raw_inode(inode);
+    let mut len = 0;
+    check(unsafe { ext4_xattr_list(inode, ptr::null_mut(), &mut len) })?;
+    // Allocated as words so that the first entry is aligned.
+    let mut buf = vec![0usize; len.div_ceil(size_of::<usize>())];
+    check(unsafe { ext4_xattr_list(inode, buf.as_mut_ptr().cast(), &mut len) })?;
+
+    let base = buf.as_ptr().cast::<u8>();
+    let mut names = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        // SAFETY: the entries were written by `ext4_xattr_list`, which packs
+        // them without regard to alignment.
+        let entry = unsafe { ptr::read_unaligned(base.add(offset).cast::<XattrListEntry>()) };
+        let name = unsafe { slice::from_raw_parts(entry.name.cast::<u8>(), entry.name_len) };
+        offset += size_of::<XattrListEntry>() + entry.name_len + 1;
+
+        let prefix = unsafe { ext4_get_xattr_name_prefix(entry.name_index, ptr::null_mut()) };
+        if prefix.is_null() {
+            continue;
+        }
+        let mut full_name = String::from(unsafe { CStr::from_ptr(prefix) }.to_string_lossy());
+        full_name.push_str(&String::from_utf8_lossy(name));
+        names.push(full_name);
+    }
+    Ok(names)
+}
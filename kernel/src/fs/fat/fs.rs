@@ -0,0 +1,187 @@
+use alloc::sync::{Arc, Weak};
+
+use axerrno::{AxError, AxResult};
+use axfs_ng_vfs::{DirEntry, Filesystem, FilesystemOps, StatFs, VfsError, VfsResult, WeakDirEntry};
+use axsync::{Mutex, MutexGuard, RawMutex};
+use fatfs::FsOptions;
+use linux_raw_sys::general::MSDOS_SUPER_MAGIC;
+use lock_api::MappedMutexGuard;
+
+use super::{FatDisk, KernelTimeProvider, codepage::Codepage, ff, inode::new_root, into_vfs_err};
+use crate::pseudofs::DeviceOps;
+
+/// The maximum length of a long name.
+const NAME_LEN: usize = 255;
+
+/// Options of a FAT mount.
+#[derive(Debug, Clone)]
+pub struct FatOptions {
+    uid: u32,
+    gid: u32,
+    /// The permissions cleared from files.
+    fmask: u32,
+    /// The permissions cleared from directories.
+    dmask: u32,
+    codepage: Codepage,
+    /// Whether changes of owners and permissions are ignored instead of
+    /// failing.
+    quiet: bool,
+}
+
+impl FatOptions {
+    /// Parses the comma-separated options in `data`.
+    ///
+    /// Nodes belong to root and their permissions are masked by `umask`,
+    /// unless other owners or masks are given.
+    pub fn parse(data: &str, umask: u32) -> AxResult<Self> {
+        let mut options = Self {
+            uid: 0,
+            gid: 0,
+            fmask: umask & 0o777,
+            dmask: umask & 0o777,
+            codepage: Codepage::new(437).unwrap(),
+            quiet: false,
+        };
+        for option in data.split(',').filter(|it| !it.is_empty()) {
+            let (key, value) = option.split_once('=').unwrap_or((option, ""));
+            let parse_id = || value.parse::<u32>().map_err(|_| AxError::InvalidInput);
+            let parse_mask = || {
+                u32::from_str_radix(value, 8)
+                    .map(|mask| mask & 0o777)
+                    .map_err(|_| AxError::InvalidInput)
+            };
+            match key {
+                "uid" => options.uid = parse_id()?,
+                "gid" => options.gid = parse_id()?,
+                "umask" => {
+                    options.fmask = parse_mask()?;
+                    options.dmask = options.fmask;
+                }
+                "fmask" => options.fmask = parse_mask()?,
+                "dmask" => options.dmask = parse_mask()?,
+                "codepage" => {
+                    let number = value.parse().map_err(|_| AxError::InvalidInput)?;
+                    options.codepage = Codepage::new(number).ok_or_else(|| {
+                        warn!("vfat: unsupported codepage {value}");
+                        AxError::InvalidInput
+                    })?;
+                }
+                // Long names are always UTF-8.
+                "iocharset" if value == "utf8" || value == "utf-8" => {}
+                "utf8" if matches!(value, "" | "1" | "yes" | "true") => {}
+                "quiet" if value.is_empty() => options.quiet = true,
+                _ => {
+                    warn!("vfat: unknown option {option:?}");
+                    return Err(AxError::InvalidInput);
+                }
+            }
+        }
+        Ok(options)
+    }
+
+    pub(super) fn owner(&self) -> (u32, u32) {
+        (self.uid, self.gid)
+    }
+
+    /// Returns the permissions of files, or of directories with `dir`.
+    pub(super) fn mode(&self, dir: bool) -> u32 {
+        0o777 & !if dir { self.dmask } else { self.fmask }
+    }
+
+    pub(super) fn quiet(&self) -> bool {
+        self.quiet
+    }
+}
+
+/// A FAT filesystem stored on a block device.
+pub struct FatFilesystem {
+    /// The open filesystem, unless it could not be opened again after a
+    /// flush. It is unmounted when dropped.
+    inner: Mutex<Option<ff::FileSystem>>,
+    dev: Arc<dyn DeviceOps>,
+    options: FatOptions,
+    me: Weak<Self>,
+    /// The root directory, held weakly as its node refers to the filesystem.
+    root_dir: Mutex<Option<WeakDirEntry>>,
+}
+
+impl FatFilesystem {
+    /// Opens the FAT filesystem stored on `dev`.
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(dev: Arc<dyn DeviceOps>, options: &FatOptions) -> VfsResult<Filesystem> {
+        let inner = Self::open(&dev, options)?;
+        Ok(Filesystem::new(Arc::new_cyclic(|me| Self {
+            inner: Mutex::new(Some(inner)),
+            dev,
+            options: options.clone(),
+            me: me.clone(),
+            root_dir: Mutex::default(),
+        })))
+    }
+
+    fn open(dev: &Arc<dyn DeviceOps>, options: &FatOptions) -> VfsResult<ff::FileSystem> {
+        let fs_options = FsOptions::new()
+            .time_provider(KernelTimeProvider)
+            .oem_cp_converter(options.codepage);
+        ff::FileSystem::new(FatDisk::new(dev.clone())?, fs_options).map_err(|err| {
+            warn!("vfat: cannot open filesystem: {err:?}");
+            into_vfs_err(err)
+        })
+    }
+
+    /// Locks the filesystem, which fails if it could not be opened again.
+    pub(super) fn lock(&self) -> VfsResult<MappedMutexGuard<'_, RawMutex, ff::FileSystem>> {
+        MutexGuard::try_map(self.inner.lock(), Option::as_mut).map_err(|_| VfsError::Io)
+    }
+
+    pub(super) fn options(&self) -> &FatOptions {
+        &self.options
+    }
+}
+
+impl FilesystemOps for FatFilesystem {
+    fn name(&self) -> &str {
+        "vfat"
+    }
+
+    fn root_dir(&self) -> DirEntry {
+        let mut root_dir = self.root_dir.lock();
+        if let Some(root) = root_dir.as_ref().and_then(WeakDirEntry::upgrade) {
+            return root;
+        }
+        let root = new_root(self.me.upgrade().unwrap());
+        *root_dir = Some(root.downgrade());
+        root
+    }
+
+    fn stat(&self) -> VfsResult<StatFs> {
+        let stats = self.lock()?.stats().map_err(into_vfs_err)?;
+        Ok(StatFs {
+            fs_type: MSDOS_SUPER_MAGIC as _,
+            block_size: stats.cluster_size() as _,
+            blocks: stats.total_clusters() as _,
+            blocks_free: stats.free_clusters() as _,
+            blocks_available: stats.free_clusters() as _,
+
+            file_count: 0,
+            free_file_count: 0,
+
+            name_length: NAME_LEN as _,
+            fragment_size: 0,
+            mount_flags: 0,
+        })
+    }
+
+    fn flush(&self) -> VfsResult<()> {
+        // `fatfs` only writes the FSInfo sector back on unmount, so the
+        // filesystem is unmounted and opened again. Nodes look their entries
+        // up on every operation and hold nothing of it.
+        let mut inner = self.inner.lock();
+        let Some(fs) = inner.take() else {
+            return Ok(());
+        };
+        let result = fs.unmount().map_err(into_vfs_err);
+        *inner = Some(Self::open(&self.dev, &self.options)?);
+        result
+    }
+}
@@ -0,0 +1,597 @@
+use alloc::{
+    borrow::ToOwned,
+    collections::BTreeMap,
+    format,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{any::Any, task::Context, time::Duration};
+
+use axfs_ng_vfs::{
+    DeviceId, DirEntry, DirEntrySink, DirNode, DirNodeOps, FileNode, FileNodeOps, FilesystemOps,
+    Metadata, MetadataUpdate, NodeFlags, NodeOps, NodePermission, NodeType, Reference, VfsError,
+    VfsResult, WeakDirEntry,
+};
+use axpoll::{IoEvents, Pollable};
+use axsync::Mutex;
+use fatfs::{DateTime, FileAttributes, Read, Seek, SeekFrom, Time, Write};
+
+use super::{
+    FatFilesystem, MAX_FILE_SIZE, dos_to_unix, ff, file_too_large, into_vfs_err, unix_to_dos,
+};
+
+/// The inode number of the root directory.
+const ROOT_INO: u64 = 1;
+
+/// Zeros written when a file grows.
+static ZEROS: [u8; 4096] = [0; 4096];
+
+/// Returns the key of `name` among the children of a directory, as names
+/// are matched ignoring case.
+fn key(name: &str) -> String {
+    name.to_uppercase()
+}
+
+/// Returns the inode number of the entry with key `key` in the directory
+/// numbered `parent`.
+///
+/// FAT has no inode numbers, so they are derived from the path of an entry
+/// (FNV-1a), which keeps them stable across lookups.
+fn entry_ino(parent: u64, key: &str) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for byte in parent.to_le_bytes().into_iter().chain(key.bytes()) {
+        hash = (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+    }
+    hash.max(ROOT_INO + 1)
+}
+
+fn is_dot(entry: &ff::DirEntry) -> bool {
+    matches!(entry.short_file_name().as_str(), "." | "..")
+}
+
+fn entry_type(entry: &ff::DirEntry) -> NodeType {
+    if entry.is_dir() {
+        NodeType::Directory
+    } else {
+        NodeType::RegularFile
+    }
+}
+
+/// Finds the entry named `name` in `dir`, by its long or short name.
+fn find_entry<'a>(dir: &ff::Dir<'a>, name: &str) -> VfsResult<Option<ff::DirEntry<'a>>> {
+    let name = key(name);
+    for entry in dir.iter() {
+        let entry = entry.map_err(into_vfs_err)?;
+        if !is_dot(&entry)
+            && (key(&entry.file_name()) == name || key(&entry.short_file_name()) == name)
+        {
+            return Ok(Some(entry));
+        }
+    }
+    Ok(None)
+}
+
+/// Writes `len` zeros to `file` at its position.
+fn write_zeros(file: &mut ff::File, mut len: u64) -> VfsResult<()> {
+    while len > 0 {
+        let count = len.min(ZEROS.len() as u64) as usize;
+        file.write_all(&ZEROS[..count]).map_err(into_vfs_err)?;
+        len -= count as u64;
+    }
+    Ok(())
+}
+
+/// Creates the root directory of `fs`.
+pub(super) fn new_root(fs: Arc<FatFilesystem>) -> DirEntry {
+    let root = Inode::new(fs, ROOT_INO, NodeType::Directory, None, String::new());
+    DirEntry::new_dir(
+        |this| {
+            *root.this.lock() = Some(this);
+            DirNode::new(root)
+        },
+        Reference::root(),
+    )
+}
+
+/// The place of a node in the directory tree.
+struct Position {
+    /// The parent directory, or `None` for the root.
+    parent: Option<Arc<Inode>>,
+    /// The name of the entry, as stored in the parent.
+    name: String,
+    /// Whether the entry has been removed.
+    removed: bool,
+}
+
+/// A FAT file or directory.
+///
+/// A node stays the same while its entry is in use, so that renames and
+/// removals reach the nodes of open files. Removed files cannot be used any
+/// more, since FAT frees their clusters right away.
+pub struct Inode {
+    fs: Arc<FatFilesystem>,
+    ino: u64,
+    node_type: NodeType,
+    me: Weak<Inode>,
+    pos: Mutex<Position>,
+    /// The nodes of the entries of a directory in use, by key.
+    children: Mutex<BTreeMap<String, Weak<Inode>>>,
+    /// The latest directory entry of a directory.
+    this: Mutex<Option<WeakDirEntry>>,
+}
+
+impl Inode {
+    fn new(
+        fs: Arc<FatFilesystem>,
+        ino: u64,
+        node_type: NodeType,
+        parent: Option<Arc<Inode>>,
+        name: String,
+    ) -> Arc<Self> {
+        Arc::new_cyclic(|me| Self {
+            fs,
+            ino,
+            node_type,
+            me: me.clone(),
+            pos: Mutex::new(Position {
+                parent,
+                name,
+                removed: false,
+            }),
+            children: Mutex::default(),
+            this: Mutex::default(),
+        })
+    }
+
+    /// Returns the node of the entry `name` of the directory, creating it if
+    /// it is not in use.
+    fn child(&self, name: &str, node_type: NodeType) -> Arc<Inode> {
+        let key = key(name);
+        let mut children = self.children.lock();
+        if let Some(child) = children.get(&key).and_then(Weak::upgrade)
+            && child.node_type == node_type
+        {
+            return child;
+        }
+        let child = Inode::new(
+            self.fs.clone(),
+            entry_ino(self.ino, &key),
+            node_type,
+            self.me.upgrade(),
+            name.to_owned(),
+        );
+        children.retain(|_, it| it.strong_count() > 0);
+        children.insert(key, Arc::downgrade(&child));
+        child
+    }
+
+    /// Returns the inode number of the entry `name` of the directory.
+    fn child_ino(&self, name: &str) -> u64 {
+        let key = key(name);
+        match self.children.lock().get(&key).and_then(Weak::upgrade) {
+            Some(child) => child.ino,
+            None => entry_ino(self.ino, &key),
+        }
+    }
+
+    /// Marks the node of the entry `name` of the directory as removed.
+    fn forget_child(&self, name: &str) {
+        if let Some(child) = self
+            .children
+            .lock()
+            .remove(&key(name))
+            .and_then(|it| it.upgrade())
+        {
+            child.pos.lock().removed = true;
+        }
+    }
+
+    /// Moves the node of the entry `name` of the directory to the entry
+    /// `dst_name` of `dst_dir`.
+    fn move_child(&self, name: &str, dst_dir: &Arc<Inode>, dst_name: &str) {
+        let Some(child) = self
+            .children
+            .lock()
+            .remove(&key(name))
+            .and_then(|it| it.upgrade())
+        else {
+            return;
+        };
+        {
+            let mut pos = child.pos.lock();
+            pos.parent = Some(dst_dir.clone());
+            pos.name = dst_name.to_owned();
+        }
+        dst_dir
+            .children
+            .lock()
+            .insert(key(dst_name), Arc::downgrade(&child));
+    }
+
+    fn create_entry(&self, node: Arc<Inode>, name: &str) -> DirEntry {
+        let reference = Reference::new(
+            self.this.lock().as_ref().and_then(WeakDirEntry::upgrade),
+            name.to_owned(),
+        );
+        if node.node_type == NodeType::Directory {
+            DirEntry::new_dir(
+                |this| {
+                    *node.this.lock() = Some(this);
+                    DirNode::new(node)
+                },
+                reference,
+            )
+        } else {
+            DirEntry::new_file(FileNode::new(node), NodeType::RegularFile, reference)
+        }
+    }
+
+    /// Returns the path of the node from the root, which is empty for the
+    /// root itself.
+    ///
+    /// The filesystem must be locked, since renames move nodes.
+    fn path(&self) -> VfsResult<String> {
+        let pos = self.pos.lock();
+        if pos.removed {
+            return Err(VfsError::NotFound);
+        }
+        let Some(parent) = &pos.parent else {
+            return Ok(String::new());
+        };
+        let path = parent.path()?;
+        Ok(if path.is_empty() {
+            pos.name.clone()
+        } else {
+            format!("{path}/{}", pos.name)
+        })
+    }
+
+    /// Opens the directory of the node.
+    fn open_dir<'a>(&self, fs: &'a ff::FileSystem) -> VfsResult<ff::Dir<'a>> {
+        let path = self.path()?;
+        if path.is_empty() {
+            Ok(fs.root_dir())
+        } else {
+            fs.root_dir().open_dir(&path).map_err(into_vfs_err)
+        }
+    }
+
+    /// Finds the entry of the node in its parent, or `None` for the root.
+    fn entry<'a>(&self, fs: &'a ff::FileSystem) -> VfsResult<Option<ff::DirEntry<'a>>> {
+        let (parent, name) = {
+            let pos = self.pos.lock();
+            if pos.removed {
+                return Err(VfsError::NotFound);
+            }
+            (pos.parent.clone(), pos.name.clone())
+        };
+        let Some(parent) = parent else {
+            return Ok(None);
+        };
+        let dir = parent.open_dir(fs)?;
+        find_entry(&dir, &name)?.ok_or(VfsError::NotFound).map(Some)
+    }
+
+    /// Finds the entry of the node, which must be a file.
+    fn file_entry<'a>(&self, fs: &'a ff::FileSystem) -> VfsResult<ff::DirEntry<'a>> {
+        match self.entry(fs)? {
+            Some(entry) if entry.is_file() => Ok(entry),
+            _ => Err(VfsError::IsADirectory),
+        }
+    }
+
+    fn parent_ino(&self) -> u64 {
+        self.pos
+            .lock()
+            .parent
+            .as_ref()
+            .map_or(self.ino, |parent| parent.ino)
+    }
+}
+
+impl NodeOps for Inode {
+    fn inode(&self) -> u64 {
+        self.ino
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        let fs = self.fs.lock()?;
+        let entry = self.entry(&fs)?;
+        let options = self.fs.options();
+        let mut mode = options.mode(self.node_type == NodeType::Directory);
+        let (size, atime, mtime, ctime) = match &entry {
+            Some(entry) => {
+                if entry.attributes().contains(FileAttributes::READ_ONLY) {
+                    mode &= !0o222;
+                }
+                let atime = DateTime::new(entry.accessed(), Time::new(0, 0, 0, 0));
+                (
+                    entry.len(),
+                    dos_to_unix(atime),
+                    dos_to_unix(entry.modified()),
+                    dos_to_unix(entry.created()),
+                )
+            }
+            // The root directory has no entry, and hence no times.
+            None => (0, Duration::ZERO, Duration::ZERO, Duration::ZERO),
+        };
+        let block_size = fs.cluster_size() as u64;
+        let (uid, gid) = options.owner();
+        Ok(Metadata {
+            device: 0,
+            inode: self.ino,
+            nlink: 1,
+            mode: NodePermission::from_bits_truncate(mode as u16),
+            node_type: self.node_type,
+            uid,
+            gid,
+            size,
+            block_size,
+            blocks: size.next_multiple_of(block_size) / 512,
+            rdev: DeviceId::default(),
+            atime,
+            mtime,
+            ctime,
+        })
+    }
+
+    fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+        let options = self.fs.options();
+        if !options.quiet() {
+            if update.owner.is_some_and(|owner| owner != options.owner()) {
+                return Err(VfsError::OperationNotPermitted);
+            }
+            if let Some(mode) = update.mode
+                && mode.bits() != self.metadata()?.mode.bits()
+            {
+                return Err(VfsError::OperationNotPermitted);
+            }
+        }
+        if update.atime.is_none() && update.mtime.is_none() {
+            return Ok(());
+        }
+        let fs = self.fs.lock()?;
+        // Only the entries of files can be edited.
+        let Some(entry) = self.entry(&fs)?.filter(|entry| entry.is_file()) else {
+            return Ok(());
+        };
+        let mut file = entry.to_file();
+        #[allow(deprecated)]
+        {
+            if let Some(atime) = update.atime {
+                file.set_accessed(unix_to_dos(atime).date);
+            }
+            if let Some(mtime) = update.mtime {
+                file.set_modified(unix_to_dos(mtime));
+            }
+        }
+        file.flush().map_err(into_vfs_err)
+    }
+
+    fn len(&self) -> VfsResult<u64> {
+        let fs = self.fs.lock()?;
+        Ok(self.entry(&fs)?.map_or(0, |entry| entry.len()))
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.fs
+    }
+
+    fn sync(&self, _data_only: bool) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn flags(&self) -> NodeFlags {
+        NodeFlags::BLOCKING
+    }
+}
+
+impl FileNodeOps for Inode {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        let fs = self.fs.lock()?;
+        let entry = self.file_entry(&fs)?;
+        if offset >= entry.len() {
+            return Ok(0);
+        }
+        let mut file = entry.to_file();
+        file.seek(SeekFrom::Start(offset)).map_err(into_vfs_err)?;
+        let mut read = 0;
+        while read < buf.len() {
+            let count = file.read(&mut buf[read..]).map_err(into_vfs_err)?;
+            if count == 0 {
+                break;
+            }
+            read += count;
+        }
+        Ok(read)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> VfsResult<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if offset + buf.len() as u64 > MAX_FILE_SIZE {
+            return Err(file_too_large());
+        }
+        let fs = self.fs.lock()?;
+        let entry = self.file_entry(&fs)?;
+        let size = entry.len();
+        let mut file = entry.to_file();
+        file.seek(SeekFrom::Start(offset.min(size)))
+            .map_err(into_vfs_err)?;
+        if offset > size {
+            write_zeros(&mut file, offset - size)?;
+        }
+        file.write_all(buf).map_err(into_vfs_err)?;
+        Ok(buf.len())
+    }
+
+    fn append(&self, buf: &[u8]) -> VfsResult<(usize, u64)> {
+        let fs = self.fs.lock()?;
+        let entry = self.file_entry(&fs)?;
+        let size = entry.len();
+        if size + buf.len() as u64 > MAX_FILE_SIZE {
+            return Err(file_too_large());
+        }
+        let mut file = entry.to_file();
+        file.seek(SeekFrom::End(0)).map_err(into_vfs_err)?;
+        file.write_all(buf).map_err(into_vfs_err)?;
+        Ok((buf.len(), size + buf.len() as u64))
+    }
+
+    fn set_len(&self, len: u64) -> VfsResult<()> {
+        if len > MAX_FILE_SIZE {
+            return Err(file_too_large());
+        }
+        let fs = self.fs.lock()?;
+        let entry = self.file_entry(&fs)?;
+        let size = entry.len();
+        let mut file = entry.to_file();
+        if len <= size {
+            file.seek(SeekFrom::Start(len)).map_err(into_vfs_err)?;
+            file.truncate().map_err(into_vfs_err)
+        } else {
+            file.seek(SeekFrom::End(0)).map_err(into_vfs_err)?;
+            write_zeros(&mut file, len - size)
+        }
+    }
+
+    fn set_symlink(&self, _target: &str) -> VfsResult<()> {
+        Err(VfsError::OperationNotPermitted)
+    }
+}
+
+impl Pollable for Inode {
+    fn poll(&self) -> IoEvents {
+        IoEvents::IN | IoEvents::OUT
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
+
+impl DirNodeOps for Inode {
+    fn read_dir(&self, offset: u64, sink: &mut dyn DirEntrySink) -> VfsResult<usize> {
+        let entries = {
+            let fs = self.fs.lock()?;
+            let dir = self.open_dir(&fs)?;
+            let mut entries = Vec::new();
+            for entry in dir.iter() {
+                let entry = entry.map_err(into_vfs_err)?;
+                if !is_dot(&entry) {
+                    let name = entry.file_name();
+                    entries.push((self.child_ino(&name), entry_type(&entry), name));
+                }
+            }
+            entries
+        };
+        let dots = [(".", self.ino), ("..", self.parent_ino())];
+        let entries = dots
+            .into_iter()
+            .map(|(name, ino)| (name, ino, NodeType::Directory))
+            .chain(
+                entries
+                    .iter()
+                    .map(|(ino, node_type, name)| (name.as_str(), *ino, *node_type)),
+            );
+        let mut count = 0;
+        for (index, (name, ino, node_type)) in entries.enumerate().skip(offset as usize) {
+            if !sink.accept(name, ino, node_type, index as u64 + 1) {
+                break;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<DirEntry> {
+        let node = {
+            let fs = self.fs.lock()?;
+            let dir = self.open_dir(&fs)?;
+            let entry = find_entry(&dir, name)?.ok_or(VfsError::NotFound)?;
+            self.child(&entry.file_name(), entry_type(&entry))
+        };
+        Ok(self.create_entry(node, name))
+    }
+
+    fn create(
+        &self,
+        name: &str,
+        node_type: NodeType,
+        _permission: NodePermission,
+    ) -> VfsResult<DirEntry> {
+        let node = {
+            let fs = self.fs.lock()?;
+            let dir = self.open_dir(&fs)?;
+            if find_entry(&dir, name)?.is_some() {
+                return Err(VfsError::AlreadyExists);
+            }
+            match node_type {
+                NodeType::RegularFile => drop(dir.create_file(name).map_err(into_vfs_err)?),
+                NodeType::Directory => drop(dir.create_dir(name).map_err(into_vfs_err)?),
+                _ => return Err(VfsError::OperationNotPermitted),
+            }
+            self.child(name, node_type)
+        };
+        Ok(self.create_entry(node, name))
+    }
+
+    fn link(&self, _name: &str, _node: &DirEntry) -> VfsResult<DirEntry> {
+        Err(VfsError::OperationNotPermitted)
+    }
+
+    fn unlink(&self, name: &str) -> VfsResult<()> {
+        let fs = self.fs.lock()?;
+        let dir = self.open_dir(&fs)?;
+        let name = find_entry(&dir, name)?
+            .ok_or(VfsError::NotFound)?
+            .file_name();
+        dir.remove(&name).map_err(into_vfs_err)?;
+        self.forget_child(&name);
+        Ok(())
+    }
+
+    fn rename(&self, src_name: &str, dst_dir: &DirNode, dst_name: &str) -> VfsResult<()> {
+        let dst_dir: Arc<Self> = dst_dir.downcast().map_err(|_| VfsError::InvalidInput)?;
+        let fs = self.fs.lock()?;
+        let src = self.open_dir(&fs)?;
+        let dst = dst_dir.open_dir(&fs)?;
+        let name = find_entry(&src, src_name)?
+            .ok_or(VfsError::NotFound)?
+            .file_name();
+        // `fatfs` leaves `..` of a directory moved elsewhere pointing to the
+        // old parent, which is harmless as nodes keep track of their parents.
+        match find_entry(&dst, dst_name)? {
+            Some(existing)
+                if dst_dir.ino == self.ino && key(&existing.file_name()) == key(&name) =>
+            {
+                // The entry is renamed to another spelling of its own name,
+                // which `fatfs` takes as a no-op, so go through a
+                // temporary name.
+                if existing.file_name() == dst_name {
+                    return Ok(());
+                }
+                let mut temp = format!(".rename-{:x}", self.ino);
+                while find_entry(&src, &temp)?.is_some() {
+                    temp.push('~');
+                }
+                src.rename(&name, &src, &temp).map_err(into_vfs_err)?;
+                src.rename(&temp, &src, dst_name).map_err(into_vfs_err)?;
+            }
+            Some(existing) => {
+                // The types have been checked by the caller.
+                let existing = existing.file_name();
+                dst.remove(&existing).map_err(into_vfs_err)?;
+                dst_dir.forget_child(&existing);
+                src.rename(&name, &dst, dst_name).map_err(into_vfs_err)?;
+            }
+            None => src.rename(&name, &dst, dst_name).map_err(into_vfs_err)?,
+        }
+        self.move_child(&name, &dst_dir, dst_name);
+        Ok(())
+    }
+}
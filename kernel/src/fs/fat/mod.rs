@@ -0,0 +1,178 @@
+//! FAT12, FAT16 and FAT32 filesystems, with long file names (vfat).
+//!
+//! The on-disk format is handled by `fatfs` (starry-fatfs), the library
+//! behind the FAT support of `axfs`, whose driver only mounts the boot disk.
+//! Its directory and file objects borrow the filesystem and must not outlive
+//! a rename or removal of their entry. Nodes therefore only remember their
+//! parent and name, and look their entry up again, under the filesystem
+//! lock, for every operation.
+//!
+//! FAT has no owners or permissions: all nodes belong to the `uid` and `gid`
+//! given at mount time, with permissions derived from the `umask`, `dmask`
+//! and `fmask` options. Short names are decoded with the code page given by
+//! the `codepage` option, and long names are always UTF-8.
+
+mod codepage;
+mod fs;
+mod inode;
+
+use alloc::sync::Arc;
+use core::time::Duration;
+
+use axerrno::LinuxError;
+use axfs_ng_vfs::VfsError;
+use chrono::{Datelike, Timelike};
+use fatfs::{Date, DateTime, IoBase, Read, Seek, SeekFrom, Time, TimeProvider, Write};
+
+pub use self::fs::{FatFilesystem, FatOptions};
+use crate::pseudofs::DeviceOps;
+
+/// The types of `fatfs` used with a [`FatDisk`].
+mod ff {
+    use super::{FatDisk, KernelTimeProvider, codepage::Codepage};
+
+    pub type FileSystem = fatfs::FileSystem<FatDisk, KernelTimeProvider, Codepage>;
+    pub type Dir<'a> = fatfs::Dir<'a, FatDisk, KernelTimeProvider, Codepage>;
+    pub type DirEntry<'a> = fatfs::DirEntry<'a, FatDisk, KernelTimeProvider, Codepage>;
+    pub type File<'a> = fatfs::File<'a, FatDisk, KernelTimeProvider, Codepage>;
+}
+
+/// The years representable in FAT timestamps.
+const MIN_YEAR: i32 = 1980;
+const MAX_YEAR: i32 = 2107;
+
+/// The largest size of a file.
+const MAX_FILE_SIZE: u64 = u32::MAX as u64;
+
+/// A block device seen as a stream by `fatfs`.
+pub struct FatDisk {
+    dev: Arc<dyn DeviceOps>,
+    pos: u64,
+    size: u64,
+}
+
+impl FatDisk {
+    fn new(dev: Arc<dyn DeviceOps>) -> Result<Self, VfsError> {
+        let size = dev.capacity()?;
+        Ok(Self { dev, pos: 0, size })
+    }
+}
+
+impl IoBase for FatDisk {
+    type Error = ();
+}
+
+impl Read for FatDisk {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, ()> {
+        let len = buf.len().min(self.size.saturating_sub(self.pos) as usize);
+        let read = self
+            .dev
+            .read_at(&mut buf[..len], self.pos)
+            .map_err(|_| ())?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for FatDisk {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, ()> {
+        let len = buf.len().min(self.size.saturating_sub(self.pos) as usize);
+        let written = self.dev.write_at(&buf[..len], self.pos).map_err(|_| ())?;
+        self.pos += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+impl Seek for FatDisk {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, ()> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = pos.filter(|&pos| pos <= self.size).ok_or(())?;
+        Ok(self.pos)
+    }
+}
+
+/// Stamps entries with the wall clock time.
+///
+/// There are no time zones, so FAT timestamps are kept in UTC.
+#[derive(Debug, Default)]
+pub struct KernelTimeProvider;
+
+impl TimeProvider for KernelTimeProvider {
+    fn get_current_date(&self) -> Date {
+        self.get_current_date_time().date
+    }
+
+    fn get_current_date_time(&self) -> DateTime {
+        unix_to_dos(axhal::time::wall_time())
+    }
+}
+
+/// Converts a FAT timestamp to the time since the Unix epoch.
+fn dos_to_unix(time: DateTime) -> Duration {
+    let (date, time) = (time.date, time.time);
+    chrono::NaiveDate::from_ymd_opt(date.year as _, date.month as _, date.day as _)
+        .and_then(|date| {
+            date.and_hms_milli_opt(
+                time.hour as _,
+                time.min as _,
+                time.sec as _,
+                time.millis as _,
+            )
+        })
+        .and_then(|time| {
+            time.and_utc()
+                .signed_duration_since(chrono::DateTime::UNIX_EPOCH)
+                .to_std()
+                .ok()
+        })
+        .unwrap_or_default()
+}
+
+/// Converts a time since the Unix epoch to a FAT timestamp, clamped to the
+/// representable years.
+fn unix_to_dos(time: Duration) -> DateTime {
+    let time = chrono::DateTime::UNIX_EPOCH + time;
+    if time.year() < MIN_YEAR {
+        return DateTime::new(Date::new(MIN_YEAR as _, 1, 1), Time::new(0, 0, 0, 0));
+    }
+    if time.year() > MAX_YEAR {
+        return DateTime::new(Date::new(MAX_YEAR as _, 12, 31), Time::new(23, 59, 59, 999));
+    }
+    DateTime::new(
+        Date::new(time.year() as _, time.month() as _, time.day() as _),
+        Time::new(
+            time.hour() as _,
+            time.minute() as _,
+            time.second() as _,
+            time.timestamp_subsec_millis() as _,
+        ),
+    )
+}
+
+/// Converts an error of `fatfs`.
+fn into_vfs_err<E>(err: fatfs::Error<E>) -> VfsError {
+    use fatfs::Error::*;
+    match err {
+        NotFound => VfsError::NotFound,
+        AlreadyExists => VfsError::AlreadyExists,
+        DirectoryIsNotEmpty => VfsError::DirectoryNotEmpty,
+        InvalidFileNameLength => VfsError::NameTooLong,
+        InvalidInput | UnsupportedFileNameCharacter => VfsError::InvalidInput,
+        CorruptedFileSystem => VfsError::InvalidData,
+        NotEnoughSpace => VfsError::StorageFull,
+        _ => VfsError::Io,
+    }
+}
+
+/// The error of growing a file past [`MAX_FILE_SIZE`].
+fn file_too_large() -> VfsError {
+    VfsError::from(LinuxError::EFBIG)
+}
@@ -0,0 +1,400 @@
+//! The connection between the kernel and a FUSE daemon, which the daemon
+//! reads requests from and writes replies to through `/dev/fuse`.
+
+use alloc::{
+    borrow::Cow,
+    collections::{VecDeque, btree_map::BTreeMap},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::{
+    future::poll_fn,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axpoll::{IoEvents, PollSet, Pollable};
+use axtask::{
+    current,
+    future::{block_on, interruptible, poll_io},
+};
+use kspin::SpinNoIrq;
+use zerocopy::{FromBytes, IntoBytes};
+
+use super::abi::*;
+use crate::{
+    file::{FileLike, IoDst, IoSrc},
+    task::AsThread,
+};
+
+/// The largest write sent to the daemon if it does not say otherwise.
+const DEFAULT_MAX_WRITE: u32 = 4096;
+
+/// A request sent to the daemon but not answered yet.
+struct InFlight {
+    opcode: u32,
+    /// Whether anyone waits for the reply, which is dropped otherwise.
+    waiting: bool,
+    reply: Option<AxResult<Vec<u8>>>,
+}
+
+enum InitState {
+    /// `FUSE_INIT` has not been answered yet.
+    Pending,
+    Done {
+        max_write: u32,
+    },
+    /// The daemon refused the connection.
+    Failed,
+}
+
+struct ConnState {
+    connected: bool,
+    init: InitState,
+    /// Requests the daemon has not read yet, with their headers.
+    pending: VecDeque<(u64, Vec<u8>)>,
+    /// Requests waiting for replies, by their unique IDs.
+    in_flight: BTreeMap<u64, InFlight>,
+    next_unique: u64,
+}
+
+/// A FUSE connection.
+pub struct FuseConn {
+    state: SpinNoIrq<ConnState>,
+    /// Woken when a request is queued or the connection is aborted.
+    poll_rx: PollSet,
+    /// Woken when a reply arrives or the connection is aborted.
+    reply_rx: PollSet,
+    /// Whether a filesystem has been mounted over the connection.
+    mounted: AtomicBool,
+}
+
+impl FuseConn {
+    fn new() -> Self {
+        Self {
+            state: SpinNoIrq::new(ConnState {
+                connected: true,
+                init: InitState::Pending,
+                pending: VecDeque::new(),
+                in_flight: BTreeMap::new(),
+                next_unique: 1,
+            }),
+            poll_rx: PollSet::new(),
+            reply_rx: PollSet::new(),
+            mounted: AtomicBool::new(false),
+        }
+    }
+
+    /// Claims the connection for a mount, which can only happen once.
+    pub fn claim(&self) -> AxResult<()> {
+        if self.mounted.swap(true, Ordering::AcqRel) {
+            return Err(AxError::InvalidInput);
+        }
+        Ok(())
+    }
+
+    /// Queues a request of `opcode` on the node `nodeid` with the arguments
+    /// `args`, and returns its unique ID.
+    ///
+    /// A reply is expected unless the request is a `FUSE_FORGET`.
+    fn push(&self, opcode: u32, nodeid: u64, args: &[&[u8]], waiting: bool) -> AxResult<u64> {
+        let len = size_of::<FuseInHeader>() + args.iter().map(|it| it.len()).sum::<usize>();
+        let pid = current()
+            .try_as_thread()
+            .map_or(0, |thread| thread.proc_data.proc.pid());
+        let mut state = self.state.lock();
+        if !state.connected {
+            return Err(AxError::from(LinuxError::ENOTCONN));
+        }
+        let unique = state.next_unique;
+        state.next_unique += 1;
+        // There are no credentials besides root.
+        let header = FuseInHeader {
+            len: len as u32,
+            opcode,
+            unique,
+            nodeid,
+            uid: 0,
+            gid: 0,
+            pid,
+            padding: 0,
+        };
+        let mut data = Vec::with_capacity(len);
+        data.extend_from_slice(header.as_bytes());
+        for arg in args {
+            data.extend_from_slice(arg);
+        }
+        state.pending.push_back((unique, data));
+        if opcode != FUSE_FORGET {
+            state.in_flight.insert(
+                unique,
+                InFlight {
+                    opcode,
+                    waiting,
+                    reply: None,
+                },
+            );
+        }
+        drop(state);
+        self.poll_rx.wake();
+        Ok(unique)
+    }
+
+    /// Sends `FUSE_INIT`, which is answered once the daemon starts reading.
+    pub fn init(&self) -> AxResult<()> {
+        let init = FuseInitIn {
+            major: FUSE_KERNEL_VERSION,
+            minor: FUSE_KERNEL_MINOR_VERSION,
+            max_readahead: 0,
+            flags: FUSE_BIG_WRITES,
+        };
+        self.push(FUSE_INIT, 0, &[init.as_bytes()], false).map(drop)
+    }
+
+    fn wait_init(&self) -> AxResult<u32> {
+        block_on(interruptible(poll_fn(|cx| {
+            self.reply_rx.register(cx.waker());
+            let state = self.state.lock();
+            if !state.connected {
+                return Poll::Ready(Err(AxError::from(LinuxError::ENOTCONN)));
+            }
+            match state.init {
+                InitState::Pending => Poll::Pending,
+                InitState::Done { max_write } => Poll::Ready(Ok(max_write)),
+                InitState::Failed => Poll::Ready(Err(AxError::from(LinuxError::ECONNREFUSED))),
+            }
+        })))?
+    }
+
+    /// Returns the largest amount of data a single `FUSE_WRITE` may carry.
+    pub fn max_write(&self) -> AxResult<u32> {
+        self.wait_init()
+    }
+
+    /// Sends a request and waits for the reply, returning its payload.
+    pub fn request(&self, opcode: u32, nodeid: u64, args: &[&[u8]]) -> AxResult<Vec<u8>> {
+        self.wait_init()?;
+        let unique = self.push(opcode, nodeid, args, true)?;
+        let result = block_on(interruptible(poll_fn(|cx| {
+            self.reply_rx.register(cx.waker());
+            let mut state = self.state.lock();
+            if !state.connected {
+                return Poll::Ready(Err(AxError::from(LinuxError::ENOTCONN)));
+            }
+            match state
+                .in_flight
+                .get_mut(&unique)
+                .and_then(|it| it.reply.take())
+            {
+                Some(reply) => Poll::Ready(reply),
+                None => Poll::Pending,
+            }
+        })));
+        // An interrupted request is abandoned, so a late reply to it is
+        // rejected.
+        let mut state = self.state.lock();
+        state.in_flight.remove(&unique);
+        state.pending.retain(|(it, _)| *it != unique);
+        result?
+    }
+
+    /// Sends a request whose reply is a single structure `T`.
+    pub fn request_as<T: FromBytes>(
+        &self,
+        opcode: u32,
+        nodeid: u64,
+        args: &[&[u8]],
+    ) -> AxResult<T> {
+        let reply = self.request(opcode, nodeid, args)?;
+        T::read_from_prefix(&reply)
+            .map(|(it, _)| it)
+            .map_err(|_| AxError::Io)
+    }
+
+    /// Sends a request without waiting for the reply, for requests that
+    /// can't fail in a way that matters, like `FUSE_RELEASE`.
+    pub fn post(&self, opcode: u32, nodeid: u64, args: &[&[u8]]) {
+        if matches!(self.state.lock().init, InitState::Done { .. }) {
+            let _ = self.push(opcode, nodeid, args, false);
+        }
+    }
+
+    /// Tells the daemon that the kernel dropped `nlookup` references to the
+    /// node `nodeid`.
+    pub fn forget(&self, nodeid: u64, nlookup: u64) {
+        self.post(FUSE_FORGET, nodeid, &[FuseForgetIn { nlookup }.as_bytes()]);
+    }
+
+    /// Aborts the connection, failing all requests.
+    pub fn abort(&self) {
+        {
+            let mut state = self.state.lock();
+            state.connected = false;
+            state.pending.clear();
+            state.in_flight.clear();
+        }
+        self.poll_rx.wake();
+        self.reply_rx.wake();
+    }
+
+    /// Fails the request `unique` with `err`.
+    fn fail(&self, unique: u64, err: AxError) {
+        if let Some(it) = self.state.lock().in_flight.get_mut(&unique) {
+            it.reply = Some(Err(err));
+        }
+        self.reply_rx.wake();
+    }
+
+    fn handle_init(state: &mut ConnState, reply: AxResult<Vec<u8>>) {
+        state.init = InitState::Failed;
+        let Ok(reply) = reply else {
+            return;
+        };
+        // Older daemons send shorter replies.
+        let mut buf = [0; size_of::<FuseInitOut>()];
+        let len = reply.len().min(buf.len());
+        buf[..len].copy_from_slice(&reply[..len]);
+        let Ok(out) = FuseInitOut::read_from_bytes(&buf) else {
+            return;
+        };
+        if out.major != FUSE_KERNEL_VERSION {
+            warn!(
+                "fuse: unsupported protocol version {}.{}",
+                out.major, out.minor
+            );
+            return;
+        }
+        let max_write = if out.flags & FUSE_BIG_WRITES != 0 {
+            out.max_write.max(DEFAULT_MAX_WRITE)
+        } else {
+            DEFAULT_MAX_WRITE
+        };
+        state.init = InitState::Done { max_write };
+    }
+
+    /// Hands the next request to the daemon.
+    fn read_request(&self, dst: &mut IoDst) -> AxResult<usize> {
+        let (unique, data) = {
+            let mut state = self.state.lock();
+            if !state.connected {
+                return Err(AxError::NoSuchDevice);
+            }
+            state.pending.pop_front().ok_or(AxError::WouldBlock)?
+        };
+        if data.len() > dst.remaining_mut() {
+            self.fail(unique, AxError::Io);
+            return Err(AxError::InvalidInput);
+        }
+        dst.write(&data).inspect_err(|err| self.fail(unique, *err))
+    }
+
+    /// Takes a reply from the daemon.
+    fn write_reply(&self, src: &mut IoSrc) -> AxResult<usize> {
+        let mut buf = vec![0; src.remaining()];
+        src.read(&mut buf)?;
+        let (header, payload) =
+            FuseOutHeader::read_from_prefix(&buf).map_err(|_| AxError::InvalidInput)?;
+        if header.len as usize != buf.len() {
+            return Err(AxError::InvalidInput);
+        }
+        // Notifications are not supported.
+        if header.unique == 0 {
+            return Err(AxError::InvalidInput);
+        }
+        let reply = match header.error {
+            0 => Ok(payload.to_vec()),
+            -4095..0 => Err(LinuxError::try_from(-header.error)
+                .map_or(AxError::Io, |err| AxError::from(err).canonicalize())),
+            _ => return Err(AxError::InvalidInput),
+        };
+
+        let mut state = self.state.lock();
+        let Some(in_flight) = state.in_flight.get_mut(&header.unique) else {
+            return Err(AxError::NotFound);
+        };
+        if in_flight.waiting {
+            in_flight.reply = Some(reply);
+        } else if state.in_flight.remove(&header.unique).unwrap().opcode == FUSE_INIT {
+            Self::handle_init(&mut state, reply);
+        }
+        drop(state);
+        self.reply_rx.wake();
+        Ok(buf.len())
+    }
+}
+
+/// An open file description of `/dev/fuse`, through which a daemon serves
+/// one FUSE connection.
+pub struct FuseDev {
+    conn: Arc<FuseConn>,
+    non_blocking: AtomicBool,
+}
+
+impl FuseDev {
+    pub fn new() -> Self {
+        Self {
+            conn: Arc::new(FuseConn::new()),
+            non_blocking: AtomicBool::new(false),
+        }
+    }
+
+    pub fn conn(&self) -> &Arc<FuseConn> {
+        &self.conn
+    }
+}
+
+impl Drop for FuseDev {
+    fn drop(&mut self) {
+        self.conn.abort();
+    }
+}
+
+impl FileLike for FuseDev {
+    fn read(&self, dst: &mut IoDst) -> AxResult<usize> {
+        if dst.remaining_mut() < FUSE_MIN_READ_BUFFER {
+            return Err(AxError::InvalidInput);
+        }
+        block_on(poll_io(self, IoEvents::IN, self.nonblocking(), || {
+            self.conn.read_request(dst)
+        }))
+    }
+
+    fn write(&self, src: &mut IoSrc) -> AxResult<usize> {
+        self.conn.write_reply(src)
+    }
+
+    fn nonblocking(&self) -> bool {
+        self.non_blocking.load(Ordering::Acquire)
+    }
+
+    fn set_nonblocking(&self, non_blocking: bool) -> AxResult {
+        self.non_blocking.store(non_blocking, Ordering::Release);
+        Ok(())
+    }
+
+    fn path(&self) -> Cow<'_, str> {
+        "/dev/fuse".into()
+    }
+}
+
+impl Pollable for FuseDev {
+    fn poll(&self) -> IoEvents {
+        let state = self.conn.state.lock();
+        let mut events = IoEvents::OUT;
+        if !state.pending.is_empty() {
+            events |= IoEvents::IN;
+        }
+        if !state.connected {
+            events |= IoEvents::ERR;
+        }
+        events
+    }
+
+    fn register(&self, context: &mut Context<'_>, events: IoEvents) {
+        if events.intersects(IoEvents::IN | IoEvents::ERR) {
+            self.conn.poll_rx.register(context.waker());
+        }
+    }
+}
@@ -0,0 +1,909 @@
+//! Filesystems in user space (FUSE).
+//!
+//! A daemon opens `/dev/fuse` and passes the descriptor to `mount` with the
+//! `fd=` option. Operations on the filesystem are then sent to the daemon as
+//! requests of the FUSE kernel protocol, which it reads from the descriptor
+//! and answers by writing replies to it.
+//!
+//! Directory entries are cached for as long as the kernel holds them, while
+//! attributes are only cached for as long as the daemon allows, and file data
+//! is not cached at all.
+
+mod abi;
+mod conn;
+
+use alloc::{borrow::ToOwned, string::String, sync::Arc, vec::Vec};
+use core::{
+    any::Any,
+    ffi::c_int,
+    str,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::Context,
+    time::Duration,
+};
+
+use axerrno::AxResult;
+use axfs::FileFlags;
+use axfs_ng_vfs::{
+    DeviceId, DirEntry, DirEntrySink, DirNode, DirNodeOps, FileNode, FileNodeOps, Filesystem,
+    FilesystemOps, Location, Metadata, MetadataUpdate, NodeFlags, NodeOps, NodePermission,
+    NodeType, Reference, StatFs, VfsError, VfsResult, WeakDirEntry,
+};
+use axhal::time::monotonic_time;
+use axpoll::{IoEvents, Pollable};
+use axsync::Mutex;
+use linux_raw_sys::general::{FUSE_SUPER_MAGIC, O_CREAT, O_EXCL, O_RDONLY, O_RDWR, O_WRONLY};
+use zerocopy::{FromBytes, IntoBytes};
+
+pub use self::conn::FuseDev;
+use self::{abi::*, conn::FuseConn};
+use crate::file::FileLike;
+
+/// The most data read with a single `FUSE_READ`.
+const MAX_READ: u32 = 128 * 1024;
+
+/// Returns `name` terminated by a NUL, as names are sent to the daemon.
+fn c_name(name: &str) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(name.len() + 1);
+    buf.extend_from_slice(name.as_bytes());
+    buf.push(0);
+    buf
+}
+
+/// Decodes a device number in the format of `new_encode_dev`.
+fn decode_dev(dev: u32) -> DeviceId {
+    DeviceId::new((dev & 0xfff00) >> 8, (dev & 0xff) | ((dev >> 12) & 0xfff00))
+}
+
+fn attr_to_metadata(attr: &FuseAttr) -> Metadata {
+    Metadata {
+        device: 0,
+        inode: attr.ino,
+        nlink: attr.nlink as _,
+        mode: NodePermission::from_bits_truncate(attr.mode as u16 & 0o7777),
+        node_type: NodeType::from(((attr.mode >> 12) & 0o17) as u8),
+        uid: attr.uid,
+        gid: attr.gid,
+        size: attr.size,
+        block_size: if attr.blksize == 0 {
+            4096
+        } else {
+            attr.blksize as _
+        },
+        blocks: attr.blocks,
+        rdev: decode_dev(attr.rdev),
+        atime: Duration::new(attr.atime, attr.atimensec),
+        mtime: Duration::new(attr.mtime, attr.mtimensec),
+        ctime: Duration::new(attr.ctime, attr.ctimensec),
+    }
+}
+
+/// A FUSE filesystem.
+struct FuseFs {
+    conn: Arc<FuseConn>,
+    /// `fuse`, or `fuse.<subtype>` as given to `mount`.
+    name: String,
+    /// The most data read with a single `FUSE_READ`.
+    max_read: u32,
+    /// Whether the daemon lacks `FUSE_CREATE`, so files are created with
+    /// `FUSE_MKNOD` instead.
+    no_create: AtomicBool,
+    root: Mutex<Option<DirEntry>>,
+}
+
+impl FilesystemOps for FuseFs {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn root_dir(&self) -> DirEntry {
+        self.root.lock().clone().unwrap()
+    }
+
+    fn stat(&self) -> VfsResult<StatFs> {
+        let st: FuseKstatfs = self.conn.request_as(FUSE_STATFS, FUSE_ROOT_ID, &[])?;
+        Ok(StatFs {
+            fs_type: FUSE_SUPER_MAGIC,
+            block_size: st.bsize,
+            blocks: st.blocks,
+            blocks_free: st.bfree,
+            blocks_available: st.bavail,
+            file_count: st.files,
+            free_file_count: st.ffree,
+            name_length: st.namelen,
+            fragment_size: st.frsize,
+            mount_flags: 0,
+        })
+    }
+}
+
+/// The state shared by all nodes of a FUSE filesystem.
+struct FuseNode {
+    fs: Arc<FuseFs>,
+    /// The node ID the daemon knows the node by, which is 0 for a symlink
+    /// whose target is not set yet.
+    nodeid: AtomicU64,
+    ino: u64,
+    /// The attributes from the last reply, and until when they are valid.
+    attr: Mutex<(FuseAttr, Duration)>,
+}
+
+impl FuseNode {
+    fn new(fs: Arc<FuseFs>, nodeid: u64, attr: FuseAttr) -> Self {
+        Self {
+            fs,
+            nodeid: AtomicU64::new(nodeid),
+            ino: attr.ino,
+            attr: Mutex::new((attr, Duration::ZERO)),
+        }
+    }
+
+    /// Creates the node of a reply that looked it up.
+    fn from_entry(fs: Arc<FuseFs>, entry: &FuseEntryOut) -> Self {
+        let node = Self::new(fs, entry.nodeid, entry.attr);
+        node.set_attr(entry.attr, entry.attr_valid, entry.attr_valid_nsec);
+        node
+    }
+
+    fn conn(&self) -> &FuseConn {
+        &self.fs.conn
+    }
+
+    fn nodeid(&self) -> VfsResult<u64> {
+        match self.nodeid.load(Ordering::Acquire) {
+            0 => Err(VfsError::NotFound),
+            nodeid => Ok(nodeid),
+        }
+    }
+
+    fn set_attr(&self, attr: FuseAttr, valid: u64, valid_nsec: u32) {
+        let valid = Duration::new(valid, valid_nsec.min(999_999_999));
+        *self.attr.lock() = (attr, monotonic_time().saturating_add(valid));
+    }
+
+    /// Drops the cached attributes, after an operation changed them.
+    fn invalidate_attr(&self) {
+        self.attr.lock().1 = Duration::ZERO;
+    }
+
+    fn attr(&self) -> VfsResult<FuseAttr> {
+        {
+            let attr = self.attr.lock();
+            if monotonic_time() < attr.1 {
+                return Ok(attr.0);
+            }
+        }
+        let getattr = FuseGetattrIn {
+            getattr_flags: 0,
+            dummy: 0,
+            fh: 0,
+        };
+        let out: FuseAttrOut =
+            self.conn()
+                .request_as(FUSE_GETATTR, self.nodeid()?, &[getattr.as_bytes()])?;
+        self.set_attr(out.attr, out.attr_valid, out.attr_valid_nsec);
+        Ok(out.attr)
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        Ok(attr_to_metadata(&self.attr()?))
+    }
+
+    fn setattr(&self, setattr: &FuseSetattrIn) -> VfsResult<()> {
+        let out: FuseAttrOut =
+            self.conn()
+                .request_as(FUSE_SETATTR, self.nodeid()?, &[setattr.as_bytes()])?;
+        self.set_attr(out.attr, out.attr_valid, out.attr_valid_nsec);
+        Ok(())
+    }
+
+    fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+        let mut setattr = FuseSetattrIn::default();
+        if let Some(mode) = update.mode {
+            setattr.valid |= FATTR_MODE;
+            setattr.mode = mode.bits() as u32;
+        }
+        if let Some((uid, gid)) = update.owner {
+            setattr.valid |= FATTR_UID | FATTR_GID;
+            setattr.uid = uid;
+            setattr.gid = gid;
+        }
+        if let Some(atime) = update.atime {
+            setattr.valid |= FATTR_ATIME;
+            setattr.atime = atime.as_secs();
+            setattr.atimensec = atime.subsec_nanos();
+        }
+        if let Some(mtime) = update.mtime {
+            setattr.valid |= FATTR_MTIME;
+            setattr.mtime = mtime.as_secs();
+            setattr.mtimensec = mtime.subsec_nanos();
+        }
+        if setattr.valid == 0 {
+            return Ok(());
+        }
+        self.setattr(&setattr)
+    }
+}
+
+impl Drop for FuseNode {
+    fn drop(&mut self) {
+        // Every node stands for a single lookup.
+        match *self.nodeid.get_mut() {
+            0 | FUSE_ROOT_ID => {}
+            nodeid => self.fs.conn.forget(nodeid, 1),
+        }
+    }
+}
+
+/// A directory of a FUSE filesystem.
+struct FuseDir {
+    node: FuseNode,
+    this: WeakDirEntry,
+    /// The handle the directory is being read through.
+    handle: Mutex<Option<u64>>,
+}
+
+impl FuseDir {
+    fn new_entry(node: FuseNode, reference: Reference) -> DirEntry {
+        DirEntry::new_dir(
+            |this| {
+                DirNode::new(Arc::new(Self {
+                    node,
+                    this,
+                    handle: Mutex::new(None),
+                }))
+            },
+            reference,
+        )
+    }
+
+    fn nodeid(&self) -> VfsResult<u64> {
+        self.node.nodeid()
+    }
+
+    /// Creates the entry named `name` for the node of a reply that looked
+    /// it up.
+    fn new_child(&self, name: &str, entry: &FuseEntryOut) -> VfsResult<DirEntry> {
+        // A node ID of 0 is a negative entry.
+        if entry.nodeid == 0 {
+            return Err(VfsError::NotFound);
+        }
+        let node = FuseNode::from_entry(self.node.fs.clone(), entry);
+        let this = self.this.upgrade().ok_or(VfsError::NotFound)?;
+        let reference = Reference::new(Some(this), name.to_owned());
+        let node_type = attr_to_metadata(&entry.attr).node_type;
+        Ok(match node_type {
+            NodeType::Directory => Self::new_entry(node, reference),
+            NodeType::Unknown => return Err(VfsError::Io),
+            node_type => DirEntry::new_file(
+                FileNode::new(Arc::new(FuseFile::new(node, node_type))),
+                node_type,
+                reference,
+            ),
+        })
+    }
+
+    /// Sends a request creating an entry named `name`, whose arguments are
+    /// `args` followed by the name.
+    fn make_entry(&self, opcode: u32, args: &[u8], name: &str) -> VfsResult<DirEntry> {
+        let entry: FuseEntryOut =
+            self.node
+                .conn()
+                .request_as(opcode, self.nodeid()?, &[args, &c_name(name)])?;
+        self.node.invalidate_attr();
+        self.new_child(name, &entry)
+    }
+
+    /// Creates a regular file with `FUSE_CREATE`, keeping the file handle it
+    /// returns, or returns `None` if the daemon does not support it.
+    fn create_file(&self, name: &str, mode: u32) -> VfsResult<Option<DirEntry>> {
+        if self.node.fs.no_create.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        let create = FuseCreateIn {
+            flags: O_CREAT | O_EXCL | O_RDWR,
+            mode,
+            umask: 0,
+            padding: 0,
+        };
+        let reply = match self.node.conn().request(
+            FUSE_CREATE,
+            self.nodeid()?,
+            &[create.as_bytes(), &c_name(name)],
+        ) {
+            Ok(reply) => reply,
+            Err(VfsError::Unsupported) => {
+                self.node.fs.no_create.store(true, Ordering::Relaxed);
+                return Ok(None);
+            }
+            Err(err) => return Err(err),
+        };
+        self.node.invalidate_attr();
+        let (entry, rest) = FuseEntryOut::read_from_prefix(&reply).map_err(|_| VfsError::Io)?;
+        let (open, _) = FuseOpenOut::read_from_prefix(rest).map_err(|_| VfsError::Io)?;
+        let child = self.new_child(name, &entry)?;
+        child.downcast::<FuseFile>()?.handles.lock().fh[O_RDWR as usize] = Some(open.fh);
+        Ok(Some(child))
+    }
+
+    /// Releases the handle the directory is read through.
+    fn release_handle(&self, handle: &mut Option<u64>) {
+        if let (Some(fh), Ok(nodeid)) = (handle.take(), self.nodeid()) {
+            let release = FuseReleaseIn {
+                fh,
+                flags: 0,
+                release_flags: 0,
+                lock_owner: 0,
+            };
+            self.node
+                .conn()
+                .post(FUSE_RELEASEDIR, nodeid, &[release.as_bytes()]);
+        }
+    }
+
+    /// Returns whether the entry `name` is a directory.
+    fn is_dir(&self, name: &str) -> VfsResult<bool> {
+        let this = self.this.upgrade().ok_or(VfsError::NotFound)?;
+        let entry = match this.as_dir()?.lookup_cache(name) {
+            Some(entry) => entry,
+            None => self.lookup(name)?,
+        };
+        Ok(entry.node_type() == NodeType::Directory)
+    }
+}
+
+impl Drop for FuseDir {
+    fn drop(&mut self) {
+        let mut handle = self.handle.get_mut().take();
+        self.release_handle(&mut handle);
+    }
+}
+
+impl NodeOps for FuseDir {
+    fn inode(&self) -> u64 {
+        self.node.ino
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        self.node.metadata()
+    }
+
+    fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+        self.node.update_metadata(update)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        self.node.fs.as_ref()
+    }
+
+    fn sync(&self, _data_only: bool) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+impl Pollable for FuseDir {
+    fn poll(&self) -> IoEvents {
+        IoEvents::IN | IoEvents::OUT
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
+
+impl DirNodeOps for FuseDir {
+    fn read_dir(&self, offset: u64, sink: &mut dyn DirEntrySink) -> VfsResult<usize> {
+        let nodeid = self.nodeid()?;
+        let conn = self.node.conn();
+        let mut handle = self.handle.lock();
+        // Reading starts over with a new handle.
+        if offset == 0 {
+            self.release_handle(&mut handle);
+        }
+        let fh = match *handle {
+            Some(fh) => fh,
+            None => {
+                let open = FuseOpenIn {
+                    flags: O_RDONLY,
+                    unused: 0,
+                };
+                let out: FuseOpenOut = conn.request_as(FUSE_OPENDIR, nodeid, &[open.as_bytes()])?;
+                *handle.insert(out.fh)
+            }
+        };
+
+        let mut offset = offset;
+        let mut count = 0;
+        loop {
+            let read = FuseReadIn {
+                fh,
+                offset,
+                size: 4096,
+                read_flags: 0,
+                lock_owner: 0,
+                flags: 0,
+                padding: 0,
+            };
+            let reply = conn.request(FUSE_READDIR, nodeid, &[read.as_bytes()])?;
+            if reply.is_empty() {
+                return Ok(count);
+            }
+            let mut rest = reply.as_slice();
+            while let Ok((dirent, name)) = FuseDirent::read_from_prefix(rest) {
+                let name = name
+                    .get(..dirent.namelen as usize)
+                    .and_then(|it| str::from_utf8(it).ok())
+                    .ok_or(VfsError::Io)?;
+                let node_type = NodeType::from(dirent.type_ as u8);
+                if !sink.accept(name, dirent.ino, node_type, dirent.off) {
+                    return Ok(count);
+                }
+                count += 1;
+                offset = dirent.off;
+                let len = (size_of::<FuseDirent>() + name.len()).next_multiple_of(8);
+                rest = rest.get(len..).unwrap_or_default();
+            }
+        }
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<DirEntry> {
+        let entry: FuseEntryOut =
+            self.node
+                .conn()
+                .request_as(FUSE_LOOKUP, self.nodeid()?, &[&c_name(name)])?;
+        self.new_child(name, &entry)
+    }
+
+    fn create(
+        &self,
+        name: &str,
+        node_type: NodeType,
+        permission: NodePermission,
+    ) -> VfsResult<DirEntry> {
+        let mode = ((node_type as u32) << 12) | permission.bits() as u32;
+        match node_type {
+            NodeType::Directory => {
+                let mkdir = FuseMkdirIn { mode, umask: 0 };
+                self.make_entry(FUSE_MKDIR, mkdir.as_bytes(), name)
+            }
+            // The daemon creates the symlink once its target is set.
+            NodeType::Symlink => {
+                let this = self.this.upgrade().ok_or(VfsError::NotFound)?;
+                let mut node = FuseNode::new(self.node.fs.clone(), 0, FuseAttr::default());
+                node.attr.get_mut().0.mode = mode;
+                let mut file = FuseFile::new(node, NodeType::Symlink);
+                file.pending = Some((this.downcast::<Self>()?, name.to_owned()));
+                Ok(DirEntry::new_file(
+                    FileNode::new(Arc::new(file)),
+                    NodeType::Symlink,
+                    Reference::new(Some(this), name.to_owned()),
+                ))
+            }
+            _ => {
+                if node_type == NodeType::RegularFile
+                    && let Some(entry) = self.create_file(name, mode)?
+                {
+                    return Ok(entry);
+                }
+                let mknod = FuseMknodIn {
+                    mode,
+                    rdev: 0,
+                    umask: 0,
+                    padding: 0,
+                };
+                self.make_entry(FUSE_MKNOD, mknod.as_bytes(), name)
+            }
+        }
+    }
+
+    fn link(&self, name: &str, node: &DirEntry) -> VfsResult<DirEntry> {
+        let file = node.downcast::<FuseFile>()?;
+        let link = FuseLinkIn {
+            oldnodeid: file.node.nodeid()?,
+        };
+        let entry = self.make_entry(FUSE_LINK, link.as_bytes(), name)?;
+        file.node.invalidate_attr();
+        Ok(entry)
+    }
+
+    fn unlink(&self, name: &str) -> VfsResult<()> {
+        let opcode = if self.is_dir(name)? {
+            FUSE_RMDIR
+        } else {
+            FUSE_UNLINK
+        };
+        self.node
+            .conn()
+            .request(opcode, self.nodeid()?, &[&c_name(name)])?;
+        self.node.invalidate_attr();
+        Ok(())
+    }
+
+    fn rename(&self, src_name: &str, dst_dir: &DirNode, dst_name: &str) -> VfsResult<()> {
+        let dst_dir = dst_dir
+            .downcast::<Self>()
+            .map_err(|_| VfsError::CrossesDevices)?;
+        let rename = FuseRenameIn {
+            newdir: dst_dir.nodeid()?,
+        };
+        self.node.conn().request(
+            FUSE_RENAME,
+            self.nodeid()?,
+            &[rename.as_bytes(), &c_name(src_name), &c_name(dst_name)],
+        )?;
+        self.node.invalidate_attr();
+        dst_dir.node.invalidate_attr();
+        Ok(())
+    }
+}
+
+/// The handles a file is opened through.
+#[derive(Default)]
+struct Handles {
+    /// Number of open file descriptions of the file.
+    opens: usize,
+    /// The handles opened for reading, writing, or both, indexed by the
+    /// access mode.
+    fh: [Option<u64>; 3],
+}
+
+/// A file of a FUSE filesystem other than a directory.
+struct FuseFile {
+    node: FuseNode,
+    node_type: NodeType,
+    handles: Mutex<Handles>,
+    /// For a symlink created but not sent to the daemon yet, its directory
+    /// and name.
+    pending: Option<(Arc<FuseDir>, String)>,
+}
+
+impl FuseFile {
+    fn new(node: FuseNode, node_type: NodeType) -> Self {
+        Self {
+            node,
+            node_type,
+            handles: Mutex::default(),
+            pending: None,
+        }
+    }
+
+    /// Returns a handle opened for `access` (`O_RDONLY`, `O_WRONLY` or
+    /// `O_RDWR`), opening the file if there is none.
+    fn handle(&self, access: u32) -> VfsResult<u64> {
+        let mut handles = self.handles.lock();
+        let usable = match access {
+            O_RDONLY => [O_RDONLY, O_RDWR],
+            O_WRONLY => [O_WRONLY, O_RDWR],
+            _ => [O_RDWR, O_RDWR],
+        };
+        if let Some(fh) = usable.iter().find_map(|it| handles.fh[*it as usize]) {
+            return Ok(fh);
+        }
+        let open = FuseOpenIn {
+            flags: access,
+            unused: 0,
+        };
+        let out: FuseOpenOut =
+            self.node
+                .conn()
+                .request_as(FUSE_OPEN, self.node.nodeid()?, &[open.as_bytes()])?;
+        handles.fh[access as usize] = Some(out.fh);
+        Ok(out.fh)
+    }
+
+    /// Releases all the handles of the file.
+    fn release(&self, handles: &mut Handles) {
+        let Ok(nodeid) = self.node.nodeid() else {
+            return;
+        };
+        for (access, fh) in handles.fh.iter_mut().enumerate() {
+            if let Some(fh) = fh.take() {
+                let release = FuseReleaseIn {
+                    fh,
+                    flags: access as u32,
+                    release_flags: 0,
+                    lock_owner: 0,
+                };
+                self.node
+                    .conn()
+                    .post(FUSE_RELEASE, nodeid, &[release.as_bytes()]);
+            }
+        }
+    }
+
+    fn read_link(&self) -> VfsResult<Vec<u8>> {
+        self.node
+            .conn()
+            .request(FUSE_READLINK, self.node.nodeid()?, &[])
+    }
+}
+
+impl Drop for FuseFile {
+    fn drop(&mut self) {
+        let mut handles = core::mem::take(self.handles.get_mut());
+        self.release(&mut handles);
+    }
+}
+
+impl NodeOps for FuseFile {
+    fn inode(&self) -> u64 {
+        self.node.ino
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        if self.node.nodeid.load(Ordering::Acquire) == 0 {
+            let attr = self.node.attr.lock().0;
+            return Ok(attr_to_metadata(&attr));
+        }
+        self.node.metadata()
+    }
+
+    fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+        self.node.update_metadata(update)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        self.node.fs.as_ref()
+    }
+
+    fn sync(&self, data_only: bool) -> VfsResult<()> {
+        let Some(fh) = self.handles.lock().fh.iter().flatten().next().copied() else {
+            return Ok(());
+        };
+        let fsync = FuseFsyncIn {
+            fh,
+            fsync_flags: if data_only { FUSE_FSYNC_FDATASYNC } else { 0 },
+            padding: 0,
+        };
+        match self
+            .node
+            .conn()
+            .request(FUSE_FSYNC, self.node.nodeid()?, &[fsync.as_bytes()])
+        {
+            Ok(_) | Err(VfsError::Unsupported) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn flags(&self) -> NodeFlags {
+        // The data always comes from the daemon.
+        NodeFlags::NON_CACHEABLE | NodeFlags::BLOCKING
+    }
+}
+
+impl Pollable for FuseFile {
+    fn poll(&self) -> IoEvents {
+        IoEvents::IN | IoEvents::OUT
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
+
+impl FileNodeOps for FuseFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        if self.node_type == NodeType::Symlink {
+            let target = self.read_link()?;
+            let start = (offset as usize).min(target.len());
+            let len = buf.len().min(target.len() - start);
+            buf[..len].copy_from_slice(&target[start..start + len]);
+            return Ok(len);
+        }
+        let fh = self.handle(O_RDONLY)?;
+        let nodeid = self.node.nodeid()?;
+        let max_read = self.node.fs.max_read.min(MAX_READ) as usize;
+        let mut read = 0;
+        while read < buf.len() {
+            let size = (buf.len() - read).min(max_read);
+            let args = FuseReadIn {
+                fh,
+                offset: offset + read as u64,
+                size: size as u32,
+                read_flags: 0,
+                lock_owner: 0,
+                flags: O_RDONLY,
+                padding: 0,
+            };
+            let reply = self
+                .node
+                .conn()
+                .request(FUSE_READ, nodeid, &[args.as_bytes()])?;
+            let len = reply.len().min(size);
+            buf[read..read + len].copy_from_slice(&reply[..len]);
+            read += len;
+            if len < size {
+                break;
+            }
+        }
+        Ok(read)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> VfsResult<usize> {
+        let fh = self.handle(O_WRONLY)?;
+        let nodeid = self.node.nodeid()?;
+        let max_write = self.node.conn().max_write()? as usize;
+        let mut written = 0;
+        for chunk in buf.chunks(max_write) {
+            let args = FuseWriteIn {
+                fh,
+                offset: offset + written as u64,
+                size: chunk.len() as u32,
+                write_flags: 0,
+                lock_owner: 0,
+                flags: O_WRONLY,
+                padding: 0,
+            };
+            let out: FuseWriteOut = self
+                .node
+                .conn()
+                .request_as(FUSE_WRITE, nodeid, &[args.as_bytes(), chunk])
+                .inspect_err(|_| self.node.invalidate_attr())?;
+            written += (out.size as usize).min(chunk.len());
+            if (out.size as usize) < chunk.len() {
+                break;
+            }
+        }
+        self.node.invalidate_attr();
+        Ok(written)
+    }
+
+    fn append(&self, buf: &[u8]) -> VfsResult<(usize, u64)> {
+        self.node.invalidate_attr();
+        let size = self.node.attr()?.size;
+        let written = self.write_at(buf, size)?;
+        Ok((written, size + written as u64))
+    }
+
+    fn set_len(&self, len: u64) -> VfsResult<()> {
+        self.node.setattr(&FuseSetattrIn {
+            valid: FATTR_SIZE,
+            size: len,
+            ..Default::default()
+        })
+    }
+
+    fn set_symlink(&self, target: &str) -> VfsResult<()> {
+        let Some((dir, name)) = &self.pending else {
+            return Err(VfsError::InvalidInput);
+        };
+        if self.node.nodeid.load(Ordering::Acquire) != 0 {
+            return Err(VfsError::AlreadyExists);
+        }
+        let entry: FuseEntryOut = dir.node.conn().request_as(
+            FUSE_SYMLINK,
+            dir.nodeid()?,
+            &[&c_name(name), &c_name(target)],
+        )?;
+        if entry.nodeid == 0 {
+            return Err(VfsError::Io);
+        }
+        dir.node.invalidate_attr();
+        self.node.nodeid.store(entry.nodeid, Ordering::Release);
+        self.node
+            .set_attr(entry.attr, entry.attr_valid, entry.attr_valid_nsec);
+        Ok(())
+    }
+}
+
+/// Returns the file of a FUSE filesystem at `loc`, if it is one.
+fn fuse_file(loc: &Location) -> Option<Arc<FuseFile>> {
+    loc.entry().downcast::<FuseFile>().ok()
+}
+
+/// Opens the FUSE file `loc` for `flags`, so that errors of the daemon are
+/// reported by `open` rather than the first read or write.
+pub fn fuse_open(loc: &Location, flags: FileFlags) -> AxResult<()> {
+    let Some(file) = fuse_file(loc) else {
+        return Ok(());
+    };
+    if file.node_type != NodeType::RegularFile {
+        return Ok(());
+    }
+    let access = match (
+        flags.contains(FileFlags::READ),
+        flags.contains(FileFlags::WRITE),
+    ) {
+        (true, true) => O_RDWR,
+        (false, true) => O_WRONLY,
+        _ => O_RDONLY,
+    };
+    file.handle(access).map(drop)
+}
+
+/// Counts an open file description of the FUSE file `loc`.
+pub fn fuse_hold(loc: &Location) {
+    if let Some(file) = fuse_file(loc) {
+        file.handles.lock().opens += 1;
+    }
+}
+
+/// Undoes [`fuse_hold`], releasing the handles of the file once it is not
+/// open anymore.
+pub fn fuse_put(loc: &Location) {
+    if let Some(file) = fuse_file(loc) {
+        let mut handles = file.handles.lock();
+        handles.opens = handles.opens.saturating_sub(1);
+        if handles.opens == 0 {
+            file.release(&mut handles);
+        }
+    }
+}
+
+/// Aborts the connection of the FUSE filesystem whose root is `root`, which
+/// is being unmounted, so that its daemon finishes.
+pub(super) fn abort_fuse(root: &Location) {
+    if let Ok(dir) = root.entry().downcast::<FuseDir>() {
+        dir.node.conn().abort();
+    }
+}
+
+/// Parses the value of the mount option `key`.
+fn parse_option<T: str::FromStr>(value: Option<&str>) -> VfsResult<T> {
+    value
+        .and_then(|it| it.parse().ok())
+        .ok_or(VfsError::InvalidInput)
+}
+
+/// Creates a FUSE filesystem of `fs_type` (`fuse` or `fuse.<subtype>`) from
+/// the mount options `data`, served by the daemon that opened `/dev/fuse` as
+/// the descriptor given with `fd=`.
+pub fn new_fuse_fs(fs_type: &str, data: &str) -> VfsResult<Filesystem> {
+    let mut fd = None;
+    let mut rootmode = None;
+    let mut user_id = None;
+    let mut group_id = None;
+    let mut max_read = u32::MAX;
+    for option in data.split(',').filter(|it| !it.is_empty()) {
+        let (key, value) = match option.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (option, None),
+        };
+        match key {
+            "fd" => fd = Some(parse_option::<c_int>(value)?),
+            "rootmode" => {
+                let value = value.ok_or(VfsError::InvalidInput)?;
+                rootmode = Some(u32::from_str_radix(value, 8).map_err(|_| VfsError::InvalidInput)?);
+            }
+            "user_id" => user_id = Some(parse_option::<u32>(value)?),
+            "group_id" => group_id = Some(parse_option::<u32>(value)?),
+            "max_read" => max_read = parse_option::<u32>(value)?.max(4096),
+            // Permissions are not checked anyway.
+            "default_permissions" | "allow_other" if value.is_none() => {}
+            _ => return Err(VfsError::InvalidInput),
+        }
+    }
+    let (Some(fd), Some(rootmode), Some(_), Some(_)) = (fd, rootmode, user_id, group_id) else {
+        return Err(VfsError::InvalidInput);
+    };
+    if NodeType::from(((rootmode >> 12) & 0o17) as u8) != NodeType::Directory {
+        return Err(VfsError::InvalidInput);
+    }
+    let conn = FuseDev::from_fd(fd)?.conn().clone();
+    conn.claim()?;
+
+    let fs = Arc::new(FuseFs {
+        conn: conn.clone(),
+        name: fs_type.to_owned(),
+        max_read,
+        no_create: AtomicBool::new(false),
+        root: Mutex::default(),
+    });
+    // The attributes of the root are fetched when first needed.
+    let attr = FuseAttr {
+        ino: FUSE_ROOT_ID,
+        mode: rootmode,
+        nlink: 2,
+        ..Default::default()
+    };
+    *fs.root.lock() = Some(FuseDir::new_entry(
+        FuseNode::new(fs.clone(), FUSE_ROOT_ID, attr),
+        Reference::root(),
+    ));
+    conn.init()?;
+    Ok(Filesystem::new(fs))
+}
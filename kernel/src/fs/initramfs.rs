@@ -0,0 +1,542 @@
+//! Initial RAM filesystems.
+//!
+//! An initramfs image is a series of newc (`070701`) or crc (`070702`) cpio
+//! archives, optionally separated by zero padding. It may be built into the
+//! kernel, or loaded by the bootloader, which passes its physical location in
+//! the `linux,initrd-start` and `linux,initrd-end` properties of the
+//! `/chosen` device tree node. Unless the platform keeps that memory out of
+//! the allocator, its pages are claimed from the page allocator until the
+//! image is unpacked.
+//!
+//! Archives compressed with gzip, xz or zstd are supported, but a compressed
+//! archive extends to the end of the image.
+//!
+//! The images are unpacked into a tmpfs that becomes the root, and the disk
+//! root is mounted at `/sysroot`, so that init can `switch_root` into it
+//! after moving `/dev`, `/proc` and friends along.
+
+use alloc::{borrow::ToOwned, collections::btree_map::BTreeMap, string::String, vec, vec::Vec};
+use core::{str, time::Duration};
+
+use axalloc::{UsageKind, global_allocator};
+use axerrno::{AxError, AxResult};
+use axfs::{FS_CONTEXT, FsContext, OpenOptions};
+use axfs_ng_vfs::{
+    DeviceId, Location, MetadataUpdate, Mountpoint, NodePermission, NodeType, path::Path,
+};
+use axhal::mem::{MemRegionFlags, PhysMemRegion, memory_regions, phys_to_virt};
+use linux_raw_sys::general::{
+    S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFMT, S_IFREG, S_IFSOCK,
+};
+use lzma_rust2::XzReader;
+use memory_addr::{PAGE_SIZE_4K, align_down_4k, align_up_4k};
+use ruzstd::{decoding::StreamingDecoder, io::Read};
+
+use super::{MountFlags, add_root_mount, mknod, mount_root_at};
+use crate::pseudofs::{MemoryFs, charge_pages};
+
+/// The directory at which the disk root is mounted.
+const SYSROOT: &str = "/sysroot";
+
+/// The program run as init unless `rdinit=` is given.
+const DEFAULT_INIT: &str = "/init";
+
+/// The length of a cpio header.
+const HEADER_LEN: usize = 110;
+
+/// The name of the last entry of a cpio archive.
+const TRAILER: &str = "TRAILER!!!";
+
+/// The header of a cpio entry.
+struct Header {
+    ino: u32,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u32,
+    mtime: u32,
+    file_size: u32,
+    dev_major: u32,
+    dev_minor: u32,
+    rdev_major: u32,
+    rdev_minor: u32,
+    name_size: u32,
+    /// The sum of all bytes of the contents, for the crc format.
+    check: Option<u32>,
+}
+
+impl Header {
+    fn parse(data: &[u8]) -> AxResult<Self> {
+        let data = data.get(..HEADER_LEN).ok_or(AxError::InvalidData)?;
+        let crc = match &data[..6] {
+            b"070701" => false,
+            b"070702" => true,
+            _ => return Err(AxError::InvalidData),
+        };
+        let mut fields = [0; 13];
+        for (i, field) in fields.iter_mut().enumerate() {
+            let hex = &data[6 + i * 8..6 + (i + 1) * 8];
+            *field = str::from_utf8(hex)
+                .ok()
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .ok_or(AxError::InvalidData)?;
+        }
+        let [
+            ino,
+            mode,
+            uid,
+            gid,
+            nlink,
+            mtime,
+            file_size,
+            dev_major,
+            dev_minor,
+            rdev_major,
+            rdev_minor,
+            name_size,
+            check,
+        ] = fields;
+        Ok(Self {
+            ino,
+            mode,
+            uid,
+            gid,
+            nlink,
+            mtime,
+            file_size,
+            dev_major,
+            dev_minor,
+            rdev_major,
+            rdev_minor,
+            name_size,
+            check: crc.then_some(check),
+        })
+    }
+}
+
+/// Unpacks cpio archives into the directory tree of a [`FsContext`].
+struct Unpacker<'a> {
+    cx: &'a FsContext,
+    /// The first entry of each hard linked file, by inode and device.
+    links: BTreeMap<(u32, u32, u32), Location>,
+    /// Modification times of directories, which are set after their
+    /// contents have been unpacked.
+    dir_times: Vec<(Location, Duration)>,
+}
+
+impl Unpacker<'_> {
+    /// Unpacks all archives in `data`.
+    fn unpack(&mut self, mut data: &[u8]) -> AxResult<()> {
+        loop {
+            let padding = data.iter().take_while(|&&it| it == 0).count();
+            data = &data[padding..];
+            if data.is_empty() {
+                return Ok(());
+            }
+            if let Some(data) = decompress(data)? {
+                return self.unpack(&data);
+            }
+            data = self.unpack_archive(data)?;
+        }
+    }
+
+    /// Unpacks the archive at the start of `data` and returns what follows
+    /// it.
+    fn unpack_archive<'d>(&mut self, mut data: &'d [u8]) -> AxResult<&'d [u8]> {
+        loop {
+            let header = Header::parse(data)?;
+            let name_end = HEADER_LEN + header.name_size as usize;
+            let body_start = name_end.next_multiple_of(4);
+            let body_end = body_start + header.file_size as usize;
+            if header.name_size == 0 || body_end > data.len() {
+                return Err(AxError::InvalidData);
+            }
+            let name = str::from_utf8(&data[HEADER_LEN..name_end - 1])
+                .map_err(|_| AxError::InvalidData)?;
+            let body = &data[body_start..body_end];
+            data = &data[body_end.next_multiple_of(4).min(data.len())..];
+
+            if let Some(check) = header.check
+                && body
+                    .iter()
+                    .fold(0u32, |sum, &it| sum.wrapping_add(it as u32))
+                    != check
+            {
+                warn!("initramfs: bad checksum of {name}");
+                return Err(AxError::InvalidData);
+            }
+            if name == TRAILER {
+                self.links.clear();
+                return Ok(data);
+            }
+            if let Err(err) = self.extract(&header, name, body) {
+                warn!("initramfs: cannot extract {name}: {err:?}");
+            }
+        }
+    }
+
+    /// Creates the node of a single entry.
+    fn extract(&mut self, header: &Header, name: &str, body: &[u8]) -> AxResult<()> {
+        let permission = NodePermission::from_bits_truncate((header.mode & 0o7777) as u16);
+        let owner = (header.uid, header.gid);
+        let mtime = Duration::from_secs(header.mtime as u64);
+
+        let path = name.trim_end_matches('/').trim_start_matches("./");
+        let path = path.trim_start_matches('/');
+        if path.is_empty() || path == "." {
+            // The root directory itself
+            let root = self.cx.root_dir().clone();
+            set_metadata(&root, Some(permission), owner, None)?;
+            self.dir_times.push((root, mtime));
+            return Ok(());
+        }
+        let (dir, name) = self.cx.resolve_parent(Path::new(path))?;
+
+        let loc = match header.mode & S_IFMT {
+            S_IFDIR => {
+                let loc = match make_room(&dir, &name, true)? {
+                    Some(loc) => loc,
+                    None => dir.create(&name, NodeType::Directory, permission)?,
+                };
+                set_metadata(&loc, Some(permission), owner, None)?;
+                self.dir_times.push((loc, mtime));
+                return Ok(());
+            }
+            S_IFLNK => {
+                let target = str::from_utf8(body).map_err(|_| AxError::InvalidData)?;
+                make_room(&dir, &name, false)?;
+                let loc = dir.create(&name, NodeType::Symlink, NodePermission::default())?;
+                loc.entry().as_file()?.set_symlink(target)?;
+                set_metadata(&loc, None, owner, Some(mtime))?;
+                return Ok(());
+            }
+            S_IFREG => {
+                make_room(&dir, &name, false)?;
+                let key = (header.ino, header.dev_major, header.dev_minor);
+                let linked = if header.nlink >= 2 {
+                    self.links.get(&key).cloned()
+                } else {
+                    None
+                };
+                let loc = match linked {
+                    // The contents may come with any of the links.
+                    Some(first) => dir.link(&name, &first)?,
+                    None => mknod(
+                        &dir,
+                        &name,
+                        NodeType::RegularFile,
+                        permission,
+                        owner,
+                        DeviceId::default(),
+                    )?,
+                };
+                if header.nlink >= 2 {
+                    self.links.entry(key).or_insert_with(|| loc.clone());
+                }
+                write_contents(&loc, body)?;
+                loc
+            }
+            mode @ (S_IFCHR | S_IFBLK | S_IFIFO | S_IFSOCK) => {
+                let node_type = match mode {
+                    S_IFCHR => NodeType::CharacterDevice,
+                    S_IFBLK => NodeType::BlockDevice,
+                    S_IFIFO => NodeType::Fifo,
+                    _ => NodeType::Socket,
+                };
+                make_room(&dir, &name, false)?;
+                let rdev = DeviceId::new(header.rdev_major, header.rdev_minor);
+                mknod(&dir, &name, node_type, permission, owner, rdev)?
+            }
+            _ => return Err(AxError::InvalidInput),
+        };
+        set_metadata(&loc, Some(permission), owner, Some(mtime))
+    }
+
+    /// Sets the modification times of the directories.
+    fn finish(self) {
+        for (loc, mtime) in self.dir_times {
+            let _ = loc.update_metadata(MetadataUpdate {
+                atime: Some(mtime),
+                mtime: Some(mtime),
+                ..Default::default()
+            });
+        }
+    }
+}
+
+/// Removes whatever is named `name` in `dir` to make room for a new node,
+/// except for a directory with `keep_dir`, which is returned instead.
+fn make_room(dir: &Location, name: &str, keep_dir: bool) -> AxResult<Option<Location>> {
+    let Ok(old) = dir.lookup_no_follow(name) else {
+        return Ok(None);
+    };
+    if old.is_dir() && keep_dir {
+        return Ok(Some(old));
+    }
+    dir.unlink(name, old.is_dir())?;
+    Ok(None)
+}
+
+fn set_metadata(
+    loc: &Location,
+    mode: Option<NodePermission>,
+    owner: (u32, u32),
+    mtime: Option<Duration>,
+) -> AxResult<()> {
+    loc.update_metadata(MetadataUpdate {
+        mode,
+        owner: Some(owner),
+        atime: mtime,
+        mtime,
+    })?;
+    Ok(())
+}
+
+/// Writes `contents` at the start of the regular file `loc`.
+fn write_contents(loc: &Location, mut contents: &[u8]) -> AxResult<()> {
+    if contents.is_empty() {
+        return Ok(());
+    }
+    let file = OpenOptions::new()
+        .write(true)
+        .open_loc(loc.clone())?
+        .into_file()?;
+    charge_pages(loc, 0, contents.len() as u64)?;
+    let mut offset = 0;
+    while !contents.is_empty() {
+        let written = file.write_at(contents, offset)?;
+        if written == 0 {
+            return Err(AxError::Io);
+        }
+        contents = &contents[written..];
+        offset += written as u64;
+    }
+    Ok(())
+}
+
+/// Decompresses `data` if it starts with a compressed stream.
+fn decompress(data: &[u8]) -> AxResult<Option<Vec<u8>>> {
+    let result = if data.starts_with(&[0x1f, 0x8b]) {
+        let deflate = skip_gzip_header(data).ok_or(AxError::InvalidData)?;
+        miniz_oxide::inflate::decompress_to_vec(deflate).map_err(|_| ())
+    } else if data.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0]) {
+        let mut reader = XzReader::new(data, false);
+        read_to_end(|buf| lzma_rust2::Read::read(&mut reader, buf).map_err(|_| ()))
+    } else if data.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        let mut decoder = StreamingDecoder::new(data).map_err(|_| AxError::InvalidData)?;
+        read_to_end(|buf| decoder.read(buf).map_err(|_| ()))
+    } else {
+        return Ok(None);
+    };
+    match result {
+        Ok(data) => Ok(Some(data)),
+        Err(()) => {
+            warn!("initramfs: corrupted compressed archive");
+            Err(AxError::InvalidData)
+        }
+    }
+}
+
+/// Returns the deflate stream of a gzip member.
+fn skip_gzip_header(data: &[u8]) -> Option<&[u8]> {
+    const FHCRC: u8 = 1 << 1;
+    const FEXTRA: u8 = 1 << 2;
+    const FNAME: u8 = 1 << 3;
+    const FCOMMENT: u8 = 1 << 4;
+
+    // Only deflate is defined
+    if *data.get(2)? != 8 {
+        return None;
+    }
+    let flags = *data.get(3)?;
+    let mut data = data.get(10..)?;
+    if flags & FEXTRA != 0 {
+        let len = u16::from_le_bytes([*data.first()?, *data.get(1)?]) as usize;
+        data = data.get(2 + len..)?;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let end = data.iter().position(|&it| it == 0)?;
+            data = &data[end + 1..];
+        }
+    }
+    if flags & FHCRC != 0 {
+        data = data.get(2..)?;
+    }
+    Some(data)
+}
+
+/// Reads everything from a decompressor.
+fn read_to_end(mut read: impl FnMut(&mut [u8]) -> Result<usize, ()>) -> Result<Vec<u8>, ()> {
+    const CHUNK: usize = 64 * 1024;
+
+    let mut out = vec![];
+    let mut len = 0;
+    loop {
+        out.resize(len + CHUNK, 0);
+        let read = read(&mut out[len..])?;
+        if read == 0 {
+            out.truncate(len);
+            return Ok(out);
+        }
+        len += read;
+    }
+}
+
+/// An image loaded by the bootloader.
+struct LoadedImage {
+    data: &'static [u8],
+    /// The pages claimed from the page allocator, as the start and the
+    /// number of pages, given back once the image is unpacked.
+    pages: Option<(usize, usize)>,
+}
+
+impl Drop for LoadedImage {
+    fn drop(&mut self) {
+        if let Some((start, num_pages)) = self.pages {
+            global_allocator().dealloc_pages(start, num_pages, UsageKind::Global);
+        }
+    }
+}
+
+/// Returns the region of memory managed by the page allocator, which is the
+/// one the runtime picks: the first free region after `.bss`, or else the
+/// largest one.
+fn page_allocator_region() -> Option<PhysMemRegion> {
+    let mut largest: Option<PhysMemRegion> = None;
+    let mut after_bss = false;
+    for region in memory_regions() {
+        if region.name == ".bss" {
+            after_bss = true;
+        } else if region.flags.contains(MemRegionFlags::FREE) {
+            if after_bss {
+                return Some(region);
+            }
+            if largest.is_none_or(|it| region.size > it.size) {
+                largest = Some(region);
+            }
+        }
+    }
+    largest
+}
+
+/// Keeps the allocator from handing out the memory at `[start, end)`.
+///
+/// Returns the claimed pages, or `None` if the platform keeps that memory
+/// out of the allocator already. Fails if the memory cannot be claimed, as
+/// some of it may have been handed out and overwritten.
+fn reserve_image(start: usize, end: usize) -> Result<Option<(usize, usize)>, ()> {
+    let (start, end) = (align_down_4k(start), align_up_4k(end));
+    let overlaps = |region: &PhysMemRegion| {
+        let region_start = region.paddr.as_usize();
+        start < region_start + region.size && region_start < end
+    };
+    if !memory_regions().any(|it| it.flags.contains(MemRegionFlags::FREE) && overlaps(&it)) {
+        return Ok(None);
+    }
+    // Other free regions are given to the byte allocator, whose memory
+    // cannot be claimed.
+    let region = page_allocator_region().ok_or(())?;
+    let region_start = region.paddr.as_usize();
+    if start < region_start || end > region_start + region.size {
+        return Err(());
+    }
+    let num_pages = (end - start) / PAGE_SIZE_4K;
+    let start = phys_to_virt(start.into()).as_usize();
+    global_allocator()
+        .alloc_pages_at(start, num_pages, PAGE_SIZE_4K, UsageKind::Global)
+        .map(|start| Some((start, num_pages)))
+        .map_err(|_| ())
+}
+
+/// Returns the image loaded by the bootloader, if any.
+fn bootloader_image() -> Option<LoadedImage> {
+    let fdt = axhal::dtb::get_fdt()?;
+    let chosen = fdt.find_nodes("/chosen").next()?;
+    let read_addr = |name| {
+        let value = chosen.find_property(name)?.raw_value();
+        Some(match value.len() {
+            4 => u32::from_be_bytes(value.try_into().ok()?) as usize,
+            8 => u64::from_be_bytes(value.try_into().ok()?) as usize,
+            _ => return None,
+        })
+    };
+    let start = read_addr("linux,initrd-start")?;
+    let end = read_addr("linux,initrd-end")?;
+    if end <= start {
+        return None;
+    }
+    let Ok(pages) = reserve_image(start, end) else {
+        warn!("initramfs: the memory of the image at {start:#x} is in use, ignoring it");
+        return None;
+    };
+    // SAFETY: the bootloader placed the image there, and the memory is kept
+    // from the allocator.
+    let data =
+        unsafe { core::slice::from_raw_parts(phys_to_virt(start.into()).as_ptr(), end - start) };
+    Some(LoadedImage { data, pages })
+}
+
+/// Returns the program to run as init from the initramfs.
+fn rdinit() -> &'static str {
+    axhal::dtb::get_chosen_bootargs()
+        .and_then(|args| {
+            args.split_whitespace()
+                .find_map(|arg| arg.strip_prefix("rdinit="))
+        })
+        .unwrap_or(DEFAULT_INIT)
+}
+
+/// Sets up the root filesystem.
+///
+/// If there is an initramfs image, built into the kernel as `builtin` or
+/// loaded by the bootloader, it is unpacked into a tmpfs that becomes the
+/// root, and the path of the program to run as init is returned. Otherwise,
+/// or if that program is missing, the disk root is kept.
+pub fn mount_root(builtin: &[u8]) -> Option<String> {
+    let disk_root = FS_CONTEXT.lock().root_dir().clone();
+    let loaded = bootloader_image();
+    let images = [builtin, loaded.as_ref().map_or(&[], |it| it.data)];
+    if images.iter().all(|it| it.is_empty()) {
+        add_root_mount(&disk_root, "/dev/root");
+        return None;
+    }
+
+    info!("Unpacking initramfs...");
+    let mountpoint = Mountpoint::new_root(&MemoryFs::new());
+    let cx = FsContext::new(mountpoint.root_location());
+    let mut unpacker = Unpacker {
+        cx: &cx,
+        links: BTreeMap::new(),
+        dir_times: Vec::new(),
+    };
+    for image in images.into_iter().filter(|it| !it.is_empty()) {
+        if let Err(err) = unpacker.unpack(image) {
+            warn!("initramfs: unpacking failed: {err:?}");
+        }
+    }
+    unpacker.finish();
+    drop(loaded);
+
+    let init = rdinit();
+    if !cx.resolve(init).is_ok_and(|it| it.is_file()) {
+        warn!("initramfs: {init} not found, booting from the disk root");
+        add_root_mount(&disk_root, "/dev/root");
+        return None;
+    }
+
+    add_root_mount(cx.root_dir(), "rootfs");
+    let sysroot = cx
+        .resolve(SYSROOT)
+        .or_else(|_| cx.create_dir(SYSROOT, NodePermission::from_bits_truncate(0o755)));
+    if let Err(err) = sysroot.and_then(|target| {
+        mount_root_at(&target, &disk_root, "/dev/root", MountFlags::empty())?;
+        Ok(())
+    }) {
+        warn!("initramfs: cannot mount the disk root at {SYSROOT}: {err:?}");
+    }
+
+    *FS_CONTEXT.lock() = cx;
+    info!("Running {init} from initramfs");
+    Some(init.to_owned())
+}
@@ -0,0 +1,109 @@
+//! Mountable filesystems and the mount table.
+
+mod bind;
+mod ext4;
+mod fat;
+mod fuse;
+mod initramfs;
+mod mount;
+mod notify;
+mod overlay;
+mod sparse;
+mod squashfs;
+mod writeback;
+mod xattr;
+
+use alloc::sync::Arc;
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axfs::FsContext;
+use axfs_ng_vfs::{DeviceId, Location, NodePermission, NodeType, OpenOptions};
+
+use self::bind::bind_source;
+pub use self::{
+    bind::new_bind_fs,
+    ext4::Ext4Filesystem,
+    fat::{FatFilesystem, FatOptions},
+    fuse::{FuseDev, fuse_hold, fuse_open, fuse_put, new_fuse_fs},
+    initramfs::mount_root,
+    mount::*,
+    notify::*,
+    overlay::new_overlay_fs,
+    sparse::*,
+    squashfs::SquashfsFilesystem,
+    writeback::*,
+    xattr::*,
+};
+use crate::pseudofs::{DeviceOps, MemoryNode, find_device};
+
+/// Resolves `path` to a block device usable as the source of a filesystem.
+pub fn open_block_device(fs: &FsContext, path: &str) -> AxResult<Arc<dyn DeviceOps>> {
+    let loc = fs.resolve(path)?;
+    if loc.node_type() != NodeType::BlockDevice {
+        return Err(AxError::from(LinuxError::ENOTBLK));
+    }
+    check_device(&loc)?;
+    Ok(find_device(&loc)?.inner().clone())
+}
+
+/// Creates a regular file in `dir` without giving it a name, which tmpfs and
+/// the ext4 filesystems mounted by the kernel support.
+pub fn create_unnamed(dir: &Location, permission: NodePermission) -> AxResult<Location> {
+    let entry = if let Ok(node) = dir.entry().downcast::<MemoryNode>() {
+        node.create_unnamed(permission)?
+    } else if let Ok(node) = dir.entry().downcast::<ext4::Inode>() {
+        node.create_unnamed(permission)?
+    } else {
+        return Err(AxError::OperationNotSupported);
+    };
+    Ok(Location::new(dir.mountpoint().clone(), entry))
+}
+
+/// Returns whether the nodes created in `dir` can keep device numbers.
+///
+/// The filesystem is told apart by its nodes rather than by its name, as the
+/// ext4 root of the runtime is named ext4 as well.
+fn can_store_rdev(dir: &Location) -> bool {
+    let mut entry = dir.entry().clone();
+    while let Some(source) = bind_source(&entry) {
+        entry = source;
+    }
+    entry.downcast::<MemoryNode>().is_ok() || entry.downcast::<ext4::Inode>().is_ok()
+}
+
+/// Creates a node of `node_type` named `name` in `dir` for `mknod`, with the
+/// device number `rdev` if it is a device node.
+///
+/// Only tmpfs and the kernel's ext4 can keep device numbers; creating a
+/// device node anywhere else fails before the node is created.
+pub fn mknod(
+    dir: &Location,
+    name: &str,
+    node_type: NodeType,
+    permission: NodePermission,
+    user: (u32, u32),
+    rdev: DeviceId,
+) -> AxResult<Location> {
+    let device = matches!(node_type, NodeType::CharacterDevice | NodeType::BlockDevice);
+    if device && !can_store_rdev(dir) {
+        return Err(AxError::OperationNotPermitted);
+    }
+    let loc = dir.open_file(
+        name,
+        &OpenOptions {
+            create: true,
+            create_new: true,
+            node_type,
+            permission,
+            user: Some(user),
+        },
+    )?;
+    if device {
+        if let Ok(node) = loc.entry().downcast::<MemoryNode>() {
+            node.set_rdev(rdev);
+        } else if let Ok(inode) = loc.entry().downcast::<ext4::Inode>() {
+            inode.set_rdev(rdev)?;
+        }
+    }
+    Ok(loc)
+}
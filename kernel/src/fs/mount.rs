@@ -0,0 +1,593 @@
+//! Mount table and per-mount flags.
+
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::{
+    fmt::Write,
+    iter,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+};
+
+use axerrno::{AxError, AxResult};
+use axfs::{FS_CONTEXT, FsContext};
+use axfs_ng_vfs::{
+    DirEntry, Filesystem, FilesystemOps, Location, Mountpoint, NodeType, StatFs, VfsResult,
+    path::{Path, PathBuf},
+};
+use axsync::Mutex;
+use bitflags::bitflags;
+use linux_raw_sys::general::{
+    MNT_DETACH, MNT_EXPIRE, MNT_FORCE, MS_NODEV, MS_NOEXEC, MS_NOSUID, MS_RDONLY, UMOUNT_NOFOLLOW,
+};
+
+use super::{fuse::abort_fuse, new_bind_fs};
+use crate::{mm::clear_elf_cache, task::processes};
+
+bitflags! {
+    /// Flags of a single mount, enforced by the VFS layer.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct MountFlags: u32 {
+        /// Mount read-only.
+        const RDONLY = MS_RDONLY;
+        /// Ignore set-user-ID and set-group-ID bits.
+        const NOSUID = MS_NOSUID;
+        /// Disallow access to device special files.
+        const NODEV = MS_NODEV;
+        /// Disallow program execution.
+        const NOEXEC = MS_NOEXEC;
+    }
+}
+
+bitflags! {
+    /// Flags for `umount2`.
+    #[derive(Debug, Clone, Copy)]
+    pub struct UnmountFlags: u32 {
+        /// Unmount even if busy.
+        const FORCE = MNT_FORCE;
+        /// Lazily detach the mount and everything below it.
+        const DETACH = MNT_DETACH;
+        /// Mark the mount as expired.
+        const EXPIRE = MNT_EXPIRE;
+        /// Don't dereference the target if it is a symlink.
+        const NOFOLLOW = UMOUNT_NOFOLLOW;
+    }
+}
+
+impl MountFlags {
+    /// Formats the flags as the mount options shown in `/proc/mounts`.
+    fn options(&self) -> String {
+        let mut options = String::from(if self.contains(Self::RDONLY) {
+            "ro"
+        } else {
+            "rw"
+        });
+        for (flag, name) in [
+            (Self::NOSUID, ",nosuid"),
+            (Self::NODEV, ",nodev"),
+            (Self::NOEXEC, ",noexec"),
+        ] {
+            if self.contains(flag) {
+                options.push_str(name);
+            }
+        }
+        options.push_str(",relatime");
+        options
+    }
+}
+
+/// A mounted filesystem.
+pub struct Mount {
+    id: u32,
+    mountpoint: Arc<Mountpoint>,
+    /// The source shown in the mount table, e.g. the path of the device.
+    source: String,
+    fs_type: String,
+    /// The path of the mount root within its filesystem, which is not `/`
+    /// only for bind mounts.
+    root: PathBuf,
+    flags: Mutex<MountFlags>,
+    /// Number of files open on the mount, see [`MountUse`]. Shared with the
+    /// entry the mount gets when moved.
+    open_files: Arc<AtomicUsize>,
+}
+
+impl Mount {
+    /// Returns the mountpoint.
+    pub fn mountpoint(&self) -> &Arc<Mountpoint> {
+        &self.mountpoint
+    }
+
+    /// Returns the flags of the mount.
+    pub fn flags(&self) -> MountFlags {
+        *self.flags.lock()
+    }
+
+    /// Returns whether this mount lives (directly or indirectly) below
+    /// `ancestor`.
+    fn is_below(&self, ancestor: &Arc<Mountpoint>) -> bool {
+        let mut loc = self.mountpoint.location();
+        while let Some(cur) = loc {
+            if Arc::ptr_eq(cur.mountpoint(), ancestor) {
+                return true;
+            }
+            loc = cur.mountpoint().location();
+        }
+        false
+    }
+}
+
+/// All mounts, in the order they are created.
+static MOUNTS: Mutex<Vec<Arc<Mount>>> = Mutex::new(Vec::new());
+
+fn add_mount(
+    mountpoint: Arc<Mountpoint>,
+    source: &str,
+    fs_type: &str,
+    root: PathBuf,
+    flags: MountFlags,
+) -> Arc<Mount> {
+    static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+    let mount = Arc::new(Mount {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        mountpoint,
+        source: source.to_string(),
+        fs_type: fs_type.to_string(),
+        root,
+        flags: Mutex::new(flags),
+        open_files: Arc::default(),
+    });
+    MOUNTS.lock().push(mount.clone());
+    mount
+}
+
+/// Records the root filesystem, which is mounted by `axfs`, in the mount
+/// table.
+pub fn add_root_mount(root: &Location, source: &str) {
+    let fs_type = root.filesystem().name().to_string();
+    add_mount(
+        root.mountpoint().clone(),
+        source,
+        &fs_type,
+        PathBuf::from("/"),
+        MountFlags::empty(),
+    );
+}
+
+/// Mounts `fs` at `target` and records it in the mount table.
+pub fn mount_at(
+    target: &Location,
+    fs: &Filesystem,
+    source: &str,
+    flags: MountFlags,
+) -> AxResult<Arc<Mount>> {
+    let mountpoint = target.mount(fs)?;
+    Ok(add_mount(
+        mountpoint,
+        source,
+        fs.name(),
+        PathBuf::from("/"),
+        flags,
+    ))
+}
+
+/// Bind mounts the directory `source` at `target` and records it in the mount
+/// table.
+pub fn bind_at(target: &Location, source: &Location, flags: MountFlags) -> AxResult<Arc<Mount>> {
+    let (source_name, fs_type, root) = match find_mount(source.mountpoint()) {
+        Some(mount) => (
+            mount.source.clone(),
+            mount.fs_type.clone(),
+            mount.root.join(
+                source
+                    .entry()
+                    .absolute_path()?
+                    .as_str()
+                    .trim_start_matches('/'),
+            ),
+        ),
+        None => (
+            String::from("none"),
+            source.filesystem().name().to_string(),
+            source.entry().absolute_path()?,
+        ),
+    };
+    let mountpoint = target.mount(&new_bind_fs(source.clone())?)?;
+    Ok(add_mount(mountpoint, &source_name, &fs_type, root, flags))
+}
+
+/// A filesystem whose root is the root of an existing mount, so that the
+/// mounted tree can be attached at another place as it is.
+struct MountedTree {
+    root: DirEntry,
+}
+
+impl FilesystemOps for MountedTree {
+    fn name(&self) -> &str {
+        self.root.filesystem().name()
+    }
+
+    fn root_dir(&self) -> DirEntry {
+        self.root.clone()
+    }
+
+    fn stat(&self) -> VfsResult<StatFs> {
+        self.root.filesystem().stat()
+    }
+
+    fn flush(&self) -> VfsResult<()> {
+        self.root.filesystem().flush()
+    }
+}
+
+/// Attaches the tree whose root is `root` at `target`.
+fn attach(target: &Location, root: &DirEntry) -> AxResult<Arc<Mountpoint>> {
+    let fs = Filesystem::new(Arc::new(MountedTree { root: root.clone() }));
+    target.mount(&fs)
+}
+
+/// Mounts the tree of the mount whose root is `root` also at `target` and
+/// records it in the mount table.
+///
+/// Unlike a bind mount, the directories are not wrapped, which is meant for
+/// trees that are not reachable otherwise, like the disk root below an
+/// initramfs.
+pub fn mount_root_at(
+    target: &Location,
+    root: &Location,
+    source: &str,
+    flags: MountFlags,
+) -> AxResult<Arc<Mount>> {
+    if !root.is_root_of_mount() {
+        return Err(AxError::InvalidInput);
+    }
+    let mountpoint = attach(target, root.entry())?;
+    Ok(add_mount(
+        mountpoint,
+        source,
+        root.filesystem().name(),
+        PathBuf::from("/"),
+        flags,
+    ))
+}
+
+/// Moves the mount whose root is `source`, along with all mounts below it,
+/// to `target`.
+///
+/// Root and working directories of processes inside the moved mounts follow
+/// them, so that `switch_root` can `chroot(".")` after moving its working
+/// directory to `/`. Open files keep referring to the old places.
+pub fn move_mount(source: &Location, target: &Location) -> AxResult<()> {
+    if !source.is_root_of_mount() || source.mountpoint().is_root() {
+        return Err(AxError::InvalidInput);
+    }
+    target.check_is_dir()?;
+    if target.is_mountpoint() {
+        return Err(AxError::ResourceBusy);
+    }
+    let mut loc = Some(target.clone());
+    while let Some(cur) = loc {
+        if Arc::ptr_eq(cur.mountpoint(), source.mountpoint()) {
+            return Err(AxError::InvalidInput);
+        }
+        loc = cur.mountpoint().location();
+    }
+
+    let children = submounts(source)?;
+    source.unmount_all()?;
+    let mountpoint = attach(target, source.entry())?;
+    let root = FsContext::new(mountpoint.root_location());
+    let mut moved = vec![(source.mountpoint().clone(), mountpoint)];
+    for (path, child) in children {
+        // Mounts stacked on the same place come in order, so each one lands
+        // on the one before.
+        let old = child.mountpoint().clone();
+        let new = attach(&root.resolve(&path)?, old.root_location().entry())?;
+        moved.push((old, new));
+    }
+
+    let mut mounts = MOUNTS.lock();
+    for mount in mounts.iter_mut() {
+        if let Some((_, new)) = moved
+            .iter()
+            .find(|(old, _)| Arc::ptr_eq(old, &mount.mountpoint))
+        {
+            *mount = Arc::new(Mount {
+                id: mount.id,
+                mountpoint: new.clone(),
+                source: mount.source.clone(),
+                fs_type: mount.fs_type.clone(),
+                root: PathBuf::from(mount.root.as_str()),
+                flags: Mutex::new(mount.flags()),
+                open_files: mount.open_files.clone(),
+            });
+        }
+    }
+    drop(mounts);
+
+    let relocate = |loc: &Location| {
+        moved
+            .iter()
+            .find(|(old, _)| Arc::ptr_eq(old, loc.mountpoint()))
+            .map(|(_, new)| Location::new(new.clone(), loc.entry().clone()))
+    };
+    for proc_data in processes() {
+        let fs = FS_CONTEXT.scope(&proc_data.scope.read()).clone();
+        let mut fs = fs.lock();
+        let cwd = relocate(fs.current_dir()).unwrap_or_else(|| fs.current_dir().clone());
+        if let Some(root) = relocate(fs.root_dir()) {
+            *fs = FsContext::new(root);
+        }
+        fs.set_current_dir(cwd)?;
+    }
+    Ok(())
+}
+
+/// Returns the mount table entry for `mountpoint`.
+pub fn find_mount(mountpoint: &Arc<Mountpoint>) -> Option<Arc<Mount>> {
+    MOUNTS
+        .lock()
+        .iter()
+        .find(|it| Arc::ptr_eq(&it.mountpoint, mountpoint))
+        .cloned()
+}
+
+/// Returns `path` as seen from `base`, or `None` if it isn't inside `base`.
+fn relative_path(path: &Path, base: &Path) -> Option<PathBuf> {
+    let mut components = path.components();
+    if !base.components().all(|it| components.next() == Some(it)) {
+        return None;
+    }
+    Some(
+        iter::once("/")
+            .chain(components.map(|it| it.as_str()))
+            .collect(),
+    )
+}
+
+/// Returns all mounts strictly below `loc` in the order they are created,
+/// along with the paths of their mountpoints relative to `loc`.
+pub fn submounts(loc: &Location) -> AxResult<Vec<(PathBuf, Arc<Mount>)>> {
+    let base = loc.absolute_path()?;
+    let mounts = MOUNTS.lock().clone();
+    let mut result = Vec::new();
+    for mount in mounts {
+        let Some(at) = mount.mountpoint.location() else {
+            continue;
+        };
+        if let Some(relative) = relative_path(&at.absolute_path()?, &base)
+            && relative.as_str() != "/"
+        {
+            result.push((relative, mount));
+        }
+    }
+    Ok(result)
+}
+
+/// Counts an open file towards the mount it is on, which can't be unmounted
+/// without `MNT_DETACH` or `MNT_FORCE` while there are any.
+pub struct MountUse(Option<Arc<AtomicUsize>>);
+
+impl MountUse {
+    /// Counts a file open at `loc`.
+    pub fn new(loc: &Location) -> Self {
+        let open_files = find_mount(loc.mountpoint()).map(|it| it.open_files.clone());
+        if let Some(open_files) = &open_files {
+            open_files.fetch_add(1, Ordering::Relaxed);
+        }
+        Self(open_files)
+    }
+}
+
+impl Drop for MountUse {
+    fn drop(&mut self) {
+        if let Some(open_files) = &self.0 {
+            open_files.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Returns whether a file is open on `mount`, or some process has its
+/// working or root directory on it.
+fn is_busy(mount: &Mount) -> bool {
+    if mount.open_files.load(Ordering::Relaxed) > 0 {
+        return true;
+    }
+    processes().iter().any(|proc_data| {
+        let fs = FS_CONTEXT.scope(&proc_data.scope.read()).clone();
+        let fs = fs.lock();
+        [fs.current_dir(), fs.root_dir()]
+            .iter()
+            .any(|it| Arc::ptr_eq(it.mountpoint(), &mount.mountpoint))
+    })
+}
+
+/// Changes the flags of the mount whose root is `loc`.
+pub fn remount(loc: &Location, flags: MountFlags) -> AxResult<()> {
+    if !loc.is_root_of_mount() {
+        return Err(AxError::InvalidInput);
+    }
+    let mount = find_mount(loc.mountpoint()).ok_or(AxError::InvalidInput)?;
+    *mount.flags.lock() = flags;
+    Ok(())
+}
+
+/// Unmounts the mount whose root is `loc`.
+pub fn unmount(loc: Location, flags: UnmountFlags) -> AxResult<()> {
+    if !loc.is_root_of_mount() {
+        return Err(AxError::InvalidInput);
+    }
+    let mountpoint = loc.mountpoint().clone();
+    if mountpoint.is_root() {
+        return Err(AxError::ResourceBusy);
+    }
+    if flags.contains(UnmountFlags::EXPIRE)
+        && flags.intersects(UnmountFlags::FORCE | UnmountFlags::DETACH)
+    {
+        return Err(AxError::InvalidInput);
+    }
+
+    let mut mounts = MOUNTS.lock();
+    if !flags.contains(UnmountFlags::DETACH) {
+        if mounts.iter().any(|it| it.is_below(&mountpoint)) {
+            return Err(AxError::ResourceBusy);
+        }
+        if !flags.contains(UnmountFlags::FORCE)
+            && mounts
+                .iter()
+                .find(|it| Arc::ptr_eq(&it.mountpoint, &mountpoint))
+                .is_some_and(|it| is_busy(it))
+        {
+            return Err(AxError::ResourceBusy);
+        }
+    }
+
+    loc.unmount_all()?;
+    // Cached executables would keep the filesystem alive.
+    clear_elf_cache();
+    abort_fuse(&loc);
+    if let Err(err) = loc.filesystem().flush() {
+        warn!("Failed to flush {}: {err:?}", loc.filesystem().name());
+    }
+    mounts.retain(|it| !Arc::ptr_eq(&it.mountpoint, &mountpoint) && !it.is_below(&mountpoint));
+    Ok(())
+}
+
+/// Flushes the filesystems of all mounts.
+pub fn flush_filesystems() -> AxResult<()> {
+    let mounts = MOUNTS.lock().clone();
+    let mut result = Ok(());
+    for mount in mounts {
+        if let Err(err) = mount.mountpoint.root_location().filesystem().flush() {
+            warn!("Failed to flush {}: {err:?}", mount.source);
+            result = Err(err);
+        }
+    }
+    result
+}
+
+/// Returns the flags of the mount containing `loc`.
+pub fn mount_flags(loc: &Location) -> MountFlags {
+    find_mount(loc.mountpoint()).map_or(MountFlags::empty(), |it| it.flags())
+}
+
+/// Checks whether `loc` may be modified.
+pub fn check_write(loc: &Location) -> AxResult<()> {
+    if mount_flags(loc).contains(MountFlags::RDONLY) {
+        return Err(AxError::ReadOnlyFilesystem);
+    }
+    Ok(())
+}
+
+/// Checks whether the directory that would contain `path` may be modified.
+///
+/// Resolution errors are ignored here and left for the actual operation to
+/// report.
+pub fn check_parent_write(fs: &FsContext, path: &str) -> AxResult<()> {
+    if let Ok((parent, _)) = fs.resolve_parent(Path::new(path)) {
+        check_write(&parent)?;
+    }
+    Ok(())
+}
+
+/// Checks whether `loc` may be executed.
+pub fn check_exec(loc: &Location) -> AxResult<()> {
+    if mount_flags(loc).contains(MountFlags::NOEXEC) {
+        return Err(AxError::PermissionDenied);
+    }
+    Ok(())
+}
+
+/// Checks whether `loc` may be opened, which is denied for device files on
+/// `nodev` mounts.
+pub fn check_device(loc: &Location) -> AxResult<()> {
+    if matches!(
+        loc.node_type(),
+        NodeType::CharacterDevice | NodeType::BlockDevice
+    ) && mount_flags(loc).contains(MountFlags::NODEV)
+    {
+        return Err(AxError::PermissionDenied);
+    }
+    Ok(())
+}
+
+/// Escapes the characters that would break the space-separated format of the
+/// mount table, the same way as Linux does.
+fn escape(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            ' ' | '\t' | '\n' | '\\' => {
+                let _ = write!(result, "\\{:03o}", c as u32);
+            }
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// Returns the mounts visible from `root` along with their mount points as
+/// seen from `root`.
+fn visible_mounts(root: &Location) -> Vec<(String, Arc<Mount>)> {
+    let Ok(base) = root.absolute_path() else {
+        return Vec::new();
+    };
+    MOUNTS
+        .lock()
+        .iter()
+        .filter_map(|mount| {
+            let path = mount.mountpoint.root_location().absolute_path().ok()?;
+            let path = relative_path(&path, &base)?;
+            Some((escape(path.as_str()), mount.clone()))
+        })
+        .collect()
+}
+
+/// Generates the content of `/proc/mounts` for a process whose root
+/// directory is `root`.
+pub fn format_mounts(root: &Location) -> String {
+    let mut result = String::new();
+    for (path, mount) in visible_mounts(root) {
+        let _ = writeln!(
+            result,
+            "{} {path} {} {} 0 0",
+            escape(&mount.source),
+            mount.fs_type,
+            mount.flags().options(),
+        );
+    }
+    result
+}
+
+/// Generates the content of `/proc/[pid]/mountinfo` for a process whose root
+/// directory is `root`.
+pub fn format_mountinfo(root: &Location) -> String {
+    let mounts = visible_mounts(root);
+    let mut result = String::new();
+    for (path, mount) in &mounts {
+        let parent_id = mount
+            .mountpoint
+            .location()
+            .and_then(|it| find_mount(it.mountpoint()))
+            .map_or(mount.id, |it| it.id);
+        // Same encoding as `st_dev` from `stat`
+        let device = mount.mountpoint.device();
+        let major = (device >> 8) & 0xfff;
+        let minor = (device & 0xff) | ((device >> 12) & !0xff);
+        // Mount flags are per mount, so the superblock is always writable
+        let _ = writeln!(
+            result,
+            "{} {parent_id} {major}:{minor} {} {path} {} - {} {} rw",
+            mount.id,
+            escape(mount.root.as_str()),
+            mount.flags().options(),
+            mount.fs_type,
+            escape(&mount.source),
+        );
+    }
+    result
+}
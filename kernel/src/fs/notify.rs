@@ -0,0 +1,149 @@
+//! Filesystem event notifications.
+//!
+//! The syscalls that change the filesystem report what they did here, which
+//! then forwards the events to the notification mechanisms (e.g. inotify).
+
+use alloc::collections::btree_map::BTreeMap;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use axfs_ng_vfs::Location;
+use kspin::SpinNoIrq;
+use linux_raw_sys::general::{
+    IN_ACCESS, IN_ATTRIB, IN_CLOSE_NOWRITE, IN_CLOSE_WRITE, IN_CREATE, IN_DELETE, IN_DELETE_SELF,
+    IN_ISDIR, IN_MODIFY, IN_MOVE_SELF, IN_MOVED_FROM, IN_MOVED_TO, IN_OPEN,
+};
+
+use crate::file::{
+    dnotify::report_dnotify,
+    inotify::{InodeKey, report_event},
+};
+
+/// Number of open files of each inode, and whether its last link is gone.
+///
+/// An inode without links lives on until its last open file is closed, which
+/// is only when `IN_DELETE_SELF` is reported.
+static OPEN_INODES: SpinNoIrq<BTreeMap<InodeKey, (usize, bool)>> = SpinNoIrq::new(BTreeMap::new());
+
+/// Reports an event on `loc` to watchers of `loc` and of its parent.
+fn notify_inode(loc: &Location, mask: u32) {
+    report_event(loc, mask, 0, None);
+    if let Some(parent) = loc.parent() {
+        notify_entry(&parent, loc, mask, 0);
+    }
+}
+
+/// Reports an event on the entry `child` to watchers of the directory `dir`.
+fn notify_entry(dir: &Location, child: &Location, mut mask: u32, cookie: u32) {
+    if child.is_dir() {
+        mask |= IN_ISDIR;
+    }
+    report_event(dir, mask, cookie, Some(child.name()));
+    report_dnotify(dir, mask);
+}
+
+/// Reports that `loc` has been read from.
+pub fn notify_access(loc: &Location) {
+    notify_inode(loc, IN_ACCESS);
+}
+
+/// Reports that the content of `loc` has been modified.
+pub fn notify_modify(loc: &Location) {
+    notify_inode(loc, IN_MODIFY);
+}
+
+/// Reports that the metadata of `loc` has been changed.
+pub fn notify_attrib(loc: &Location) {
+    notify_inode(loc, IN_ATTRIB);
+}
+
+/// Reports that `loc` has been opened.
+pub fn notify_open(loc: &Location) {
+    notify_inode(loc, IN_OPEN);
+}
+
+/// Reports that a file description of `loc` has been closed.
+pub fn notify_close(loc: &Location, write: bool) {
+    notify_inode(
+        loc,
+        if write {
+            IN_CLOSE_WRITE
+        } else {
+            IN_CLOSE_NOWRITE
+        },
+    );
+}
+
+/// Reports that `loc` has been created.
+pub fn notify_create(loc: &Location) {
+    // An unlinked inode may be linked again, e.g. an `O_TMPFILE` one.
+    if let Some((_, unlinked)) = OPEN_INODES.lock().get_mut(&InodeKey::of(loc)) {
+        *unlinked = false;
+    }
+    if let Some(parent) = loc.parent() {
+        notify_entry(&parent, loc, IN_CREATE, 0);
+    }
+}
+
+/// Reports that `loc` has been unlinked from its parent.
+///
+/// `last_link` tells whether that was the last link to it. The inode itself
+/// is gone once it is not open anymore either, see [`notify_put`].
+pub fn notify_delete(loc: &Location, last_link: bool) {
+    report_event(loc, IN_ATTRIB, 0, None);
+    if let Some(parent) = loc.parent() {
+        notify_entry(&parent, loc, IN_DELETE, 0);
+    }
+    if last_link {
+        let mut open = OPEN_INODES.lock();
+        if let Some((_, unlinked)) = open.get_mut(&InodeKey::of(loc)) {
+            *unlinked = true;
+        } else {
+            drop(open);
+            report_event(loc, IN_DELETE_SELF, 0, None);
+        }
+    }
+}
+
+/// Records that a file of `loc` has been opened, which keeps its inode from
+/// being reported as deleted.
+pub fn notify_hold(loc: &Location) {
+    OPEN_INODES
+        .lock()
+        .entry(InodeKey::of(loc))
+        .or_insert((0, false))
+        .0 += 1;
+}
+
+/// Undoes [`notify_hold`], reporting that the inode of `loc` is gone if this
+/// was its last open file and it has no link left.
+pub fn notify_put(loc: &Location) {
+    let key = InodeKey::of(loc);
+    let mut open = OPEN_INODES.lock();
+    let Some((count, unlinked)) = open.get_mut(&key) else {
+        return;
+    };
+    *count -= 1;
+    if *count > 0 {
+        return;
+    }
+    let unlinked = *unlinked;
+    open.remove(&key);
+    drop(open);
+    if unlinked {
+        report_event(loc, IN_DELETE_SELF, 0, None);
+    }
+}
+
+/// Reports that the entry `from` has been renamed to `to`.
+pub fn notify_move(from: &Location, to: &Location) {
+    static NEXT_COOKIE: AtomicU32 = AtomicU32::new(1);
+
+    let cookie = NEXT_COOKIE.fetch_add(1, Ordering::Relaxed);
+    if let Some(parent) = from.parent() {
+        notify_entry(&parent, from, IN_MOVED_FROM, cookie);
+    }
+    if let Some(parent) = to.parent() {
+        notify_entry(&parent, to, IN_MOVED_TO, cookie);
+    }
+    report_event(to, IN_MOVE_SELF, 0, None);
+}
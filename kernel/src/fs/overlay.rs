@@ -0,0 +1,1005 @@
+//! Overlay filesystems.
+//!
+//! An overlay merges a writable upper directory tree over read-only lower
+//! ones, given topmost first. Names are looked up in the upper tree first,
+//! then in the lower trees in order, and the directories of the same name in
+//! all layers are merged. Modifying a file of a lower layer first copies it
+//! up to the upper layer, together with the directories leading to it. Nodes
+//! are copied into the work directory and then renamed into place, so that a
+//! copy-up that fails halfway leaves nothing behind in the upper layer.
+//!
+//! As with Linux, removing a name that exists in a lower layer leaves a
+//! whiteout (a character device with device number 0) in its place in the
+//! upper layer, and a directory that replaces a lower one is marked opaque
+//! with the `trusted.overlay.opaque` attribute, so that the lower directories
+//! of the same name are not merged into it.
+
+use alloc::{
+    borrow::ToOwned,
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::{
+    any::Any,
+    sync::atomic::{AtomicU64, Ordering},
+    task::Context,
+};
+
+use axerrno::{AxError, AxResult};
+use axfs::{File, FsContext, OpenOptions};
+use axfs_ng_vfs::{
+    DeviceId, DirEntry, DirEntrySink, DirNode, DirNodeOps, FileNode, FileNodeOps, Filesystem,
+    FilesystemOps, Location, Metadata, MetadataUpdate, NodeOps, NodePermission, NodeType,
+    Reference, StatFs, VfsError, VfsResult, WeakDirEntry,
+};
+use axpoll::{IoEvents, Pollable};
+use axsync::Mutex;
+use hashbrown::HashSet;
+use linux_raw_sys::general::OVERLAYFS_SUPER_MAGIC;
+use memory_addr::PAGE_SIZE_4K;
+
+use super::{XattrFlags, XattrOps, mknod, xattr::xattr_ops};
+
+/// The attribute marking a directory as opaque.
+const OPAQUE_XATTR: &str = "trusted.overlay.opaque";
+/// The prefix of the attributes used by the overlay itself, which are not
+/// copied up.
+const OVERLAY_XATTR_PREFIX: &str = "trusted.overlay.";
+
+/// An entry of a directory in one of the layers, or of a merged directory.
+struct LayerEntry {
+    name: String,
+    ino: u64,
+    node_type: NodeType,
+}
+
+/// Looks up `name` in the layer directory `dir`, without crossing into
+/// filesystems mounted below it.
+fn layer_lookup(dir: &Location, name: &str) -> VfsResult<Option<Location>> {
+    match dir.entry().as_dir()?.lookup(name) {
+        Ok(entry) => Ok(Some(Location::new(dir.mountpoint().clone(), entry))),
+        Err(VfsError::NotFound) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Lists the entries of the layer directory `dir`, except `.` and `..`.
+fn read_layer(dir: &Location) -> VfsResult<Vec<LayerEntry>> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    loop {
+        let read = dir.read_dir(offset, &mut |name: &str, ino, node_type, next| {
+            offset = next;
+            if name != "." && name != ".." {
+                entries.push(LayerEntry {
+                    name: name.to_owned(),
+                    ino,
+                    node_type,
+                });
+            }
+            true
+        })?;
+        if read == 0 {
+            break;
+        }
+    }
+    Ok(entries)
+}
+
+fn is_whiteout(loc: &Location) -> bool {
+    loc.node_type() == NodeType::CharacterDevice && loc.metadata().is_ok_and(|it| it.rdev.0 == 0)
+}
+
+fn create_whiteout(dir: &Location, name: &str) -> VfsResult<()> {
+    mknod(
+        dir,
+        name,
+        NodeType::CharacterDevice,
+        NodePermission::empty(),
+        (0, 0),
+        DeviceId(0),
+    )
+    .map(drop)
+}
+
+/// Removes the whiteouts in the upper directory `dir`, which is about to be
+/// removed or replaced.
+fn clear_whiteouts(dir: &Location) -> VfsResult<()> {
+    for entry in read_layer(dir)? {
+        if let Some(loc) = layer_lookup(dir, &entry.name)?
+            && is_whiteout(&loc)
+        {
+            dir.unlink(&entry.name, false)?;
+        }
+    }
+    Ok(())
+}
+
+fn is_opaque(loc: &Location) -> bool {
+    loc.is_dir()
+        && xattr_ops(loc)
+            .and_then(|ops| ops.get_xattr(OPAQUE_XATTR))
+            .is_ok_and(|value| value == b"y")
+}
+
+fn set_opaque(loc: &Location) -> VfsResult<()> {
+    xattr_ops(loc)?.set_xattr(OPAQUE_XATTR, b"y", XattrFlags::empty())
+}
+
+/// Opens the regular file `loc` of a layer, going through its page cache.
+fn open_layer(loc: &Location, write: bool) -> VfsResult<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(write)
+        .open_loc(loc.clone())?
+        .into_file()
+}
+
+/// Copies the owner, mode, times and extended attributes of `src` to `dst`.
+fn copy_attrs(src: &Location, dst: &Location) -> VfsResult<()> {
+    let metadata = src.metadata()?;
+    dst.update_metadata(MetadataUpdate {
+        mode: Some(metadata.mode),
+        owner: Some((metadata.uid, metadata.gid)),
+        atime: Some(metadata.atime),
+        mtime: Some(metadata.mtime),
+    })?;
+    if let (Ok(src), Ok(dst)) = (xattr_ops(src), xattr_ops(dst)) {
+        for name in src.list_xattr()? {
+            if !name.starts_with(OVERLAY_XATTR_PREFIX) {
+                dst.set_xattr(&name, &src.get_xattr(&name)?, XattrFlags::empty())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn copy_contents(src: &Location, dst: &Location, metadata: &Metadata) -> VfsResult<()> {
+    match metadata.node_type {
+        NodeType::RegularFile => {
+            let (src, dst) = (open_layer(src, false)?, open_layer(dst, true)?);
+            let mut buf = vec![0; PAGE_SIZE_4K];
+            let mut offset = 0;
+            while offset < metadata.size {
+                let read = src.read_at(&mut buf[..], offset)?;
+                if read == 0 {
+                    break;
+                }
+                dst.write_at(&buf[..read], offset)?;
+                offset += read as u64;
+            }
+        }
+        NodeType::Symlink => dst.entry().as_file()?.set_symlink(&src.read_link()?)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Copies the non-directory `src` to `name` in the upper directory `dir`.
+fn copy_node(src: &Location, dir: &Location, name: &str) -> VfsResult<Location> {
+    let metadata = src.metadata()?;
+    let loc = match metadata.node_type {
+        node_type @ (NodeType::RegularFile | NodeType::Symlink) => {
+            dir.create(name, node_type, metadata.mode)?
+        }
+        node_type => mknod(
+            dir,
+            name,
+            node_type,
+            metadata.mode,
+            (metadata.uid, metadata.gid),
+            metadata.rdev,
+        )?,
+    };
+    if let Err(err) = copy_contents(src, &loc, &metadata).and_then(|_| copy_attrs(src, &loc)) {
+        let _ = dir.unlink(name, false);
+        return Err(err);
+    }
+    Ok(loc)
+}
+
+/// Fails for the attributes used by the overlay itself, which are hidden
+/// from the users of the overlay.
+fn check_xattr_name(name: &str) -> AxResult {
+    if name.starts_with(OVERLAY_XATTR_PREFIX) {
+        return Err(AxError::OperationNotSupported);
+    }
+    Ok(())
+}
+
+/// Extended attributes of an overlay node, whose topmost node is returned by
+/// `top`. They are modified on the node in the upper layer, returned by
+/// `copy_up`.
+struct OverlayXattrs<T, C> {
+    top: T,
+    copy_up: C,
+}
+
+impl<T, C> OverlayXattrs<T, C>
+where
+    T: Fn() -> VfsResult<Location>,
+    C: Fn() -> VfsResult<Location>,
+{
+    fn get(&self, name: &str) -> AxResult<Vec<u8>> {
+        check_xattr_name(name)?;
+        xattr_ops(&(self.top)()?)?.get_xattr(name)
+    }
+
+    fn set(&self, name: &str, value: &[u8], flags: XattrFlags) -> AxResult {
+        check_xattr_name(name)?;
+        xattr_ops(&(self.copy_up)()?)?.set_xattr(name, value, flags)
+    }
+
+    fn remove(&self, name: &str) -> AxResult {
+        // Removing a missing attribute does not copy the node up.
+        self.get(name)?;
+        xattr_ops(&(self.copy_up)()?)?.remove_xattr(name)
+    }
+
+    fn list(&self) -> AxResult<Vec<String>> {
+        let mut names = xattr_ops(&(self.top)()?)?.list_xattr()?;
+        names.retain(|name| !name.starts_with(OVERLAY_XATTR_PREFIX));
+        Ok(names)
+    }
+}
+
+/// An overlay filesystem.
+struct OverlayFs {
+    /// The upper layer, absent for a read-only overlay.
+    upper: Option<Location>,
+    lowers: Vec<Location>,
+    /// The `work` directory in the work directory.
+    work: Option<Location>,
+    /// Counter for the names of files in the work directory.
+    next_temp: AtomicU64,
+    root: Mutex<Option<DirEntry>>,
+}
+
+impl OverlayFs {
+    /// Returns the work directory, which only writable overlays have.
+    fn work(&self) -> VfsResult<&Location> {
+        self.work.as_ref().ok_or(VfsError::ReadOnlyFilesystem)
+    }
+
+    /// Returns a new name for a node in the work directory.
+    fn temp_name(&self) -> String {
+        format!("#{:x}", self.next_temp.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl FilesystemOps for OverlayFs {
+    fn name(&self) -> &str {
+        "overlay"
+    }
+
+    fn root_dir(&self) -> DirEntry {
+        self.root.lock().clone().unwrap()
+    }
+
+    fn stat(&self) -> VfsResult<StatFs> {
+        let layer = self.upper.as_ref().unwrap_or(&self.lowers[0]);
+        Ok(StatFs {
+            fs_type: OVERLAYFS_SUPER_MAGIC,
+            ..layer.filesystem().stat()?
+        })
+    }
+
+    fn flush(&self) -> VfsResult<()> {
+        match &self.upper {
+            Some(upper) => upper.filesystem().flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A directory of an overlay, merging the directories of the same name in
+/// all layers.
+struct OverlayDir {
+    fs: Arc<OverlayFs>,
+    this: WeakDirEntry,
+    ino: u64,
+    /// The directory in the upper layer, created when first needed.
+    upper: Mutex<Option<Location>>,
+    /// The directories in the lower layers, topmost first, down to the first
+    /// opaque one.
+    lowers: Vec<Location>,
+    /// The merged entries, listed again whenever reading starts over.
+    entries: Mutex<Option<Arc<Vec<LayerEntry>>>>,
+}
+
+impl OverlayDir {
+    fn new_entry(
+        fs: Arc<OverlayFs>,
+        upper: Option<Location>,
+        lowers: Vec<Location>,
+        reference: Reference,
+    ) -> DirEntry {
+        let ino = upper.as_ref().unwrap_or(&lowers[0]).inode();
+        DirEntry::new_dir(
+            |this| {
+                DirNode::new(Arc::new(Self {
+                    fs,
+                    this,
+                    ino,
+                    upper: Mutex::new(upper),
+                    lowers,
+                    entries: Mutex::new(None),
+                }))
+            },
+            reference,
+        )
+    }
+
+    fn upper(&self) -> Option<Location> {
+        self.upper.lock().clone()
+    }
+
+    /// Returns the topmost of the merged directories.
+    fn top(&self) -> Location {
+        self.upper().unwrap_or_else(|| self.lowers[0].clone())
+    }
+
+    /// Returns the directory in the upper layer, copying this directory and
+    /// its ancestors up first if needed.
+    fn copy_up(&self) -> VfsResult<Location> {
+        let mut upper = self.upper.lock();
+        if let Some(upper) = upper.as_ref() {
+            return Ok(upper.clone());
+        }
+        let work = self.fs.work()?;
+        // The root always has an upper directory in a writable overlay.
+        let this = self.this.upgrade().ok_or(VfsError::NotFound)?;
+        let parent = this.parent().ok_or(VfsError::NotFound)?;
+        let parent_upper = parent.downcast::<Self>()?.copy_up()?;
+
+        let lower = &self.lowers[0];
+        let name = self.fs.temp_name();
+        let loc = work.create(&name, NodeType::Directory, lower.metadata()?.mode)?;
+        if let Err(err) =
+            copy_attrs(lower, &loc).and_then(|_| work.rename(&name, &parent_upper, this.name()))
+        {
+            let _ = work.unlink(&name, true);
+            return Err(err);
+        }
+        let loc = layer_lookup(&parent_upper, this.name())?.ok_or(VfsError::NotFound)?;
+        *upper = Some(loc.clone());
+        Ok(loc)
+    }
+
+    /// Looks up `name` in all layers, returning its node in the upper layer
+    /// and in the lower layers. Only directories can have several lower
+    /// nodes, which are merged.
+    fn lookup_layers(&self, name: &str) -> VfsResult<(Option<Location>, Vec<Location>)> {
+        let mut upper = None;
+        if let Some(dir) = self.upper()
+            && let Some(loc) = layer_lookup(&dir, name)?
+        {
+            if is_whiteout(&loc) {
+                return Err(VfsError::NotFound);
+            }
+            if !loc.is_dir() || is_opaque(&loc) {
+                return Ok((Some(loc), Vec::new()));
+            }
+            upper = Some(loc);
+        }
+
+        let mut lowers = Vec::new();
+        for dir in &self.lowers {
+            let Some(loc) = layer_lookup(dir, name)? else {
+                continue;
+            };
+            if is_whiteout(&loc) {
+                break;
+            }
+            if !loc.is_dir() {
+                if upper.is_none() && lowers.is_empty() {
+                    lowers.push(loc);
+                }
+                break;
+            }
+            let opaque = is_opaque(&loc);
+            lowers.push(loc);
+            if opaque {
+                break;
+            }
+        }
+
+        if upper.is_none() && lowers.is_empty() {
+            return Err(VfsError::NotFound);
+        }
+        Ok((upper, lowers))
+    }
+
+    /// Returns whether `name` is visible in the lower layers, so that
+    /// removing it from the upper layer needs a whiteout.
+    fn in_lowers(&self, name: &str) -> VfsResult<bool> {
+        for dir in &self.lowers {
+            if let Some(loc) = layer_lookup(dir, name)? {
+                return Ok(!is_whiteout(&loc));
+            }
+        }
+        Ok(false)
+    }
+
+    fn new_child(
+        &self,
+        name: &str,
+        upper: Option<Location>,
+        lowers: Vec<Location>,
+    ) -> VfsResult<DirEntry> {
+        let this = self.this.upgrade().ok_or(VfsError::NotFound)?;
+        let reference = Reference::new(Some(this.clone()), name.to_owned());
+        let top = upper.as_ref().unwrap_or(&lowers[0]);
+        if top.is_dir() {
+            return Ok(Self::new_entry(self.fs.clone(), upper, lowers, reference));
+        }
+        let file = OverlayFile {
+            parent: this.downcast::<Self>()?,
+            name: name.to_owned(),
+            ino: top.inode(),
+            node_type: top.node_type(),
+            lower: lowers.into_iter().next(),
+            state: Mutex::new(FileState { upper, file: None }),
+        };
+        let node_type = file.node_type;
+        Ok(DirEntry::new_file(
+            FileNode::new(Arc::new(file)),
+            node_type,
+            reference,
+        ))
+    }
+
+    /// Lists the merged entries: those of the upper directory first, then
+    /// those of each lower directory that are not hidden by a layer above.
+    fn merge_entries(&self) -> VfsResult<Vec<LayerEntry>> {
+        let parent_ino = self
+            .this
+            .upgrade()
+            .and_then(|this| this.parent())
+            .map_or(self.ino, |parent| parent.inode());
+        let mut entries = vec![
+            LayerEntry {
+                name: ".".to_string(),
+                ino: self.ino,
+                node_type: NodeType::Directory,
+            },
+            LayerEntry {
+                name: "..".to_string(),
+                ino: parent_ino,
+                node_type: NodeType::Directory,
+            },
+        ];
+        let mut seen = HashSet::new();
+        for dir in self.upper().iter().chain(&self.lowers) {
+            for entry in read_layer(dir)? {
+                if !seen.insert(entry.name.clone()) {
+                    continue;
+                }
+                // Whiteouts hide the name in the layers below.
+                if entry.node_type == NodeType::CharacterDevice
+                    && layer_lookup(dir, &entry.name)?.is_some_and(|it| is_whiteout(&it))
+                {
+                    continue;
+                }
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    fn xattrs(
+        &self,
+    ) -> OverlayXattrs<impl Fn() -> VfsResult<Location>, impl Fn() -> VfsResult<Location>> {
+        OverlayXattrs {
+            top: || Ok(self.top()),
+            copy_up: || self.copy_up(),
+        }
+    }
+
+    fn invalidate(&self) {
+        *self.entries.lock() = None;
+    }
+
+    /// Prepares the upper directory for a new entry `name`, removing the
+    /// whiteout in its place. Returns the upper directory and whether there
+    /// was a whiteout.
+    fn prepare_create(&self, name: &str) -> VfsResult<(Location, bool)> {
+        match self.lookup_layers(name) {
+            Ok(_) => return Err(VfsError::AlreadyExists),
+            Err(VfsError::NotFound) => {}
+            Err(err) => return Err(err),
+        }
+        let upper = self.copy_up()?;
+        // Anything left in the upper directory is a whiteout.
+        let whiteout = layer_lookup(&upper, name)?.is_some();
+        if whiteout {
+            upper.unlink(name, false)?;
+        }
+        Ok((upper, whiteout))
+    }
+}
+
+impl NodeOps for OverlayDir {
+    fn inode(&self) -> u64 {
+        self.ino
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        let mut metadata = self.top().metadata()?;
+        metadata.inode = self.ino;
+        Ok(metadata)
+    }
+
+    fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+        self.copy_up()?.update_metadata(update)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        self.fs.as_ref()
+    }
+
+    fn len(&self) -> VfsResult<u64> {
+        self.top().len()
+    }
+
+    fn sync(&self, data_only: bool) -> VfsResult<()> {
+        match self.upper() {
+            Some(upper) => upper.sync(data_only),
+            None => Ok(()),
+        }
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+impl XattrOps for OverlayDir {
+    fn get_xattr(&self, name: &str) -> AxResult<Vec<u8>> {
+        self.xattrs().get(name)
+    }
+
+    fn set_xattr(&self, name: &str, value: &[u8], flags: XattrFlags) -> AxResult {
+        self.xattrs().set(name, value, flags)
+    }
+
+    fn remove_xattr(&self, name: &str) -> AxResult {
+        self.xattrs().remove(name)
+    }
+
+    fn list_xattr(&self) -> AxResult<Vec<String>> {
+        self.xattrs().list()
+    }
+}
+
+impl Pollable for OverlayDir {
+    fn poll(&self) -> IoEvents {
+        IoEvents::IN | IoEvents::OUT
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
+
+impl DirNodeOps for OverlayDir {
+    fn read_dir(&self, offset: u64, sink: &mut dyn DirEntrySink) -> VfsResult<usize> {
+        let entries = {
+            let mut cache = self.entries.lock();
+            match cache.as_ref() {
+                Some(entries) if offset != 0 => entries.clone(),
+                _ => cache.insert(Arc::new(self.merge_entries()?)).clone(),
+            }
+        };
+        let mut count = 0;
+        for (index, entry) in entries.iter().enumerate().skip(offset as usize) {
+            if !sink.accept(&entry.name, entry.ino, entry.node_type, index as u64 + 1) {
+                break;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<DirEntry> {
+        let (upper, lowers) = self.lookup_layers(name)?;
+        self.new_child(name, upper, lowers)
+    }
+
+    fn create(
+        &self,
+        name: &str,
+        node_type: NodeType,
+        permission: NodePermission,
+    ) -> VfsResult<DirEntry> {
+        let (upper, whiteout) = self.prepare_create(name)?;
+        let result = upper.create(name, node_type, permission).and_then(|loc| {
+            // A new directory must not show the contents of the one it
+            // replaces.
+            if whiteout && node_type == NodeType::Directory {
+                set_opaque(&loc)?;
+            }
+            Ok(loc)
+        });
+        self.invalidate();
+        match result {
+            Ok(loc) => self.new_child(name, Some(loc), Vec::new()),
+            Err(err) => {
+                if whiteout {
+                    let _ = upper.unlink(name, node_type == NodeType::Directory);
+                    let _ = create_whiteout(&upper, name);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    fn link(&self, name: &str, node: &DirEntry) -> VfsResult<DirEntry> {
+        let file = node
+            .downcast::<OverlayFile>()
+            .map_err(|_| VfsError::CrossesDevices)?;
+        let src = file.copy_up()?;
+        let (upper, whiteout) = self.prepare_create(name)?;
+        let result = upper.link(name, &src);
+        self.invalidate();
+        match result {
+            Ok(loc) => self.new_child(name, Some(loc), Vec::new()),
+            Err(err) => {
+                if whiteout {
+                    let _ = create_whiteout(&upper, name);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    fn unlink(&self, name: &str) -> VfsResult<()> {
+        let (upper_loc, lowers) = self.lookup_layers(name)?;
+        let is_dir = upper_loc.as_ref().unwrap_or_else(|| &lowers[0]).is_dir();
+        if is_dir {
+            let this = self.this.upgrade().ok_or(VfsError::NotFound)?;
+            let child = Self::new_entry(
+                self.fs.clone(),
+                upper_loc.clone(),
+                lowers.clone(),
+                Reference::new(Some(this), name.to_owned()),
+            );
+            if child.as_dir()?.has_children()? {
+                return Err(VfsError::DirectoryNotEmpty);
+            }
+        }
+        let upper = self.copy_up()?;
+        if let Some(loc) = upper_loc {
+            if is_dir {
+                clear_whiteouts(&loc)?;
+            }
+            upper.unlink(name, is_dir)?;
+        }
+        if self.in_lowers(name)? {
+            create_whiteout(&upper, name)?;
+        }
+        self.invalidate();
+        Ok(())
+    }
+
+    fn rename(&self, src_name: &str, dst_dir: &DirNode, dst_name: &str) -> VfsResult<()> {
+        let dst: Arc<Self> = dst_dir.downcast().map_err(|_| VfsError::CrossesDevices)?;
+        let (src_upper, src_lowers) = self.lookup_layers(src_name)?;
+        let is_dir = src_upper
+            .as_ref()
+            .unwrap_or_else(|| &src_lowers[0])
+            .is_dir();
+        if is_dir {
+            // Like Linux without `redirect_dir`, only directories that are
+            // entirely in the upper layer can be renamed; `mv` falls back to
+            // copying the others.
+            if !src_lowers.is_empty() {
+                return Err(VfsError::CrossesDevices);
+            }
+        } else {
+            let this = self.this.upgrade().ok_or(VfsError::NotFound)?;
+            let entry = this.as_dir()?.lookup(src_name)?;
+            entry.downcast::<OverlayFile>()?.copy_up()?;
+        }
+
+        let src_dir = self.copy_up()?;
+        let dst_upper = dst.copy_up()?;
+        match layer_lookup(&dst_upper, dst_name)? {
+            Some(loc) if is_whiteout(&loc) => dst_upper.unlink(dst_name, false)?,
+            Some(loc) if loc.is_dir() => clear_whiteouts(&loc)?,
+            _ => {}
+        }
+        src_dir.rename(src_name, &dst_upper, dst_name)?;
+        if is_dir
+            && dst.in_lowers(dst_name)?
+            && let Some(loc) = layer_lookup(&dst_upper, dst_name)?
+        {
+            set_opaque(&loc)?;
+        }
+        if self.in_lowers(src_name)? {
+            create_whiteout(&src_dir, src_name)?;
+        }
+        self.invalidate();
+        dst.invalidate();
+        Ok(())
+    }
+}
+
+struct FileState {
+    /// The file in the upper layer, once there is one.
+    upper: Option<Location>,
+    /// The contents of the topmost file, if it is a regular file that was
+    /// accessed.
+    file: Option<File>,
+}
+
+/// A non-directory of an overlay, which is in either layer.
+struct OverlayFile {
+    parent: Arc<OverlayDir>,
+    name: String,
+    ino: u64,
+    node_type: NodeType,
+    /// The file in a lower layer, if it was not created in the upper layer.
+    lower: Option<Location>,
+    state: Mutex<FileState>,
+}
+
+impl OverlayFile {
+    fn top(&self) -> VfsResult<Location> {
+        let state = self.state.lock();
+        state
+            .upper
+            .as_ref()
+            .or(self.lower.as_ref())
+            .cloned()
+            .ok_or(VfsError::NotFound)
+    }
+
+    /// Copies the file up to the upper layer unless it is already.
+    fn copy_up_locked(&self, state: &mut FileState) -> VfsResult<Location> {
+        if let Some(upper) = &state.upper {
+            return Ok(upper.clone());
+        }
+        let lower = self.lower.as_ref().ok_or(VfsError::NotFound)?;
+        let fs = &self.parent.fs;
+        let work = fs.work()?;
+        let dir = self.parent.copy_up()?;
+
+        let name = fs.temp_name();
+        let loc = copy_node(lower, work, &name)?;
+        // A file removed while in use stays in the work directory instead of
+        // coming back to life. tmpfs keeps it around while in use, on other
+        // filesystems it stays there until the next mount.
+        let loc = if layer_lookup(&dir, &self.name)?.is_some() {
+            if work.filesystem().name() == "tmpfs" {
+                work.unlink(&name, false)?;
+            }
+            loc
+        } else {
+            if let Err(err) = work.rename(&name, &dir, &self.name) {
+                let _ = work.unlink(&name, false);
+                return Err(err);
+            }
+            self.parent.invalidate();
+            layer_lookup(&dir, &self.name)?.ok_or(VfsError::NotFound)?
+        };
+        state.upper = Some(loc.clone());
+        state.file = None;
+        Ok(loc)
+    }
+
+    fn copy_up(&self) -> VfsResult<Location> {
+        self.copy_up_locked(&mut self.state.lock())
+    }
+
+    fn xattrs(
+        &self,
+    ) -> OverlayXattrs<impl Fn() -> VfsResult<Location>, impl Fn() -> VfsResult<Location>> {
+        OverlayXattrs {
+            top: || self.top(),
+            copy_up: || self.copy_up(),
+        }
+    }
+
+    /// Runs `f` on the contents of the topmost file, copying it up first if
+    /// it is about to be written.
+    fn with_file<R>(&self, write: bool, f: impl FnOnce(&File) -> VfsResult<R>) -> VfsResult<R> {
+        let mut guard = self.state.lock();
+        let state = &mut *guard;
+        if write {
+            self.copy_up_locked(state)?;
+        }
+        let file = match &mut state.file {
+            Some(file) => file,
+            file => {
+                let loc = state
+                    .upper
+                    .as_ref()
+                    .or(self.lower.as_ref())
+                    .ok_or(VfsError::NotFound)?;
+                file.insert(open_layer(loc, state.upper.is_some())?)
+            }
+        };
+        f(file)
+    }
+}
+
+impl NodeOps for OverlayFile {
+    fn inode(&self) -> u64 {
+        self.ino
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        let mut metadata = self.top()?.metadata()?;
+        metadata.inode = self.ino;
+        Ok(metadata)
+    }
+
+    fn update_metadata(&self, update: MetadataUpdate) -> VfsResult<()> {
+        self.copy_up()?.update_metadata(update)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        self.parent.fs.as_ref()
+    }
+
+    fn len(&self) -> VfsResult<u64> {
+        self.top()?.len()
+    }
+
+    fn sync(&self, data_only: bool) -> VfsResult<()> {
+        match &self.state.lock().file {
+            Some(file) => file.sync(data_only),
+            None => Ok(()),
+        }
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+impl XattrOps for OverlayFile {
+    fn get_xattr(&self, name: &str) -> AxResult<Vec<u8>> {
+        self.xattrs().get(name)
+    }
+
+    fn set_xattr(&self, name: &str, value: &[u8], flags: XattrFlags) -> AxResult {
+        self.xattrs().set(name, value, flags)
+    }
+
+    fn remove_xattr(&self, name: &str) -> AxResult {
+        self.xattrs().remove(name)
+    }
+
+    fn list_xattr(&self) -> AxResult<Vec<String>> {
+        self.xattrs().list()
+    }
+}
+
+impl Pollable for OverlayFile {
+    fn poll(&self) -> IoEvents {
+        IoEvents::IN | IoEvents::OUT
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
+
+impl FileNodeOps for OverlayFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        if self.node_type == NodeType::RegularFile {
+            self.with_file(false, |file| file.read_at(buf, offset))
+        } else {
+            self.top()?.entry().as_file()?.read_at(buf, offset)
+        }
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> VfsResult<usize> {
+        self.with_file(true, |file| file.write_at(buf, offset))
+    }
+
+    fn append(&self, buf: &[u8]) -> VfsResult<(usize, u64)> {
+        self.with_file(true, |file| file.backend()?.append(buf))
+    }
+
+    fn set_len(&self, len: u64) -> VfsResult<()> {
+        self.with_file(true, |file| file.backend()?.set_len(len))
+    }
+
+    fn set_symlink(&self, target: &str) -> VfsResult<()> {
+        self.copy_up()?.entry().as_file()?.set_symlink(target)
+    }
+}
+
+/// Resolves the layer directory at `path`.
+fn resolve_layer(fs: &FsContext, path: &str) -> VfsResult<Location> {
+    let loc = fs.resolve(path)?;
+    loc.check_is_dir()?;
+    Ok(loc)
+}
+
+/// Returns the `work` directory in the work directory `dir`, emptied of the
+/// files and the empty directories left behind by a previous mount.
+fn prepare_work(dir: &Location) -> VfsResult<Location> {
+    let work = match layer_lookup(dir, "work")? {
+        Some(work) => work,
+        None => dir.create("work", NodeType::Directory, NodePermission::empty())?,
+    };
+    work.check_is_dir()?;
+    for entry in read_layer(&work)? {
+        let is_dir = entry.node_type == NodeType::Directory;
+        match work.unlink(&entry.name, is_dir) {
+            Err(VfsError::DirectoryNotEmpty) if is_dir => {}
+            result => result?,
+        }
+    }
+    Ok(work)
+}
+
+/// Returns the extended attribute storage of `entry` if it is a node of an
+/// overlay.
+pub(super) fn overlay_xattr_ops(entry: &DirEntry) -> Option<Arc<dyn XattrOps>> {
+    if let Ok(node) = entry.downcast::<OverlayDir>() {
+        return Some(node);
+    }
+    entry
+        .downcast::<OverlayFile>()
+        .ok()
+        .map(|node| node as Arc<dyn XattrOps>)
+}
+
+/// Creates an overlay filesystem from the mount options `data`
+/// (`lowerdir=`, `upperdir=` and `workdir=`), resolving the directories in
+/// `fs`. Without an upper directory, the overlay is read-only.
+pub fn new_overlay_fs(fs: &FsContext, data: &str) -> VfsResult<Filesystem> {
+    let mut lowers = Vec::new();
+    let mut upper = None;
+    let mut work = None;
+    for option in data.split(',').filter(|it| !it.is_empty()) {
+        match option.split_once('=') {
+            Some(("lowerdir", dirs)) => {
+                lowers = dirs
+                    .split(':')
+                    .map(|path| resolve_layer(fs, path))
+                    .collect::<VfsResult<_>>()?;
+            }
+            Some(("upperdir", dir)) => upper = Some(resolve_layer(fs, dir)?),
+            Some(("workdir", dir)) => work = Some(resolve_layer(fs, dir)?),
+            _ => return Err(VfsError::InvalidInput),
+        }
+    }
+    if lowers.is_empty() {
+        return Err(VfsError::InvalidInput);
+    }
+    let work = match (&upper, work) {
+        (None, None) => None,
+        (Some(upper), Some(work)) => {
+            // Like Linux, require both on the same mount, with neither
+            // containing the other.
+            if !Arc::ptr_eq(upper.mountpoint(), work.mountpoint())
+                || upper.entry().is_ancestor_of(work.entry())?
+                || work.entry().is_ancestor_of(upper.entry())?
+            {
+                return Err(VfsError::InvalidInput);
+            }
+            Some(prepare_work(&work)?)
+        }
+        _ => return Err(VfsError::InvalidInput),
+    };
+
+    let fs = Arc::new(OverlayFs {
+        upper: upper.clone(),
+        lowers: lowers.clone(),
+        work,
+        next_temp: AtomicU64::new(0),
+        root: Mutex::default(),
+    });
+    *fs.root.lock() = Some(OverlayDir::new_entry(
+        fs.clone(),
+        upper,
+        lowers,
+        Reference::root(),
+    ));
+    Ok(Filesystem::new(fs))
+}
@@ -0,0 +1,291 @@
+//! Sparse files and `fallocate`.
+//!
+//! The content of tmpfs files lives in the page cache only, and the pages
+//! charged to a file are its page map: the pages that are not charged are
+//! the holes of a file. They read as zeros without being populated. On ext4
+//! the holes are the blocks not mapped by any written extent, and other
+//! filesystems are treated as having no holes.
+//!
+//! The `fallocate` modes work on the page cache, so on disk their changes are
+//! written back like any other write. Pages of tmpfs files may be mapped, so
+//! punching a hole zeroes them in the page cache, and releases them from the
+//! file and the size limit of its tmpfs.
+
+use alloc::{vec, vec::Vec};
+use core::ops::Range;
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axfs::{CachedFile, FileBackend, FileFlags};
+use axio::{IoBufMut, Seek, SeekFrom, Write};
+use memory_addr::PAGE_SIZE_4K;
+
+use super::{ext4::Inode as Ext4Inode, mark_dirty};
+use crate::pseudofs::{charge_pages, tmpfs_data_pages, uncharge_pages};
+
+const PAGE_SIZE: u64 = PAGE_SIZE_4K as u64;
+
+/// Zeros used to read holes and fill ranges.
+static ZEROS: [u8; PAGE_SIZE_4K] = [0; PAGE_SIZE_4K];
+
+/// Returns the page cache of `backend` if it holds the whole content.
+fn in_memory_cache(backend: &FileBackend) -> Option<&CachedFile> {
+    match backend {
+        FileBackend::Cached(cache) if cache.in_memory() => Some(cache),
+        _ => None,
+    }
+}
+
+/// Calls `f` with the page number and the range within the page of each
+/// page overlapping `start..end`.
+fn for_each_page(
+    start: u64,
+    end: u64,
+    mut f: impl FnMut(u32, usize, usize) -> AxResult,
+) -> AxResult {
+    let mut pos = start;
+    while pos < end {
+        let offset = (pos % PAGE_SIZE) as usize;
+        let len = (end - pos).min(PAGE_SIZE - offset as u64) as usize;
+        f((pos / PAGE_SIZE) as u32, offset, offset + len)?;
+        pos += len as u64;
+    }
+    Ok(())
+}
+
+fn read_backend(
+    backend: &FileBackend,
+    mut dst: impl Write + IoBufMut,
+    offset: u64,
+) -> AxResult<usize> {
+    let Some(cache) = in_memory_cache(backend) else {
+        return backend.read_at(dst, offset);
+    };
+    let end = offset
+        .saturating_add(dst.remaining_mut() as u64)
+        .min(cache.location().len()?);
+    if end <= offset {
+        return Ok(0);
+    }
+    for_each_page(offset, end, |pn, start, end| {
+        cache.with_page(pn, |page| match page {
+            Some(page) => dst.write_all(&page.data()[start..end]),
+            None => dst.write_all(&ZEROS[start..end]),
+        })
+    })?;
+    Ok((end - offset) as usize)
+}
+
+/// Reads from `file` at `offset` into `dst` like [`axfs::File::read_at`],
+/// without populating the holes of in-memory files.
+pub fn read_at(file: &axfs::File, dst: impl Write + IoBufMut, offset: u64) -> AxResult<usize> {
+    read_backend(file.access(FileFlags::READ)?, dst, offset)
+}
+
+/// Reads from the current position of `file` into `dst` like
+/// [`axfs::File::read`], without populating the holes of in-memory files.
+pub fn read(mut file: &axfs::File, dst: impl Write + IoBufMut) -> AxResult<usize> {
+    if file
+        .backend()
+        .map_or(true, |it| in_memory_cache(it).is_none())
+    {
+        return file.read(dst);
+    }
+    let offset = file.seek(SeekFrom::Current(0))?;
+    let read = read_at(file, dst, offset)?;
+    file.seek(SeekFrom::Start(offset + read as u64))?;
+    Ok(read)
+}
+
+/// Returns the ranges of `backend` holding data that end after `offset`, in
+/// order, or `None` if the filesystem has no holes.
+fn data_ranges(backend: &FileBackend, offset: u64, size: u64) -> AxResult<Option<Vec<Range<u64>>>> {
+    let loc = backend.location();
+    if let Some(pages) = tmpfs_data_pages(loc, (offset / PAGE_SIZE) as u32)? {
+        return Ok(Some(
+            pages
+                .into_iter()
+                .map(|it| it.start as u64 * PAGE_SIZE..it.end as u64 * PAGE_SIZE)
+                .collect(),
+        ));
+    }
+    match loc.entry().downcast::<Ext4Inode>() {
+        Ok(inode) => {
+            // Cached pages not written back have no blocks yet.
+            backend.sync(true)?;
+            Ok(Some(inode.data_ranges(offset, size)?))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Returns the offset of the first data at or after `offset` in `file`, or
+/// of the first hole if `hole` is set, for `SEEK_DATA` and `SEEK_HOLE`.
+///
+/// The end of the file counts as a hole.
+pub fn seek_data(file: &axfs::File, offset: u64, hole: bool) -> AxResult<u64> {
+    let backend = file.backend()?;
+    let size = backend.location().len()?;
+    if offset >= size {
+        return Err(AxError::from(LinuxError::ENXIO));
+    }
+    let Some(ranges) = data_ranges(backend, offset, size)? else {
+        return Ok(if hole { size } else { offset });
+    };
+    let mut pos = offset;
+    for range in ranges.into_iter().filter(|it| it.end > offset) {
+        if !hole {
+            return if range.start < size {
+                Ok(range.start.max(offset))
+            } else {
+                Err(AxError::from(LinuxError::ENXIO))
+            };
+        }
+        if range.start > pos {
+            break;
+        }
+        pos = range.end;
+    }
+    if hole {
+        Ok(pos.min(size))
+    } else {
+        Err(AxError::from(LinuxError::ENXIO))
+    }
+}
+
+/// Returns the block size ranges moved by `fallocate` have to be aligned to.
+fn block_size(backend: &FileBackend) -> AxResult<u64> {
+    if in_memory_cache(backend).is_some() {
+        return Ok(PAGE_SIZE);
+    }
+    let block_size = backend.location().metadata()?.block_size;
+    Ok(if block_size == 0 {
+        PAGE_SIZE
+    } else {
+        block_size
+    })
+}
+
+/// Fills `start..end` of `file` with zeros, leaving the holes of in-memory
+/// files alone.
+fn fill_zero(file: &axfs::File, start: u64, end: u64) -> AxResult {
+    let backend = file.access(FileFlags::WRITE)?;
+    if let Some(cache) = in_memory_cache(backend) {
+        return for_each_page(start, end, |pn, start, end| {
+            cache.with_page(pn, |page| {
+                if let Some(page) = page {
+                    page.data()[start..end].fill(0);
+                }
+            });
+            Ok(())
+        });
+    }
+    for_each_page(start, end, |pn, page_start, page_end| {
+        let offset = pn as u64 * PAGE_SIZE + page_start as u64;
+        let written = backend.write_at(&ZEROS[page_start..page_end], offset)?;
+        mark_dirty(file, offset, written);
+        Ok(())
+    })
+}
+
+/// Moves `len` bytes of `file` from `src` to `dst`, in the order that keeps
+/// overlapping ranges intact. Holes of in-memory files are kept where the
+/// destination is a hole already.
+fn move_range(file: &axfs::File, src: u64, dst: u64, len: u64) -> AxResult {
+    let backend = file.access(FileFlags::WRITE)?;
+    let cache = in_memory_cache(backend);
+    let mut buf = vec![0; PAGE_SIZE_4K];
+    let chunks = len.div_ceil(PAGE_SIZE);
+    for i in 0..chunks {
+        let i = if dst > src { chunks - 1 - i } else { i };
+        let offset = i * PAGE_SIZE;
+        let chunk = &mut buf[..(len - offset).min(PAGE_SIZE) as usize];
+        read_backend(backend, &mut *chunk, src + offset)?;
+        if let Some(cache) = cache
+            && chunk.iter().all(|&it| it == 0)
+            && cache.with_page(((dst + offset) / PAGE_SIZE) as u32, |page| page.is_none())
+        {
+            continue;
+        }
+        charge_pages(file.location(), dst + offset, chunk.len() as u64)?;
+        let written = backend.write_at(&*chunk, dst + offset)?;
+        mark_dirty(file, dst + offset, written);
+    }
+    Ok(())
+}
+
+/// Allocates `offset..offset + len` of `file`, extending it unless
+/// `keep_size` is set.
+pub fn allocate_range(file: &axfs::File, offset: u64, len: u64, keep_size: bool) -> AxResult {
+    let backend = file.access(FileFlags::WRITE)?;
+    let end = offset + len;
+    if let Some(cache) = in_memory_cache(backend) {
+        charge_pages(file.location(), offset, len)?;
+        for pn in (offset / PAGE_SIZE) as u32..end.div_ceil(PAGE_SIZE) as u32 {
+            cache.with_page_or_insert(pn, |_, _| Ok(()))?;
+        }
+    }
+    if !keep_size && end > backend.location().len()? {
+        backend.set_len(end)?;
+    }
+    Ok(())
+}
+
+/// Turns `offset..offset + len` of `file` into a hole, keeping its size.
+pub fn punch_hole(file: &axfs::File, offset: u64, len: u64) -> AxResult {
+    let size = file.location().len()?;
+    let end = (offset + len).min(size);
+    fill_zero(file, offset, end)?;
+    // A hole up to the end of the file takes its last page along.
+    let end = if end == size {
+        end.next_multiple_of(PAGE_SIZE)
+    } else {
+        end
+    };
+    uncharge_pages(file.location(), offset, end.saturating_sub(offset))
+}
+
+/// Zeroes `offset..offset + len` of `file`, extending it unless `keep_size`
+/// is set.
+pub fn zero_range(file: &axfs::File, offset: u64, len: u64, keep_size: bool) -> AxResult {
+    let backend = file.access(FileFlags::WRITE)?;
+    let size = backend.location().len()?;
+    let end = offset + len;
+    fill_zero(file, offset, end.min(size))?;
+    if !keep_size && end > size {
+        backend.set_len(end)?;
+    }
+    Ok(())
+}
+
+/// Removes `offset..offset + len` from `file`, moving the data after it down.
+pub fn collapse_range(file: &axfs::File, offset: u64, len: u64) -> AxResult {
+    let backend = file.access(FileFlags::WRITE)?;
+    let block_size = block_size(backend)?;
+    if !offset.is_multiple_of(block_size) || !len.is_multiple_of(block_size) {
+        return Err(AxError::InvalidInput);
+    }
+    let size = backend.location().len()?;
+    if offset + len >= size {
+        return Err(AxError::InvalidInput);
+    }
+    move_range(file, offset + len, offset, size - offset - len)?;
+    backend.set_len(size - len)?;
+    Ok(())
+}
+
+/// Inserts a hole of `len` bytes at `offset` of `file`, moving the data after
+/// it up.
+pub fn insert_range(file: &axfs::File, offset: u64, len: u64) -> AxResult {
+    let backend = file.access(FileFlags::WRITE)?;
+    let block_size = block_size(backend)?;
+    if !offset.is_multiple_of(block_size) || !len.is_multiple_of(block_size) {
+        return Err(AxError::InvalidInput);
+    }
+    let size = backend.location().len()?;
+    if offset >= size {
+        return Err(AxError::InvalidInput);
+    }
+    backend.set_len(size + len)?;
+    move_range(file, offset, offset + len, size - offset)?;
+    fill_zero(file, offset, offset + len)
+}
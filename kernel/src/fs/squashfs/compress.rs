@@ -0,0 +1,72 @@
+//! Decompression of metadata and data blocks.
+
+use alloc::{vec, vec::Vec};
+
+use axfs_ng_vfs::{VfsError, VfsResult};
+use lzma_rust2::{Read, XzReader};
+use ruzstd::decoding::FrameDecoder;
+
+/// The compression algorithm of a filesystem.
+#[derive(Debug, Clone, Copy)]
+pub enum Compressor {
+    Gzip,
+    Xz,
+    Lz4,
+    Zstd,
+}
+
+impl Compressor {
+    /// Returns the compressor of the ID stored in the superblock.
+    ///
+    /// lzma and lzo are not supported.
+    pub fn from_id(id: u16) -> VfsResult<Self> {
+        Ok(match id {
+            1 => Self::Gzip,
+            4 => Self::Xz,
+            5 => Self::Lz4,
+            6 => Self::Zstd,
+            _ => {
+                warn!("squashfs: unsupported compression {id}");
+                return Err(VfsError::InvalidInput);
+            }
+        })
+    }
+
+    /// Decompresses a block, which expands to at most `max_len` bytes.
+    pub fn decompress(self, src: &[u8], max_len: usize) -> VfsResult<Vec<u8>> {
+        match self {
+            // Despite the name, gzip blocks are zlib streams.
+            Self::Gzip => miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(src, max_len)
+                .map_err(|_| VfsError::Io),
+            Self::Xz => {
+                let mut reader = XzReader::new(src, false);
+                let mut out = vec![0; max_len];
+                let mut len = 0;
+                while len < max_len {
+                    let read = reader.read(&mut out[len..]).map_err(|_| VfsError::Io)?;
+                    if read == 0 {
+                        break;
+                    }
+                    len += read;
+                }
+                out.truncate(len);
+                Ok(out)
+            }
+            Self::Lz4 => {
+                let mut out = vec![0; max_len];
+                let len =
+                    lz4_flex::block::decompress_into(src, &mut out).map_err(|_| VfsError::Io)?;
+                out.truncate(len);
+                Ok(out)
+            }
+            Self::Zstd => {
+                let mut out = vec![0; max_len];
+                let len = FrameDecoder::new()
+                    .decode_all(src, &mut out)
+                    .map_err(|_| VfsError::Io)?;
+                out.truncate(len);
+                Ok(out)
+            }
+        }
+    }
+}
@@ -0,0 +1,433 @@
+use alloc::{collections::VecDeque, format, string::String, sync::Arc, vec, vec::Vec};
+use core::{mem::size_of, str};
+
+use axfs_ng_vfs::{DirEntry, Filesystem, FilesystemOps, StatFs, VfsError, VfsResult};
+use axsync::Mutex;
+use linux_raw_sys::general::SQUASHFS_MAGIC;
+use zerocopy::{
+    FromBytes, FromZeros, IntoBytes,
+    little_endian::{U16, U32, U64},
+};
+
+use super::{
+    DATA_UNCOMPRESSED, FragmentEntry, INVALID_BLK, INVALID_INDEX, MAX_BLOCK_SIZE, METADATA_SIZE,
+    METADATA_UNCOMPRESSED, NAME_LEN, NO_XATTRS, SuperBlock, XATTR_PREFIXES, XATTR_VALUE_OOL,
+    XattrEntry, XattrId, XattrIdTable, compress::Compressor, inode::new_root, split_ref,
+};
+use crate::{fs::XATTR_SIZE_MAX, pseudofs::DeviceOps};
+
+/// The number of metadata blocks kept decompressed.
+const METADATA_CACHE_SIZE: usize = 64;
+/// The number of data and fragment blocks kept decompressed.
+const DATA_CACHE_SIZE: usize = 4;
+
+/// The most recently used decompressed blocks, keyed by their position on
+/// the device.
+struct BlockCache {
+    /// The position, contents and position of the next block of each block,
+    /// most recently used first.
+    blocks: VecDeque<(u64, Arc<[u8]>, u64)>,
+    capacity: usize,
+}
+
+impl BlockCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            blocks: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    fn get(&mut self, pos: u64) -> Option<(Arc<[u8]>, u64)> {
+        let index = self.blocks.iter().position(|block| block.0 == pos)?;
+        let block = self.blocks.remove(index)?;
+        let result = (block.1.clone(), block.2);
+        self.blocks.push_front(block);
+        Some(result)
+    }
+
+    fn insert(&mut self, pos: u64, data: Arc<[u8]>, next: u64) {
+        if self.blocks.len() >= self.capacity {
+            self.blocks.pop_back();
+        }
+        self.blocks.push_front((pos, data, next));
+    }
+}
+
+/// A SquashFS filesystem stored on a block device.
+pub struct SquashfsFilesystem {
+    dev: Arc<dyn DeviceOps>,
+    compressor: Compressor,
+    block_size: u32,
+    bytes_used: u64,
+    inodes: u32,
+    inode_table: u64,
+    directory_table: u64,
+    fragments: u32,
+    /// The positions of the metadata blocks of the fragment table.
+    fragment_index: Vec<u64>,
+    ids: Vec<u32>,
+    xattr_table: u64,
+    xattr_ids: u32,
+    /// The positions of the metadata blocks of the extended attribute ID
+    /// table.
+    xattr_index: Vec<u64>,
+    metadata_cache: Mutex<BlockCache>,
+    data_cache: Mutex<BlockCache>,
+    root_dir: Mutex<Option<DirEntry>>,
+}
+
+impl SquashfsFilesystem {
+    /// Opens the SquashFS filesystem stored on `dev`.
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(dev: Arc<dyn DeviceOps>) -> VfsResult<Filesystem> {
+        let mut sb = SuperBlock::new_zeroed();
+        read_exact_at(&*dev, sb.as_mut_bytes(), 0)?;
+        if sb.magic.get() != SQUASHFS_MAGIC {
+            return Err(VfsError::InvalidInput);
+        }
+        let version = (sb.s_major.get(), sb.s_minor.get());
+        if version != (4, 0) {
+            warn!("squashfs: unsupported version {}.{}", version.0, version.1);
+            return Err(VfsError::InvalidInput);
+        }
+        let compressor = Compressor::from_id(sb.compression.get())?;
+
+        let block_size = sb.block_size.get();
+        if !(4096..=MAX_BLOCK_SIZE).contains(&block_size)
+            || 1u32.checked_shl(sb.block_log.get() as u32) != Some(block_size)
+        {
+            return Err(VfsError::InvalidInput);
+        }
+        let bytes_used = sb.bytes_used.get();
+        if bytes_used > dev.capacity()? {
+            return Err(VfsError::InvalidInput);
+        }
+        if split_ref(sb.root_inode.get()).1 >= METADATA_SIZE {
+            return Err(VfsError::InvalidInput);
+        }
+
+        let mut fs = Self {
+            dev,
+            compressor,
+            block_size,
+            bytes_used,
+            inodes: sb.inodes.get(),
+            inode_table: sb.inode_table_start.get(),
+            directory_table: sb.directory_table_start.get(),
+            fragments: sb.fragments.get(),
+            fragment_index: Vec::new(),
+            ids: Vec::new(),
+            xattr_table: 0,
+            xattr_ids: 0,
+            xattr_index: Vec::new(),
+            metadata_cache: Mutex::new(BlockCache::new(METADATA_CACHE_SIZE)),
+            data_cache: Mutex::new(BlockCache::new(DATA_CACHE_SIZE)),
+            root_dir: Mutex::default(),
+        };
+
+        let id_index = fs.read_index(sb.id_table_start.get(), sb.no_ids.get() as u32, 4)?;
+        fs.ids = (0..sb.no_ids.get() as u32)
+            .map(|i| Ok(fs.table_entry::<U32>(&id_index, i)?.get()))
+            .collect::<VfsResult<_>>()?;
+
+        if fs.fragments != 0 && sb.fragment_table_start.get() != INVALID_BLK {
+            fs.fragment_index = fs.read_index(
+                sb.fragment_table_start.get(),
+                fs.fragments,
+                size_of::<FragmentEntry>(),
+            )?;
+        }
+
+        let xattr_pos = sb.xattr_id_table_start.get();
+        if sb.flags.get() & NO_XATTRS == 0 && xattr_pos != INVALID_BLK {
+            let mut table = XattrIdTable::new_zeroed();
+            fs.read_at(table.as_mut_bytes(), xattr_pos)?;
+            fs.xattr_table = table.xattr_table_start.get();
+            fs.xattr_ids = table.xattr_ids.get();
+            fs.xattr_index = fs.read_index(
+                xattr_pos + size_of::<XattrIdTable>() as u64,
+                fs.xattr_ids,
+                size_of::<XattrId>(),
+            )?;
+        }
+
+        let fs = Arc::new(fs);
+        let root = new_root(fs.clone(), sb.root_inode.get())?;
+        *fs.root_dir.lock() = Some(root);
+        Ok(Filesystem::new(fs))
+    }
+
+    pub(super) fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    pub(super) fn bytes_used(&self) -> u64 {
+        self.bytes_used
+    }
+
+    /// Returns whether `ino` is a valid inode number.
+    pub(super) fn has_inode(&self, ino: u32) -> bool {
+        (1..=self.inodes).contains(&ino)
+    }
+
+    /// Reads `buf.len()` bytes at `pos`, which must be within the image.
+    fn read_at(&self, buf: &mut [u8], pos: u64) -> VfsResult<()> {
+        match pos.checked_add(buf.len() as u64) {
+            Some(end) if end <= self.bytes_used => read_exact_at(&*self.dev, buf, pos),
+            _ => Err(VfsError::InvalidData),
+        }
+    }
+
+    /// Reads the positions of the metadata blocks of a table of `count`
+    /// entries of `entry_size` bytes, which are stored at `pos`.
+    fn read_index(&self, pos: u64, count: u32, entry_size: usize) -> VfsResult<Vec<u64>> {
+        let blocks = (count as usize * entry_size).div_ceil(METADATA_SIZE);
+        let mut index = vec![U64::ZERO; blocks];
+        self.read_at(index.as_mut_bytes(), pos)?;
+        Ok(index.into_iter().map(U64::get).collect())
+    }
+
+    /// Reads entry `i` of a table whose metadata blocks are at `index`.
+    fn table_entry<T: FromBytes + IntoBytes>(&self, index: &[u64], i: u32) -> VfsResult<T> {
+        let pos = i as usize * size_of::<T>();
+        let block = *index
+            .get(pos / METADATA_SIZE)
+            .ok_or(VfsError::InvalidData)?;
+        self.metadata_reader(block, pos % METADATA_SIZE)?
+            .read_struct()
+    }
+
+    /// Reads `size` bytes at `pos` and decompresses them if `compressed`.
+    fn read_block(
+        &self,
+        pos: u64,
+        size: usize,
+        compressed: bool,
+        max_len: usize,
+    ) -> VfsResult<Arc<[u8]>> {
+        let mut data = vec![0; size];
+        self.read_at(&mut data, pos)?;
+        if compressed {
+            data = self.compressor.decompress(&data, max_len)?;
+        }
+        Ok(data.into())
+    }
+
+    /// Returns the contents of the metadata block at `pos`, and the position
+    /// of the block following it.
+    fn metadata_block(&self, pos: u64) -> VfsResult<(Arc<[u8]>, u64)> {
+        if let Some(block) = self.metadata_cache.lock().get(pos) {
+            return Ok(block);
+        }
+        let mut header = U16::ZERO;
+        self.read_at(header.as_mut_bytes(), pos)?;
+        let header = header.get();
+        let size = (header & !METADATA_UNCOMPRESSED) as usize;
+        if size == 0 || size > METADATA_SIZE {
+            return Err(VfsError::InvalidData);
+        }
+        let data = self.read_block(
+            pos + 2,
+            size,
+            header & METADATA_UNCOMPRESSED == 0,
+            METADATA_SIZE,
+        )?;
+        if data.is_empty() {
+            return Err(VfsError::InvalidData);
+        }
+        let next = pos + 2 + size as u64;
+        self.metadata_cache.lock().insert(pos, data.clone(), next);
+        Ok((data, next))
+    }
+
+    /// Returns a reader of the metadata starting at `offset` into the
+    /// metadata block at `pos`.
+    fn metadata_reader(&self, pos: u64, offset: usize) -> VfsResult<MetadataReader<'_>> {
+        let (data, next) = self.metadata_block(pos)?;
+        if offset > data.len() {
+            return Err(VfsError::InvalidData);
+        }
+        Ok(MetadataReader {
+            fs: self,
+            data,
+            offset,
+            next,
+        })
+    }
+
+    /// Returns a reader of the inode referred to by `reference`.
+    pub(super) fn inode_reader(&self, reference: u64) -> VfsResult<MetadataReader<'_>> {
+        let (block, offset) = split_ref(reference);
+        self.metadata_reader(self.inode_table + block, offset)
+    }
+
+    /// Returns a reader of the directory listing at `offset` into the
+    /// metadata block at `block` of the directory table.
+    pub(super) fn directory_reader(
+        &self,
+        block: u32,
+        offset: u16,
+    ) -> VfsResult<MetadataReader<'_>> {
+        self.metadata_reader(self.directory_table + block as u64, offset as usize)
+    }
+
+    /// Returns the contents of the data or fragment block at `pos`, given
+    /// its size as stored in the block list or fragment table.
+    pub(super) fn data_block(&self, pos: u64, size: u32) -> VfsResult<Arc<[u8]>> {
+        if let Some((data, _)) = self.data_cache.lock().get(pos) {
+            return Ok(data);
+        }
+        let len = size & !DATA_UNCOMPRESSED;
+        if len > self.block_size {
+            return Err(VfsError::InvalidData);
+        }
+        let data = self.read_block(
+            pos,
+            len as usize,
+            size & DATA_UNCOMPRESSED == 0,
+            self.block_size as usize,
+        )?;
+        self.data_cache.lock().insert(pos, data.clone(), 0);
+        Ok(data)
+    }
+
+    /// Returns the contents of fragment block `index`.
+    pub(super) fn fragment(&self, index: u32) -> VfsResult<Arc<[u8]>> {
+        if index >= self.fragments {
+            return Err(VfsError::InvalidData);
+        }
+        let entry: FragmentEntry = self.table_entry(&self.fragment_index, index)?;
+        self.data_block(entry.start_block.get(), entry.size.get())
+    }
+
+    /// Returns the user or group ID at `index` of the ID table.
+    pub(super) fn id(&self, index: u16) -> VfsResult<u32> {
+        self.ids
+            .get(index as usize)
+            .copied()
+            .ok_or(VfsError::InvalidData)
+    }
+
+    /// Returns the extended attributes at `index` of the extended attribute
+    /// ID table, as pairs of full names and values.
+    pub(super) fn xattrs(&self, index: u32) -> VfsResult<Vec<(String, Vec<u8>)>> {
+        if index == INVALID_INDEX {
+            return Ok(Vec::new());
+        }
+        if index >= self.xattr_ids {
+            return Err(VfsError::InvalidData);
+        }
+        let id: XattrId = self.table_entry(&self.xattr_index, index)?;
+        let (block, offset) = split_ref(id.xattr.get());
+        let mut reader = self.metadata_reader(self.xattr_table + block, offset)?;
+        let mut xattrs = Vec::new();
+        for _ in 0..id.count.get() {
+            let entry: XattrEntry = reader.read_struct()?;
+            let name = reader.read_bytes(entry.size.get() as usize)?;
+            let size = reader.read_struct::<U32>()?.get() as usize;
+            let value = if entry.entry_type.get() & XATTR_VALUE_OOL != 0 {
+                // The value is stored elsewhere, and referred to here.
+                let (block, offset) = split_ref(reader.read_struct::<U64>()?.get());
+                let mut reader = self.metadata_reader(self.xattr_table + block, offset)?;
+                let size = reader.read_struct::<U32>()?.get() as usize;
+                read_value(&mut reader, size)?
+            } else {
+                read_value(&mut reader, size)?
+            };
+            let prefix = XATTR_PREFIXES.get((entry.entry_type.get() & 0xff) as usize);
+            if let (Some(prefix), Ok(name)) = (prefix, str::from_utf8(&name)) {
+                xattrs.push((format!("{prefix}{name}"), value));
+            }
+        }
+        Ok(xattrs)
+    }
+}
+
+impl FilesystemOps for SquashfsFilesystem {
+    fn name(&self) -> &str {
+        "squashfs"
+    }
+
+    fn root_dir(&self) -> DirEntry {
+        self.root_dir.lock().clone().unwrap()
+    }
+
+    fn stat(&self) -> VfsResult<StatFs> {
+        Ok(StatFs {
+            fs_type: SQUASHFS_MAGIC as _,
+            block_size: self.block_size as _,
+            blocks: self.bytes_used.div_ceil(self.block_size as u64),
+            blocks_free: 0,
+            blocks_available: 0,
+
+            file_count: self.inodes as _,
+            free_file_count: 0,
+
+            name_length: NAME_LEN as _,
+            fragment_size: 0,
+            mount_flags: 0,
+        })
+    }
+
+    fn flush(&self) -> VfsResult<()> {
+        Ok(())
+    }
+}
+
+/// A reader of the metadata stream, which continues into the following
+/// metadata blocks.
+pub(super) struct MetadataReader<'a> {
+    fs: &'a SquashfsFilesystem,
+    data: Arc<[u8]>,
+    offset: usize,
+    next: u64,
+}
+
+impl MetadataReader<'_> {
+    pub fn read(&mut self, mut buf: &mut [u8]) -> VfsResult<()> {
+        while !buf.is_empty() {
+            if self.offset == self.data.len() {
+                (self.data, self.next) = self.fs.metadata_block(self.next)?;
+                self.offset = 0;
+            }
+            let len = buf.len().min(self.data.len() - self.offset);
+            buf[..len].copy_from_slice(&self.data[self.offset..][..len]);
+            self.offset += len;
+            buf = &mut buf[len..];
+        }
+        Ok(())
+    }
+
+    pub fn read_struct<T: FromBytes + IntoBytes>(&mut self) -> VfsResult<T> {
+        let mut value = T::new_zeroed();
+        self.read(value.as_mut_bytes())?;
+        Ok(value)
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> VfsResult<Vec<u8>> {
+        let mut buf = vec![0; len];
+        self.read(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+fn read_value(reader: &mut MetadataReader, size: usize) -> VfsResult<Vec<u8>> {
+    if size > XATTR_SIZE_MAX {
+        return Err(VfsError::InvalidData);
+    }
+    reader.read_bytes(size)
+}
+
+/// Reads `buf.len()` bytes from `dev` at `offset`.
+fn read_exact_at(dev: &dyn DeviceOps, mut buf: &mut [u8], mut offset: u64) -> VfsResult<()> {
+    while !buf.is_empty() {
+        let read = dev.read_at(buf, offset)?;
+        if read == 0 {
+            return Err(VfsError::Io);
+        }
+        buf = &mut buf[read..];
+        offset += read as u64;
+    }
+    Ok(())
+}
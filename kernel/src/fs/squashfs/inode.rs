@@ -0,0 +1,534 @@
+use alloc::{borrow::ToOwned, string::String, sync::Arc, vec::Vec};
+use core::{any::Any, mem::size_of, task::Context, time::Duration};
+
+use axerrno::{AxError, AxResult};
+use axfs_ng_vfs::{
+    DeviceId, DirEntry, DirEntrySink, DirNode, DirNodeOps, FileNode, FileNodeOps, FilesystemOps,
+    Metadata, MetadataUpdate, NodeFlags, NodeOps, NodePermission, NodeType, Reference, VfsError,
+    VfsResult, WeakDirEntry,
+};
+use axpoll::{IoEvents, Pollable};
+use axsync::Mutex;
+use memory_addr::PAGE_SIZE_4K;
+use zerocopy::little_endian::U32;
+
+use super::{
+    DATA_UNCOMPRESSED, DIR_COUNT, DevInode, DirEntryHeader, DirHeader, DirInode, INVALID_INDEX,
+    InodeHeader, LDirInode, LRegInode, NAME_LEN, RegInode, SquashfsFilesystem, SymlinkInode,
+    fs::MetadataReader, node_type,
+};
+use crate::fs::{XattrFlags, XattrOps, no_data};
+
+/// The type-specific contents of an inode.
+enum InodeData {
+    Dir {
+        block: u32,
+        offset: u16,
+        parent: u32,
+    },
+    File {
+        /// The position and stored size of each full block.
+        blocks: Vec<(u64, u32)>,
+        /// The fragment holding the tail of the file, and the offset of the
+        /// tail in it.
+        fragment: Option<(u32, u32)>,
+    },
+    Symlink(Vec<u8>),
+    Device(DeviceId),
+    Ipc,
+}
+
+/// The contents of an inode, as read from the inode table.
+struct InodeInfo {
+    ino: u32,
+    node_type: NodeType,
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    mtime: u32,
+    nlink: u32,
+    size: u64,
+    xattr: u32,
+    data: InodeData,
+}
+
+impl InodeInfo {
+    fn read(fs: &SquashfsFilesystem, reference: u64) -> VfsResult<Self> {
+        let mut reader = fs.inode_reader(reference)?;
+        let header: InodeHeader = reader.read_struct()?;
+        let inode_type = header.inode_type.get();
+        let mut xattr = INVALID_INDEX;
+        let (nlink, size, data) = match inode_type {
+            1 => {
+                let dir: DirInode = reader.read_struct()?;
+                let data = InodeData::Dir {
+                    block: dir.start_block.get(),
+                    offset: dir.offset.get(),
+                    parent: dir.parent_inode.get(),
+                };
+                (dir.nlink.get(), dir.file_size.get() as u64, data)
+            }
+            8 => {
+                let dir: LDirInode = reader.read_struct()?;
+                xattr = dir.xattr.get();
+                let data = InodeData::Dir {
+                    block: dir.start_block.get(),
+                    offset: dir.offset.get(),
+                    parent: dir.parent_inode.get(),
+                };
+                (dir.nlink.get(), dir.file_size.get() as u64, data)
+            }
+            2 => {
+                let reg: RegInode = reader.read_struct()?;
+                let size = reg.file_size.get() as u64;
+                let data = read_blocks(
+                    fs,
+                    &mut reader,
+                    reg.start_block.get() as u64,
+                    size,
+                    reg.fragment.get(),
+                    reg.offset.get(),
+                )?;
+                (1, size, data)
+            }
+            9 => {
+                let reg: LRegInode = reader.read_struct()?;
+                xattr = reg.xattr.get();
+                let size = reg.file_size.get();
+                let data = read_blocks(
+                    fs,
+                    &mut reader,
+                    reg.start_block.get(),
+                    size,
+                    reg.fragment.get(),
+                    reg.offset.get(),
+                )?;
+                (reg.nlink.get(), size, data)
+            }
+            3 | 10 => {
+                let symlink: SymlinkInode = reader.read_struct()?;
+                let size = symlink.symlink_size.get() as usize;
+                if size > PAGE_SIZE_4K {
+                    return Err(VfsError::InvalidData);
+                }
+                let target = reader.read_bytes(size)?;
+                if inode_type == 10 {
+                    xattr = reader.read_struct::<U32>()?.get();
+                }
+                (symlink.nlink.get(), size as u64, InodeData::Symlink(target))
+            }
+            4 | 5 | 11 | 12 => {
+                let dev: DevInode = reader.read_struct()?;
+                if inode_type >= 11 {
+                    xattr = reader.read_struct::<U32>()?.get();
+                }
+                (
+                    dev.nlink.get(),
+                    0,
+                    InodeData::Device(decode_dev(dev.rdev.get())),
+                )
+            }
+            6 | 7 | 13 | 14 => {
+                let nlink = reader.read_struct::<U32>()?.get();
+                if inode_type >= 13 {
+                    xattr = reader.read_struct::<U32>()?.get();
+                }
+                (nlink, 0, InodeData::Ipc)
+            }
+            _ => return Err(VfsError::InvalidData),
+        };
+        Ok(Self {
+            ino: header.inode_number.get(),
+            node_type: node_type(inode_type)?,
+            mode: header.mode.get() & 0o7777,
+            uid: fs.id(header.uid.get())?,
+            gid: fs.id(header.guid.get())?,
+            mtime: header.mtime.get(),
+            nlink,
+            size,
+            xattr,
+            data,
+        })
+    }
+}
+
+/// Reads the block list of a regular file starting at `start`, which
+/// follows the inode.
+fn read_blocks(
+    fs: &SquashfsFilesystem,
+    reader: &mut MetadataReader,
+    start: u64,
+    size: u64,
+    fragment: u32,
+    offset: u32,
+) -> VfsResult<InodeData> {
+    let block_size = fs.block_size() as u64;
+    let count = if fragment == INVALID_INDEX {
+        size.div_ceil(block_size)
+    } else {
+        size / block_size
+    };
+    // The block list is stored in the image, which bounds its length.
+    if count.saturating_mul(4) > fs.bytes_used() {
+        return Err(VfsError::InvalidData);
+    }
+    let mut blocks = Vec::with_capacity(count as usize);
+    let mut pos = start;
+    for _ in 0..count {
+        let size = reader.read_struct::<U32>()?.get();
+        blocks.push((pos, size));
+        pos += (size & !DATA_UNCOMPRESSED) as u64;
+    }
+    Ok(InodeData::File {
+        blocks,
+        fragment: (fragment != INVALID_INDEX).then_some((fragment, offset)),
+    })
+}
+
+/// Takes `len` bytes off the `remaining` length of a directory listing.
+fn consume(remaining: &mut u64, len: usize) -> VfsResult<()> {
+    *remaining = remaining
+        .checked_sub(len as u64)
+        .ok_or(VfsError::InvalidData)?;
+    Ok(())
+}
+
+/// Decodes a device number in the format of `new_encode_dev`.
+fn decode_dev(dev: u32) -> DeviceId {
+    DeviceId::new((dev & 0xfff00) >> 8, (dev & 0xff) | ((dev >> 12) & 0xfff00))
+}
+
+/// An entry of a directory listing.
+struct ListEntry {
+    name: String,
+    ino: u32,
+    node_type: NodeType,
+    /// The reference to the inode of the entry.
+    reference: u64,
+}
+
+/// Creates the root directory of `fs`, whose inode is referred to by
+/// `reference`.
+pub(super) fn new_root(fs: Arc<SquashfsFilesystem>, reference: u64) -> VfsResult<DirEntry> {
+    let info = InodeInfo::read(&fs, reference)?;
+    if info.node_type != NodeType::Directory {
+        return Err(VfsError::InvalidData);
+    }
+    Ok(DirEntry::new_dir(
+        |this| DirNode::new(Inode::new(fs, info, Some(this))),
+        Reference::root(),
+    ))
+}
+
+/// A SquashFS inode.
+pub struct Inode {
+    fs: Arc<SquashfsFilesystem>,
+    info: InodeInfo,
+    this: Option<WeakDirEntry>,
+    /// The listing of a directory, read when first needed.
+    entries: Mutex<Option<Arc<Vec<ListEntry>>>>,
+}
+
+impl Inode {
+    fn new(fs: Arc<SquashfsFilesystem>, info: InodeInfo, this: Option<WeakDirEntry>) -> Arc<Self> {
+        Arc::new(Self {
+            fs,
+            info,
+            this,
+            entries: Mutex::default(),
+        })
+    }
+
+    fn create_entry(&self, name: &str, info: InodeInfo) -> DirEntry {
+        let reference = Reference::new(
+            self.this.as_ref().and_then(WeakDirEntry::upgrade),
+            name.to_owned(),
+        );
+        let fs = self.fs.clone();
+        if info.node_type == NodeType::Directory {
+            DirEntry::new_dir(
+                |this| DirNode::new(Inode::new(fs, info, Some(this))),
+                reference,
+            )
+        } else {
+            let node_type = info.node_type;
+            DirEntry::new_file(
+                FileNode::new(Inode::new(fs, info, None)),
+                node_type,
+                reference,
+            )
+        }
+    }
+
+    fn entries(&self) -> VfsResult<Arc<Vec<ListEntry>>> {
+        if let Some(entries) = self.entries.lock().as_ref() {
+            return Ok(entries.clone());
+        }
+        let entries = Arc::new(self.read_entries()?);
+        *self.entries.lock() = Some(entries.clone());
+        Ok(entries)
+    }
+
+    fn read_entries(&self) -> VfsResult<Vec<ListEntry>> {
+        let InodeData::Dir { block, offset, .. } = self.info.data else {
+            return Err(VfsError::NotADirectory);
+        };
+        // The size includes the (absent) entries of `.` and `..`.
+        let mut remaining = self.info.size.saturating_sub(3);
+        let mut reader = self.fs.directory_reader(block, offset)?;
+        let mut entries = Vec::new();
+        while remaining > 0 {
+            let header: DirHeader = reader.read_struct()?;
+            consume(&mut remaining, size_of::<DirHeader>())?;
+            let count = header.count.get() + 1;
+            if count > DIR_COUNT {
+                return Err(VfsError::InvalidData);
+            }
+            for _ in 0..count {
+                let entry: DirEntryHeader = reader.read_struct()?;
+                let len = entry.size.get() as usize + 1;
+                if len > NAME_LEN {
+                    return Err(VfsError::InvalidData);
+                }
+                let name = reader.read_bytes(len)?;
+                consume(&mut remaining, size_of::<DirEntryHeader>() + len)?;
+                let name = String::from_utf8(name).map_err(|_| VfsError::InvalidData)?;
+                if name == "." || name == ".." || name.contains('/') {
+                    return Err(VfsError::InvalidData);
+                }
+                let ino = header
+                    .inode_number
+                    .get()
+                    .wrapping_add_signed(entry.inode_number.get() as i32);
+                entries.push(ListEntry {
+                    name,
+                    ino,
+                    node_type: node_type(entry.entry_type.get())?,
+                    reference: ((header.start_block.get() as u64) << 16)
+                        | entry.offset.get() as u64,
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Returns the inode number of the parent of a directory.
+    fn parent_ino(&self) -> u64 {
+        match self.info.data {
+            // The parent of the root is past the last inode.
+            InodeData::Dir { parent, .. } if self.fs.has_inode(parent) => parent as u64,
+            _ => self.info.ino as u64,
+        }
+    }
+}
+
+impl NodeOps for Inode {
+    fn inode(&self) -> u64 {
+        self.info.ino as _
+    }
+
+    fn metadata(&self) -> VfsResult<Metadata> {
+        let info = &self.info;
+        let mtime = Duration::from_secs(info.mtime as u64);
+        Ok(Metadata {
+            device: 0,
+            inode: info.ino as _,
+            nlink: info.nlink as _,
+            mode: NodePermission::from_bits_truncate(info.mode),
+            node_type: info.node_type,
+            uid: info.uid,
+            gid: info.gid,
+            size: info.size,
+            block_size: self.fs.block_size() as _,
+            blocks: info.size.div_ceil(512),
+            rdev: match info.data {
+                InodeData::Device(rdev) => rdev,
+                _ => DeviceId::default(),
+            },
+            atime: mtime,
+            mtime,
+            ctime: mtime,
+        })
+    }
+
+    fn update_metadata(&self, _update: MetadataUpdate) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn len(&self) -> VfsResult<u64> {
+        Ok(self.info.size)
+    }
+
+    fn filesystem(&self) -> &dyn FilesystemOps {
+        &*self.fs
+    }
+
+    fn sync(&self, _data_only: bool) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn flags(&self) -> NodeFlags {
+        NodeFlags::BLOCKING
+    }
+}
+
+impl FileNodeOps for Inode {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        let (blocks, fragment) = match &self.info.data {
+            InodeData::File { blocks, fragment } => (blocks, fragment),
+            InodeData::Symlink(target) => {
+                let start = target.len().min(offset as usize);
+                let len = buf.len().min(target.len() - start);
+                buf[..len].copy_from_slice(&target[start..][..len]);
+                return Ok(len);
+            }
+            _ => return Ok(0),
+        };
+        if offset >= self.info.size {
+            return Ok(0);
+        }
+        let len = buf.len().min((self.info.size - offset) as usize);
+        let block_size = self.fs.block_size() as u64;
+        let mut read = 0;
+        while read < len {
+            let pos = offset + read as u64;
+            let index = (pos / block_size) as usize;
+            let start = (pos % block_size) as usize;
+            let count = (len - read).min(block_size as usize - start);
+            let dst = &mut buf[read..][..count];
+            if let Some(&(block, size)) = blocks.get(index) {
+                if size & !DATA_UNCOMPRESSED == 0 {
+                    // A hole.
+                    dst.fill(0);
+                } else {
+                    let data = self.fs.data_block(block, size)?;
+                    let src = data
+                        .get(start..start + count)
+                        .ok_or(VfsError::InvalidData)?;
+                    dst.copy_from_slice(src);
+                }
+            } else if let Some((fragment, tail)) = *fragment {
+                let data = self.fs.fragment(fragment)?;
+                let start = tail as usize + start;
+                let src = data
+                    .get(start..start + count)
+                    .ok_or(VfsError::InvalidData)?;
+                dst.copy_from_slice(src);
+            } else {
+                return Err(VfsError::InvalidData);
+            }
+            read += count;
+        }
+        Ok(len)
+    }
+
+    fn write_at(&self, _buf: &[u8], _offset: u64) -> VfsResult<usize> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn append(&self, _buf: &[u8]) -> VfsResult<(usize, u64)> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn set_len(&self, _len: u64) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn set_symlink(&self, _target: &str) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+}
+
+impl Pollable for Inode {
+    fn poll(&self) -> IoEvents {
+        IoEvents::IN | IoEvents::OUT
+    }
+
+    fn register(&self, _context: &mut Context<'_>, _events: IoEvents) {}
+}
+
+impl DirNodeOps for Inode {
+    fn read_dir(&self, offset: u64, sink: &mut dyn DirEntrySink) -> VfsResult<usize> {
+        let entries = self.entries()?;
+        let dots = [(".", self.info.ino as u64), ("..", self.parent_ino())];
+        let entries = dots
+            .into_iter()
+            .map(|(name, ino)| (name, ino, NodeType::Directory))
+            .chain(
+                entries
+                    .iter()
+                    .map(|entry| (entry.name.as_str(), entry.ino as u64, entry.node_type)),
+            );
+        let mut count = 0;
+        for (index, (name, ino, node_type)) in entries.enumerate().skip(offset as usize) {
+            if !sink.accept(name, ino, node_type, index as u64 + 1) {
+                break;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn lookup(&self, name: &str) -> VfsResult<DirEntry> {
+        let entries = self.entries()?;
+        let entry = entries
+            .iter()
+            .find(|entry| entry.name == name)
+            .ok_or(VfsError::NotFound)?;
+        let info = InodeInfo::read(&self.fs, entry.reference)?;
+        Ok(self.create_entry(name, info))
+    }
+
+    fn create(
+        &self,
+        _name: &str,
+        _node_type: NodeType,
+        _permission: NodePermission,
+    ) -> VfsResult<DirEntry> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn link(&self, _name: &str, _node: &DirEntry) -> VfsResult<DirEntry> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn unlink(&self, _name: &str) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+
+    fn rename(&self, _src_name: &str, _dst_dir: &DirNode, _dst_name: &str) -> VfsResult<()> {
+        Err(VfsError::ReadOnlyFilesystem)
+    }
+}
+
+impl XattrOps for Inode {
+    fn get_xattr(&self, name: &str) -> AxResult<Vec<u8>> {
+        self.fs
+            .xattrs(self.info.xattr)?
+            .into_iter()
+            .find(|(it, _)| it == name)
+            .map(|(_, value)| value)
+            .ok_or_else(no_data)
+    }
+
+    fn set_xattr(&self, _name: &str, _value: &[u8], _flags: XattrFlags) -> AxResult {
+        Err(AxError::ReadOnlyFilesystem)
+    }
+
+    fn remove_xattr(&self, _name: &str) -> AxResult {
+        Err(AxError::ReadOnlyFilesystem)
+    }
+
+    fn list_xattr(&self) -> AxResult<Vec<String>> {
+        Ok(self
+            .fs
+            .xattrs(self.info.xattr)?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect())
+    }
+}
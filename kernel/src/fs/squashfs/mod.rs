@@ -0,0 +1,212 @@
+//! Read-only SquashFS (version 4.0) filesystems.
+//!
+//! Inodes, directories and the lookup tables are kept in metadata blocks of
+//! at most 8 KiB each, which are compressed independently of each other.
+//! File contents are stored in data blocks of the block size chosen when the
+//! image was made, and the tails of files smaller than a block may be packed
+//! together into shared fragment blocks.
+//!
+//! Images compressed with gzip, xz, lz4 and zstd are supported, as are
+//! extended attributes. Decompressed blocks are kept in small caches, since
+//! neighbouring reads mostly hit the same block.
+
+mod compress;
+mod fs;
+mod inode;
+
+use axfs_ng_vfs::{NodeType, VfsError, VfsResult};
+use zerocopy::{
+    FromBytes, Immutable, IntoBytes, KnownLayout,
+    little_endian::{I16, U16, U32, U64},
+};
+
+pub use self::{fs::SquashfsFilesystem, inode::Inode};
+
+/// The size of the (uncompressed) contents of a metadata block.
+const METADATA_SIZE: usize = 8192;
+/// Set in the header of a metadata block stored uncompressed.
+const METADATA_UNCOMPRESSED: u16 = 1 << 15;
+/// Set in the size of a data or fragment block stored uncompressed.
+const DATA_UNCOMPRESSED: u32 = 1 << 24;
+
+/// The largest block size of a filesystem.
+const MAX_BLOCK_SIZE: u32 = 1 << 20;
+/// The maximum length of a name.
+const NAME_LEN: usize = 256;
+/// The maximum number of entries following a directory header.
+const DIR_COUNT: u32 = 256;
+
+/// Marks a missing table in the superblock.
+const INVALID_BLK: u64 = u64::MAX;
+/// Marks the absence of a fragment or of extended attributes in an inode.
+const INVALID_INDEX: u32 = u32::MAX;
+
+/// Superblock flag: extended attributes are not stored.
+const NO_XATTRS: u16 = 1 << 9;
+
+/// The prefixes of extended attribute names, by type.
+const XATTR_PREFIXES: [&str; 3] = ["user.", "trusted.", "security."];
+/// Set in the type of an extended attribute whose value is stored elsewhere.
+const XATTR_VALUE_OOL: u16 = 1 << 8;
+
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout)]
+struct SuperBlock {
+    magic: U32,
+    inodes: U32,
+    mkfs_time: U32,
+    block_size: U32,
+    fragments: U32,
+    compression: U16,
+    block_log: U16,
+    flags: U16,
+    no_ids: U16,
+    s_major: U16,
+    s_minor: U16,
+    root_inode: U64,
+    bytes_used: U64,
+    id_table_start: U64,
+    xattr_id_table_start: U64,
+    inode_table_start: U64,
+    directory_table_start: U64,
+    fragment_table_start: U64,
+    lookup_table_start: U64,
+}
+
+/// The header common to all inodes.
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout)]
+struct InodeHeader {
+    inode_type: U16,
+    mode: U16,
+    uid: U16,
+    guid: U16,
+    mtime: U32,
+    inode_number: U32,
+}
+
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout)]
+struct DirInode {
+    start_block: U32,
+    nlink: U32,
+    file_size: U16,
+    offset: U16,
+    parent_inode: U32,
+}
+
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout)]
+struct LDirInode {
+    nlink: U32,
+    file_size: U32,
+    start_block: U32,
+    parent_inode: U32,
+    i_count: U16,
+    offset: U16,
+    xattr: U32,
+}
+
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout)]
+struct RegInode {
+    start_block: U32,
+    fragment: U32,
+    offset: U32,
+    file_size: U32,
+}
+
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout)]
+struct LRegInode {
+    start_block: U64,
+    file_size: U64,
+    sparse: U64,
+    nlink: U32,
+    fragment: U32,
+    offset: U32,
+    xattr: U32,
+}
+
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout)]
+struct SymlinkInode {
+    nlink: U32,
+    symlink_size: U32,
+}
+
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout)]
+struct DevInode {
+    nlink: U32,
+    rdev: U32,
+}
+
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout)]
+struct DirHeader {
+    count: U32,
+    start_block: U32,
+    inode_number: U32,
+}
+
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout)]
+struct DirEntryHeader {
+    offset: U16,
+    inode_number: I16,
+    entry_type: U16,
+    size: U16,
+}
+
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout)]
+struct FragmentEntry {
+    start_block: U64,
+    size: U32,
+    unused: U32,
+}
+
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout)]
+struct XattrIdTable {
+    xattr_table_start: U64,
+    xattr_ids: U32,
+    unused: U32,
+}
+
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout)]
+struct XattrId {
+    xattr: U64,
+    count: U32,
+    size: U32,
+}
+
+#[repr(C)]
+#[derive(FromBytes, IntoBytes, Immutable, KnownLayout)]
+struct XattrEntry {
+    entry_type: U16,
+    size: U16,
+}
+
+/// Returns the node type of a (basic or extended) inode type.
+fn node_type(inode_type: u16) -> VfsResult<NodeType> {
+    Ok(match inode_type {
+        1 | 8 => NodeType::Directory,
+        2 | 9 => NodeType::RegularFile,
+        3 | 10 => NodeType::Symlink,
+        4 | 11 => NodeType::BlockDevice,
+        5 | 12 => NodeType::CharacterDevice,
+        6 | 13 => NodeType::Fifo,
+        7 | 14 => NodeType::Socket,
+        _ => return Err(VfsError::InvalidData),
+    })
+}
+
+/// Splits a reference to a metadata stream into the position of the
+/// metadata block, relative to the start of its table, and the offset into
+/// the block.
+fn split_ref(reference: u64) -> (u64, usize) {
+    (reference >> 16, (reference & 0xffff) as usize)
+}
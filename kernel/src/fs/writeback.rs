@@ -0,0 +1,226 @@
+//! Writeback of dirty pages in the page cache.
+//!
+//! Pages written through the page cache only reach the disk when they are
+//! evicted, which may never happen for a busy file. Writes to files on disk
+//! therefore record the pages they dirty here, and a flusher task writes them
+//! back once they are old enough or there are too many of them.
+//!
+//! Pages written back stay in the page cache, so the cache of a file is never
+//! dropped under its shared mappings.
+
+use alloc::{
+    borrow::ToOwned,
+    collections::{btree_map::BTreeMap, btree_set::BTreeSet},
+    vec::Vec,
+};
+use core::{
+    ops::Range,
+    ptr,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use axerrno::AxResult;
+use axfs::{CachedFile, FileBackend};
+use axfs_ng_vfs::{FilesystemOps, Location};
+use axhal::time::{monotonic_time, wall_time};
+use axtask::future::{block_on, timeout_at};
+use event_listener::{Event, listener};
+use kspin::SpinNoIrq;
+use memory_addr::PAGE_SIZE_4K;
+
+use super::flush_filesystems;
+
+/// Interval of the periodic writeback, in centiseconds. Zero disables it.
+pub static DIRTY_WRITEBACK_CENTISECS: AtomicU64 = AtomicU64::new(500);
+/// Age after which dirty pages are written back, in centiseconds.
+pub static DIRTY_EXPIRE_CENTISECS: AtomicU64 = AtomicU64::new(3000);
+/// Amount of dirty data after which the flusher starts writing back
+/// everything. Zero disables the limit.
+pub static DIRTY_BACKGROUND_BYTES: AtomicU64 = AtomicU64::new(0);
+/// Amount of dirty data after which writers write back everything
+/// themselves. Zero disables the limit.
+pub static DIRTY_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// A file with dirty pages.
+struct DirtyFile {
+    cache: CachedFile,
+    pages: BTreeSet<u32>,
+    /// When the first page became dirty.
+    since: Duration,
+}
+
+/// Files with dirty pages, by their entries.
+static DIRTY_FILES: SpinNoIrq<BTreeMap<usize, DirtyFile>> = SpinNoIrq::new(BTreeMap::new());
+/// Number of dirty pages in [`DIRTY_FILES`].
+static DIRTY_PAGES: AtomicUsize = AtomicUsize::new(0);
+/// Wakes up the flusher task.
+static FLUSHER_EVENT: Event = Event::new();
+
+fn dirty_bytes() -> u64 {
+    (DIRTY_PAGES.load(Ordering::Relaxed) * PAGE_SIZE_4K) as u64
+}
+
+fn over_limit(limit: &AtomicU64) -> bool {
+    let limit = limit.load(Ordering::Relaxed);
+    limit != 0 && dirty_bytes() > limit
+}
+
+/// Records that `pages` of `cache` are dirty.
+pub fn mark_cache_dirty(cache: &CachedFile, pages: Range<u32>) {
+    if cache.in_memory() || pages.is_empty() {
+        return;
+    }
+    let key = cache.location().entry().as_ptr();
+    let mut files = DIRTY_FILES.lock();
+    let file = files.entry(key).or_insert_with(|| DirtyFile {
+        cache: cache.clone(),
+        pages: BTreeSet::new(),
+        since: monotonic_time(),
+    });
+    for pn in pages {
+        if file.pages.insert(pn) {
+            DIRTY_PAGES.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    drop(files);
+
+    if over_limit(&DIRTY_BACKGROUND_BYTES) {
+        FLUSHER_EVENT.notify(1);
+    }
+}
+
+/// Records that `len` bytes at `offset` of `file` were written, and writes
+/// back all dirty pages if there are too many of them.
+pub fn mark_dirty(file: &axfs::File, offset: u64, len: usize) {
+    let Ok(FileBackend::Cached(cache)) = file.backend() else {
+        return;
+    };
+    let start = (offset / PAGE_SIZE_4K as u64) as u32;
+    let end = (offset + len as u64).div_ceil(PAGE_SIZE_4K as u64) as u32;
+    mark_cache_dirty(cache, start..end);
+
+    if over_limit(&DIRTY_BYTES)
+        && let Err(err) = writeback(|_| true)
+    {
+        warn!("Failed to write back dirty pages: {err:?}");
+    }
+}
+
+/// Writes `pages` of `cache` that are still in the page cache back to the
+/// file. Pages that were evicted have been written back already.
+fn write_pages(cache: &CachedFile, pages: impl Iterator<Item = u32>) -> AxResult<()> {
+    let file = cache.location().entry().as_file()?;
+    let size = file.len()?;
+    for pn in pages {
+        let start = pn as u64 * PAGE_SIZE_4K as u64;
+        if start >= size {
+            break;
+        }
+        let len = (size - start).min(PAGE_SIZE_4K as u64) as usize;
+        cache.with_page(pn, |page| match page {
+            Some(page) => file.write_at(&page.data()[..len], start).map(|_| ()),
+            None => Ok(()),
+        })?;
+    }
+    Ok(())
+}
+
+/// Writes back the dirty files matching `filter`.
+///
+/// Returns whether anything was written back.
+fn writeback(filter: impl Fn(&DirtyFile) -> bool) -> AxResult<bool> {
+    let files = {
+        let mut files = DIRTY_FILES.lock();
+        let keys = files
+            .iter()
+            .filter(|(_, it)| filter(it))
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        keys.into_iter()
+            .filter_map(|key| files.remove(&key))
+            .collect::<Vec<_>>()
+    };
+    // The files are dropped outside of the lock, since dropping the last
+    // handle of a cache flushes it.
+    let written = !files.is_empty();
+    let mut result = Ok(written);
+    for file in files {
+        DIRTY_PAGES.fetch_sub(file.pages.len(), Ordering::Relaxed);
+        if let Err(err) = write_pages(&file.cache, file.pages.into_iter()) {
+            result = Err(err);
+        }
+    }
+    result
+}
+
+/// Writes back the pages of `cache` in `pages` and flushes its filesystem, so
+/// that writes through shared mappings persist.
+pub fn sync_pages(cache: &CachedFile, pages: Range<u32>) -> AxResult<()> {
+    write_pages(cache, pages)?;
+    cache.location().filesystem().flush()
+}
+
+/// Writes back `file` and flushes its filesystem, so that its content
+/// persists.
+pub fn sync_file(file: &axfs::File, data_only: bool) -> AxResult<()> {
+    let key = file.location().entry().as_ptr();
+    let dirty = DIRTY_FILES.lock().remove(&key);
+    if let Some(dirty) = &dirty {
+        DIRTY_PAGES.fetch_sub(dirty.pages.len(), Ordering::Relaxed);
+    }
+    drop(dirty);
+    file.sync(data_only)?;
+    file.location().filesystem().flush()
+}
+
+/// Writes back all dirty files in the filesystem containing `loc` and flushes
+/// it.
+pub fn sync_filesystem(loc: &Location) -> AxResult<()> {
+    let fs = loc.filesystem() as *const dyn FilesystemOps;
+    writeback(|it| ptr::addr_eq(it.cache.location().filesystem(), fs))?;
+    loc.filesystem().flush()
+}
+
+/// Writes back all dirty files and flushes all filesystems.
+pub fn sync_all() -> AxResult<()> {
+    let result = writeback(|_| true);
+    flush_filesystems()?;
+    result.map(|_| ())
+}
+
+async fn flusher_task() {
+    loop {
+        listener!(FLUSHER_EVENT => listener);
+        let interval = DIRTY_WRITEBACK_CENTISECS.load(Ordering::Relaxed);
+        let deadline = (interval != 0).then(|| wall_time() + Duration::from_millis(interval * 10));
+        let _ = timeout_at(deadline, listener).await;
+
+        let result = if over_limit(&DIRTY_BACKGROUND_BYTES) {
+            writeback(|_| true)
+        } else {
+            let expire = Duration::from_millis(DIRTY_EXPIRE_CENTISECS.load(Ordering::Relaxed) * 10);
+            let now = monotonic_time();
+            writeback(|it| now.saturating_sub(it.since) >= expire)
+        };
+        match result {
+            Ok(true) => {
+                if let Err(err) = flush_filesystems() {
+                    warn!("Failed to flush filesystems: {err:?}");
+                }
+            }
+            Ok(false) => {}
+            Err(err) => warn!("Failed to write back dirty pages: {err:?}"),
+        }
+    }
+}
+
+/// Spawns the flusher task.
+pub fn spawn_flusher_task() {
+    info!("Initialize flusher...");
+    axtask::spawn_raw(
+        || block_on(flusher_task()),
+        "flusher".to_owned(),
+        axconfig::TASK_STACK_SIZE,
+    );
+}
@@ -0,0 +1,237 @@
+//! Extended attributes.
+//!
+//! Attribute names are namespaced by their prefix. Only the `user.`,
+//! `trusted.`, `security.` namespaces and the POSIX ACLs of `system.` are
+//! supported, and only by filesystems that implement [`XattrOps`].
+//!
+//! The ext4 driver of `axfs`, which mounts the root disk, gives no access to
+//! the attributes on disk, so those of the root filesystem are kept in memory
+//! instead: they are lost on reboot. Filesystems mounted with `mount(2)`
+//! store them on disk.
+
+use alloc::{
+    borrow::ToOwned, collections::btree_map::BTreeMap, string::String, sync::Arc, vec::Vec,
+};
+use core::ptr;
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axfs::ROOT_FS_CONTEXT;
+use axfs_ng_vfs::{DirEntry, Location, NodeType};
+use axsync::Mutex;
+use bitflags::bitflags;
+use linux_raw_sys::general::{XATTR_CREATE, XATTR_REPLACE};
+
+use super::{
+    bind::bind_source, check_write, ext4::Inode as Ext4Inode, notify_attrib,
+    overlay::overlay_xattr_ops, squashfs::Inode as SquashfsInode,
+};
+use crate::pseudofs::MemoryNode;
+
+/// Maximum length of an attribute name.
+pub const XATTR_NAME_MAX: usize = 255;
+/// Maximum size of an attribute value.
+pub const XATTR_SIZE_MAX: usize = 65536;
+/// Maximum size of the list of attribute names.
+pub const XATTR_LIST_MAX: usize = 65536;
+
+bitflags! {
+    /// Flags for setting an extended attribute.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct XattrFlags: u32 {
+        /// Fail if the attribute already exists.
+        const CREATE = XATTR_CREATE;
+        /// Fail if the attribute does not exist.
+        const REPLACE = XATTR_REPLACE;
+    }
+}
+
+/// Extended attribute storage of a node.
+///
+/// Names passed in are full names, including the namespace prefix, and have
+/// been validated already.
+pub trait XattrOps: Send + Sync {
+    /// Returns the value of attribute `name`.
+    fn get_xattr(&self, name: &str) -> AxResult<Vec<u8>>;
+
+    /// Sets attribute `name` to `value`.
+    fn set_xattr(&self, name: &str, value: &[u8], flags: XattrFlags) -> AxResult;
+
+    /// Removes attribute `name`.
+    fn remove_xattr(&self, name: &str) -> AxResult;
+
+    /// Returns the names of all attributes.
+    fn list_xattr(&self) -> AxResult<Vec<String>>;
+}
+
+/// Returns the error for a missing attribute.
+pub fn no_data() -> AxError {
+    AxError::from(LinuxError::ENODATA)
+}
+
+/// Checks `flags` against whether the attribute to be set already `exists`.
+pub fn check_set_flags(flags: XattrFlags, exists: bool) -> AxResult {
+    if flags.contains(XattrFlags::CREATE) && exists {
+        return Err(AxError::AlreadyExists);
+    }
+    if flags.contains(XattrFlags::REPLACE) && !exists {
+        return Err(no_data());
+    }
+    Ok(())
+}
+
+/// Attributes of the nodes of the root filesystem mounted by the runtime, by
+/// inode number.
+static ROOT_XATTRS: Mutex<BTreeMap<u64, BTreeMap<String, Vec<u8>>>> = Mutex::new(BTreeMap::new());
+
+/// Extended attributes of a node of the root filesystem mounted by the
+/// runtime, kept in [`ROOT_XATTRS`].
+struct RootXattrs(u64);
+
+impl XattrOps for RootXattrs {
+    fn get_xattr(&self, name: &str) -> AxResult<Vec<u8>> {
+        ROOT_XATTRS
+            .lock()
+            .get(&self.0)
+            .and_then(|xattrs| xattrs.get(name))
+            .cloned()
+            .ok_or_else(no_data)
+    }
+
+    fn set_xattr(&self, name: &str, value: &[u8], flags: XattrFlags) -> AxResult {
+        let mut all = ROOT_XATTRS.lock();
+        let xattrs = all.entry(self.0).or_default();
+        check_set_flags(flags, xattrs.contains_key(name))?;
+        xattrs.insert(name.to_owned(), value.to_vec());
+        Ok(())
+    }
+
+    fn remove_xattr(&self, name: &str) -> AxResult {
+        let mut all = ROOT_XATTRS.lock();
+        let xattrs = all.get_mut(&self.0).ok_or_else(no_data)?;
+        xattrs.remove(name).ok_or_else(no_data)?;
+        if xattrs.is_empty() {
+            all.remove(&self.0);
+        }
+        Ok(())
+    }
+
+    fn list_xattr(&self) -> AxResult<Vec<String>> {
+        Ok(ROOT_XATTRS
+            .lock()
+            .get(&self.0)
+            .map(|xattrs| xattrs.keys().cloned().collect())
+            .unwrap_or_default())
+    }
+}
+
+/// Returns whether `entry` is on the root filesystem mounted by the runtime.
+fn on_runtime_root(entry: &DirEntry) -> bool {
+    ROOT_FS_CONTEXT
+        .get()
+        .is_some_and(|cx| ptr::addr_eq(cx.root_dir().filesystem(), entry.filesystem()))
+}
+
+/// Drops the attributes of `loc`, whose last link has been removed.
+///
+/// Only the attributes kept in memory for the root filesystem need this, as
+/// the inode number may be reused.
+pub fn forget_xattrs(loc: &Location) {
+    if on_runtime_root(loc.entry()) {
+        ROOT_XATTRS.lock().remove(&loc.inode());
+    }
+}
+
+pub(super) fn xattr_ops(loc: &Location) -> AxResult<Arc<dyn XattrOps>> {
+    let mut entry: DirEntry = loc.entry().clone();
+    while let Some(source) = bind_source(&entry) {
+        entry = source;
+    }
+    if let Ok(node) = entry.downcast::<MemoryNode>() {
+        return Ok(node);
+    }
+    if let Ok(node) = entry.downcast::<Ext4Inode>() {
+        return Ok(node);
+    }
+    if let Ok(node) = entry.downcast::<SquashfsInode>() {
+        return Ok(node);
+    }
+    if let Some(ops) = overlay_xattr_ops(&entry) {
+        return Ok(ops);
+    }
+    if on_runtime_root(&entry) {
+        return Ok(Arc::new(RootXattrs(entry.inode())));
+    }
+    Err(AxError::OperationNotSupported)
+}
+
+/// Checks that `name` is a valid attribute name that may be accessed on
+/// `loc`.
+///
+/// `user.` attributes are only allowed on regular files and directories;
+/// accessing them on anything else fails with `denied`.
+fn check_name(loc: &Location, name: &str, denied: AxError) -> AxResult {
+    if name.is_empty() || name.len() > XATTR_NAME_MAX {
+        return Err(AxError::OutOfRange);
+    }
+    let suffix = ["user.", "trusted.", "security.", "system."]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .ok_or(AxError::OperationNotSupported)?;
+    if suffix.is_empty() {
+        return Err(AxError::InvalidInput);
+    }
+    if name.starts_with("system.") && !matches!(suffix, "posix_acl_access" | "posix_acl_default") {
+        return Err(AxError::OperationNotSupported);
+    }
+    if name.starts_with("user.")
+        && !matches!(loc.node_type(), NodeType::RegularFile | NodeType::Directory)
+    {
+        return Err(denied);
+    }
+    Ok(())
+}
+
+/// Returns the value of attribute `name` of `loc`.
+pub fn get_xattr(loc: &Location, name: &str) -> AxResult<Vec<u8>> {
+    check_name(loc, name, no_data())?;
+    xattr_ops(loc)?.get_xattr(name)
+}
+
+/// Sets attribute `name` of `loc` to `value`.
+pub fn set_xattr(loc: &Location, name: &str, value: &[u8], flags: XattrFlags) -> AxResult {
+    check_name(loc, name, AxError::OperationNotPermitted)?;
+    if value.len() > XATTR_SIZE_MAX {
+        return Err(AxError::ArgumentListTooLong);
+    }
+    check_write(loc)?;
+    xattr_ops(loc)?.set_xattr(name, value, flags)?;
+    notify_attrib(loc);
+    Ok(())
+}
+
+/// Removes attribute `name` of `loc`.
+pub fn remove_xattr(loc: &Location, name: &str) -> AxResult {
+    check_name(loc, name, AxError::OperationNotPermitted)?;
+    check_write(loc)?;
+    xattr_ops(loc)?.remove_xattr(name)?;
+    notify_attrib(loc);
+    Ok(())
+}
+
+/// Returns the names of the attributes of `loc`, each terminated by a NUL.
+pub fn list_xattr(loc: &Location) -> AxResult<Vec<u8>> {
+    let names = match xattr_ops(loc) {
+        Ok(ops) => ops.list_xattr()?,
+        Err(AxError::OperationNotSupported) => Vec::new(),
+        Err(err) => return Err(err),
+    };
+    let mut list = Vec::new();
+    for name in names {
+        list.extend_from_slice(name.as_bytes());
+        list.push(0);
+    }
+    if list.len() > XATTR_LIST_MAX {
+        return Err(AxError::ArgumentListTooLong);
+    }
+    Ok(list)
+}
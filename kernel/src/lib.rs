@@ -17,6 +17,7 @@ pub mod entry;
 
 mod config;
 mod file;
+mod fs;
 mod mm;
 mod pseudofs;
 mod syscall;
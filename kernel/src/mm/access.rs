@@ -22,6 +22,7 @@ use starry_vm::{VmError, VmIo, VmResult, vm_load_until_nul, vm_read_slice, vm_wr
 
 use crate::{
     config::{USER_SPACE_BASE, USER_SPACE_SIZE},
+    mm::PageFaultResult,
     task::AsThread,
 };
 
@@ -268,10 +269,18 @@ fn handle_page_fault(vaddr: VirtAddr, access_flags: MappingFlags) -> bool {
         return false;
     }
 
-    thr.proc_data
+    let result = thr
+        .proc_data
         .aspace
         .lock()
-        .handle_page_fault(vaddr, access_flags)
+        .handle_page_fault(vaddr, access_flags);
+    match result {
+        PageFaultResult::Handled => true,
+        // The access is retried once the fault is resolved, and fails if the
+        // wait is interrupted.
+        PageFaultResult::UserFault(uffd, uffd_flags) => uffd.handle_fault(vaddr, uffd_flags),
+        PageFaultResult::Invalid => false,
+    }
 }
 
 pub fn vm_load_string(ptr: *const c_char) -> AxResult<String> {
@@ -73,6 +73,27 @@ impl FrameTableRefCount {
 
 static FRAME_TABLE: SpinNoIrq<FrameTableRefCount> = SpinNoIrq::new(FrameTableRefCount::new());
 
+/// A reference to a frame of a copy-on-write mapping held by the kernel, see
+/// [`AddrSpace::gift_page`].
+pub struct GiftedPage(PhysAddr);
+
+impl GiftedPage {
+    /// Returns the physical address of the frame.
+    pub fn paddr(&self) -> PhysAddr {
+        self.0
+    }
+}
+
+impl Drop for GiftedPage {
+    fn drop(&mut self) {
+        let frame = FRAME_TABLE
+            .lock()
+            .get_frame_ref(self.0)
+            .expect("dropping unreferenced gifted frame");
+        frame.lock().drop_frame(self.0, PageSize::Size4K);
+    }
+}
+
 /// Copy-on-write mapping backend.
 ///
 /// This corresponds to the `MAP_PRIVATE` flag.
@@ -156,6 +177,35 @@ impl CowBackend {
 
         Ok(())
     }
+
+    /// Takes a reference to the frame mapped at `vaddr`, write-protecting it
+    /// so that the next write copies the frame. Returns `None` if the page is
+    /// not mapped or not a 4K page.
+    pub fn gift(
+        &self,
+        vaddr: VirtAddr,
+        flags: MappingFlags,
+        pt: &mut PageTableCursor,
+    ) -> AxResult<Option<GiftedPage>> {
+        if self.size != PageSize::Size4K {
+            return Ok(None);
+        }
+        let Ok((paddr, ..)) = pt.query(vaddr) else {
+            return Ok(None);
+        };
+        let frame = FRAME_TABLE
+            .lock()
+            .get_frame_ref(paddr)
+            .ok_or(AxError::BadAddress)?;
+        let mut frame = frame.lock();
+        assert!(frame.0 > 0, "referencing unreferenced frame");
+        if frame.0 == u8::MAX - 1 {
+            return Ok(None);
+        }
+        frame.0 += 1;
+        pt.protect(vaddr, flags - MappingFlags::WRITE)?;
+        Ok(Some(GiftedPage(paddr)))
+    }
 }
 
 impl BackendOps for CowBackend {
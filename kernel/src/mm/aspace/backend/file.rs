@@ -3,15 +3,19 @@ use alloc::{
     sync::{Arc, Weak},
     vec::Vec,
 };
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use axerrno::{AxError, AxResult};
 use axfs::{CachedFile, FileFlags};
 use axhal::paging::{MappingFlags, PageSize, PageTableCursor, PagingError};
 use axsync::Mutex;
-use memory_addr::{PAGE_SIZE_4K, VirtAddr, VirtAddrRange};
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K, VirtAddr, VirtAddrRange};
 
 use super::{AddrSpace, Backend, BackendOps, PopulateCallback, pages_in};
+use crate::{fs::mark_cache_dirty, pseudofs::charge_pages};
 
 #[doc(hidden)]
 pub struct FileBackendInner {
@@ -21,6 +25,9 @@ pub struct FileBackendInner {
     offset_page: u32,
     handle: AtomicUsize,
     futex_handle: Arc<()>,
+    /// Held while the file can be written through this mapping, see
+    /// [`Memfd::map_shared`](crate::file::memfd::Memfd::map_shared).
+    writer: Option<Arc<()>>,
 }
 impl Drop for FileBackendInner {
     fn drop(&mut self) {
@@ -104,6 +111,18 @@ impl FileBackend {
     pub fn futex_handle(&self) -> Weak<()> {
         Arc::downgrade(&self.0.futex_handle)
     }
+
+    /// Returns the page cache of the mapped file.
+    pub fn cache(&self) -> &CachedFile {
+        &self.0.cache
+    }
+
+    /// Returns the page numbers in the file of the pages mapped at `range`.
+    pub fn file_pages(&self, range: VirtAddrRange) -> Range<u32> {
+        let page =
+            |addr: VirtAddr| ((addr - self.0.start) / PAGE_SIZE_4K) as u32 + self.0.offset_page;
+        page(range.start)..page(range.end.align_up_4k())
+    }
 }
 
 impl BackendOps for FileBackend {
@@ -168,6 +187,7 @@ impl BackendOps for FileBackend {
                             pages += 1;
                             AxResult::Ok(())
                         })?;
+                        mark_cache_dirty(&self.0.cache, pn..pn + 1);
                     } else if page_flags.contains(access_flags) {
                         pages += 1;
                     }
@@ -182,6 +202,9 @@ impl BackendOps for FileBackend {
                     } else {
                         flags - MappingFlags::WRITE
                     };
+                    if self.0.cache.in_memory() {
+                        charge_pages(self.0.cache.location(), pn as u64 * PAGE_SIZE_4K as u64, 1)?;
+                    }
                     self.0.cache.with_page_or_insert(pn, |page, evicted| {
                         if let Some((pn, _)) = evicted {
                             to_be_evicted.push(pn);
@@ -224,6 +247,7 @@ impl BackendOps for FileBackend {
             offset_page: self.0.offset_page,
             handle: AtomicUsize::new(0),
             futex_handle: self.0.futex_handle.clone(),
+            writer: self.0.writer.clone(),
         });
         inner.register_listener(new_aspace);
         Ok(Backend::File(FileBackend(inner)))
@@ -236,6 +260,7 @@ impl Backend {
         cache: CachedFile,
         flags: FileFlags,
         offset: usize,
+        writer: Option<Arc<()>>,
         aspace: &Arc<Mutex<AddrSpace>>,
     ) -> Self {
         let offset_page = (offset / PAGE_SIZE_4K) as u32;
@@ -246,6 +271,7 @@ impl Backend {
             offset_page,
             handle: AtomicUsize::new(0),
             futex_handle: Arc::new(()),
+            writer,
         });
         inner.register_listener(aspace);
         Self::File(FileBackend(inner))
@@ -17,7 +17,7 @@ mod file;
 mod linear;
 mod shared;
 
-pub use self::shared::SharedPages;
+pub use self::{cow::GiftedPage, shared::SharedPages};
 use super::AddrSpace;
 
 fn divide_page(size: usize, page_size: PageSize) -> usize {
@@ -1,4 +1,4 @@
-use alloc::sync::Arc;
+use alloc::{collections::btree_set::BTreeSet, sync::Arc, vec::Vec};
 use core::{fmt, ops::DerefMut};
 
 use axerrno::{AxError, AxResult, ax_bail};
@@ -14,14 +14,32 @@ use memory_addr::{
 use memory_set::{MemoryArea, MemorySet};
 
 mod backend;
+mod userfault;
 
 pub use self::backend::*;
+use self::userfault::UserfaultRange;
+use crate::file::userfaultfd::UserFaultFd;
+
+/// The result of handling a page fault.
+pub enum PageFaultResult {
+    /// The fault is resolved.
+    Handled,
+    /// The access is invalid.
+    Invalid,
+    /// The fault is to be resolved by the reader of a userfaultfd, and is
+    /// reported with the `UFFD_PAGEFAULT_FLAG_*` flags.
+    UserFault(Arc<UserFaultFd>, u32),
+}
 
 /// The virtual memory address space.
 pub struct AddrSpace {
     va_range: VirtAddrRange,
     areas: MemorySet<Backend>,
     pt: PageTable,
+    /// Ranges registered with userfaultfds.
+    userfaults: Vec<UserfaultRange>,
+    /// Pages write-protected through userfaultfds.
+    userfault_wp: BTreeSet<VirtAddr>,
 }
 
 impl AddrSpace {
@@ -66,6 +84,8 @@ impl AddrSpace {
             va_range: VirtAddrRange::from_start_size(base, size),
             areas: MemorySet::new(),
             pt: PageTable::try_new().map_err(|_| AxError::NoMemory)?,
+            userfaults: Vec::new(),
+            userfault_wp: BTreeSet::new(),
         })
     }
 
@@ -176,6 +196,30 @@ impl AddrSpace {
         Ok(())
     }
 
+    /// Gives the page at `vaddr` to the kernel, for `vmsplice` with
+    /// `SPLICE_F_GIFT`.
+    ///
+    /// Only pages of private mappings can be given away. The process shares
+    /// them copy-on-write from then on, so it does not see the kernel use them
+    /// and vice versa. Returns `None` for other pages, which are to be copied.
+    pub fn gift_page(&mut self, vaddr: VirtAddr) -> AxResult<Option<GiftedPage>> {
+        let vaddr = vaddr.align_down_4k();
+        if !self
+            .areas
+            .find(vaddr)
+            .is_some_and(|area| area.flags().contains(MappingFlags::READ))
+        {
+            return Err(AxError::BadAddress);
+        }
+        self.populate_area(vaddr, PAGE_SIZE_4K, MappingFlags::READ)
+            .map_err(|_| AxError::BadAddress)?;
+        let area = self.areas.find(vaddr).unwrap();
+        match area.backend() {
+            Backend::Cow(cow) => cow.gift(vaddr, area.flags(), &mut self.pt.cursor()),
+            _ => Ok(None),
+        }
+    }
+
     /// Removes mappings within the specified virtual address range.
     ///
     /// Returns an error if the address range is out of the address space or not
@@ -184,6 +228,7 @@ impl AddrSpace {
         self.validate_region(start, size)?;
 
         self.areas.unmap(start, size, &mut self.pt)?;
+        self.cut_userfaults(VirtAddrRange::from_start_size(start, size), |_| true);
         Ok(())
     }
 
@@ -261,6 +306,8 @@ impl AddrSpace {
     /// Removes all mappings in the address space.
     pub fn clear(&mut self) {
         self.areas.clear(&mut self.pt).unwrap();
+        self.userfaults.clear();
+        self.userfault_wp.clear();
     }
 
     /// Checks whether an access to the specified memory region is valid.
@@ -302,15 +349,22 @@ impl AddrSpace {
     ///
     /// `access_flags` indicates the access type that caused the page fault.
     ///
-    /// Returns `true` if the page fault is handled successfully (not a real
-    /// fault).
-    pub fn handle_page_fault(&mut self, vaddr: VirtAddr, access_flags: PageFaultFlags) -> bool {
+    /// Faults in ranges registered with a userfaultfd are left to its reader,
+    /// see [`PageFaultResult::UserFault`].
+    pub fn handle_page_fault(
+        &mut self,
+        vaddr: VirtAddr,
+        access_flags: PageFaultFlags,
+    ) -> PageFaultResult {
         if !self.va_range.contains(vaddr) {
-            return false;
+            return PageFaultResult::Invalid;
         }
         if let Some(area) = self.areas.find(vaddr) {
             let flags = area.flags();
             if flags.contains(access_flags) {
+                if let Some((uffd, flags)) = self.find_userfault(vaddr, access_flags) {
+                    return PageFaultResult::UserFault(uffd, flags);
+                }
                 let page_size = area.backend().page_size();
                 let populate_result = area.backend().populate(
                     VirtAddrRange::from_start_size(vaddr.align_down(page_size), page_size as _),
@@ -325,19 +379,19 @@ impl AddrSpace {
                         }
                         if n == 0 {
                             warn!("No pages populated for {vaddr:?} ({flags:?})");
-                            false
+                            PageFaultResult::Invalid
                         } else {
-                            true
+                            PageFaultResult::Handled
                         }
                     }
                     Err(err) => {
                         warn!("Failed to populate pages for {vaddr:?} ({flags:?}): {err}");
-                        false
+                        PageFaultResult::Invalid
                     }
                 };
             }
         }
-        false
+        PageFaultResult::Invalid
     }
 
     /// Attempts to clone the current address space into a new one.
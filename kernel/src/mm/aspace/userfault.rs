@@ -0,0 +1,230 @@
+//! Ranges of an address space whose faults are handled by userfaultfd.
+
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{mem, ptr};
+
+use axerrno::{AxError, AxResult};
+use axhal::{
+    mem::phys_to_virt,
+    paging::{MappingFlags, PageSize, PagingError},
+};
+use linux_raw_sys::general::{
+    UFFD_PAGEFAULT_FLAG_WP, UFFD_PAGEFAULT_FLAG_WRITE, UFFDIO_REGISTER_MODE_MISSING,
+    UFFDIO_REGISTER_MODE_WP,
+};
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K, PageIter4K, VirtAddr, VirtAddrRange};
+
+use super::{AddrSpace, Backend, BackendOps};
+use crate::file::userfaultfd::UserFaultFd;
+
+/// A range registered with a userfaultfd.
+pub(super) struct UserfaultRange {
+    range: VirtAddrRange,
+    /// The `UFFDIO_REGISTER_MODE_*` flags.
+    mode: u64,
+    uffd: Weak<UserFaultFd>,
+}
+
+impl UserfaultRange {
+    fn is_live(&self) -> bool {
+        self.uffd.strong_count() > 0
+    }
+}
+
+impl AddrSpace {
+    /// Returns the userfaultfd that resolves a fault at `vaddr` with
+    /// `access_flags`, and the `UFFD_PAGEFAULT_FLAG_*` flags to report it
+    /// with.
+    pub(super) fn find_userfault(
+        &self,
+        vaddr: VirtAddr,
+        access_flags: MappingFlags,
+    ) -> Option<(Arc<UserFaultFd>, u32)> {
+        let reg = self.userfaults.iter().find(|it| it.range.contains(vaddr))?;
+        let page = vaddr.align_down_4k();
+        let write = access_flags.contains(MappingFlags::WRITE);
+        let flags = match self.pt.query(page) {
+            Err(PagingError::NotMapped) if reg.mode & UFFDIO_REGISTER_MODE_MISSING as u64 != 0 => {
+                if write {
+                    UFFD_PAGEFAULT_FLAG_WRITE
+                } else {
+                    0
+                }
+            }
+            Ok(_) if write && self.userfault_wp.contains(&page) => {
+                UFFD_PAGEFAULT_FLAG_WRITE | UFFD_PAGEFAULT_FLAG_WP
+            }
+            _ => return None,
+        };
+        Some((reg.uffd.upgrade()?, flags))
+    }
+
+    /// Registers `range` with `uffd` in `mode`, replacing the previous
+    /// registrations of `uffd` in it.
+    ///
+    /// Only private mappings of 4K pages can be registered.
+    pub fn register_userfault(
+        &mut self,
+        range: VirtAddrRange,
+        mode: u64,
+        uffd: Weak<UserFaultFd>,
+    ) -> AxResult {
+        let mut start = range.start;
+        while start < range.end {
+            let area = self.areas.find(start).ok_or(AxError::InvalidInput)?;
+            if !matches!(area.backend(), Backend::Cow(_))
+                || area.backend().page_size() != PageSize::Size4K
+            {
+                return Err(AxError::InvalidInput);
+            }
+            start = area.end();
+        }
+        if self
+            .userfaults
+            .iter()
+            .any(|it| it.range.overlaps(range) && it.is_live() && !Weak::ptr_eq(&it.uffd, &uffd))
+        {
+            return Err(AxError::ResourceBusy);
+        }
+
+        self.userfaults.retain(UserfaultRange::is_live);
+        self.cut_userfaults(range, |it| Weak::ptr_eq(&it.uffd, &uffd));
+        self.userfaults.push(UserfaultRange { range, mode, uffd });
+        Ok(())
+    }
+
+    /// Unregisters `range` from `uffd`.
+    pub fn unregister_userfault(&mut self, range: VirtAddrRange, uffd: &Weak<UserFaultFd>) {
+        self.cut_userfaults(range, |it| Weak::ptr_eq(&it.uffd, uffd));
+    }
+
+    /// Unregisters all ranges of the userfaultfd `uffd`, as it is released.
+    pub fn release_userfaults(&mut self, uffd: *const UserFaultFd) {
+        let (released, kept) = mem::take(&mut self.userfaults)
+            .into_iter()
+            .partition::<Vec<_>, _>(|it| ptr::eq(it.uffd.as_ptr(), uffd));
+        self.userfaults = kept;
+        for reg in released {
+            self.userfault_wp.retain(|it| !reg.range.contains(*it));
+        }
+    }
+
+    /// Removes the parts within `range` of the registrations matching
+    /// `filter`, and write protection along with them.
+    pub(super) fn cut_userfaults(
+        &mut self,
+        range: VirtAddrRange,
+        filter: impl Fn(&UserfaultRange) -> bool,
+    ) {
+        let mut result = Vec::with_capacity(self.userfaults.len());
+        for reg in mem::take(&mut self.userfaults) {
+            if !reg.range.overlaps(range) || !filter(&reg) {
+                result.push(reg);
+                continue;
+            }
+            if reg.range.start < range.start {
+                result.push(UserfaultRange {
+                    range: VirtAddrRange::new(reg.range.start, range.start),
+                    mode: reg.mode,
+                    uffd: reg.uffd.clone(),
+                });
+            }
+            if reg.range.end > range.end {
+                result.push(UserfaultRange {
+                    range: VirtAddrRange::new(range.end, reg.range.end),
+                    mode: reg.mode,
+                    uffd: reg.uffd.clone(),
+                });
+            }
+            let cut = VirtAddrRange::new(
+                reg.range.start.max(range.start),
+                reg.range.end.min(range.end),
+            );
+            self.userfault_wp.retain(|it| !cut.contains(*it));
+        }
+        self.userfaults = result;
+    }
+
+    fn find_userfault_range(&self, page: VirtAddr) -> AxResult<&UserfaultRange> {
+        self.userfaults
+            .iter()
+            .find(|it| it.range.contains(page) && it.is_live())
+            .ok_or(AxError::NotFound)
+    }
+
+    /// Maps a page at the missing `page` of a registered range, filled with
+    /// `data`, or zeroed if it is `None`. The page is write-protected if `wp`
+    /// is set.
+    pub fn fill_userfault_page(
+        &mut self,
+        page: VirtAddr,
+        data: Option<&[u8; PAGE_SIZE_4K]>,
+        wp: bool,
+    ) -> AxResult {
+        let reg = self.find_userfault_range(page)?;
+        if wp && reg.mode & UFFDIO_REGISTER_MODE_WP as u64 == 0 {
+            return Err(AxError::InvalidInput);
+        }
+        if self.pt.query(page).is_ok() {
+            return Err(AxError::AlreadyExists);
+        }
+        let area = self.areas.find(page).ok_or(AxError::NotFound)?;
+        let flags = area.flags();
+        let (_, callback) = area.backend().populate(
+            VirtAddrRange::from_start_size(page, PAGE_SIZE_4K),
+            flags,
+            MappingFlags::empty(),
+            &mut self.pt.cursor(),
+        )?;
+        if let Some(cb) = callback {
+            cb(self);
+        }
+
+        let (paddr, ..) = self.pt.query(page).map_err(|_| AxError::BadAddress)?;
+        let dst = phys_to_virt(paddr).as_mut_ptr();
+        unsafe {
+            match data {
+                Some(data) => ptr::copy_nonoverlapping(data.as_ptr(), dst, PAGE_SIZE_4K),
+                None => ptr::write_bytes(dst, 0, PAGE_SIZE_4K),
+            }
+        }
+        if wp {
+            self.pt
+                .cursor()
+                .protect(page, flags - MappingFlags::WRITE)?;
+            self.userfault_wp.insert(page);
+        }
+        Ok(())
+    }
+
+    /// Write-protects the mapped pages in `range`, or removes their write
+    /// protection if `protect` is not set.
+    ///
+    /// The whole range must be registered in write-protect mode.
+    pub fn write_protect_userfault(&mut self, range: VirtAddrRange, protect: bool) -> AxResult {
+        let pages = || PageIter4K::new(range.start, range.end).ok_or(AxError::InvalidInput);
+        for page in pages()? {
+            if self.find_userfault_range(page)?.mode & UFFDIO_REGISTER_MODE_WP as u64 == 0 {
+                return Err(AxError::NotFound);
+            }
+        }
+
+        let mut cursor = self.pt.cursor();
+        for page in pages()? {
+            let Ok((_, flags, _)) = cursor.query(page) else {
+                continue;
+            };
+            if protect {
+                cursor.protect(page, flags - MappingFlags::WRITE)?;
+                self.userfault_wp.insert(page);
+            } else {
+                // Write access is restored by the next write fault on the page.
+                self.userfault_wp.remove(&page);
+            }
+        }
+        Ok(())
+    }
+}
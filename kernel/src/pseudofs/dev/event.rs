@@ -326,24 +326,26 @@ pub fn input_devices(fs: Arc<SimpleFs>) -> DirMapping {
     let mut input_id = 0;
     let input_devices = axinput::take_inputs();
     let mut keys = [0; 0x300usize.div_ceil(8)];
-    for (i, mut device) in input_devices.into_iter().enumerate() {
+    for mut device in input_devices {
         assert!(device.get_event_bits(EventType::Key, &mut keys).unwrap());
 
-        let dev = Device::new(
-            fs.clone(),
-            NodeType::CharacterDevice,
-            DeviceId::new(13, (i + 1) as _),
-            Arc::new(EventDev::new(device)),
-        );
-
         const BTN_MOUSE: usize = 0x110;
-        if keys[BTN_MOUSE / 8] & (1 << (BTN_MOUSE % 8)) != 0 {
+        // Numbered like on Linux, so that nodes created with `mknod` match.
+        let (name, minor) = if keys[BTN_MOUSE / 8] & (1 << (BTN_MOUSE % 8)) != 0 {
             // Mouse
-            inputs.add("mice", dev);
+            ("mice".into(), 63)
         } else {
-            inputs.add(format!("event{input_id}"), dev);
             input_id += 1;
-        }
+            (format!("event{}", input_id - 1), 63 + input_id)
+        };
+        let dev = Device::new(
+            fs.clone(),
+            NodeType::CharacterDevice,
+            DeviceId::new(13, minor),
+            Arc::new(EventDev::new(device)),
+        )
+        .register();
+        inputs.add(name, dev);
     }
     inputs
 }
@@ -115,8 +115,7 @@ impl DeviceOps for LoopDevice {
             }
             // TODO: the following should apply to any block devices
             BLKGETSIZE | BLKGETSIZE64 => {
-                let file = self.clone_file()?;
-                let sectors = file.location().len()? / 512;
+                let sectors = self.capacity()? / 512;
                 if cmd == BLKGETSIZE {
                     (arg as *mut u32).vm_write(sectors as _)?;
                 } else {
@@ -160,6 +159,10 @@ impl DeviceOps for LoopDevice {
         }
     }
 
+    fn capacity(&self) -> VfsResult<u64> {
+        self.clone_file()?.location().len()
+    }
+
     fn flags(&self) -> NodeFlags {
         NodeFlags::NON_CACHEABLE
     }
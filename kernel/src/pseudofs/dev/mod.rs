@@ -122,6 +122,27 @@ impl DeviceOps for Full {
     }
 }
 
+/// `/dev/fuse`, every open of which starts a new FUSE connection.
+pub struct FuseDevice;
+
+impl DeviceOps for FuseDevice {
+    fn read_at(&self, _buf: &mut [u8], _offset: u64) -> VfsResult<usize> {
+        Err(AxError::NoSuchDevice)
+    }
+
+    fn write_at(&self, _buf: &[u8], _offset: u64) -> VfsResult<usize> {
+        Err(AxError::NoSuchDevice)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn flags(&self) -> NodeFlags {
+        NodeFlags::NON_CACHEABLE | NodeFlags::STREAM
+    }
+}
+
 struct CpuDmaLatency;
 
 impl DeviceOps for CpuDmaLatency {
@@ -151,7 +172,8 @@ fn builder(fs: Arc<SimpleFs>) -> DirMaker {
             NodeType::CharacterDevice,
             DeviceId::new(1, 3),
             Arc::new(Null),
-        ),
+        )
+        .register(),
     );
     root.add(
         "zero",
@@ -160,7 +182,8 @@ fn builder(fs: Arc<SimpleFs>) -> DirMaker {
             NodeType::CharacterDevice,
             DeviceId::new(1, 5),
             Arc::new(Zero),
-        ),
+        )
+        .register(),
     );
     root.add(
         "full",
@@ -169,7 +192,8 @@ fn builder(fs: Arc<SimpleFs>) -> DirMaker {
             NodeType::CharacterDevice,
             DeviceId::new(1, 7),
             Arc::new(Full),
-        ),
+        )
+        .register(),
     );
     root.add(
         "random",
@@ -178,7 +202,8 @@ fn builder(fs: Arc<SimpleFs>) -> DirMaker {
             NodeType::CharacterDevice,
             DeviceId::new(1, 8),
             Arc::new(Random::new()),
-        ),
+        )
+        .register(),
     );
     root.add(
         "urandom",
@@ -187,7 +212,8 @@ fn builder(fs: Arc<SimpleFs>) -> DirMaker {
             NodeType::CharacterDevice,
             DeviceId::new(1, 9),
             Arc::new(Random::new()),
-        ),
+        )
+        .register(),
     );
     root.add(
         "rtc0",
@@ -196,7 +222,8 @@ fn builder(fs: Arc<SimpleFs>) -> DirMaker {
             NodeType::CharacterDevice,
             rtc::RTC0_DEVICE_ID,
             Arc::new(rtc::Rtc),
-        ),
+        )
+        .register(),
     );
     if axdisplay::has_display() {
         root.add(
@@ -206,7 +233,8 @@ fn builder(fs: Arc<SimpleFs>) -> DirMaker {
                 NodeType::CharacterDevice,
                 DeviceId::new(29, 0),
                 Arc::new(fb::FrameBuffer::new()),
-            ),
+            )
+            .register(),
         );
     }
 
@@ -217,7 +245,8 @@ fn builder(fs: Arc<SimpleFs>) -> DirMaker {
             NodeType::CharacterDevice,
             DeviceId::new(5, 0),
             Arc::new(tty::CurrentTty),
-        ),
+        )
+        .register(),
     );
     root.add(
         "console",
@@ -226,7 +255,8 @@ fn builder(fs: Arc<SimpleFs>) -> DirMaker {
             NodeType::CharacterDevice,
             DeviceId::new(5, 1),
             tty::N_TTY.clone(),
-        ),
+        )
+        .register(),
     );
 
     root.add(
@@ -236,7 +266,8 @@ fn builder(fs: Arc<SimpleFs>) -> DirMaker {
             NodeType::CharacterDevice,
             DeviceId::new(5, 2),
             Arc::new(tty::Ptmx(fs.clone())),
-        ),
+        )
+        .register(),
     );
     root.add(
         "pts",
@@ -256,7 +287,8 @@ fn builder(fs: Arc<SimpleFs>) -> DirMaker {
             NodeType::CharacterDevice,
             DeviceId::new(114, 514),
             Arc::new(memtrack::MemTrack),
-        ),
+        )
+        .register(),
     );
 
     root.add(
@@ -266,7 +298,19 @@ fn builder(fs: Arc<SimpleFs>) -> DirMaker {
             NodeType::CharacterDevice,
             DeviceId::new(10, 1024),
             Arc::new(CpuDmaLatency),
-        ),
+        )
+        .register(),
+    );
+
+    root.add(
+        "fuse",
+        Device::new(
+            fs.clone(),
+            NodeType::CharacterDevice,
+            DeviceId::new(10, 229),
+            Arc::new(FuseDevice),
+        )
+        .register(),
     );
 
     // This is mounted to a tmpfs in `new_procfs`
@@ -277,7 +321,7 @@ fn builder(fs: Arc<SimpleFs>) -> DirMaker {
 
     // Loop devices
     for i in 0..16 {
-        let dev_id = DeviceId::new(7, 0);
+        let dev_id = DeviceId::new(7, i);
         root.add(
             format!("loop{i}"),
             Device::new(
@@ -285,7 +329,8 @@ fn builder(fs: Arc<SimpleFs>) -> DirMaker {
                 NodeType::BlockDevice,
                 dev_id,
                 Arc::new(r#loop::LoopDevice::new(i, dev_id)),
-            ),
+            )
+            .register(),
         );
     }
 
@@ -1,16 +1,19 @@
-use alloc::sync::Arc;
+use alloc::{borrow::ToOwned, collections::btree_map::BTreeMap, sync::Arc};
 use core::{any::Any, task::Context};
 
+use axerrno::{AxError, AxResult, LinuxError};
 use axfs::CachedFile;
 use axfs_ng_vfs::{
-    DeviceId, FileNodeOps, FilesystemOps, Metadata, MetadataUpdate, NodeFlags, NodeOps,
-    NodePermission, NodeType, VfsError, VfsResult,
+    DeviceId, DirEntry, FileNode, FileNodeOps, FilesystemOps, Location, Metadata, MetadataUpdate,
+    NodeFlags, NodeOps, NodePermission, NodeType, Reference, VfsError, VfsResult,
 };
 use axpoll::{IoEvents, Pollable};
 use inherit_methods_macro::inherit_methods;
+use kspin::SpinNoIrq;
 use memory_addr::PhysAddrRange;
 
 use super::{SimpleFs, SimpleFsNode};
+use crate::mm::SharedPages;
 
 /// Mmap behavior for devices.
 pub enum DeviceMmap {
@@ -22,6 +25,8 @@ pub enum DeviceMmap {
     ReadOnly,
     /// Maps to a cached file.
     Cache(CachedFile),
+    /// Maps to pages shared with the kernel.
+    Shared(Arc<SharedPages>),
 }
 
 /// Trait for device operations.
@@ -48,12 +53,20 @@ pub trait DeviceOps: Send + Sync {
         DeviceMmap::None
     }
 
+    /// Returns the capacity of a block device in bytes.
+    fn capacity(&self) -> VfsResult<u64> {
+        Err(VfsError::NotATty)
+    }
+
     /// Returns the flags for the device node.
     fn flags(&self) -> NodeFlags {
         NodeFlags::empty()
     }
 }
 
+/// Registered devices by whether they are block devices and their number.
+static DEVICES: SpinNoIrq<BTreeMap<(bool, u64), Arc<Device>>> = SpinNoIrq::new(BTreeMap::new());
+
 /// A device node in the filesystem.
 pub struct Device {
     node: SimpleFsNode,
@@ -73,6 +86,17 @@ impl Device {
         Arc::new(Self { node, ops })
     }
 
+    /// Registers the device under its type and number, so that device nodes
+    /// created with `mknod` on other filesystems reach it.
+    pub fn register(self: Arc<Self>) -> Arc<Self> {
+        let key = {
+            let metadata = self.node.metadata.lock();
+            (metadata.node_type == NodeType::BlockDevice, metadata.rdev.0)
+        };
+        DEVICES.lock().insert(key, self.clone());
+        self
+    }
+
     /// Returns the inner device operations.
     pub fn inner(&self) -> &Arc<dyn DeviceOps> {
         &self.ops
@@ -162,3 +186,37 @@ impl Pollable for Device {
         }
     }
 }
+
+/// Returns the device the device node `loc` refers to, which is either a
+/// devfs node itself or a node created with `mknod` anywhere else.
+pub fn find_device(loc: &Location) -> AxResult<Arc<Device>> {
+    if let Ok(device) = loc.entry().downcast::<Device>() {
+        return Ok(device);
+    }
+    let block = match loc.node_type() {
+        NodeType::CharacterDevice => false,
+        NodeType::BlockDevice => true,
+        _ => return Err(AxError::NoSuchDevice),
+    };
+    let rdev = loc.metadata()?.rdev;
+    DEVICES
+        .lock()
+        .get(&(block, rdev.0))
+        .cloned()
+        .ok_or_else(|| AxError::from(LinuxError::ENXIO))
+}
+
+/// Returns a location for the device node `loc` whose node is the device it
+/// refers to, so that opening it reaches the device.
+pub fn device_location(loc: &Location) -> AxResult<Location> {
+    if loc.entry().downcast::<Device>().is_ok() {
+        return Ok(loc.clone());
+    }
+    let device = find_device(loc)?;
+    let entry = DirEntry::new_file(
+        FileNode::new(device),
+        loc.node_type(),
+        Reference::new(loc.entry().parent(), loc.name().to_owned()),
+    );
+    Ok(Location::new(loc.mountpoint().clone(), entry))
+}
@@ -16,9 +16,14 @@ use axfs_ng_vfs::{
     DirNodeOps, FileNodeOps, Filesystem, NodePermission, WeakDirEntry,
     path::{Path, PathBuf},
 };
-pub use tmp::MemoryFs;
+pub use proc::fd_link_target;
+pub use tmp::{
+    MemoryFs, MemoryNode, TmpfsOptions, TruncateListener, add_truncate_listener, charge_pages,
+    tmpfs_data_pages, uncharge_pages,
+};
 
 pub use self::{device::*, dir::*, file::*, fs::*};
+use crate::fs::MountFlags;
 
 /// A callback that builds a `Arc<dyn DirNodeOps>` for a given
 /// `WeakDirEntry`.
@@ -48,11 +53,17 @@ impl<T: FileNodeOps> From<Arc<T>> for NodeOpsMux {
 
 const DIR_PERMISSION: NodePermission = NodePermission::from_bits_truncate(0o755);
 
-fn mount_at(fs: &FsContext, path: &str, mount_fs: Filesystem) -> LinuxResult<()> {
+fn mount_at(
+    fs: &FsContext,
+    path: &str,
+    mount_fs: Filesystem,
+    source: &str,
+    flags: MountFlags,
+) -> LinuxResult<()> {
     if fs.resolve(path).is_err() {
         fs.create_dir(path, DIR_PERMISSION)?;
     }
-    fs.resolve(path)?.mount(&mount_fs)?;
+    crate::fs::mount_at(&fs.resolve(path)?, &mount_fs, source, flags)?;
     info!("Mounted {} at {}", mount_fs.name(), path);
     Ok(())
 }
@@ -62,12 +73,36 @@ pub fn mount_all() -> LinuxResult<()> {
     info!("Initialize pseudofs...");
 
     let fs = FS_CONTEXT.lock();
-    mount_at(&fs, "/dev", dev::new_devfs())?;
-    mount_at(&fs, "/dev/shm", tmp::MemoryFs::new())?;
-    mount_at(&fs, "/tmp", tmp::MemoryFs::new())?;
-    mount_at(&fs, "/proc", proc::new_procfs())?;
+    mount_at(&fs, "/dev", dev::new_devfs(), "devfs", MountFlags::NOSUID)?;
+    mount_at(
+        &fs,
+        "/dev/shm",
+        tmp::MemoryFs::with_options(&TmpfsOptions::default()),
+        "tmpfs",
+        MountFlags::NOSUID | MountFlags::NODEV,
+    )?;
+    mount_at(
+        &fs,
+        "/tmp",
+        tmp::MemoryFs::with_options(&TmpfsOptions::default()),
+        "tmpfs",
+        MountFlags::empty(),
+    )?;
+    mount_at(
+        &fs,
+        "/proc",
+        proc::new_procfs(),
+        "proc",
+        MountFlags::NOSUID | MountFlags::NODEV | MountFlags::NOEXEC,
+    )?;
 
-    mount_at(&fs, "/sys", tmp::MemoryFs::new())?;
+    mount_at(
+        &fs,
+        "/sys",
+        tmp::MemoryFs::new(),
+        "sysfs",
+        MountFlags::NOSUID | MountFlags::NODEV | MountFlags::NOEXEC,
+    )?;
     let mut path = PathBuf::new();
     for comp in Path::new("/sys/class/graphics/fb0/device").components() {
         path.push(comp.as_str());
@@ -10,16 +10,21 @@ use alloc::{
 use core::{
     ffi::CStr,
     iter,
-    sync::atomic::{AtomicUsize, Ordering},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
 };
 
-use axfs_ng_vfs::{Filesystem, NodeType, VfsError, VfsResult};
+use axfs::FS_CONTEXT;
+use axfs_ng_vfs::{Filesystem, Location, NodeType, VfsError, VfsResult};
 use axtask::{AxTaskRef, WeakAxTaskRef, current};
 use indoc::indoc;
 use starry_process::Process;
 
 use crate::{
-    file::FD_TABLE,
+    file::{Directory, FD_TABLE, File, lease::LEASE_BREAK_TIME},
+    fs::{
+        DIRTY_BACKGROUND_BYTES, DIRTY_BYTES, DIRTY_EXPIRE_CENTISECS, DIRTY_WRITEBACK_CENTISECS,
+        format_mountinfo, format_mounts,
+    },
     pseudofs::{
         DirMaker, DirMapping, NodeOpsMux, RwFile, SimpleDir, SimpleDirOps, SimpleFile,
         SimpleFileOperation, SimpleFs,
@@ -187,6 +192,46 @@ impl SimpleDirOps for ThreadFdDir {
     }
 }
 
+/// Returns the file that the `/proc/[pid]/fd/[fd]` link `loc` refers to.
+///
+/// Unlike the target path of the link, this also works for files that have
+/// been removed or never had a name.
+pub fn fd_link_target(loc: &Location) -> Option<Location> {
+    if loc.filesystem().name() != "proc" || loc.node_type() != NodeType::Symlink {
+        return None;
+    }
+    let fd = loc.name().parse::<u32>().ok()?;
+    let fd_dir = loc.parent()?;
+    if fd_dir.name() != "fd" {
+        return None;
+    }
+    let task = match fd_dir.parent()?.name() {
+        "self" => current().clone(),
+        name => get_task(name.parse().ok()?).ok()?,
+    };
+    let file = FD_TABLE
+        .scope(&task.as_thread().proc_data.scope.read())
+        .read()
+        .get(fd as _)?
+        .inner
+        .clone();
+    if let Some(file) = file.downcast_ref::<File>() {
+        Some(file.inner().location().clone())
+    } else {
+        file.downcast_ref::<Directory>()
+            .map(|dir| dir.inner().clone())
+    }
+}
+
+/// Returns the root directory of the process `task` belongs to.
+fn task_root(task: &AxTaskRef) -> Location {
+    FS_CONTEXT
+        .scope(&task.as_thread().proc_data.scope.read())
+        .lock()
+        .root_dir()
+        .clone()
+}
+
 /// The /proc/[pid] directory
 struct ThreadDir {
     fs: Arc<SimpleFs>,
@@ -203,6 +248,7 @@ impl SimpleDirOps for ThreadDir {
                 "task",
                 "maps",
                 "mounts",
+                "mountinfo",
                 "cmdline",
                 "comm",
                 "exe",
@@ -258,10 +304,12 @@ impl SimpleDirOps for ThreadDir {
                 "})
             })
             .into(),
-            "mounts" => SimpleFile::new_regular(fs, move || {
-                Ok("proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0\n")
-            })
-            .into(),
+            "mounts" => {
+                SimpleFile::new_regular(fs, move || Ok(format_mounts(&task_root(&task)))).into()
+            }
+            "mountinfo" => {
+                SimpleFile::new_regular(fs, move || Ok(format_mountinfo(&task_root(&task)))).into()
+            }
             "cmdline" => SimpleFile::new_regular(fs, move || {
                 let cmdline = task.as_thread().proc_data.cmdline.read();
                 let mut buf = Vec::new();
@@ -356,12 +404,34 @@ impl SimpleDirOps for ProcFsHandler {
     }
 }
 
+/// A file exposing an integer tunable.
+fn tunable(fs: Arc<SimpleFs>, value: &'static AtomicU64) -> Arc<SimpleFile> {
+    SimpleFile::new_regular(
+        fs,
+        RwFile::new(move |req| match req {
+            SimpleFileOperation::Read => Ok(Some(
+                format!("{}\n", value.load(Ordering::Relaxed)).into_bytes(),
+            )),
+            SimpleFileOperation::Write(data) => {
+                if !data.is_empty() {
+                    let new = str::from_utf8(data)
+                        .ok()
+                        .and_then(|it| it.trim().parse::<u64>().ok())
+                        .ok_or(VfsError::InvalidInput)?;
+                    value.store(new, Ordering::Relaxed);
+                }
+                Ok(None)
+            }
+        }),
+    )
+}
+
 fn builder(fs: Arc<SimpleFs>) -> DirMaker {
     let mut root = DirMapping::new();
     root.add(
         "mounts",
         SimpleFile::new_regular(fs.clone(), || {
-            Ok("proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0\n")
+            Ok(format_mounts(FS_CONTEXT.lock().root_dir()))
         }),
     );
     root.add(
@@ -417,6 +487,27 @@ fn builder(fs: Arc<SimpleFs>) -> DirMaker {
             SimpleDir::new_maker(fs.clone(), Arc::new(kernel))
         });
 
+        sys.add("fs", {
+            let mut fs_dir = DirMapping::new();
+            fs_dir.add("lease-break-time", tunable(fs.clone(), &LEASE_BREAK_TIME));
+            SimpleDir::new_maker(fs.clone(), Arc::new(fs_dir))
+        });
+
+        sys.add("vm", {
+            let mut vm = DirMapping::new();
+
+            for (name, value) in [
+                ("dirty_writeback_centisecs", &DIRTY_WRITEBACK_CENTISECS),
+                ("dirty_expire_centisecs", &DIRTY_EXPIRE_CENTISECS),
+                ("dirty_background_bytes", &DIRTY_BACKGROUND_BYTES),
+                ("dirty_bytes", &DIRTY_BYTES),
+            ] {
+                vm.add(name, tunable(fs.clone(), value));
+            }
+
+            SimpleDir::new_maker(fs.clone(), Arc::new(vm))
+        });
+
         SimpleDir::new_maker(fs.clone(), Arc::new(sys))
     });
 
@@ -1,17 +1,37 @@
-use alloc::{borrow::ToOwned, string::String, sync::Arc};
-use core::{any::Any, borrow::Borrow, cmp::Ordering, task::Context, time::Duration};
+use alloc::{
+    borrow::ToOwned,
+    collections::{btree_map::BTreeMap, btree_set::BTreeSet},
+    format,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    any::Any,
+    borrow::Borrow,
+    cmp::Ordering,
+    ops::Range,
+    sync::atomic::{self, AtomicU64},
+    task::Context,
+    time::Duration,
+};
 
+use axerrno::{AxError, AxResult};
 use axfs_ng_vfs::{
     DeviceId, DirEntry, DirEntrySink, DirNode, DirNodeOps, FileNode, FileNodeOps, Filesystem,
-    FilesystemOps, Metadata, MetadataUpdate, NodeFlags, NodeOps, NodePermission, NodeType,
-    Reference, StatFs, VfsError, VfsResult, WeakDirEntry,
+    FilesystemOps, Location, Metadata, MetadataUpdate, NodeFlags, NodeOps, NodePermission,
+    NodeType, Reference, StatFs, VfsError, VfsResult, WeakDirEntry,
 };
 use axpoll::{IoEvents, Pollable};
 use axsync::Mutex;
 use hashbrown::HashMap;
+use memory_addr::PAGE_SIZE_4K;
 use slab::Slab;
 
-use crate::pseudofs::dummy_stat_fs;
+use crate::{
+    fs::{XattrFlags, XattrOps, check_set_flags, no_data},
+    pseudofs::dummy_stat_fs,
+};
 
 #[derive(PartialEq, Eq, Hash, Clone)]
 struct FileName(String);
@@ -50,26 +70,152 @@ impl Borrow<str> for FileName {
     }
 }
 
+/// `statfs` magic of tmpfs.
+const TMPFS_MAGIC: u32 = 0x01021994;
+
+const PAGE_SIZE: u64 = PAGE_SIZE_4K as u64;
+
+/// Returns the number of pages of memory.
+fn total_pages() -> u64 {
+    let allocator = axalloc::global_allocator();
+    (allocator.used_pages() + allocator.available_pages()) as u64
+}
+
+/// Parses a number with an optional `k`, `m`, `g`, `t`, `p` or `e` suffix.
+fn parse_number(value: &str) -> AxResult<u64> {
+    let shift = match value.as_bytes().last() {
+        Some(b'k' | b'K') => 10,
+        Some(b'm' | b'M') => 20,
+        Some(b'g' | b'G') => 30,
+        Some(b't' | b'T') => 40,
+        Some(b'p' | b'P') => 50,
+        Some(b'e' | b'E') => 60,
+        _ => 0,
+    };
+    let digits = if shift == 0 {
+        value
+    } else {
+        &value[..value.len() - 1]
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|it| it.checked_mul(1 << shift))
+        .ok_or(AxError::InvalidInput)
+}
+
+/// Parses a size in bytes or in percent of the memory into pages.
+fn parse_size(value: &str) -> AxResult<u64> {
+    if let Some(percent) = value.strip_suffix('%') {
+        let percent = percent.parse::<u64>().map_err(|_| AxError::InvalidInput)?;
+        return Ok(total_pages().saturating_mul(percent) / 100);
+    }
+    Ok(parse_number(value)?.div_ceil(PAGE_SIZE))
+}
+
+/// Options of a tmpfs mount, given in the data of `mount`.
+///
+/// Options that are not given keep their defaults on mount, and their current
+/// values on remount. Limits of zero mean no limit.
+#[derive(Debug, Clone, Default)]
+pub struct TmpfsOptions {
+    max_pages: Option<u64>,
+    max_inodes: Option<u64>,
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    noswap: bool,
+}
+
+impl TmpfsOptions {
+    /// Parses the comma-separated options in `data`.
+    pub fn parse(data: &str) -> AxResult<Self> {
+        let mut options = Self::default();
+        for option in data.split(',').filter(|it| !it.is_empty()) {
+            let (key, value) = option.split_once('=').unwrap_or((option, ""));
+            let parse_id = || value.parse::<u32>().map_err(|_| AxError::InvalidInput);
+            match key {
+                "size" => options.max_pages = Some(parse_size(value)?),
+                "nr_blocks" => options.max_pages = Some(parse_number(value)?),
+                "nr_inodes" => options.max_inodes = Some(parse_number(value)?),
+                "mode" => {
+                    let mode = u32::from_str_radix(value, 8).map_err(|_| AxError::InvalidInput)?;
+                    options.mode = Some(mode & 0o7777);
+                }
+                "uid" => options.uid = Some(parse_id()?),
+                "gid" => options.gid = Some(parse_id()?),
+                // There is no swap, so this only needs to be remembered.
+                "noswap" if value.is_empty() => options.noswap = true,
+                _ => {
+                    warn!("tmpfs: unknown option {option:?}");
+                    return Err(AxError::InvalidInput);
+                }
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Limits of a [`MemoryFs`]. Zero means no limit.
+#[derive(Default)]
+struct Limits {
+    max_pages: u64,
+    max_inodes: u64,
+    noswap: bool,
+}
+
 /// A simple in-memory filesystem that supports basic file operations.
 pub struct MemoryFs {
     inodes: Mutex<Slab<Arc<Inode>>>,
     root: Mutex<Option<DirEntry>>,
+    limits: Mutex<Limits>,
+    /// Number of pages charged to the files.
+    used_pages: AtomicU64,
 }
 
 impl MemoryFs {
-    /// Creates a new empty memory filesystem.
+    /// Creates a new empty memory filesystem without limits.
     #[allow(clippy::new_ret_no_self)]
     pub fn new() -> Filesystem {
+        Self::create(Limits::default(), 0o755, (0, 0))
+    }
+
+    /// Creates a new empty memory filesystem for a tmpfs mount with
+    /// `options`.
+    ///
+    /// By default, it may use half of the memory for pages and as many
+    /// inodes.
+    pub fn with_options(options: &TmpfsOptions) -> Filesystem {
+        let limits = Limits {
+            max_pages: options.max_pages.unwrap_or_else(|| total_pages() / 2),
+            max_inodes: options.max_inodes.unwrap_or_else(|| total_pages() / 2),
+            noswap: options.noswap,
+        };
+        Self::create(
+            limits,
+            options.mode.unwrap_or(0o1777),
+            (options.uid.unwrap_or(0), options.gid.unwrap_or(0)),
+        )
+    }
+
+    fn create(limits: Limits, mode: u32, (uid, gid): (u32, u32)) -> Filesystem {
         let fs = Arc::new(Self {
             inodes: Mutex::new(Slab::new()),
             root: Mutex::default(),
+            limits: Mutex::new(limits),
+            used_pages: AtomicU64::new(0),
         });
         let root_ino = Inode::new(
             &fs,
             None,
             NodeType::Directory,
-            NodePermission::from_bits_truncate(0o755),
+            NodePermission::from_bits_truncate(mode as _),
         );
+        {
+            let mut metadata = root_ino.metadata.lock();
+            metadata.uid = uid;
+            metadata.gid = gid;
+        }
         *fs.root.lock() = Some(DirEntry::new_dir(
             |this| DirNode::new(MemoryNode::new(fs.clone(), root_ino, Some(this))),
             Reference::root(),
@@ -77,9 +223,97 @@ impl MemoryFs {
         Filesystem::new(fs)
     }
 
+    /// Returns the memory filesystem `loc` is on, if it is on one.
+    pub fn of(loc: &Location) -> Option<Arc<Self>> {
+        loc.entry()
+            .downcast::<MemoryNode>()
+            .ok()
+            .map(|node| node.fs.clone())
+    }
+
+    /// Changes the limits of the filesystem and the owner and mode of its
+    /// root on remount.
+    ///
+    /// Limits can't be lowered below the current usage, and can't be added
+    /// to a filesystem without them.
+    pub fn reconfigure(&self, options: &TmpfsOptions) -> AxResult {
+        let mut limits = self.limits.lock();
+        let check = |limit: Option<u64>, current: u64, used: u64| match limit {
+            Some(limit) if limit != 0 && (current == 0 || limit < used) => {
+                Err(AxError::InvalidInput)
+            }
+            _ => Ok(()),
+        };
+        check(
+            options.max_pages,
+            limits.max_pages,
+            self.used_pages.load(atomic::Ordering::Relaxed),
+        )?;
+        check(
+            options.max_inodes,
+            limits.max_inodes,
+            self.inodes.lock().len() as u64,
+        )?;
+        if options.noswap && !limits.noswap {
+            return Err(AxError::InvalidInput);
+        }
+        if let Some(max_pages) = options.max_pages {
+            limits.max_pages = max_pages;
+        }
+        if let Some(max_inodes) = options.max_inodes {
+            limits.max_inodes = max_inodes;
+        }
+        drop(limits);
+
+        // The owner and mode apply to the root directory, which is the first
+        // inode.
+        let root = self.get(1);
+        let mut metadata = root.metadata.lock();
+        if let Some(mode) = options.mode {
+            metadata.mode = NodePermission::from_bits_truncate(mode as _);
+        }
+        if let Some(uid) = options.uid {
+            metadata.uid = uid;
+        }
+        if let Some(gid) = options.gid {
+            metadata.gid = gid;
+        }
+        Ok(())
+    }
+
     fn get(&self, ino: u64) -> Arc<Inode> {
         self.inodes.lock()[ino as usize - 1].clone()
     }
+
+    /// Fails with `ENOSPC` if no more inodes may be created.
+    fn check_inode_limit(&self) -> VfsResult<()> {
+        let max_inodes = self.limits.lock().max_inodes;
+        if max_inodes != 0 && self.inodes.lock().len() as u64 >= max_inodes {
+            return Err(VfsError::StorageFull);
+        }
+        Ok(())
+    }
+
+    /// Charges `count` more pages, failing with `ENOSPC` if that exceeds the
+    /// limit.
+    fn charge_pages(&self, count: u64) -> VfsResult<()> {
+        let max_pages = self.limits.lock().max_pages;
+        self.used_pages
+            .fetch_update(
+                atomic::Ordering::Relaxed,
+                atomic::Ordering::Relaxed,
+                |used| {
+                    let used = used + count;
+                    (max_pages == 0 || used <= max_pages).then_some(used)
+                },
+            )
+            .map(|_| ())
+            .map_err(|_| VfsError::StorageFull)
+    }
+
+    fn uncharge_pages(&self, count: u64) {
+        self.used_pages.fetch_sub(count, atomic::Ordering::Relaxed);
+    }
 }
 
 impl FilesystemOps for MemoryFs {
@@ -92,7 +326,18 @@ impl FilesystemOps for MemoryFs {
     }
 
     fn stat(&self) -> VfsResult<StatFs> {
-        Ok(dummy_stat_fs(0x01021994))
+        let limits = self.limits.lock();
+        let used_pages = self.used_pages.load(atomic::Ordering::Relaxed);
+        let used_inodes = self.inodes.lock().len() as u64;
+        Ok(StatFs {
+            block_size: PAGE_SIZE_4K as _,
+            blocks: limits.max_pages,
+            blocks_free: limits.max_pages.saturating_sub(used_pages),
+            blocks_available: limits.max_pages.saturating_sub(used_pages),
+            file_count: limits.max_inodes,
+            free_file_count: limits.max_inodes.saturating_sub(used_inodes),
+            ..dummy_stat_fs(TMPFS_MAGIC)
+        })
     }
 }
 
@@ -102,6 +347,9 @@ fn release_inode(fs: &MemoryFs, inode: &Arc<Inode>, nlink: u64) {
     metadata.nlink -= nlink;
     if metadata.nlink == 0 && Arc::strong_count(inode) == 2 {
         inodes.remove(metadata.inode as usize - 1);
+        if let NodeContent::File(file) = &inode.content {
+            fs.uncharge_pages(file.pages.lock().len() as u64);
+        }
     }
 }
 
@@ -110,9 +358,15 @@ struct FileContent {
     /// The length of the file content.
     ///
     /// We only need to store the length here because we delegate the actual
-    /// content management to page cache.
+    /// content management to page cache. Pages that are not charged are
+    /// holes, see [`crate::fs::seek_data`].
     length: Mutex<u64>,
+    /// The pages charged to the file, which are about all of its pages in the
+    /// page cache.
+    pages: Mutex<BTreeSet<u32>>,
     symlink: Mutex<Option<String>>,
+    /// See [`add_truncate_listener`].
+    truncate_listeners: Mutex<Vec<Weak<TruncateListener>>>,
 }
 
 #[derive(Default)]
@@ -129,6 +383,7 @@ struct Inode {
     ino: u64,
     metadata: Mutex<Metadata>,
     content: NodeContent,
+    xattrs: Mutex<BTreeMap<String, Vec<u8>>>,
 }
 
 impl Inode {
@@ -165,6 +420,7 @@ impl Inode {
             ino,
             metadata: Mutex::new(metadata),
             content,
+            xattrs: Mutex::default(),
         });
         entry.insert(result.clone());
         drop(inodes);
@@ -216,17 +472,76 @@ impl Drop for InodeRef {
     }
 }
 
-struct MemoryNode {
+/// A node of a [`MemoryFs`].
+pub struct MemoryNode {
     fs: Arc<MemoryFs>,
     inode: Arc<Inode>,
     this: Option<WeakDirEntry>,
 }
 
 impl MemoryNode {
-    pub fn new(fs: Arc<MemoryFs>, inode: Arc<Inode>, this: Option<WeakDirEntry>) -> Arc<Self> {
+    fn new(fs: Arc<MemoryFs>, inode: Arc<Inode>, this: Option<WeakDirEntry>) -> Arc<Self> {
         Arc::new(Self { fs, inode, this })
     }
 
+    /// Sets the device number of a device node.
+    pub fn set_rdev(&self, rdev: DeviceId) {
+        self.inode.metadata.lock().rdev = rdev;
+    }
+
+    /// Creates a regular file in this directory without giving it a name.
+    /// It is freed once released, unless it is linked into place first.
+    pub fn create_unnamed(&self, permission: NodePermission) -> VfsResult<DirEntry> {
+        self.inode.as_dir()?;
+        self.fs.check_inode_limit()?;
+        let inode = Inode::new(
+            &self.fs,
+            Some(self.inode.ino),
+            NodeType::RegularFile,
+            permission,
+        );
+        self.new_entry(&format!("#{}", inode.ino), NodeType::RegularFile, inode)
+    }
+
+    /// Charges the pages overlapping `offset..offset + len` that are not
+    /// charged yet.
+    fn charge(&self, offset: u64, len: u64) -> VfsResult<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let mut pages = self.inode.as_file()?.pages.lock();
+        let range =
+            (offset / PAGE_SIZE) as u32..offset.saturating_add(len).div_ceil(PAGE_SIZE) as u32;
+        let count = range.clone().filter(|pn| !pages.contains(pn)).count() as u64;
+        if count > 0 {
+            self.fs.charge_pages(count)?;
+            pages.extend(range);
+        }
+        Ok(())
+    }
+
+    /// Releases the pages within `start..end`, which have become a hole.
+    fn uncharge(&self, start: u32, end: u32) -> VfsResult<()> {
+        let mut pages = self.inode.as_file()?.pages.lock();
+        let mut hole = pages.split_off(&start);
+        pages.append(&mut hole.split_off(&end));
+        self.fs.uncharge_pages(hole.len() as u64);
+        Ok(())
+    }
+
+    /// Returns the runs of pages from page `pn` on that hold data, which are
+    /// the pages charged to the file.
+    fn data_pages(&self, pn: u32) -> VfsResult<Vec<Range<u32>>> {
+        let mut runs: Vec<Range<u32>> = Vec::new();
+        for &page in self.inode.as_file()?.pages.lock().range(pn..) {
+            match runs.last_mut() {
+                Some(run) if run.end == page => run.end += 1,
+                _ => runs.push(page..page + 1),
+            }
+        }
+        Ok(runs)
+    }
+
     fn new_entry(&self, name: &str, node_type: NodeType, inode: Arc<Inode>) -> VfsResult<DirEntry> {
         let fs = self.fs.clone();
         let reference = Reference::new(
@@ -258,6 +573,8 @@ impl NodeOps for MemoryNode {
         match &self.inode.content {
             NodeContent::File(content) => {
                 metadata.size = *content.length.lock();
+                metadata.block_size = PAGE_SIZE;
+                metadata.blocks = content.pages.lock().len() as u64 * (PAGE_SIZE / 512);
             }
             NodeContent::Dir(dir) => {
                 metadata.size = dir.entries.lock().len() as u64;
@@ -322,7 +639,26 @@ impl FileNodeOps for MemoryNode {
     }
 
     fn set_len(&self, len: u64) -> VfsResult<()> {
-        *self.inode.as_file()?.length.lock() = len;
+        let file = self.inode.as_file()?;
+        let mut length = file.length.lock();
+        // Shrinking drops the pages after the new last page from the page
+        // cache.
+        if len < *length {
+            let first = (len / PAGE_SIZE) as u32 + 1;
+            file.truncate_listeners.lock().retain(|listener| {
+                let Some(listener) = listener.upgrade() else {
+                    return false;
+                };
+                listener(first);
+                true
+            });
+            let dropped = file
+                .pages
+                .lock()
+                .split_off(&(len.div_ceil(PAGE_SIZE) as u32));
+            self.fs.uncharge_pages(dropped.len() as u64);
+        }
+        *length = len;
         Ok(())
     }
 
@@ -388,6 +724,7 @@ impl DirNodeOps for MemoryNode {
         if entries.contains_key(name) {
             return Err(VfsError::AlreadyExists);
         }
+        self.fs.check_inode_limit()?;
         let inode = Inode::new(&self.fs, Some(self.inode.ino), node_type, permission);
         entries.insert(name.into(), InodeRef::new(self.fs.clone(), inode.ino));
         self.new_entry(name, node_type, inode)
@@ -452,6 +789,87 @@ impl DirNodeOps for MemoryNode {
     }
 }
 
+impl XattrOps for MemoryNode {
+    fn get_xattr(&self, name: &str) -> AxResult<Vec<u8>> {
+        self.inode
+            .xattrs
+            .lock()
+            .get(name)
+            .cloned()
+            .ok_or_else(no_data)
+    }
+
+    fn set_xattr(&self, name: &str, value: &[u8], flags: XattrFlags) -> AxResult {
+        let mut xattrs = self.inode.xattrs.lock();
+        check_set_flags(flags, xattrs.contains_key(name))?;
+        xattrs.insert(name.to_owned(), value.to_vec());
+        Ok(())
+    }
+
+    fn remove_xattr(&self, name: &str) -> AxResult {
+        self.inode
+            .xattrs
+            .lock()
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(no_data)
+    }
+
+    fn list_xattr(&self) -> AxResult<Vec<String>> {
+        Ok(self.inode.xattrs.lock().keys().cloned().collect())
+    }
+}
+
+/// Charges the pages of `loc` overlapping `offset..offset + len`, which are
+/// about to be populated, against the size limit of its tmpfs. Other files
+/// are not limited.
+pub fn charge_pages(loc: &Location, offset: u64, len: u64) -> AxResult {
+    match loc.entry().downcast::<MemoryNode>() {
+        Ok(node) => Ok(node.charge(offset, len)?),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Releases the pages of `loc` that lie entirely within
+/// `offset..offset + len`, after a hole is punched there.
+pub fn uncharge_pages(loc: &Location, offset: u64, len: u64) -> AxResult {
+    match loc.entry().downcast::<MemoryNode>() {
+        Ok(node) => Ok(node.uncharge(
+            offset.div_ceil(PAGE_SIZE) as u32,
+            (offset.saturating_add(len) / PAGE_SIZE) as u32,
+        )?),
+        Err(_) => Ok(()),
+    }
+}
+
+/// A callback for the pages of a tmpfs file that are about to be dropped from
+/// the page cache, see [`add_truncate_listener`].
+pub type TruncateListener = dyn Fn(u32) + Send + Sync;
+
+/// Registers `listener` to be called with the first page number of `loc` that
+/// is dropped from the page cache whenever the file shrinks, if it is a tmpfs
+/// file. The page cache of tmpfs does not call its evict listeners for them.
+///
+/// The listener is called before the pages are freed, as long as `listener`
+/// is not dropped.
+pub fn add_truncate_listener(loc: &Location, listener: &Arc<TruncateListener>) -> AxResult {
+    if let Ok(node) = loc.entry().downcast::<MemoryNode>() {
+        let mut listeners = node.inode.as_file()?.truncate_listeners.lock();
+        listeners.retain(|it| it.strong_count() > 0);
+        listeners.push(Arc::downgrade(listener));
+    }
+    Ok(())
+}
+
+/// Returns the runs of pages of `loc` from page `pn` on that hold data if it
+/// is a tmpfs file, whose holes are the pages not charged to it.
+pub fn tmpfs_data_pages(loc: &Location, pn: u32) -> AxResult<Option<Vec<Range<u32>>>> {
+    match loc.entry().downcast::<MemoryNode>() {
+        Ok(node) => Ok(Some(node.data_pages(pn)?)),
+        Err(_) => Ok(None),
+    }
+}
+
 impl Drop for MemoryNode {
     fn drop(&mut self) {
         if let NodeContent::Dir(dir) = &self.inode.content {
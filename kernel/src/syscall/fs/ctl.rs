@@ -7,18 +7,32 @@ use core::{
 
 use axerrno::{AxError, AxResult};
 use axfs::{FS_CONTEXT, FsContext};
-use axfs_ng_vfs::{MetadataUpdate, NodePermission, NodeType, path::Path};
+use axfs_ng_vfs::{DeviceId, MetadataUpdate, NodePermission, NodeType, path::Path};
 use axhal::time::wall_time;
 use axtask::current;
 use linux_raw_sys::{
     general::*,
-    ioctl::{FIONBIO, TIOCGWINSZ},
+    ioctl::{FIOASYNC, FIOGETOWN, FIONBIO, FIOSETOWN, SIOCGPGRP, SIOCSPGRP, TIOCGWINSZ},
 };
-use starry_vm::{VmPtr, vm_write_slice};
+use starry_vm::{VmMutPtr, VmPtr, vm_write_slice};
 
 use crate::{
-    file::{Directory, FileLike, get_file_like, resolve_at, with_fs},
+    file::{
+        Directory, FileLike, ResolveAtResult,
+        fasync::{AsyncFile, AsyncOwner, is_async},
+        get_file_like,
+        memfd::check_chmod_seals,
+        resolve_at,
+        tmpfile::check_link_source,
+        with_fs,
+    },
+    fs::{
+        check_parent_write, check_write, forget_xattrs, mknod, notify_attrib, notify_create,
+        notify_delete, notify_move, sync_all, sync_filesystem,
+    },
     mm::vm_load_string,
+    pseudofs::fd_link_target,
+    syscall::sys::{sys_getegid, sys_geteuid},
     task::AsThread,
     time::TimeValueLike,
 };
@@ -36,6 +50,26 @@ pub fn sys_ioctl(fd: i32, cmd: u32, arg: usize) -> AxResult<isize> {
         f.set_nonblocking(val != 0)?;
         return Ok(0);
     }
+    match cmd {
+        FIOASYNC => {
+            let enabled = (arg as *const c_int).vm_read()? != 0;
+            if enabled || is_async(&f) {
+                AsyncFile::of(&f).set_enabled(fd, enabled);
+            }
+            return Ok(0);
+        }
+        FIOSETOWN | SIOCSPGRP => {
+            let pid = (arg as *const c_int).vm_read()?;
+            AsyncFile::of(&f).set_owner(AsyncOwner::from_pid(pid)?)?;
+            return Ok(0);
+        }
+        FIOGETOWN | SIOCGPGRP => {
+            let owner = AsyncFile::get(&f).and_then(|it| it.owner());
+            (arg as *mut c_int).vm_write(AsyncOwner::to_pid(owner))?;
+            return Ok(0);
+        }
+        _ => {}
+    }
     f.ioctl(cmd, arg)
         .map(|result| result as isize)
         .inspect_err(|err| {
@@ -68,6 +102,39 @@ pub fn sys_fchdir(dirfd: i32) -> AxResult<isize> {
     Ok(0)
 }
 
+pub fn sys_mknodat(dirfd: i32, path: *const c_char, mode: u32, dev: u32) -> AxResult<isize> {
+    let path = vm_load_string(path)?;
+    debug!("sys_mknodat <= dirfd: {dirfd}, path: {path}, mode: {mode:#o}, dev: {dev:#x}");
+
+    let node_type = match mode & S_IFMT {
+        0 | S_IFREG => NodeType::RegularFile,
+        S_IFCHR => NodeType::CharacterDevice,
+        S_IFBLK => NodeType::BlockDevice,
+        S_IFIFO => NodeType::Fifo,
+        S_IFSOCK => NodeType::Socket,
+        _ => return Err(AxError::InvalidInput),
+    };
+    let mode = mode & !S_IFMT & !current().as_thread().proc_data.umask();
+    let mode = NodePermission::from_bits_truncate(mode as u16);
+    let user = (sys_geteuid()? as _, sys_getegid()? as _);
+
+    with_fs(dirfd, |fs| {
+        check_parent_write(fs, &path)?;
+        let (dir, name) = fs.resolve_nonexistent(path.as_ref())?;
+        if dir.lookup_no_follow(name).is_ok() {
+            return Err(AxError::AlreadyExists);
+        }
+        let loc = mknod(&dir, name, node_type, mode, user, DeviceId(dev as u64))?;
+        notify_create(&loc);
+        Ok(0)
+    })
+}
+
+#[cfg(target_arch = "x86_64")]
+pub fn sys_mknod(path: *const c_char, mode: u32, dev: u32) -> AxResult<isize> {
+    sys_mknodat(AT_FDCWD, path, mode, dev)
+}
+
 #[cfg(target_arch = "x86_64")]
 pub fn sys_mkdir(path: *const c_char, mode: u32) -> AxResult<isize> {
     sys_mkdirat(AT_FDCWD, path, mode)
@@ -94,7 +161,8 @@ pub fn sys_mkdirat(dirfd: i32, path: *const c_char, mode: u32) -> AxResult<isize
     let mode = NodePermission::from_bits_truncate(mode as u16);
 
     with_fs(dirfd, |fs| {
-        fs.create_dir(path, mode)?;
+        check_parent_write(fs, &path)?;
+        notify_create(&fs.create_dir(path, mode)?);
         Ok(0)
     })
 }
@@ -196,20 +264,35 @@ pub fn sys_linkat(
          new_path: {new_path}, flags: {flags}"
     );
 
-    if flags != 0 {
-        warn!("Unsupported flags: {flags}");
+    if flags & !(AT_EMPTY_PATH | AT_SYMLINK_FOLLOW) != 0 {
+        return Err(AxError::InvalidInput);
     }
 
-    let old = resolve_at(old_dirfd, old_path.as_deref(), flags)?
-        .into_file()
-        .ok_or(AxError::BadFileDescriptor)?;
+    let resolve = |flags| {
+        resolve_at(old_dirfd, old_path.as_deref(), flags)?
+            .into_file()
+            .ok_or(AxError::BadFileDescriptor)
+    };
+    let mut old = resolve((flags & AT_EMPTY_PATH) | AT_SYMLINK_NOFOLLOW)?;
+    if flags & AT_SYMLINK_FOLLOW != 0 && old.node_type() == NodeType::Symlink {
+        // Links in /proc/[pid]/fd are followed to the open file itself, which
+        // is how unnamed files are given a name.
+        old = match fd_link_target(&old) {
+            Some(target) => target,
+            None => resolve(flags & AT_EMPTY_PATH)?,
+        };
+    }
     if old.is_dir() {
         return Err(AxError::OperationNotPermitted);
     }
+    check_link_source(&old)?;
     let (new_dir, new_name) =
         with_fs(new_dirfd, |fs| fs.resolve_nonexistent(Path::new(&new_path)))?;
+    check_write(&new_dir)?;
 
-    new_dir.link(new_name, &old)?;
+    let new = new_dir.link(new_name, &old)?;
+    notify_attrib(&old);
+    notify_create(&new);
     Ok(0)
 }
 
@@ -229,11 +312,18 @@ pub fn sys_unlinkat(dirfd: i32, path: *const c_char, flags: usize) -> AxResult<i
     debug!("sys_unlinkat <= dirfd: {dirfd}, path: {path:?}, flags: {flags}");
 
     with_fs(dirfd, |fs| {
+        check_parent_write(fs, &path)?;
+        let target = fs.resolve_no_follow(&path)?;
+        let last_link = target.is_dir() || target.metadata()?.nlink <= 1;
         if flags == AT_REMOVEDIR as _ {
             fs.remove_dir(path)?;
         } else {
             fs.remove_file(path)?;
         }
+        if last_link {
+            forget_xattrs(&target);
+        }
+        notify_delete(&target, last_link);
         Ok(0)
     })
 }
@@ -284,7 +374,8 @@ pub fn sys_symlinkat(
     debug!("sys_symlinkat <= target: {target:?}, new_dirfd: {new_dirfd}, linkpath: {linkpath:?}");
 
     with_fs(new_dirfd, |fs| {
-        fs.symlink(target, linkpath)?;
+        check_parent_write(fs, &linkpath)?;
+        notify_create(&fs.symlink(target, linkpath)?);
         Ok(0)
     })
 }
@@ -339,6 +430,7 @@ pub fn sys_fchownat(
     let loc = resolve_at(dirfd, path.as_deref(), flags)?
         .into_file()
         .ok_or(AxError::BadFileDescriptor)?;
+    check_write(&loc)?;
     let meta = loc.metadata()?;
 
     let mut mode = meta.mode;
@@ -356,6 +448,7 @@ pub fn sys_fchownat(
         mode: Some(mode),
         ..Default::default()
     })?;
+    notify_attrib(&loc);
     Ok(0)
 }
 
@@ -370,13 +463,16 @@ pub fn sys_fchmod(fd: i32, mode: u32) -> AxResult<isize> {
 
 pub fn sys_fchmodat(dirfd: i32, path: *const c_char, mode: u32, flags: u32) -> AxResult<isize> {
     let path = path.nullable().map(vm_load_string).transpose()?;
-    resolve_at(dirfd, path.as_deref(), flags)?
+    let loc = resolve_at(dirfd, path.as_deref(), flags)?
         .into_file()
-        .ok_or(AxError::BadFileDescriptor)?
-        .update_metadata(MetadataUpdate {
-            mode: Some(NodePermission::from_bits_truncate(mode as u16)),
-            ..Default::default()
-        })?;
+        .ok_or(AxError::BadFileDescriptor)?;
+    check_write(&loc)?;
+    check_chmod_seals(&loc, mode)?;
+    loc.update_metadata(MetadataUpdate {
+        mode: Some(NodePermission::from_bits_truncate(mode as u16)),
+        ..Default::default()
+    })?;
+    notify_attrib(&loc);
     Ok(0)
 }
 
@@ -388,14 +484,16 @@ fn update_times(
     flags: u32,
 ) -> AxResult<()> {
     let path = path.nullable().map(vm_load_string).transpose()?;
-    resolve_at(dirfd, path.as_deref(), flags)?
+    let loc = resolve_at(dirfd, path.as_deref(), flags)?
         .into_file()
-        .ok_or(AxError::BadFileDescriptor)?
-        .update_metadata(MetadataUpdate {
-            atime,
-            mtime,
-            ..Default::default()
-        })?;
+        .ok_or(AxError::BadFileDescriptor)?;
+    check_write(&loc)?;
+    loc.update_metadata(MetadataUpdate {
+        atime,
+        mtime,
+        ..Default::default()
+    })?;
+    notify_attrib(&loc);
     Ok(())
 }
 
@@ -509,17 +607,34 @@ pub fn sys_renameat2(
     let (old_dir, old_name) = with_fs(old_dirfd, |fs| fs.resolve_parent(Path::new(&old_path)))?;
     let (new_dir, new_name) =
         with_fs(new_dirfd, |fs| fs.resolve_nonexistent(Path::new(&new_path)))?;
-
+    check_write(&old_dir)?;
+    check_write(&new_dir)?;
+
+    let old = old_dir.lookup_no_follow(&old_name)?;
+    let replaced = new_dir
+        .lookup_no_follow(new_name)
+        .ok()
+        .filter(|it| it.inode() != old.inode())
+        .filter(|it| it.is_dir() || it.metadata().is_ok_and(|it| it.nlink <= 1));
     old_dir.rename(&old_name, &new_dir, new_name)?;
+    if let Some(replaced) = replaced {
+        forget_xattrs(&replaced);
+    }
+    notify_move(&old, &new_dir.lookup_no_follow(new_name)?);
     Ok(0)
 }
 
 pub fn sys_sync() -> AxResult<isize> {
-    warn!("dummy sys_sync");
+    debug!("sys_sync");
+    sync_all()?;
     Ok(0)
 }
 
-pub fn sys_syncfs(_fd: i32) -> AxResult<isize> {
-    warn!("dummy sys_syncfs");
+pub fn sys_syncfs(fd: i32) -> AxResult<isize> {
+    debug!("sys_syncfs <= fd: {fd}");
+    // Descriptors that are not backed by a filesystem have nothing to sync.
+    if let ResolveAtResult::File(loc) = resolve_at(fd, None, AT_EMPTY_PATH)? {
+        sync_filesystem(&loc)?;
+    }
     Ok(0)
 }
@@ -1,24 +1,46 @@
 use alloc::{format, string::ToString, sync::Arc};
 use core::{
     ffi::{c_char, c_int},
-    mem,
+    mem::{self, size_of},
     ops::{Deref, DerefMut},
 };
 
 use axerrno::{AxError, AxResult};
-use axfs::{FS_CONTEXT, FileBackend, OpenOptions, OpenResult};
+use axfs::{FS_CONTEXT, FileBackend, FileFlags, FsContext, OpenOptions, OpenResult};
 use axfs_ng_vfs::{DirEntry, FileNode, Location, NodePermission, NodeType, Reference};
+use axio::{Seek, SeekFrom};
 use axtask::current;
 use bitflags::bitflags;
 use linux_raw_sys::general::*;
+use memory_addr::PAGE_SIZE_4K;
+use starry_vm::{VmPtr, vm_load};
 
 use crate::{
     file::{
-        Directory, FD_TABLE, File, FileLike, Pipe, add_file_like, close_file_like, get_file_like,
+        Directory, FD_TABLE, File, FileLike, Pipe, ResolveFlags, Resolver, add_file_like,
+        close_file_like,
+        dnotify::set_dnotify,
+        fasync::{AsyncFile, AsyncOwner, is_async},
+        get_file_like,
+        lease::{break_lease, get_lease, set_lease},
+        lock::{
+            LockKind, LockOwner, RecordLock, flock, funlock, lock_location, release_posix_locks,
+            set_record_lock, test_record_lock,
+        },
+        memfd::{add_seals, get_seals},
+        tmpfile::TmpFile,
         with_fs,
     },
+    fs::{
+        FuseDev, check_device, check_parent_write, check_write, fuse_open, notify_create,
+        notify_open,
+    },
     mm::{UserPtr, vm_load_string},
-    pseudofs::{Device, dev::tty},
+    pseudofs::{
+        Device,
+        dev::{FuseDevice, tty},
+        device_location,
+    },
     syscall::sys::{sys_getegid, sys_geteuid},
     task::AsThread,
 };
@@ -60,9 +82,92 @@ fn flags_to_options(flags: c_int, mode: __kernel_mode_t, (uid, gid): (u32, u32))
     options
 }
 
+/// Checks the mount flags of the file to be opened, and returns whether the
+/// file is going to be created.
+fn check_open(fs: &FsContext, path: &str, flags: u32) -> AxResult<bool> {
+    if flags & O_PATH != 0 {
+        return Ok(false);
+    }
+    let resolved = if flags & O_NOFOLLOW != 0 {
+        fs.resolve_no_follow(path)
+    } else {
+        fs.resolve(path)
+    };
+    match resolved {
+        Ok(loc) => check_open_loc(&loc, flags)?,
+        Err(AxError::NotFound) if flags & O_CREAT != 0 => {
+            check_parent_write(fs, path)?;
+            return Ok(true);
+        }
+        Err(_) => {}
+    }
+    Ok(false)
+}
+
+/// Checks the mount flags of the existing file `loc` to be opened, and breaks
+/// the leases on it that conflict with the open.
+fn check_open_loc(loc: &Location, flags: u32) -> AxResult<()> {
+    if flags & O_PATH != 0 {
+        return Ok(());
+    }
+    check_device(loc)?;
+    let writes = flags & 0b11 != O_RDONLY || flags & O_TRUNC != 0;
+    if writes && matches!(loc.node_type(), NodeType::RegularFile | NodeType::Directory) {
+        check_write(loc)?;
+    }
+    if loc.node_type() == NodeType::RegularFile {
+        break_lease(loc, writes, flags & O_NONBLOCK != 0)?;
+    }
+    Ok(())
+}
+
+/// Checks the flags of an `O_TMPFILE` open: the file has to be writable, and
+/// is never looked up by name.
+fn check_tmpfile_flags(flags: u32) -> AxResult<()> {
+    if flags & O_TMPFILE != O_TMPFILE || flags & O_CREAT != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    if flags & 0b11 == O_RDONLY {
+        return Err(AxError::InvalidInput);
+    }
+    Ok(())
+}
+
+/// Creates an unnamed file in the directory `dir` and opens it.
+///
+/// Unless `O_EXCL` is given, the file can later be linked into place.
+fn open_tmpfile(
+    dir: &Location,
+    flags: u32,
+    mode: __kernel_mode_t,
+    user: (u32, u32),
+) -> AxResult<OpenResult> {
+    check_write(dir)?;
+    let loc = TmpFile::create(dir, mode, user, flags & O_EXCL == 0)?;
+    let flags = flags & !(O_TMPFILE | O_EXCL);
+    let result = flags_to_options(flags as _, mode, user).open_loc(loc)?;
+    if let OpenResult::File(file) = &result {
+        notify_open(file.location());
+    }
+    Ok(result)
+}
+
 fn add_to_fd(result: OpenResult, flags: u32) -> AxResult<i32> {
     let f: Arc<dyn FileLike> = match result {
-        OpenResult::File(mut file) => {
+        OpenResult::File(mut file) if !file.flags().contains(FileFlags::PATH) => {
+            let loc = file.location().clone();
+            match loc.node_type() {
+                NodeType::Fifo => {
+                    let pipe = Pipe::open_fifo(loc, flags)?;
+                    return add_file_like(Arc::new(pipe), flags & O_CLOEXEC != 0);
+                }
+                // Device nodes outside of devfs open the registered device
+                NodeType::CharacterDevice | NodeType::BlockDevice => {
+                    file =
+                        axfs::File::new(FileBackend::Direct(device_location(&loc)?), file.flags());
+                }
+                _ => {}
+            }
             // /dev/xx handling
             if let Ok(device) = file.location().entry().downcast::<Device>() {
                 let inner = device.inner().as_any();
@@ -96,10 +201,17 @@ fn add_to_fd(result: OpenResult, flags: u32) -> AxResult<i32> {
                     };
                     let loc = FS_CONTEXT.lock().resolve(&path)?;
                     file = axfs::File::new(FileBackend::Direct(loc), file.flags());
+                } else if inner.is::<FuseDevice>() {
+                    // Opening /dev/fuse starts a new FUSE connection
+                    let dev = FuseDev::new();
+                    dev.set_nonblocking(flags & O_NONBLOCK != 0)?;
+                    return dev.add_to_fd_table(flags & O_CLOEXEC != 0);
                 }
             }
+            fuse_open(file.location(), file.flags())?;
             Arc::new(File::new(file))
         }
+        OpenResult::File(file) => Arc::new(File::new(file)),
         OpenResult::Dir(dir) => Arc::new(Directory::new(dir)),
     };
     if flags & O_NONBLOCK != 0 {
@@ -125,9 +237,200 @@ pub fn sys_openat(
 
     let mode = mode & !current().as_thread().proc_data.umask();
 
-    let options = flags_to_options(flags, mode, (sys_geteuid()? as _, sys_getegid()? as _));
-    with_fs(dirfd, |fs| options.open(fs, path))
+    let user = (sys_geteuid()? as _, sys_getegid()? as _);
+    if flags as u32 & __O_TMPFILE != 0 {
+        check_tmpfile_flags(flags as _)?;
+        return with_fs(dirfd, |fs| {
+            open_tmpfile(&fs.resolve(&path)?, flags as _, mode, user)
+        })
         .and_then(|it| add_to_fd(it, flags as _))
+        .map(|fd| fd as isize);
+    }
+
+    let options = flags_to_options(flags, mode, user);
+    with_fs(dirfd, |fs| {
+        let create = check_open(fs, &path, flags as _)?;
+        let result = options.open(fs, path)?;
+        if flags as u32 & O_PATH == 0 {
+            let loc = match &result {
+                OpenResult::File(file) => file.location(),
+                OpenResult::Dir(dir) => dir,
+            };
+            if create {
+                notify_create(loc);
+            }
+            notify_open(loc);
+        }
+        Ok(result)
+    })
+    .and_then(|it| add_to_fd(it, flags as _))
+    .map(|fd| fd as isize)
+}
+
+/// Flags accepted by `openat2`.
+const VALID_OPEN_FLAGS: u32 = O_ACCMODE
+    | O_CREAT
+    | O_EXCL
+    | O_NOCTTY
+    | O_TRUNC
+    | O_APPEND
+    | O_NONBLOCK
+    | O_DSYNC
+    | O_SYNC
+    | FASYNC
+    | O_DIRECT
+    | O_LARGEFILE
+    | O_DIRECTORY
+    | O_NOFOLLOW
+    | O_NOATIME
+    | O_CLOEXEC
+    | O_PATH
+    | O_TMPFILE;
+
+/// Flags that can be combined with `O_PATH` in `openat2`.
+const O_PATH_FLAGS: u32 = O_DIRECTORY | O_NOFOLLOW | O_PATH | O_CLOEXEC;
+
+/// Reads the `open_how` of `size` bytes at `how`. Newer versions of the
+/// struct are accepted as long as the fields unknown here are zero.
+fn load_open_how(how: *const open_how, size: usize) -> AxResult<open_how> {
+    const SIZE_VER0: usize = size_of::<open_how>();
+    if size < SIZE_VER0 {
+        return Err(AxError::InvalidInput);
+    }
+    if size > PAGE_SIZE_4K {
+        return Err(AxError::ArgumentListTooLong);
+    }
+    if size > SIZE_VER0 {
+        let rest = vm_load(how.cast::<u8>().wrapping_add(SIZE_VER0), size - SIZE_VER0)?;
+        if rest.iter().any(|&it| it != 0) {
+            return Err(AxError::ArgumentListTooLong);
+        }
+    }
+    // FIXME: AnyBitPattern
+    Ok(unsafe { how.vm_read_uninit()?.assume_init() })
+}
+
+/// Opens `path` like `openat`, resolving it under the restrictions of
+/// `resolve`.
+fn open_resolved(
+    fs: &FsContext,
+    path: &str,
+    flags: u32,
+    mode: __kernel_mode_t,
+    resolve: ResolveFlags,
+) -> AxResult<OpenResult> {
+    let user = (sys_geteuid()? as _, sys_getegid()? as _);
+    let options = flags_to_options(flags as _, mode, user);
+    let mut resolver = Resolver::new(fs, resolve);
+    let (dir, name) = resolver.resolve_parent(path)?;
+    if flags & __O_TMPFILE != 0 {
+        let dir = match name {
+            Some(name) => resolver.lookup(&dir, name, true)?,
+            None => dir,
+        };
+        return open_tmpfile(&dir, flags, mode, user);
+    }
+    let mut create = false;
+    let loc = match name {
+        None if flags & O_CREAT != 0 => return Err(AxError::IsADirectory),
+        None => dir,
+        Some(name) => match resolver.lookup(&dir, name, false) {
+            Ok(_) if flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL => {
+                return Err(AxError::AlreadyExists);
+            }
+            Ok(loc) if loc.node_type() == NodeType::Symlink => {
+                if flags & O_NOFOLLOW == 0 {
+                    resolver.follow(&dir, loc)?
+                } else if flags & O_PATH == 0 {
+                    return Err(AxError::FilesystemLoop);
+                } else {
+                    loc
+                }
+            }
+            Ok(loc) => loc,
+            Err(AxError::NotFound) if flags & O_CREAT != 0 => {
+                check_write(&dir)?;
+                create = true;
+                dir.open_file(
+                    name,
+                    &axfs_ng_vfs::OpenOptions {
+                        create: true,
+                        create_new: true,
+                        node_type: NodeType::RegularFile,
+                        permission: NodePermission::from_bits_truncate(mode as _),
+                        user: Some(user),
+                    },
+                )?
+            }
+            Err(err) => return Err(err),
+        },
+    };
+    if !create {
+        check_open_loc(&loc, flags)?;
+    }
+    let result = options.open_loc(loc)?;
+    if flags & O_PATH == 0 {
+        let loc = match &result {
+            OpenResult::File(file) => file.location(),
+            OpenResult::Dir(dir) => dir,
+        };
+        if create {
+            notify_create(loc);
+        }
+        notify_open(loc);
+    }
+    Ok(result)
+}
+
+/// Open a file like [`sys_openat`], with the flags, mode and resolution
+/// restrictions given in `how`.
+pub fn sys_openat2(
+    dirfd: c_int,
+    path: *const c_char,
+    how: *const open_how,
+    size: usize,
+) -> AxResult<isize> {
+    let how = load_open_how(how, size)?;
+    let path = vm_load_string(path)?;
+    debug!(
+        "sys_openat2 <= {dirfd} {path:?} flags: {:#o}, mode: {:#o}, resolve: {:#x}",
+        how.flags, how.mode, how.resolve
+    );
+
+    // Unlike `openat`, unknown flags are rejected.
+    let flags = u32::try_from(how.flags).map_err(|_| AxError::InvalidInput)?;
+    if flags & !VALID_OPEN_FLAGS != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    if flags & O_PATH != 0 && flags & !O_PATH_FLAGS != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    if flags & O_CREAT != 0 && flags & O_DIRECTORY != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    if flags & __O_TMPFILE != 0 {
+        check_tmpfile_flags(flags)?;
+    }
+    // `O_TMPFILE` includes `O_DIRECTORY`, so only its own bit is checked.
+    if flags & (O_CREAT | __O_TMPFILE) != 0 {
+        if how.mode & !0o7777 != 0 {
+            return Err(AxError::InvalidInput);
+        }
+    } else if how.mode != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    let resolve = ResolveFlags::from_bits(how.resolve).ok_or(AxError::InvalidInput)?;
+    if resolve.contains(ResolveFlags::BENEATH | ResolveFlags::IN_ROOT) {
+        return Err(AxError::InvalidInput);
+    }
+    // Creating or truncating files never goes through the cache only.
+    if resolve.contains(ResolveFlags::CACHED) && flags & (O_CREAT | O_TRUNC | __O_TMPFILE) != 0 {
+        return Err(AxError::WouldBlock);
+    }
+
+    let mode = how.mode as __kernel_mode_t & !current().as_thread().proc_data.umask();
+    with_fs(dirfd, |fs| open_resolved(fs, &path, flags, mode, resolve))
+        .and_then(|it| add_to_fd(it, flags))
         .map(|fd| fd as isize)
 }
 
@@ -177,8 +480,8 @@ pub fn sys_close_range(first: i32, last: i32, flags: u32) -> AxResult<isize> {
                 if let Some(f) = fd_table.get_mut(fd as _) {
                     f.cloexec = true;
                 }
-            } else {
-                fd_table.remove(fd as _);
+            } else if let Some(f) = fd_table.remove(fd as _) {
+                release_posix_locks(&f.inner);
             }
         }
     }
@@ -228,7 +531,9 @@ pub fn sys_dup3(old_fd: c_int, new_fd: c_int, flags: c_int) -> AxResult<isize> {
         .ok_or(AxError::BadFileDescriptor)?;
     f.cloexec = flags.contains(Dup3Flags::O_CLOEXEC);
 
-    fd_table.remove(new_fd as _);
+    if let Some(old) = fd_table.remove(new_fd as _) {
+        release_posix_locks(&old.inner);
+    }
     fd_table
         .add_at(new_fd as _, f)
         .map_err(|_| AxError::BadFileDescriptor)?;
@@ -242,15 +547,65 @@ pub fn sys_fcntl(fd: c_int, cmd: c_int, arg: usize) -> AxResult<isize> {
     match cmd as u32 {
         F_DUPFD => dup_fd(fd, false),
         F_DUPFD_CLOEXEC => dup_fd(fd, true),
-        F_SETLK | F_SETLKW => Ok(0),
-        F_OFD_SETLK | F_OFD_SETLKW => Ok(0),
+        F_SETLK | F_SETLKW | F_OFD_SETLK | F_OFD_SETLKW => {
+            let f = get_file_like(fd)?;
+            let ofd = matches!(cmd as u32, F_OFD_SETLK | F_OFD_SETLKW);
+            let wait = matches!(cmd as u32, F_SETLKW | F_OFD_SETLKW);
+            let lock = UserPtr::<flock64>::from(arg).get_as_mut()?;
+            let owner = lock_owner(&f, lock, ofd)?;
+            let (start, end) = lock_range(&f, lock)?;
+            let kind = match lock.l_type as u32 {
+                F_RDLCK => Some(LockKind::Shared),
+                F_WRLCK => Some(LockKind::Exclusive),
+                F_UNLCK => None,
+                _ => return Err(AxError::InvalidInput),
+            };
+            check_lock_access(&f, kind)?;
+            set_record_lock(&lock_location(&f)?, owner, kind, start, end, wait)?;
+            Ok(0)
+        }
         F_GETLK | F_OFD_GETLK => {
-            let arg = UserPtr::<flock64>::from(arg);
-            arg.get_as_mut()?.l_type = F_UNLCK as _;
+            let f = get_file_like(fd)?;
+            let lock = UserPtr::<flock64>::from(arg).get_as_mut()?;
+            let owner = lock_owner(&f, lock, cmd as u32 == F_OFD_GETLK)?;
+            let (start, end) = lock_range(&f, lock)?;
+            let kind = match lock.l_type as u32 {
+                F_RDLCK => LockKind::Shared,
+                F_WRLCK => LockKind::Exclusive,
+                _ => return Err(AxError::InvalidInput),
+            };
+            let request = RecordLock {
+                owner,
+                kind,
+                start,
+                end,
+            };
+            match test_record_lock(&lock_location(&f)?, &request) {
+                Some(conflict) => {
+                    lock.l_type = match conflict.kind {
+                        LockKind::Shared => F_RDLCK,
+                        LockKind::Exclusive => F_WRLCK,
+                    } as _;
+                    lock.l_whence = SEEK_SET as _;
+                    lock.l_start = conflict.start as _;
+                    lock.l_len = if conflict.end == u64::MAX {
+                        0
+                    } else {
+                        (conflict.end - conflict.start) as _
+                    };
+                    lock.l_pid = conflict.owner.pid();
+                }
+                None => lock.l_type = F_UNLCK as _,
+            }
             Ok(0)
         }
         F_SETFL => {
-            get_file_like(fd)?.set_nonblocking(arg & (O_NONBLOCK as usize) > 0)?;
+            let f = get_file_like(fd)?;
+            f.set_nonblocking(arg & (O_NONBLOCK as usize) > 0)?;
+            let enabled = arg & (FASYNC as usize) > 0;
+            if enabled || is_async(&f) {
+                AsyncFile::of(&f).set_enabled(fd, enabled);
+            }
             Ok(0)
         }
         F_GETFL => {
@@ -260,6 +615,9 @@ pub fn sys_fcntl(fd: c_int, cmd: c_int, arg: usize) -> AxResult<isize> {
             if f.nonblocking() {
                 ret |= O_NONBLOCK;
             }
+            if is_async(&f) {
+                ret |= FASYNC;
+            }
 
             let perm = NodePermission::from_bits_truncate(f.stat()?.mode as _);
             if perm.contains(NodePermission::OWNER_WRITE) {
@@ -289,6 +647,93 @@ pub fn sys_fcntl(fd: c_int, cmd: c_int, arg: usize) -> AxResult<isize> {
                 .cloexec = cloexec;
             Ok(0)
         }
+        F_ADD_SEALS => {
+            let f = get_file_like(fd)?;
+            let f = f.downcast_ref::<File>().ok_or(AxError::InvalidInput)?;
+            if !f.inner().flags().contains(FileFlags::WRITE) {
+                return Err(AxError::OperationNotPermitted);
+            }
+            add_seals(f.inner().location(), arg as _)?;
+            Ok(0)
+        }
+        F_GET_SEALS => {
+            let f = get_file_like(fd)?;
+            let f = f.downcast_ref::<File>().ok_or(AxError::InvalidInput)?;
+            Ok(get_seals(f.inner().location())? as _)
+        }
+        F_SETOWN => {
+            let f = get_file_like(fd)?;
+            AsyncFile::of(&f).set_owner(AsyncOwner::from_pid(arg as i32)?)?;
+            Ok(0)
+        }
+        F_GETOWN => {
+            let f = get_file_like(fd)?;
+            Ok(AsyncOwner::to_pid(AsyncFile::get(&f).and_then(|it| it.owner())) as _)
+        }
+        F_SETOWN_EX => {
+            let f = get_file_like(fd)?;
+            let owner = UserPtr::<f_owner_ex>::from(arg).get_as_mut()?;
+            AsyncFile::of(&f).set_owner(AsyncOwner::from_owner_ex(owner)?)?;
+            Ok(0)
+        }
+        F_GETOWN_EX => {
+            let f = get_file_like(fd)?;
+            *UserPtr::<f_owner_ex>::from(arg).get_as_mut()? =
+                AsyncOwner::to_owner_ex(AsyncFile::get(&f).and_then(|it| it.owner()));
+            Ok(0)
+        }
+        F_SETSIG => {
+            let f = get_file_like(fd)?;
+            AsyncFile::of(&f).set_signal(arg as _)?;
+            Ok(0)
+        }
+        F_GETSIG => {
+            let f = get_file_like(fd)?;
+            Ok(AsyncFile::get(&f).map_or(0, |it| it.signal()) as _)
+        }
+        F_SETLEASE => {
+            let f = get_file_like(fd)?;
+            let file = f.downcast_ref::<File>().ok_or(AxError::InvalidInput)?;
+            let loc = file.inner().location();
+            if loc.node_type() != NodeType::RegularFile {
+                return Err(AxError::InvalidInput);
+            }
+            let kind = match arg as u32 {
+                F_RDLCK => Some(LockKind::Shared),
+                F_WRLCK => Some(LockKind::Exclusive),
+                F_UNLCK => None,
+                _ => return Err(AxError::InvalidInput),
+            };
+            let euid = sys_geteuid()? as u32;
+            if kind.is_some() && euid != 0 && euid != loc.metadata()?.uid {
+                return Err(AxError::PermissionDenied);
+            }
+            set_lease(loc, &f, fd, kind)?;
+            if kind.is_some() {
+                AsyncFile::of(&f).set_owner(Some(AsyncOwner::current_process()))?;
+            }
+            Ok(0)
+        }
+        F_GETLEASE => {
+            let f = get_file_like(fd)?;
+            let file = f.downcast_ref::<File>().ok_or(AxError::InvalidInput)?;
+            Ok(match get_lease(file.inner().location(), &f) {
+                Some(LockKind::Shared) => F_RDLCK,
+                Some(LockKind::Exclusive) => F_WRLCK,
+                None => F_UNLCK,
+            } as _)
+        }
+        F_NOTIFY => {
+            let f = get_file_like(fd)?;
+            let dir = f
+                .downcast_ref::<Directory>()
+                .ok_or(AxError::NotADirectory)?;
+            set_dnotify(dir.inner(), &f, fd, arg as _)?;
+            if arg != 0 {
+                AsyncFile::of(&f).set_owner(Some(AsyncOwner::current_process()))?;
+            }
+            Ok(0)
+        }
         F_GETPIPE_SZ => {
             let pipe = Pipe::from_fd(fd)?;
             Ok(pipe.capacity() as _)
@@ -305,8 +750,69 @@ pub fn sys_fcntl(fd: c_int, cmd: c_int, arg: usize) -> AxResult<isize> {
     }
 }
 
+/// Returns the owner of a record lock requested through `f`.
+fn lock_owner(f: &Arc<dyn FileLike>, lock: &flock64, ofd: bool) -> AxResult<LockOwner> {
+    if !ofd {
+        return Ok(LockOwner::current_process());
+    }
+    if lock.l_pid != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    Ok(LockOwner::file(f))
+}
+
+/// Returns the byte range described by `lock` as `start..end`.
+fn lock_range(f: &Arc<dyn FileLike>, lock: &flock64) -> AxResult<(u64, u64)> {
+    let base = match lock.l_whence as u32 {
+        SEEK_SET => 0,
+        SEEK_CUR => match f.downcast_ref::<File>() {
+            Some(file) => file.inner().seek(SeekFrom::Current(0))?,
+            None => 0,
+        },
+        SEEK_END => lock_location(f)?.len()?,
+        _ => return Err(AxError::InvalidInput),
+    };
+    let start = base
+        .checked_add_signed(lock.l_start)
+        .ok_or(AxError::InvalidInput)?;
+    Ok(match lock.l_len {
+        0 => (start, u64::MAX),
+        len if len > 0 => (start, start.saturating_add(len as u64)),
+        len => (
+            start.checked_add_signed(len).ok_or(AxError::InvalidInput)?,
+            start,
+        ),
+    })
+}
+
+/// Checks that `f` is open for reading for shared locks, or writing for
+/// exclusive ones.
+fn check_lock_access(f: &Arc<dyn FileLike>, kind: Option<LockKind>) -> AxResult<()> {
+    let Some(file) = f.downcast_ref::<File>() else {
+        return Ok(());
+    };
+    let required = match kind {
+        Some(LockKind::Shared) => FileFlags::READ,
+        Some(LockKind::Exclusive) => FileFlags::WRITE,
+        None => return Ok(()),
+    };
+    if !file.inner().flags().contains(required) {
+        return Err(AxError::BadFileDescriptor);
+    }
+    Ok(())
+}
+
 pub fn sys_flock(fd: c_int, operation: c_int) -> AxResult<isize> {
     debug!("flock <= fd: {fd}, operation: {operation}");
-    // TODO: flock
+    let f = get_file_like(fd)?;
+    let loc = lock_location(&f)?;
+    let owner = LockOwner::file(&f);
+    let wait = operation as u32 & LOCK_NB == 0;
+    match operation as u32 & !LOCK_NB {
+        LOCK_SH => flock(&loc, owner, LockKind::Shared, wait)?,
+        LOCK_EX => flock(&loc, owner, LockKind::Exclusive, wait)?,
+        LOCK_UN => funlock(&loc, owner),
+        _ => return Err(AxError::InvalidInput),
+    }
     Ok(0)
 }
@@ -4,18 +4,32 @@ use core::{
     task::Context,
 };
 
-use axerrno::{AxError, AxResult};
-use axfs::{FS_CONTEXT, FileFlags, OpenOptions};
-use axio::{Seek, SeekFrom};
+use axerrno::{AxError, AxResult, LinuxError};
+use axfs::{FS_CONTEXT, FileBackend, FileFlags, OpenOptions};
+use axfs_ng_vfs::NodeType;
+use axio::{IoBuf, Seek, SeekFrom};
 use axpoll::{IoEvents, Pollable};
 use axtask::current;
-use linux_raw_sys::general::__kernel_off_t;
+use linux_raw_sys::general::{
+    __kernel_off_t, FALLOC_FL_ALLOCATE_RANGE, FALLOC_FL_COLLAPSE_RANGE, FALLOC_FL_INSERT_RANGE,
+    FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE, FALLOC_FL_ZERO_RANGE, SEEK_CUR, SEEK_DATA, SEEK_END,
+    SEEK_HOLE, SEEK_SET, SPLICE_F_GIFT, SPLICE_F_MORE, SPLICE_F_MOVE, SPLICE_F_NONBLOCK,
+};
 use starry_vm::{VmMutPtr, VmPtr};
 use syscalls::Sysno;
 
 use crate::{
-    file::{File, FileLike, Pipe, get_file_like},
+    file::{
+        File, FileLike, Pipe, get_file_like,
+        lease::break_lease,
+        memfd::{check_modify_seals, check_resize_seals, check_write_seals},
+    },
+    fs::{
+        allocate_range, check_write, collapse_range, insert_range, mark_dirty, notify_access,
+        notify_modify, punch_hole, read_at, seek_data, sync_file, zero_range,
+    },
     mm::{IoVec, IoVectorBuf, UserConstPtr, VmBytes, VmBytesMut},
+    pseudofs::charge_pages,
 };
 
 struct DummyFd;
@@ -74,13 +88,18 @@ pub fn sys_writev(fd: i32, iov: *const IoVec, iovcnt: usize) -> AxResult<isize>
 
 pub fn sys_lseek(fd: c_int, offset: __kernel_off_t, whence: c_int) -> AxResult<isize> {
     debug!("sys_lseek <= {fd} {offset} {whence}");
-    let pos = match whence {
-        0 => SeekFrom::Start(offset as _),
-        1 => SeekFrom::Current(offset as _),
-        2 => SeekFrom::End(offset as _),
+    let f = File::from_fd(fd)?;
+    let pos = match whence as u32 {
+        SEEK_SET => SeekFrom::Start(offset as _),
+        SEEK_CUR => SeekFrom::Current(offset as _),
+        SEEK_END => SeekFrom::End(offset as _),
+        whence @ (SEEK_DATA | SEEK_HOLE) => {
+            let offset = u64::try_from(offset).map_err(|_| AxError::from(LinuxError::ENXIO))?;
+            SeekFrom::Start(seek_data(f.inner(), offset, whence == SEEK_HOLE)?)
+        }
         _ => return Err(AxError::InvalidInput),
     };
-    let off = File::from_fd(fd)?.inner().seek(pos)?;
+    let off = f.inner().seek(pos)?;
     Ok(off as _)
 }
 
@@ -94,14 +113,24 @@ pub fn sys_truncate(path: UserConstPtr<c_char>, length: __kernel_off_t) -> AxRes
         .write(true)
         .open(&FS_CONTEXT.lock(), path)?
         .into_file()?;
+    check_write(file.location())?;
+    check_resize_seals(file.location(), length as _)?;
+    break_lease(file.location(), true, false)?;
     file.access(FileFlags::WRITE)?.set_len(length as _)?;
+    notify_modify(file.location());
     Ok(0)
 }
 
 pub fn sys_ftruncate(fd: c_int, length: __kernel_off_t) -> AxResult<isize> {
     debug!("sys_ftruncate <= {fd} {length}");
+    if length < 0 {
+        return Err(AxError::InvalidInput);
+    }
     let f = File::from_fd(fd)?;
-    f.inner().access(FileFlags::WRITE)?.set_len(length as _)?;
+    let file = f.inner().access(FileFlags::WRITE)?;
+    check_resize_seals(file.location(), length as _)?;
+    file.set_len(length as _)?;
+    notify_modify(f.inner().location());
     Ok(0)
 }
 
@@ -111,28 +140,77 @@ pub fn sys_fallocate(
     offset: __kernel_off_t,
     len: __kernel_off_t,
 ) -> AxResult<isize> {
-    debug!("sys_fallocate <= fd: {fd}, mode: {mode}, offset: {offset}, len: {len}");
-    if mode != 0 {
+    debug!("sys_fallocate <= fd: {fd}, mode: {mode:#x}, offset: {offset}, len: {len}");
+    if offset < 0 || len <= 0 {
         return Err(AxError::InvalidInput);
     }
+    let keep_size = mode & FALLOC_FL_KEEP_SIZE != 0;
+    match mode & !FALLOC_FL_KEEP_SIZE {
+        FALLOC_FL_ALLOCATE_RANGE | FALLOC_FL_ZERO_RANGE => {}
+        FALLOC_FL_PUNCH_HOLE if keep_size => {}
+        FALLOC_FL_COLLAPSE_RANGE | FALLOC_FL_INSERT_RANGE if !keep_size => {}
+        _ => return Err(AxError::OperationNotSupported),
+    }
     let f = File::from_fd(fd)?;
     let inner = f.inner();
-    let file = inner.access(FileFlags::WRITE)?;
-    file.set_len(file.location().len()?.max(offset as u64 + len as u64))?;
+    let loc = inner.access(FileFlags::WRITE)?.location();
+    if loc.node_type() != NodeType::RegularFile {
+        return Err(AxError::NoSuchDevice);
+    }
+    let (offset, len) = (offset as u64, len as u64);
+    let end = offset
+        .checked_add(len)
+        .filter(|it| *it <= i64::MAX as u64)
+        .ok_or(AxError::from(LinuxError::EFBIG))?;
+    let size = loc.len()?;
+
+    match mode & !FALLOC_FL_KEEP_SIZE {
+        FALLOC_FL_PUNCH_HOLE => {
+            check_modify_seals(loc)?;
+            punch_hole(inner, offset, len)?;
+        }
+        FALLOC_FL_ZERO_RANGE => {
+            check_modify_seals(loc)?;
+            if !keep_size && end > size {
+                check_resize_seals(loc, end)?;
+            }
+            zero_range(inner, offset, len, keep_size)?;
+        }
+        FALLOC_FL_COLLAPSE_RANGE => {
+            check_modify_seals(loc)?;
+            check_resize_seals(loc, size.saturating_sub(len))?;
+            collapse_range(inner, offset, len)?;
+        }
+        FALLOC_FL_INSERT_RANGE => {
+            check_modify_seals(loc)?;
+            if size.checked_add(len).is_none_or(|it| it > i64::MAX as u64) {
+                return Err(AxError::from(LinuxError::EFBIG));
+            }
+            check_resize_seals(loc, size + len)?;
+            insert_range(inner, offset, len)?;
+        }
+        _ => {
+            if !keep_size && end > size {
+                check_resize_seals(loc, end)?;
+            }
+            allocate_range(inner, offset, len, keep_size)?;
+        }
+    }
+    notify_modify(loc);
     Ok(0)
 }
 
 pub fn sys_fsync(fd: c_int) -> AxResult<isize> {
     debug!("sys_fsync <= {fd}");
     let f = File::from_fd(fd)?;
-    f.inner().sync(false)?;
+    sync_file(f.inner(), false)?;
     Ok(0)
 }
 
 pub fn sys_fdatasync(fd: c_int) -> AxResult<isize> {
     debug!("sys_fdatasync <= {fd}");
     let f = File::from_fd(fd)?;
-    f.inner().sync(true)?;
+    sync_file(f.inner(), true)?;
     Ok(0)
 }
 
@@ -157,7 +235,10 @@ pub fn sys_pread64(fd: c_int, buf: *mut u8, len: usize, offset: __kernel_off_t)
     if offset < 0 {
         return Err(AxError::InvalidInput);
     }
-    let read = f.inner().read_at(VmBytesMut::new(buf, len), offset as _)?;
+    let read = read_at(f.inner(), VmBytesMut::new(buf, len), offset as _)?;
+    if read > 0 {
+        notify_access(f.inner().location());
+    }
     Ok(read as _)
 }
 
@@ -171,7 +252,13 @@ pub fn sys_pwrite64(
         return Ok(0);
     }
     let f = File::from_fd(fd)?;
+    check_write_seals(f.inner().location(), offset as _, len)?;
+    charge_pages(f.inner().location(), offset as _, len as _)?;
     let write = f.inner().write_at(VmBytes::new(buf, len), offset as _)?;
+    if write > 0 {
+        mark_dirty(f.inner(), offset as _, write);
+        notify_modify(f.inner().location());
+    }
     Ok(write as _)
 }
 
@@ -202,9 +289,12 @@ pub fn sys_preadv2(
 ) -> AxResult<isize> {
     debug!("sys_preadv2 <= fd: {fd}, iovcnt: {iovcnt}, offset: {offset}, flags: {_flags}");
     let f = File::from_fd(fd)?;
-    f.inner()
-        .read_at(IoVectorBuf::new(iov, iovcnt)?.into_io(), offset as _)
-        .map(|n| n as _)
+    read_at(
+        f.inner(),
+        IoVectorBuf::new(iov, iovcnt)?.into_io(),
+        offset as _,
+    )
+    .map(|n| n as _)
 }
 
 pub fn sys_pwritev2(
@@ -216,9 +306,19 @@ pub fn sys_pwritev2(
 ) -> AxResult<isize> {
     debug!("sys_pwritev2 <= fd: {fd}, iovcnt: {iovcnt}, offset: {offset}, flags: {_flags}");
     let f = File::from_fd(fd)?;
-    f.inner()
-        .read_at(IoVectorBuf::new(iov, iovcnt)?.into_io(), offset as _)
-        .map(|n| n as _)
+    let src = IoVectorBuf::new(iov, iovcnt)?.into_io();
+    let len = src.remaining();
+    if len == 0 {
+        return Ok(0);
+    }
+    check_write_seals(f.inner().location(), offset as _, len)?;
+    charge_pages(f.inner().location(), offset as _, len as _)?;
+    let write = f.inner().write_at(src, offset as _)?;
+    if write > 0 {
+        mark_dirty(f.inner(), offset as _, write);
+        notify_modify(f.inner().location());
+    }
+    Ok(write as _)
 }
 
 enum SendFile {
@@ -242,6 +342,7 @@ impl SendFile {
                 let off = offset.vm_read()?;
                 let bytes_read = file.inner().read_at(&mut buf, off)?;
                 offset.vm_write(off + bytes_read as u64)?;
+                notify_access(file.inner().location());
                 Ok(bytes_read)
             }
         }
@@ -252,8 +353,12 @@ impl SendFile {
             SendFile::Direct(file) => file.write(&mut buf),
             SendFile::Offset(file, offset) => {
                 let off = offset.vm_read()?;
+                check_write_seals(file.inner().location(), off, buf.len())?;
+                charge_pages(file.inner().location(), off, buf.len() as _)?;
                 let bytes_written = file.inner().write_at(buf, off)?;
                 offset.vm_write(off + bytes_written as u64)?;
+                mark_dirty(file.inner(), off, bytes_written);
+                notify_modify(file.inner().location());
                 Ok(bytes_written)
             }
         }
@@ -320,7 +425,7 @@ pub fn sys_copy_file_range(
     fd_out: c_int,
     off_out: *mut u64,
     len: usize,
-    _flags: u32,
+    flags: u32,
 ) -> AxResult<isize> {
     debug!(
         "sys_copy_file_range <= fd_in: {}, off_in: {}, fd_out: {}, off_out: {}, len: {}, flags: {}",
@@ -329,23 +434,63 @@ pub fn sys_copy_file_range(
         fd_out,
         !off_out.is_null(),
         len,
-        _flags
+        flags
     );
 
-    // TODO: check flags
-    // TODO: check both regular files
-    // TODO: check same file and overlap
+    if flags != 0 {
+        return Err(AxError::InvalidInput);
+    }
+
+    let file_in = File::from_fd(fd_in)?;
+    let file_out = File::from_fd(fd_out)?;
+    for file in [&file_in, &file_out] {
+        match file.inner().location().node_type() {
+            NodeType::RegularFile => {}
+            NodeType::Directory => return Err(AxError::IsADirectory),
+            _ => return Err(AxError::InvalidInput),
+        }
+    }
+    file_in.inner().access(FileFlags::READ)?;
+    file_out.inner().access(FileFlags::WRITE)?;
+    if file_out.inner().flags().contains(FileFlags::APPEND) {
+        return Err(AxError::BadFileDescriptor);
+    }
+
+    let pos_in = if off_in.is_null() {
+        file_in.inner().seek(SeekFrom::Current(0))?
+    } else {
+        off_in.vm_read()?
+    };
+    let pos_out = if off_out.is_null() {
+        file_out.inner().seek(SeekFrom::Current(0))?
+    } else {
+        off_out.vm_read()?
+    };
+    if file_in
+        .inner()
+        .location()
+        .entry()
+        .ptr_eq(file_out.inner().location().entry())
+        && pos_in < pos_out.saturating_add(len as u64)
+        && pos_out < pos_in.saturating_add(len as u64)
+    {
+        return Err(AxError::InvalidInput);
+    }
+
+    if len == 0 {
+        return Ok(0);
+    }
 
     let src = if !off_in.is_null() {
-        SendFile::Offset(File::from_fd(fd_in)?, off_in)
+        SendFile::Offset(file_in, off_in)
     } else {
-        SendFile::Direct(get_file_like(fd_in)?)
+        SendFile::Direct(file_in)
     };
 
     let dst = if !off_out.is_null() {
-        SendFile::Offset(File::from_fd(fd_out)?, off_out)
+        SendFile::Offset(file_out, off_out)
     } else {
-        SendFile::Direct(get_file_like(fd_out)?)
+        SendFile::Direct(file_out)
     };
 
     do_send(src, dst, len).map(|n| n as _)
@@ -357,7 +502,7 @@ pub fn sys_splice(
     fd_out: c_int,
     off_out: *mut i64,
     len: usize,
-    _flags: u32,
+    flags: u32,
 ) -> AxResult<isize> {
     debug!(
         "sys_splice <= fd_in: {}, off_in: {}, fd_out: {}, off_out: {}, len: {}, flags: {}",
@@ -366,26 +511,31 @@ pub fn sys_splice(
         fd_out,
         !off_out.is_null(),
         len,
-        _flags
+        flags
     );
 
-    let mut has_pipe = false;
+    if flags & !(SPLICE_F_MOVE | SPLICE_F_NONBLOCK | SPLICE_F_MORE | SPLICE_F_GIFT) != 0 {
+        return Err(AxError::InvalidInput);
+    }
 
     if DummyFd::from_fd(fd_in).is_ok() || DummyFd::from_fd(fd_out).is_ok() {
         return Err(AxError::BadFileDescriptor);
     }
 
-    let src = if !off_in.is_null() {
+    let pipe_in = Pipe::from_fd(fd_in).ok();
+    let pipe_out = Pipe::from_fd(fd_out).ok();
+
+    let mut src = if !off_in.is_null() {
+        if pipe_in.is_some() {
+            return Err(AxError::from(LinuxError::ESPIPE));
+        }
         if off_in.vm_read()? < 0 {
             return Err(AxError::InvalidInput);
         }
         SendFile::Offset(File::from_fd(fd_in)?, off_in.cast())
     } else {
-        if let Ok(src) = Pipe::from_fd(fd_in) {
-            if !src.is_read() {
-                return Err(AxError::BadFileDescriptor);
-            }
-            has_pipe = true;
+        if pipe_in.as_ref().is_some_and(|pipe| !pipe.is_read()) {
+            return Err(AxError::BadFileDescriptor);
         }
         if let Ok(file) = File::from_fd(fd_in)
             && file.inner().is_path()
@@ -395,17 +545,17 @@ pub fn sys_splice(
         SendFile::Direct(get_file_like(fd_in)?)
     };
 
-    let dst = if !off_out.is_null() {
+    let mut dst = if !off_out.is_null() {
+        if pipe_out.is_some() {
+            return Err(AxError::from(LinuxError::ESPIPE));
+        }
         if off_out.vm_read()? < 0 {
             return Err(AxError::InvalidInput);
         }
         SendFile::Offset(File::from_fd(fd_out)?, off_out.cast())
     } else {
-        if let Ok(dst) = Pipe::from_fd(fd_out) {
-            if !dst.is_write() {
-                return Err(AxError::BadFileDescriptor);
-            }
-            has_pipe = true;
+        if pipe_out.as_ref().is_some_and(|pipe| !pipe.is_write()) {
+            return Err(AxError::BadFileDescriptor);
         }
         if let Ok(file) = File::from_fd(fd_out)
             && file.inner().access(FileFlags::APPEND).is_ok()
@@ -417,9 +567,105 @@ pub fn sys_splice(
         SendFile::Direct(f)
     };
 
-    if !has_pipe {
+    let nonblocking = flags & SPLICE_F_NONBLOCK != 0;
+    match (pipe_in, pipe_out) {
+        (Some(pipe_in), Some(pipe_out)) => pipe_in.splice_to(
+            &pipe_out,
+            len,
+            false,
+            nonblocking || pipe_in.nonblocking() || pipe_out.nonblocking(),
+        ),
+        (Some(pipe_in), None) => {
+            pipe_in.splice_out(len, nonblocking || pipe_in.nonblocking(), |buf| {
+                dst.write(buf)
+            })
+        }
+        (None, Some(pipe_out)) => {
+            let nonblocking = nonblocking || pipe_out.nonblocking();
+            match splice_from_cache(fd_in, off_in.cast(), &pipe_out, len, nonblocking)? {
+                Some(read) => Ok(read),
+                None => pipe_out.splice_in(len, nonblocking, |buf| src.read(buf)),
+            }
+        }
+        (None, None) => Err(AxError::InvalidInput),
+    }
+    .map(|n| n as _)
+}
+
+/// Splices up to `len` bytes of the file `fd` at `*offset`, or its position if
+/// `offset` is null, into `pipe` straight from the page cache. Returns `None`
+/// if the file is not read through the page cache.
+fn splice_from_cache(
+    fd: c_int,
+    offset: *mut u64,
+    pipe: &Pipe,
+    len: usize,
+    nonblocking: bool,
+) -> AxResult<Option<usize>> {
+    let Ok(file) = File::from_fd(fd) else {
+        return Ok(None);
+    };
+    let mut inner = file.inner();
+    let FileBackend::Cached(cache) = inner.access(FileFlags::READ)? else {
+        return Ok(None);
+    };
+    let off = if offset.is_null() {
+        inner.seek(SeekFrom::Current(0))?
+    } else {
+        offset.vm_read()?
+    };
+    let read = pipe.splice_cache(cache, off, len, nonblocking)?;
+    if offset.is_null() {
+        inner.seek(SeekFrom::Start(off + read as u64))?;
+    } else {
+        offset.vm_write(off + read as u64)?;
+    }
+    if read > 0 {
+        notify_access(inner.location());
+    }
+    Ok(Some(read))
+}
+
+/// Duplicates up to `len` bytes from the pipe `fd_in` to the pipe `fd_out`
+/// without consuming them.
+pub fn sys_tee(fd_in: c_int, fd_out: c_int, len: usize, flags: u32) -> AxResult<isize> {
+    debug!("sys_tee <= fd_in: {fd_in}, fd_out: {fd_out}, len: {len}, flags: {flags}");
+
+    if flags & !(SPLICE_F_MOVE | SPLICE_F_NONBLOCK | SPLICE_F_MORE | SPLICE_F_GIFT) != 0 {
         return Err(AxError::InvalidInput);
     }
+    let pipe_in = Pipe::from_fd(fd_in)?;
+    let pipe_out = Pipe::from_fd(fd_out)?;
+    let nonblocking =
+        flags & SPLICE_F_NONBLOCK != 0 || pipe_in.nonblocking() || pipe_out.nonblocking();
+    pipe_in
+        .splice_to(&pipe_out, len, true, nonblocking)
+        .map(|n| n as _)
+}
 
-    do_send(src, dst, len).map(|n| n as _)
+/// Writes the user memory described by `iov` to the pipe `fd`, or reads the
+/// pipe into it if `fd` is the read end.
+///
+/// With `SPLICE_F_GIFT`, the pages of private mappings are given to the pipe
+/// instead of copying them, and the process keeps a copy-on-write view of
+/// them.
+pub fn sys_vmsplice(fd: c_int, iov: *const IoVec, nr_segs: usize, flags: u32) -> AxResult<isize> {
+    debug!("sys_vmsplice <= fd: {fd}, nr_segs: {nr_segs}, flags: {flags}");
+
+    if flags & !(SPLICE_F_MOVE | SPLICE_F_NONBLOCK | SPLICE_F_MORE | SPLICE_F_GIFT) != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    let pipe = Pipe::from_fd(fd).map_err(|_| AxError::BadFileDescriptor)?;
+    let nonblocking = flags & SPLICE_F_NONBLOCK != 0 || pipe.nonblocking();
+    let buf = IoVectorBuf::new(iov, nr_segs)?;
+    if pipe.is_write() && flags & SPLICE_F_GIFT != 0 {
+        return pipe.splice_gift(buf, nonblocking).map(|n| n as _);
+    }
+    let mut buf = buf.into_io();
+    if pipe.is_write() {
+        pipe.write_from(&mut buf, nonblocking)
+    } else {
+        pipe.read_to(&mut buf, nonblocking)
+    }
+    .map(|n| n as _)
 }
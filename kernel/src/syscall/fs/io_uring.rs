@@ -0,0 +1,613 @@
+use alloc::{
+    boxed::Box,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use core::{future::poll_fn, task::Poll};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axhal::time::TimeValue;
+use axpoll::IoEvents;
+use bitflags::bitflags;
+use linux_raw_sys::{
+    general::__kernel_timespec,
+    io_uring::{
+        IO_URING_OP_SUPPORTED, IORING_ACCEPT_MULTISHOT, IORING_ENTER_GETEVENTS,
+        IORING_ENTER_SQ_WAIT, IORING_ENTER_SQ_WAKEUP, IORING_FSYNC_DATASYNC, IORING_POLL_ADD_LEVEL,
+        IORING_RECV_MULTISHOT, IORING_SETUP_CLAMP, IORING_SETUP_COOP_TASKRUN, IORING_SETUP_CQSIZE,
+        IORING_SETUP_DEFER_TASKRUN, IORING_SETUP_SINGLE_ISSUER, IORING_SETUP_SUBMIT_ALL,
+        IORING_SETUP_TASKRUN_FLAG, IORING_TIMEOUT_ABS, IORING_TIMEOUT_BOOTTIME,
+        IORING_TIMEOUT_CLOCK_MASK, IORING_TIMEOUT_ETIME_SUCCESS, IORING_TIMEOUT_REALTIME,
+        io_uring_op, io_uring_params, io_uring_register_op, io_uring_sqe_flags_bit,
+    },
+};
+use starry_signal::SignalSet;
+use starry_vm::{VmMutPtr, VmPtr, vm_write_slice};
+
+use super::{
+    sys_close, sys_fdatasync, sys_fsync, sys_openat, sys_pread64, sys_preadv, sys_pwrite64,
+    sys_pwritev, sys_read, sys_readv, sys_write, sys_writev,
+};
+use crate::{
+    file::{
+        File, FileLike, add_file_like, get_file_like,
+        io_uring::{IoUring, MAX_CQ_ENTRIES, MAX_SQ_ENTRIES, RequestFuture, RequestKind, Sqe},
+    },
+    mm::{IoVec, UserConstPtr, UserPtr},
+    syscall::{
+        net::{
+            sys_accept4, sys_connect, sys_recvfrom, sys_recvmsg, sys_sendmsg, sys_sendto,
+            sys_shutdown,
+        },
+        signal::check_sigset_size,
+    },
+    task::{AlarmKey, call_at, cancel_alarm, with_blocked_signals},
+    time::{Clock, TimeValueLike},
+};
+
+bitflags! {
+    /// Flags for the `io_uring_setup` syscall.
+    ///
+    /// Requests run on the submitting task or on the workers of the ring, so
+    /// the task running hints have no effect.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct IoUringSetupFlags: u32 {
+        /// The size of the completion queue is given.
+        const CQSIZE = IORING_SETUP_CQSIZE;
+        /// Clamp the queue sizes to the maximum instead of failing.
+        const CLAMP = IORING_SETUP_CLAMP;
+        /// Continue submitting after an entry fails.
+        const SUBMIT_ALL = IORING_SETUP_SUBMIT_ALL;
+        /// Do not interrupt tasks to run completions.
+        const COOP_TASKRUN = IORING_SETUP_COOP_TASKRUN;
+        /// Flag the ring when completions need to be run.
+        const TASKRUN_FLAG = IORING_SETUP_TASKRUN_FLAG;
+        /// Only one task submits requests.
+        const SINGLE_ISSUER = IORING_SETUP_SINGLE_ISSUER;
+        /// Run completions only when the ring is entered.
+        const DEFER_TASKRUN = IORING_SETUP_DEFER_TASKRUN;
+    }
+}
+
+bitflags! {
+    /// Flags for the `io_uring_enter` syscall.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct IoUringEnterFlags: u32 {
+        /// Wait for completions.
+        const GETEVENTS = IORING_ENTER_GETEVENTS;
+        /// Wake up the submission queue thread.
+        const SQ_WAKEUP = IORING_ENTER_SQ_WAKEUP;
+        /// Wait for space in the submission queue.
+        const SQ_WAIT = IORING_ENTER_SQ_WAIT;
+    }
+}
+
+bitflags! {
+    /// Flags of submission queue entries.
+    #[derive(Debug, Clone, Copy, Default)]
+    struct SqeFlags: u8 {
+        const FIXED_FILE = 1 << io_uring_sqe_flags_bit::IOSQE_FIXED_FILE_BIT as u8;
+        const IO_DRAIN = 1 << io_uring_sqe_flags_bit::IOSQE_IO_DRAIN_BIT as u8;
+        const IO_LINK = 1 << io_uring_sqe_flags_bit::IOSQE_IO_LINK_BIT as u8;
+        const IO_HARDLINK = 1 << io_uring_sqe_flags_bit::IOSQE_IO_HARDLINK_BIT as u8;
+        const ASYNC = 1 << io_uring_sqe_flags_bit::IOSQE_ASYNC_BIT as u8;
+        const BUFFER_SELECT = 1 << io_uring_sqe_flags_bit::IOSQE_BUFFER_SELECT_BIT as u8;
+        const CQE_SKIP_SUCCESS = 1 << io_uring_sqe_flags_bit::IOSQE_CQE_SKIP_SUCCESS_BIT as u8;
+    }
+}
+
+const IORING_OP_NOP: u8 = io_uring_op::IORING_OP_NOP as u8;
+const IORING_OP_READV: u8 = io_uring_op::IORING_OP_READV as u8;
+const IORING_OP_WRITEV: u8 = io_uring_op::IORING_OP_WRITEV as u8;
+const IORING_OP_FSYNC: u8 = io_uring_op::IORING_OP_FSYNC as u8;
+const IORING_OP_READ_FIXED: u8 = io_uring_op::IORING_OP_READ_FIXED as u8;
+const IORING_OP_WRITE_FIXED: u8 = io_uring_op::IORING_OP_WRITE_FIXED as u8;
+const IORING_OP_POLL_ADD: u8 = io_uring_op::IORING_OP_POLL_ADD as u8;
+const IORING_OP_POLL_REMOVE: u8 = io_uring_op::IORING_OP_POLL_REMOVE as u8;
+const IORING_OP_SENDMSG: u8 = io_uring_op::IORING_OP_SENDMSG as u8;
+const IORING_OP_RECVMSG: u8 = io_uring_op::IORING_OP_RECVMSG as u8;
+const IORING_OP_TIMEOUT: u8 = io_uring_op::IORING_OP_TIMEOUT as u8;
+const IORING_OP_TIMEOUT_REMOVE: u8 = io_uring_op::IORING_OP_TIMEOUT_REMOVE as u8;
+const IORING_OP_ACCEPT: u8 = io_uring_op::IORING_OP_ACCEPT as u8;
+const IORING_OP_ASYNC_CANCEL: u8 = io_uring_op::IORING_OP_ASYNC_CANCEL as u8;
+const IORING_OP_CONNECT: u8 = io_uring_op::IORING_OP_CONNECT as u8;
+const IORING_OP_OPENAT: u8 = io_uring_op::IORING_OP_OPENAT as u8;
+const IORING_OP_CLOSE: u8 = io_uring_op::IORING_OP_CLOSE as u8;
+const IORING_OP_READ: u8 = io_uring_op::IORING_OP_READ as u8;
+const IORING_OP_WRITE: u8 = io_uring_op::IORING_OP_WRITE as u8;
+const IORING_OP_SEND: u8 = io_uring_op::IORING_OP_SEND as u8;
+const IORING_OP_RECV: u8 = io_uring_op::IORING_OP_RECV as u8;
+const IORING_OP_SHUTDOWN: u8 = io_uring_op::IORING_OP_SHUTDOWN as u8;
+const IORING_OP_LAST: u8 = io_uring_op::IORING_OP_LAST as u8;
+
+/// Operations reported as supported by `IORING_REGISTER_PROBE`.
+const SUPPORTED_OPS: &[u8] = &[
+    IORING_OP_NOP,
+    IORING_OP_READV,
+    IORING_OP_WRITEV,
+    IORING_OP_FSYNC,
+    IORING_OP_READ_FIXED,
+    IORING_OP_WRITE_FIXED,
+    IORING_OP_POLL_ADD,
+    IORING_OP_POLL_REMOVE,
+    IORING_OP_SENDMSG,
+    IORING_OP_RECVMSG,
+    IORING_OP_TIMEOUT,
+    IORING_OP_TIMEOUT_REMOVE,
+    IORING_OP_ACCEPT,
+    IORING_OP_ASYNC_CANCEL,
+    IORING_OP_CONNECT,
+    IORING_OP_OPENAT,
+    IORING_OP_CLOSE,
+    IORING_OP_READ,
+    IORING_OP_WRITE,
+    IORING_OP_SEND,
+    IORING_OP_RECV,
+    IORING_OP_SHUTDOWN,
+];
+
+/// Maximum number of buffers registered on a ring.
+const MAX_REG_BUFFERS: u32 = 1 << 14;
+
+/// Returns the size of a queue asked to hold `entries` entries.
+fn queue_size(entries: u32, max: u32, clamp: bool) -> AxResult<u32> {
+    if entries == 0 || (entries > max && !clamp) {
+        return Err(AxError::InvalidInput);
+    }
+    Ok(entries.min(max).next_power_of_two())
+}
+
+pub fn sys_io_uring_setup(entries: u32, params: *mut io_uring_params) -> AxResult<isize> {
+    // FIXME: AnyBitPattern
+    let mut p = unsafe { params.vm_read_uninit()?.assume_init() };
+    debug!(
+        "sys_io_uring_setup <= entries: {entries}, flags: {:#x}",
+        p.flags
+    );
+
+    let flags = IoUringSetupFlags::from_bits(p.flags).ok_or(AxError::InvalidInput)?;
+    if p.resv != [0; 3] {
+        return Err(AxError::InvalidInput);
+    }
+    let clamp = flags.contains(IoUringSetupFlags::CLAMP);
+    let sq_entries = queue_size(entries, MAX_SQ_ENTRIES, clamp)?;
+    let cq_entries = if flags.contains(IoUringSetupFlags::CQSIZE) {
+        let cq_entries = queue_size(p.cq_entries, MAX_CQ_ENTRIES, clamp)?;
+        if cq_entries < sq_entries {
+            return Err(AxError::InvalidInput);
+        }
+        cq_entries
+    } else {
+        2 * sq_entries
+    };
+
+    let ring = IoUring::new(sq_entries, cq_entries)?;
+    ring.fill_params(&mut p);
+    params.vm_write(p)?;
+    add_file_like(ring as _, true).map(|fd| fd as _)
+}
+
+pub fn sys_io_uring_enter(
+    fd: i32,
+    to_submit: u32,
+    min_complete: u32,
+    flags: u32,
+    sigmask: UserConstPtr<SignalSet>,
+    sigsetsize: usize,
+) -> AxResult<isize> {
+    debug!(
+        "sys_io_uring_enter <= fd: {fd}, to_submit: {to_submit}, min_complete: {min_complete}, \
+         flags: {flags:#x}"
+    );
+
+    let flags = IoUringEnterFlags::from_bits(flags).ok_or(AxError::InvalidInput)?;
+    let ring = get_file_like(fd)?
+        .downcast_arc::<IoUring>()
+        .map_err(|_| AxError::OperationNotSupported)?;
+
+    let sqes = ring.take_sqes(to_submit);
+    let submitted = sqes.len();
+    for sqe in sqes {
+        submit(&ring, sqe);
+    }
+
+    if flags.contains(IoUringEnterFlags::GETEVENTS) {
+        let sigmask = if sigmask.is_null() {
+            None
+        } else {
+            check_sigset_size(sigsetsize)?;
+            Some(*sigmask.get_as_ref()?)
+        };
+        let result = with_blocked_signals(sigmask, || ring.wait(min_complete));
+        // Waiting is not an error once entries are consumed.
+        if let Err(err) = result
+            && submitted == 0
+        {
+            return Err(err);
+        }
+    }
+    Ok(submitted as _)
+}
+
+pub fn sys_io_uring_register(fd: i32, opcode: u32, arg: usize, nr_args: u32) -> AxResult<isize> {
+    debug!("sys_io_uring_register <= fd: {fd}, opcode: {opcode}, nr_args: {nr_args}");
+
+    let ring = get_file_like(fd)?
+        .downcast_arc::<IoUring>()
+        .map_err(|_| AxError::OperationNotSupported)?;
+
+    const REGISTER_BUFFERS: u32 = io_uring_register_op::IORING_REGISTER_BUFFERS as u32;
+    const UNREGISTER_BUFFERS: u32 = io_uring_register_op::IORING_UNREGISTER_BUFFERS as u32;
+    const REGISTER_PROBE: u32 = io_uring_register_op::IORING_REGISTER_PROBE as u32;
+    match opcode {
+        REGISTER_BUFFERS => {
+            if nr_args == 0 || nr_args > MAX_REG_BUFFERS {
+                return Err(AxError::InvalidInput);
+            }
+            let iovs = arg as *const IoVec;
+            let buffers = (0..nr_args as usize)
+                .map(|i| {
+                    let iov = iovs.wrapping_add(i).vm_read()?;
+                    if iov.iov_base.is_null() || iov.iov_len <= 0 || iov.iov_len > 1 << 30 {
+                        return Err(AxError::InvalidInput);
+                    }
+                    Ok((iov.iov_base as usize, iov.iov_len as usize))
+                })
+                .collect::<AxResult<Vec<_>>>()?;
+            ring.register_buffers(buffers)?;
+        }
+        UNREGISTER_BUFFERS => {
+            if arg != 0 || nr_args != 0 {
+                return Err(AxError::InvalidInput);
+            }
+            ring.unregister_buffers()?;
+        }
+        REGISTER_PROBE => {
+            // `struct io_uring_probe` followed by `nr_args` of
+            // `struct io_uring_probe_op`.
+            let ops_len = nr_args.min(IORING_OP_LAST as u32) as usize;
+            let mut buf = vec![0u8; 16 + ops_len * 8];
+            buf[0] = IORING_OP_LAST - 1;
+            buf[1] = ops_len as u8;
+            for (op, entry) in buf[16..].chunks_exact_mut(8).enumerate() {
+                entry[0] = op as u8;
+                if SUPPORTED_OPS.contains(&(op as u8)) {
+                    entry[2..4].copy_from_slice(&(IO_URING_OP_SUPPORTED as u16).to_ne_bytes());
+                }
+            }
+            vm_write_slice(arg as *mut u8, &buf)?;
+        }
+        _ => return Err(AxError::InvalidInput),
+    }
+    Ok(0)
+}
+
+/// Converts the result of a syscall to that of a completion.
+fn completion(result: AxResult<isize>) -> i32 {
+    match result {
+        Ok(res) => res as i32,
+        Err(err) => -LinuxError::from(err).code(),
+    }
+}
+
+/// Starts the request described by `sqe`. Requests that cannot start
+/// complete with an error.
+fn submit(ring: &Arc<IoUring>, sqe: Sqe) {
+    let skip_success = sqe.flags & SqeFlags::CQE_SKIP_SUCCESS.bits() != 0;
+    let etime_success =
+        sqe.opcode == IORING_OP_TIMEOUT && sqe.op_flags & IORING_TIMEOUT_ETIME_SUCCESS != 0;
+    match prepare(ring, &sqe) {
+        Ok((kind, future)) => ring.submit(
+            sqe.user_data,
+            kind,
+            skip_success,
+            etime_success,
+            may_block(&sqe),
+            future,
+        ),
+        Err(err) => {
+            let res = completion(Err(err));
+            ring.submit(
+                sqe.user_data,
+                RequestKind::Other,
+                false,
+                false,
+                false,
+                Box::pin(async move { res }),
+            )
+        }
+    }
+}
+
+/// Returns whether the request `sqe` may block while it runs, rather than
+/// wait for readiness or for a timer, so that it is left to the workers.
+fn may_block(sqe: &Sqe) -> bool {
+    if sqe.flags & SqeFlags::ASYNC.bits() != 0 {
+        return true;
+    }
+    match sqe.opcode {
+        IORING_OP_FSYNC | IORING_OP_OPENAT | IORING_OP_CONNECT => true,
+        IORING_OP_READ
+        | IORING_OP_READ_FIXED
+        | IORING_OP_READV
+        | IORING_OP_WRITE
+        | IORING_OP_WRITE_FIXED
+        | IORING_OP_WRITEV => File::from_fd(sqe.fd).is_ok_and(|file| file.is_blocking()),
+        _ => false,
+    }
+}
+
+/// Returns the offset to access a file at, or `None` to use the file
+/// position: for an offset of -1, or for files that cannot seek.
+fn file_offset(fd: i32, off: u64) -> Option<i64> {
+    (off != u64::MAX && File::from_fd(fd).is_ok()).then_some(off as i64)
+}
+
+fn prepare(ring: &Arc<IoUring>, sqe: &Sqe) -> AxResult<(RequestKind, RequestFuture)> {
+    // Fixed files, links and provided buffers are not supported.
+    SqeFlags::from_bits(sqe.flags)
+        .filter(|it| (SqeFlags::ASYNC | SqeFlags::CQE_SKIP_SUCCESS).contains(*it))
+        .ok_or(AxError::InvalidInput)?;
+
+    let Sqe {
+        fd,
+        off,
+        addr,
+        len,
+        op_flags,
+        ..
+    } = *sqe;
+    let (addr, len) = (addr as usize, len as usize);
+    let ioprio = sqe.ioprio as u32;
+
+    let future: RequestFuture =
+        match sqe.opcode {
+            IORING_OP_NOP => Box::pin(async { 0 }),
+            IORING_OP_READ | IORING_OP_READ_FIXED => {
+                if sqe.opcode == IORING_OP_READ_FIXED {
+                    ring.check_fixed_buffer(sqe.buf_index, addr, len)?;
+                }
+                let offset = file_offset(fd, off);
+                Box::pin(when_ready(fd, IoEvents::IN, move || match offset {
+                    Some(offset) => sys_pread64(fd, addr as _, len, offset),
+                    None => sys_read(fd, addr as _, len),
+                }))
+            }
+            IORING_OP_WRITE | IORING_OP_WRITE_FIXED => {
+                if sqe.opcode == IORING_OP_WRITE_FIXED {
+                    ring.check_fixed_buffer(sqe.buf_index, addr, len)?;
+                }
+                let offset = file_offset(fd, off);
+                Box::pin(when_ready(fd, IoEvents::OUT, move || match offset {
+                    Some(offset) => sys_pwrite64(fd, addr as _, len, offset),
+                    None => sys_write(fd, addr as _, len),
+                }))
+            }
+            IORING_OP_READV => {
+                let offset = file_offset(fd, off);
+                Box::pin(when_ready(fd, IoEvents::IN, move || match offset {
+                    Some(offset) => sys_preadv(fd, addr as _, len, offset),
+                    None => sys_readv(fd, addr as _, len),
+                }))
+            }
+            IORING_OP_WRITEV => {
+                let offset = file_offset(fd, off);
+                Box::pin(when_ready(fd, IoEvents::OUT, move || match offset {
+                    Some(offset) => sys_pwritev(fd, addr as _, len, offset),
+                    None => sys_writev(fd, addr as _, len),
+                }))
+            }
+            IORING_OP_FSYNC => {
+                let datasync = match op_flags {
+                    0 => false,
+                    IORING_FSYNC_DATASYNC => true,
+                    _ => return Err(AxError::InvalidInput),
+                };
+                Box::pin(async move {
+                    completion(if datasync {
+                        sys_fdatasync(fd)
+                    } else {
+                        sys_fsync(fd)
+                    })
+                })
+            }
+            IORING_OP_POLL_ADD => {
+                // Multishot and updating polls are not supported.
+                if len as u32 & !IORING_POLL_ADD_LEVEL != 0 {
+                    return Err(AxError::InvalidInput);
+                }
+                let events = IoEvents::from_bits_truncate(op_flags & 0xffff);
+                return Ok((RequestKind::Poll, Box::pin(poll_add(fd, events))));
+            }
+            IORING_OP_POLL_REMOVE | IORING_OP_TIMEOUT_REMOVE | IORING_OP_ASYNC_CANCEL => {
+                // Updating requests and matching by anything other than the user
+                // data are not supported.
+                if op_flags != 0 {
+                    return Err(AxError::InvalidInput);
+                }
+                let kind = match sqe.opcode {
+                    IORING_OP_POLL_REMOVE => Some(RequestKind::Poll),
+                    IORING_OP_TIMEOUT_REMOVE => Some(RequestKind::Timeout),
+                    _ => None,
+                };
+                if !ring.cancel(addr as u64, kind) {
+                    return Err(AxError::NotFound);
+                }
+                Box::pin(async { 0 })
+            }
+            IORING_OP_TIMEOUT => {
+                let deadline = timeout_deadline(sqe)?;
+                let target = (off != 0).then(|| ring.completed() + off);
+                let future = timeout(Arc::downgrade(ring), deadline, target);
+                return Ok((RequestKind::Timeout, Box::pin(future)));
+            }
+            IORING_OP_ACCEPT => {
+                if ioprio & IORING_ACCEPT_MULTISHOT != 0 {
+                    return Err(AxError::InvalidInput);
+                }
+                Box::pin(when_ready(fd, IoEvents::IN, move || {
+                    sys_accept4(
+                        fd,
+                        UserPtr::from(addr),
+                        UserPtr::from(off as usize),
+                        op_flags,
+                    )
+                }))
+            }
+            IORING_OP_CONNECT => Box::pin(async move {
+                completion(sys_connect(fd, UserConstPtr::from(addr), off as u32))
+            }),
+            IORING_OP_SEND => Box::pin(when_ready(fd, IoEvents::OUT, move || {
+                sys_sendto(fd, addr as _, len, op_flags, UserConstPtr::from(0), 0)
+            })),
+            IORING_OP_SENDMSG => Box::pin(when_ready(fd, IoEvents::OUT, move || {
+                sys_sendmsg(fd, UserConstPtr::from(addr), op_flags)
+            })),
+            IORING_OP_RECV | IORING_OP_RECVMSG => {
+                if ioprio & IORING_RECV_MULTISHOT != 0 {
+                    return Err(AxError::InvalidInput);
+                }
+                let msg = sqe.opcode == IORING_OP_RECVMSG;
+                Box::pin(when_ready(fd, IoEvents::IN, move || {
+                    if msg {
+                        sys_recvmsg(fd, UserPtr::from(addr), op_flags)
+                    } else {
+                        sys_recvfrom(
+                            fd,
+                            addr as _,
+                            len,
+                            op_flags,
+                            UserPtr::from(0),
+                            UserPtr::from(0),
+                        )
+                    }
+                }))
+            }
+            IORING_OP_SHUTDOWN => Box::pin(async move { completion(sys_shutdown(fd, len as u32)) }),
+            IORING_OP_OPENAT => {
+                // Direct descriptors are not supported.
+                if sqe.file_index != 0 {
+                    return Err(AxError::InvalidInput);
+                }
+                Box::pin(async move {
+                    completion(sys_openat(fd, addr as _, op_flags as i32, len as u32))
+                })
+            }
+            IORING_OP_CLOSE => {
+                if sqe.file_index != 0 {
+                    return Err(AxError::InvalidInput);
+                }
+                Box::pin(async move { completion(sys_close(fd)) })
+            }
+            _ => return Err(AxError::InvalidInput),
+        };
+    Ok((RequestKind::Other, future))
+}
+
+/// Runs `f` once `fd` is ready for `events`, and again whenever it would
+/// block.
+async fn when_ready(
+    fd: i32,
+    events: IoEvents,
+    mut f: impl FnMut() -> AxResult<isize> + Send,
+) -> i32 {
+    let file = match get_file_like(fd) {
+        Ok(file) => file,
+        Err(err) => return completion(Err(err)),
+    };
+    let events = events | IoEvents::ALWAYS_POLL;
+    completion(
+        poll_fn(|cx| {
+            file.register(cx, events);
+            if file.poll().intersects(events) {
+                match f() {
+                    Err(AxError::WouldBlock) => {}
+                    result => return Poll::Ready(result),
+                }
+            }
+            Poll::Pending
+        })
+        .await,
+    )
+}
+
+/// Waits for `fd` to become ready for `events`, returning the events that
+/// are.
+async fn poll_add(fd: i32, events: IoEvents) -> i32 {
+    let file = match get_file_like(fd) {
+        Ok(file) => file,
+        Err(err) => return completion(Err(err)),
+    };
+    let events = events | IoEvents::ALWAYS_POLL;
+    poll_fn(|cx| {
+        file.register(cx, events);
+        let ready = file.poll() & events;
+        if ready.is_empty() {
+            Poll::Pending
+        } else {
+            Poll::Ready(ready.bits() as i32)
+        }
+    })
+    .await
+}
+
+/// Returns the deadline of a timeout request, in wall time.
+fn timeout_deadline(sqe: &Sqe) -> AxResult<TimeValue> {
+    const VALID_FLAGS: u32 =
+        IORING_TIMEOUT_ABS | IORING_TIMEOUT_CLOCK_MASK | IORING_TIMEOUT_ETIME_SUCCESS;
+    if sqe.len != 1 || sqe.op_flags & !VALID_FLAGS != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    let clock = match sqe.op_flags & IORING_TIMEOUT_CLOCK_MASK {
+        0 | IORING_TIMEOUT_BOOTTIME => Clock::Monotonic,
+        IORING_TIMEOUT_REALTIME => Clock::Realtime,
+        _ => return Err(AxError::InvalidInput),
+    };
+    // FIXME: AnyBitPattern
+    let ts = unsafe {
+        (sqe.addr as *const __kernel_timespec)
+            .vm_read_uninit()?
+            .assume_init()
+    }
+    .try_into_time_value()?;
+    let deadline = if sqe.op_flags & IORING_TIMEOUT_ABS != 0 {
+        ts
+    } else {
+        clock.now() + ts
+    };
+    Ok(clock.to_wall_time(deadline))
+}
+
+/// Cancels an alarm when dropped.
+struct AlarmGuard(AlarmKey);
+
+impl Drop for AlarmGuard {
+    fn drop(&mut self) {
+        cancel_alarm(self.0);
+    }
+}
+
+/// Waits until `deadline`, or until the ring has posted `target`
+/// completions.
+async fn timeout(ring: Weak<IoUring>, deadline: TimeValue, target: Option<u64>) -> i32 {
+    let mut alarm = None;
+    poll_fn(|cx| {
+        if let Some(target) = target
+            && ring
+                .upgrade()
+                .is_some_and(|ring| ring.completed() >= target)
+        {
+            return Poll::Ready(0);
+        }
+        if axhal::time::wall_time() >= deadline {
+            return Poll::Ready(-LinuxError::ETIME.code());
+        }
+        if alarm.is_none() {
+            let waker = cx.waker().clone();
+            alarm = Some(AlarmGuard(call_at(deadline, move || waker.wake())));
+        }
+        Poll::Pending
+    })
+    .await
+}
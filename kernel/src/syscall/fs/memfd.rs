@@ -1,32 +1,66 @@
-use alloc::format;
 use core::ffi::c_char;
 
 use axerrno::{AxError, AxResult};
-use axfs::{FS_CONTEXT, OpenOptions};
-use linux_raw_sys::general::MFD_CLOEXEC;
+use bitflags::bitflags;
+use linux_raw_sys::general::{
+    F_SEAL_EXEC, F_SEAL_SEAL, MFD_ALLOW_SEALING, MFD_CLOEXEC, MFD_EXEC, MFD_HUGE_1GB, MFD_HUGE_2MB,
+    MFD_HUGE_MASK, MFD_HUGE_SHIFT, MFD_HUGETLB, MFD_NOEXEC_SEAL,
+};
 
 use crate::{
-    file::{File, FileLike},
+    file::{File, FileLike, memfd::Memfd},
     mm::UserConstPtr,
 };
 
-// TODO: correct memfd implementation
+/// Maximum length of a memfd name, excluding the `memfd:` prefix.
+const MFD_NAME_MAX_LEN: usize = 249;
+
+bitflags! {
+    /// Flags for [`sys_memfd_create`].
+    #[derive(Debug, Clone, Copy)]
+    struct MemfdFlags: u32 {
+        /// Set close-on-exec on the new file descriptor.
+        const CLOEXEC = MFD_CLOEXEC;
+        /// Allow seals to be added to the file.
+        const ALLOW_SEALING = MFD_ALLOW_SEALING;
+        /// Back the file with huge pages.
+        const HUGETLB = MFD_HUGETLB;
+        /// Make the file non-executable for good.
+        const NOEXEC_SEAL = MFD_NOEXEC_SEAL;
+        /// Make the file executable.
+        const EXEC = MFD_EXEC;
+        /// The encoded huge page size.
+        const HUGE_SIZE = MFD_HUGE_MASK << MFD_HUGE_SHIFT;
+    }
+}
 
-pub fn sys_memfd_create(_name: UserConstPtr<c_char>, flags: u32) -> AxResult<isize> {
-    // This is cursed
-    for id in 0..0xffff {
-        let name = format!("/tmp/memfd-{id:04x}");
-        let fs = FS_CONTEXT.lock().clone();
-        if fs.resolve(&name).is_err() {
-            let file = OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .open(&fs, &name)?
-                .into_file()?;
-            let cloexec = flags & MFD_CLOEXEC != 0;
-            return File::new(file).add_to_fd_table(cloexec).map(|fd| fd as _);
+pub fn sys_memfd_create(name: UserConstPtr<c_char>, flags: u32) -> AxResult<isize> {
+    let flags = MemfdFlags::from_bits(flags).ok_or(AxError::InvalidInput)?;
+    let name = name.get_as_str()?;
+    debug!("sys_memfd_create <= name: {name:?}, flags: {flags:?}");
+
+    if name.len() > MFD_NAME_MAX_LEN || flags.contains(MemfdFlags::EXEC | MemfdFlags::NOEXEC_SEAL) {
+        return Err(AxError::InvalidInput);
+    }
+    if flags.contains(MemfdFlags::HUGETLB) {
+        // The file is backed by regular pages anyway, but only the huge page
+        // sizes that mappings support are accepted.
+        let size = (flags & MemfdFlags::HUGE_SIZE).bits();
+        if ![0, MFD_HUGE_2MB, MFD_HUGE_1GB].contains(&size) {
+            return Err(AxError::InvalidInput);
         }
+    } else if flags.intersects(MemfdFlags::HUGE_SIZE) {
+        return Err(AxError::InvalidInput);
     }
-    Err(AxError::TooManyOpenFiles)
+
+    let (mode, seals) = if flags.contains(MemfdFlags::NOEXEC_SEAL) {
+        (0o666, F_SEAL_EXEC)
+    } else if flags.contains(MemfdFlags::ALLOW_SEALING) {
+        (0o777, 0)
+    } else {
+        (0o777, F_SEAL_SEAL)
+    };
+    let file = Memfd::create(name, mode, seals)?;
+    let cloexec = flags.contains(MemfdFlags::CLOEXEC);
+    File::new(file).add_to_fd_table(cloexec).map(|fd| fd as _)
 }
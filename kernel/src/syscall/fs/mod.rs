@@ -1,14 +1,20 @@
 mod ctl;
 mod event;
 mod fd_ops;
+mod inotify;
 mod io;
+mod io_uring;
 mod memfd;
 mod mount;
 mod pidfd;
 mod pipe;
 mod signalfd;
 mod stat;
+mod timerfd;
+mod userfaultfd;
+mod xattr;
 
 pub use self::{
-    ctl::*, event::*, fd_ops::*, io::*, memfd::*, mount::*, pidfd::*, pipe::*, signalfd::*, stat::*,
+    ctl::*, event::*, fd_ops::*, inotify::*, io::*, io_uring::*, memfd::*, mount::*, pidfd::*,
+    pipe::*, signalfd::*, stat::*, timerfd::*, userfaultfd::*, xattr::*,
 };
@@ -1,38 +1,150 @@
+use alloc::vec;
 use core::ffi::{c_char, c_void};
 
 use axerrno::{AxError, AxResult};
-use axfs::FS_CONTEXT;
+use axfs::{FS_CONTEXT, FsContext};
+use axfs_ng_vfs::{Filesystem, Location};
+use axtask::current;
+use linux_raw_sys::general::{
+    MS_BIND, MS_MOVE, MS_PRIVATE, MS_REC, MS_REMOUNT, MS_SHARED, MS_SLAVE, MS_UNBINDABLE,
+};
+use starry_vm::VmPtr;
 
-use crate::{mm::vm_load_string, pseudofs::MemoryFs};
+use crate::{
+    fs::{
+        Ext4Filesystem, FatFilesystem, FatOptions, MountFlags, SquashfsFilesystem, UnmountFlags,
+        bind_at, mount_at, move_mount, new_fuse_fs, new_overlay_fs, open_block_device, remount,
+        submounts, unmount,
+    },
+    mm::vm_load_string,
+    pseudofs::{MemoryFs, TmpfsOptions},
+    task::AsThread,
+};
+
+const MS_PROPAGATION: u32 = MS_SHARED | MS_PRIVATE | MS_SLAVE | MS_UNBINDABLE;
+
+fn new_filesystem(
+    fs: &FsContext,
+    fs_type: &str,
+    source: Option<&str>,
+    data: Option<&str>,
+) -> AxResult<Filesystem> {
+    Ok(match fs_type {
+        "tmpfs" => MemoryFs::with_options(&TmpfsOptions::parse(data.unwrap_or(""))?),
+        "ext2" | "ext3" | "ext4" => {
+            let source = source.ok_or(AxError::InvalidInput)?;
+            Ext4Filesystem::new(open_block_device(fs, source)?)?
+        }
+        "vfat" | "msdos" | "fat" => {
+            let source = source.ok_or(AxError::InvalidInput)?;
+            let umask = current().as_thread().proc_data.umask();
+            FatFilesystem::new(
+                open_block_device(fs, source)?,
+                &FatOptions::parse(data.unwrap_or(""), umask)?,
+            )?
+        }
+        "squashfs" => {
+            let source = source.ok_or(AxError::InvalidInput)?;
+            SquashfsFilesystem::new(open_block_device(fs, source)?)?
+        }
+        "overlay" => new_overlay_fs(fs, data.unwrap_or(""))?,
+        "fuse" => new_fuse_fs(fs_type, data.unwrap_or(""))?,
+        _ if fs_type.starts_with("fuse.") => new_fuse_fs(fs_type, data.unwrap_or(""))?,
+        _ => return Err(AxError::NoSuchDevice),
+    })
+}
+
+/// Bind mounts `source` at `target`, and with `recursive`, all mounts below
+/// `source` at the corresponding places below `target`.
+fn bind_mount(
+    source: &Location,
+    target: &Location,
+    flags: MountFlags,
+    recursive: bool,
+) -> AxResult<()> {
+    let children = if recursive {
+        submounts(source)?
+    } else {
+        vec![]
+    };
+    let mount = bind_at(target, source, flags)?;
+    let root = FsContext::new(mount.mountpoint().root_location());
+    for (path, child) in children {
+        let child_root = child.mountpoint().root_location();
+        bind_at(&root.resolve(&path)?, &child_root, child.flags())?;
+    }
+    Ok(())
+}
 
 pub fn sys_mount(
     source: *const c_char,
     target: *const c_char,
     fs_type: *const c_char,
-    _flags: i32,
-    _data: *const c_void,
+    flags: u32,
+    data: *const c_void,
 ) -> AxResult<isize> {
-    let source = vm_load_string(source)?;
+    let source = source.nullable().map(vm_load_string).transpose()?;
     let target = vm_load_string(target)?;
-    let fs_type = vm_load_string(fs_type)?;
-    debug!("sys_mount <= source: {source:?}, target: {target:?}, fs_type: {fs_type:?}");
-
-    if fs_type != "tmpfs" {
-        return Err(AxError::NoSuchDevice);
-    }
+    let fs_type = fs_type.nullable().map(vm_load_string).transpose()?;
+    let data = data
+        .nullable()
+        .map(|data| vm_load_string(data.cast()))
+        .transpose()?;
+    debug!(
+        "sys_mount <= source: {source:?}, target: {target:?}, fs_type: {fs_type:?}, flags: \
+         {flags:#x}, data: {data:?}"
+    );
 
-    let fs = MemoryFs::new();
+    let mount_flags = MountFlags::from_bits_truncate(flags);
+    let fs = FS_CONTEXT.lock();
+    let target = fs.resolve(&target)?;
 
-    let target = FS_CONTEXT.lock().resolve(target)?;
-    target.mount(&fs)?;
+    if flags & MS_REMOUNT != 0 {
+        // Remounting a bind mount only changes the flags of the mount.
+        if flags & MS_BIND == 0
+            && target.is_root_of_mount()
+            && let Some(tmpfs) = MemoryFs::of(&target)
+        {
+            tmpfs.reconfigure(&TmpfsOptions::parse(data.as_deref().unwrap_or(""))?)?;
+        }
+        remount(&target, mount_flags)?;
+    } else if flags & MS_BIND != 0 {
+        let source = fs.resolve(source.ok_or(AxError::InvalidInput)?)?;
+        bind_mount(&source, &target, mount_flags, flags & MS_REC != 0)?;
+    } else if flags & MS_PROPAGATION != 0 {
+        // There are no peer groups, so every mount is effectively private.
+    } else if flags & MS_MOVE != 0 {
+        let source = fs.resolve(source.ok_or(AxError::InvalidInput)?)?;
+        // Moving updates the filesystem contexts of all processes.
+        drop(fs);
+        move_mount(&source, &target)?;
+    } else {
+        let fs_type = fs_type.ok_or(AxError::InvalidInput)?;
+        let new_fs = new_filesystem(&fs, &fs_type, source.as_deref(), data.as_deref())?;
+        mount_at(
+            &target,
+            &new_fs,
+            source.as_deref().unwrap_or("none"),
+            mount_flags,
+        )?;
+    }
 
     Ok(0)
 }
 
-pub fn sys_umount2(target: *const c_char, _flags: i32) -> AxResult<isize> {
+pub fn sys_umount2(target: *const c_char, flags: u32) -> AxResult<isize> {
     let target = vm_load_string(target)?;
-    debug!("sys_umount2 <= target: {target:?}");
-    let target = FS_CONTEXT.lock().resolve(target)?;
-    target.unmount()?;
+    debug!("sys_umount2 <= target: {target:?}, flags: {flags:#x}");
+
+    let flags = UnmountFlags::from_bits(flags).ok_or(AxError::InvalidInput)?;
+    let target = {
+        let fs = FS_CONTEXT.lock();
+        if flags.contains(UnmountFlags::NOFOLLOW) {
+            fs.resolve_no_follow(target)?
+        } else {
+            fs.resolve(target)?
+        }
+    };
+    unmount(target, flags)?;
     Ok(0)
 }
@@ -2,14 +2,15 @@ use core::ffi::{c_char, c_int};
 
 use axerrno::{AxError, AxResult};
 use axfs::FS_CONTEXT;
-use axfs_ng_vfs::{Location, NodePermission};
+use axfs_ng_vfs::{Location, NodePermission, NodeType};
 use linux_raw_sys::general::{
     __kernel_fsid_t, AT_EMPTY_PATH, R_OK, W_OK, X_OK, stat, statfs, statx,
 };
 use starry_vm::{VmMutPtr, VmPtr};
 
 use crate::{
-    file::{File, FileLike, resolve_at},
+    file::{File, FileLike, ResolveAtResult, resolve_at},
+    fs::{check_exec, check_write, mount_flags},
     mm::vm_load_string,
 };
 
@@ -128,6 +129,16 @@ pub fn sys_faccessat2(dirfd: c_int, path: *const c_char, mode: u32, flags: u32)
     if (file.stat()?.mode as u16 & required_mode) != required_mode {
         return Err(AxError::PermissionDenied);
     }
+    if let ResolveAtResult::File(loc) = &file {
+        if mode & W_OK != 0
+            && matches!(loc.node_type(), NodeType::RegularFile | NodeType::Directory)
+        {
+            check_write(loc)?;
+        }
+        if mode & X_OK != 0 && loc.node_type() == NodeType::RegularFile {
+            check_exec(loc)?;
+        }
+    }
 
     Ok(0)
 }
@@ -149,7 +160,8 @@ fn statfs(loc: &Location) -> AxResult<statfs> {
     };
     result.f_namelen = stat.name_length as _;
     result.f_frsize = stat.fragment_size as _;
-    result.f_flags = stat.mount_flags as _;
+    // `ST_*` flags share the values of their `MS_*` counterparts
+    result.f_flags = (stat.mount_flags | mount_flags(loc).bits()) as _;
     Ok(result)
 }
 
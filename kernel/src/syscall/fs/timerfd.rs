@@ -0,0 +1,92 @@
+use axerrno::{AxError, AxResult};
+use bitflags::bitflags;
+use linux_raw_sys::general::{
+    TFD_CLOEXEC, TFD_NONBLOCK, TFD_TIMER_ABSTIME, TFD_TIMER_CANCEL_ON_SET, itimerspec, timespec,
+};
+use starry_vm::{VmMutPtr, VmPtr};
+
+use crate::{
+    file::{FileLike, add_file_like, timerfd::TimerFd},
+    time::{Clock, TimeValueLike},
+};
+
+bitflags! {
+    /// Flags for the `timerfd_create` syscall.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct TimerFdFlags: u32 {
+        /// Create a file descriptor that is closed on `exec`.
+        const CLOEXEC = TFD_CLOEXEC;
+        /// Create a non-blocking timerfd.
+        const NONBLOCK = TFD_NONBLOCK;
+    }
+}
+
+bitflags! {
+    /// Flags for the `timerfd_settime` syscall.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct TimerFdSetFlags: u32 {
+        /// The expiration time is absolute instead of relative.
+        const ABSTIME = TFD_TIMER_ABSTIME;
+        /// Cancel the timer when the realtime clock is set.
+        const CANCEL_ON_SET = TFD_TIMER_CANCEL_ON_SET;
+    }
+}
+
+pub fn sys_timerfd_create(clock_id: u32, flags: u32) -> AxResult<isize> {
+    debug!("sys_timerfd_create <= clock_id: {clock_id}, flags: {flags:#x}");
+
+    let clock = Clock::from_id(clock_id)?;
+    let flags = TimerFdFlags::from_bits(flags).ok_or(AxError::InvalidInput)?;
+
+    let timer_fd = TimerFd::new(clock);
+    timer_fd.set_nonblocking(flags.contains(TimerFdFlags::NONBLOCK))?;
+    add_file_like(timer_fd as _, flags.contains(TimerFdFlags::CLOEXEC)).map(|fd| fd as _)
+}
+
+pub fn sys_timerfd_settime(
+    fd: i32,
+    flags: u32,
+    new_value: *const itimerspec,
+    old_value: *mut itimerspec,
+) -> AxResult<isize> {
+    let flags = TimerFdSetFlags::from_bits(flags).ok_or(AxError::InvalidInput)?;
+    // FIXME: AnyBitPattern
+    let new_value = unsafe { new_value.vm_read_uninit()?.assume_init() };
+    let value = new_value.it_value.try_into_time_value()?;
+    let interval = new_value.it_interval.try_into_time_value()?;
+    debug!(
+        "sys_timerfd_settime <= fd: {fd}, flags: {flags:?}, value: {value:?}, interval: \
+         {interval:?}"
+    );
+
+    let timer_fd = TimerFd::from_fd(fd)?;
+    // The realtime clock cannot be set, so `CANCEL_ON_SET` never cancels the
+    // timer.
+    let deadline = if value.is_zero() {
+        None
+    } else if flags.contains(TimerFdSetFlags::ABSTIME) {
+        Some(value)
+    } else {
+        Some(timer_fd.clock().now() + value)
+    };
+    let old = timer_fd.set(deadline, interval);
+
+    if let Some(old_value) = old_value.nullable() {
+        old_value.vm_write(itimerspec {
+            it_interval: timespec::from_time_value(old.0),
+            it_value: timespec::from_time_value(old.1),
+        })?;
+    }
+    Ok(0)
+}
+
+pub fn sys_timerfd_gettime(fd: i32, curr_value: *mut itimerspec) -> AxResult<isize> {
+    debug!("sys_timerfd_gettime <= fd: {fd}");
+
+    let (interval, value) = TimerFd::from_fd(fd)?.get();
+    curr_value.vm_write(itimerspec {
+        it_interval: timespec::from_time_value(interval),
+        it_value: timespec::from_time_value(value),
+    })?;
+    Ok(0)
+}
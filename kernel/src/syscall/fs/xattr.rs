@@ -0,0 +1,206 @@
+use alloc::vec::Vec;
+use core::ffi::{c_char, c_int};
+
+use axerrno::{AxError, AxResult};
+use axfs_ng_vfs::Location;
+use linux_raw_sys::general::{AT_EMPTY_PATH, AT_FDCWD, AT_SYMLINK_NOFOLLOW};
+use starry_vm::{VmPtr, vm_load, vm_write_slice};
+
+use crate::{
+    file::{ResolveAtResult, resolve_at},
+    fs::{
+        XATTR_LIST_MAX, XATTR_SIZE_MAX, XattrFlags, get_xattr, list_xattr, remove_xattr, set_xattr,
+    },
+    mm::vm_load_string,
+};
+
+/// Resolves the file an xattr syscall operates on: `path` for the path
+/// variants, with `flags` telling whether to follow symlinks, or `fd` for
+/// the `f` variants.
+fn resolve_xattr_file(fd: c_int, path: *const c_char, flags: u32) -> AxResult<Location> {
+    // Only the `f` variants, which pass `AT_EMPTY_PATH`, go without a path.
+    if path.is_null() && flags & AT_EMPTY_PATH == 0 {
+        return Err(AxError::BadAddress);
+    }
+    let path = path.nullable().map(vm_load_string).transpose()?;
+    match resolve_at(fd, path.as_deref(), flags)? {
+        ResolveAtResult::File(loc) => Ok(loc),
+        ResolveAtResult::Other(_) => Err(AxError::OperationNotSupported),
+    }
+}
+
+/// Copies `data` to the user buffer `buf` of `size` bytes, or only returns
+/// its length if `size` is zero.
+fn write_result(data: &[u8], buf: *mut u8, size: usize) -> AxResult<isize> {
+    if size == 0 {
+        return Ok(data.len() as _);
+    }
+    if data.len() > size {
+        return Err(AxError::OutOfRange);
+    }
+    vm_write_slice(buf, data)?;
+    Ok(data.len() as _)
+}
+
+fn setxattr_at(
+    fd: c_int,
+    path: *const c_char,
+    at_flags: u32,
+    name: *const c_char,
+    value: *const u8,
+    size: usize,
+    flags: u32,
+) -> AxResult<isize> {
+    let flags = XattrFlags::from_bits(flags).ok_or(AxError::InvalidInput)?;
+    let loc = resolve_xattr_file(fd, path, at_flags)?;
+    let name = vm_load_string(name)?;
+    debug!("sys_setxattr <= fd: {fd}, name: {name:?}, size: {size}, flags: {flags:?}");
+    if size > XATTR_SIZE_MAX {
+        return Err(AxError::ArgumentListTooLong);
+    }
+    let value = if size == 0 {
+        Vec::new()
+    } else {
+        vm_load(value, size)?
+    };
+    set_xattr(&loc, &name, &value, flags)?;
+    Ok(0)
+}
+
+fn getxattr_at(
+    fd: c_int,
+    path: *const c_char,
+    at_flags: u32,
+    name: *const c_char,
+    value: *mut u8,
+    size: usize,
+) -> AxResult<isize> {
+    let loc = resolve_xattr_file(fd, path, at_flags)?;
+    let name = vm_load_string(name)?;
+    debug!("sys_getxattr <= fd: {fd}, name: {name:?}, size: {size}");
+    let data = get_xattr(&loc, &name)?;
+    write_result(&data, value, size.min(XATTR_SIZE_MAX))
+}
+
+fn listxattr_at(
+    fd: c_int,
+    path: *const c_char,
+    at_flags: u32,
+    list: *mut c_char,
+    size: usize,
+) -> AxResult<isize> {
+    let loc = resolve_xattr_file(fd, path, at_flags)?;
+    debug!("sys_listxattr <= fd: {fd}, size: {size}");
+    let data = list_xattr(&loc)?;
+    write_result(&data, list.cast(), size.min(XATTR_LIST_MAX))
+}
+
+fn removexattr_at(
+    fd: c_int,
+    path: *const c_char,
+    at_flags: u32,
+    name: *const c_char,
+) -> AxResult<isize> {
+    let loc = resolve_xattr_file(fd, path, at_flags)?;
+    let name = vm_load_string(name)?;
+    debug!("sys_removexattr <= fd: {fd}, name: {name:?}");
+    remove_xattr(&loc, &name)?;
+    Ok(0)
+}
+
+pub fn sys_setxattr(
+    path: *const c_char,
+    name: *const c_char,
+    value: *const u8,
+    size: usize,
+    flags: u32,
+) -> AxResult<isize> {
+    setxattr_at(AT_FDCWD, path, 0, name, value, size, flags)
+}
+
+pub fn sys_lsetxattr(
+    path: *const c_char,
+    name: *const c_char,
+    value: *const u8,
+    size: usize,
+    flags: u32,
+) -> AxResult<isize> {
+    setxattr_at(
+        AT_FDCWD,
+        path,
+        AT_SYMLINK_NOFOLLOW,
+        name,
+        value,
+        size,
+        flags,
+    )
+}
+
+pub fn sys_fsetxattr(
+    fd: c_int,
+    name: *const c_char,
+    value: *const u8,
+    size: usize,
+    flags: u32,
+) -> AxResult<isize> {
+    setxattr_at(
+        fd,
+        core::ptr::null(),
+        AT_EMPTY_PATH,
+        name,
+        value,
+        size,
+        flags,
+    )
+}
+
+pub fn sys_getxattr(
+    path: *const c_char,
+    name: *const c_char,
+    value: *mut u8,
+    size: usize,
+) -> AxResult<isize> {
+    getxattr_at(AT_FDCWD, path, 0, name, value, size)
+}
+
+pub fn sys_lgetxattr(
+    path: *const c_char,
+    name: *const c_char,
+    value: *mut u8,
+    size: usize,
+) -> AxResult<isize> {
+    getxattr_at(AT_FDCWD, path, AT_SYMLINK_NOFOLLOW, name, value, size)
+}
+
+pub fn sys_fgetxattr(
+    fd: c_int,
+    name: *const c_char,
+    value: *mut u8,
+    size: usize,
+) -> AxResult<isize> {
+    getxattr_at(fd, core::ptr::null(), AT_EMPTY_PATH, name, value, size)
+}
+
+pub fn sys_listxattr(path: *const c_char, list: *mut c_char, size: usize) -> AxResult<isize> {
+    listxattr_at(AT_FDCWD, path, 0, list, size)
+}
+
+pub fn sys_llistxattr(path: *const c_char, list: *mut c_char, size: usize) -> AxResult<isize> {
+    listxattr_at(AT_FDCWD, path, AT_SYMLINK_NOFOLLOW, list, size)
+}
+
+pub fn sys_flistxattr(fd: c_int, list: *mut c_char, size: usize) -> AxResult<isize> {
+    listxattr_at(fd, core::ptr::null(), AT_EMPTY_PATH, list, size)
+}
+
+pub fn sys_removexattr(path: *const c_char, name: *const c_char) -> AxResult<isize> {
+    removexattr_at(AT_FDCWD, path, 0, name)
+}
+
+pub fn sys_lremovexattr(path: *const c_char, name: *const c_char) -> AxResult<isize> {
+    removexattr_at(AT_FDCWD, path, AT_SYMLINK_NOFOLLOW, name)
+}
+
+pub fn sys_fremovexattr(fd: c_int, name: *const c_char) -> AxResult<isize> {
+    removexattr_at(fd, core::ptr::null(), AT_EMPTY_PATH, name)
+}
@@ -1,15 +1,16 @@
-use alloc::sync::Arc;
+use alloc::{sync::Arc, vec::Vec};
 
 use axerrno::{AxError, AxResult};
-use axfs::FileBackend;
+use axfs::{FileBackend, FileFlags};
 use axhal::paging::{MappingFlags, PageSize};
 use axtask::current;
 use linux_raw_sys::general::*;
-use memory_addr::{MemoryAddr, VirtAddr, VirtAddrRange, align_up_4k};
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K, VirtAddr, VirtAddrRange, align_up_4k};
 use starry_vm::{vm_load, vm_write_slice};
 
 use crate::{
-    file::{File, FileLike},
+    file::{File, get_file_like, memfd::Memfd},
+    fs::{MountFlags, mark_cache_dirty, mount_flags, sync_pages},
     mm::{Backend, SharedPages},
     pseudofs::{Device, DeviceMmap},
     task::AsThread,
@@ -172,11 +173,26 @@ pub fn sys_mmap(
             .ok_or(AxError::NoMemory)?
     };
 
+    // Files outside of the filesystem provide their own mappings.
+    let mut special = None;
     let file = if fd > 0 {
-        Some(File::from_fd(fd)?)
+        let file_like = get_file_like(fd)?;
+        match file_like.clone().downcast_arc::<File>() {
+            Ok(file) => Some(file),
+            Err(_) => {
+                special = Some(file_like.mmap(offset as u64)?);
+                None
+            }
+        }
     } else {
         None
     };
+    if let Some(file) = &file
+        && permission_flags.contains(MmapProt::EXEC)
+        && mount_flags(file.inner().location()).contains(MountFlags::NOEXEC)
+    {
+        return Err(AxError::OperationNotPermitted);
+    }
 
     let backend = match map_type {
         MmapFlags::SHARED | MmapFlags::SHARED_VALIDATE => {
@@ -185,12 +201,26 @@ pub fn sys_mmap(
                 let backend = file.backend()?.clone();
                 match file.backend()?.clone() {
                     FileBackend::Cached(cache) => {
+                        let mut flags = file.flags();
+                        let mut writer = None;
+                        if let Some(memfd) = Memfd::of(file.location()) {
+                            writer = memfd.map_shared(
+                                flags.contains(FileFlags::WRITE),
+                                permission_flags.contains(MmapProt::WRITE),
+                            )?;
+                            // Sealed files cannot be made writable by
+                            // `mprotect` either.
+                            if writer.is_none() {
+                                flags.remove(FileFlags::WRITE);
+                            }
+                        }
                         // TODO(mivik): file mmap page size
                         Backend::new_file(
                             start,
                             cache,
-                            file.flags(),
+                            flags,
                             offset,
+                            writer,
                             &curr.as_thread().proc_data.aspace,
                         )
                     }
@@ -222,16 +252,30 @@ pub fn sys_mmap(
                                 cache,
                                 file.flags(),
                                 offset,
+                                None,
                                 &curr.as_thread().proc_data.aspace,
                             ),
+                            DeviceMmap::Shared(pages) => {
+                                length = length.min(pages.len() * PAGE_SIZE_4K);
+                                Backend::new_shared(start, pages)
+                            }
                         }
                     }
                 }
+            } else if let Some(mmap) = special {
+                let DeviceMmap::Shared(pages) = mmap else {
+                    return Err(AxError::NoSuchDevice);
+                };
+                length = length.min(pages.len() * PAGE_SIZE_4K);
+                Backend::new_shared(start, pages)
             } else {
                 Backend::new_shared(start, Arc::new(SharedPages::new(length, PageSize::Size4K)?))
             }
         }
         MmapFlags::PRIVATE => {
+            if special.is_some() {
+                return Err(AxError::InvalidInput);
+            }
             if let Some(file) = file {
                 // Private mapping from a file
                 let backend = file.inner().backend()?.clone();
@@ -322,9 +366,54 @@ pub fn sys_madvise(addr: usize, length: usize, advice: i32) -> AxResult<isize> {
     Ok(0)
 }
 
+bitflags::bitflags! {
+    /// Flags for [`sys_msync`].
+    #[derive(Debug, Clone, Copy)]
+    struct MsyncFlags: u32 {
+        /// Schedule the writeback.
+        const ASYNC = MS_ASYNC;
+        /// Invalidate other mappings of the file.
+        const INVALIDATE = MS_INVALIDATE;
+        /// Write back and wait for it to complete.
+        const SYNC = MS_SYNC;
+    }
+}
+
 pub fn sys_msync(addr: usize, length: usize, flags: u32) -> AxResult<isize> {
     debug!("sys_msync <= addr: {addr:#x}, length: {length:x}, flags: {flags:#x}");
 
+    let flags = MsyncFlags::from_bits(flags).ok_or(AxError::InvalidInput)?;
+    if !addr.is_multiple_of(PAGE_SIZE_4K) || flags.contains(MsyncFlags::ASYNC | MsyncFlags::SYNC) {
+        return Err(AxError::InvalidInput);
+    }
+    let range = VirtAddrRange::from_start_size(VirtAddr::from(addr), align_up_4k(length));
+
+    let curr = current();
+    let aspace = curr.as_thread().proc_data.aspace.lock();
+    let mut files = Vec::new();
+    let mut start = range.start;
+    while start < range.end {
+        let area = aspace.find_area(start).ok_or(AxError::NoMemory)?;
+        let end = area.end().min(range.end);
+        if let Backend::File(file) = area.backend() {
+            let pages = file.file_pages(VirtAddrRange::new(start, end));
+            files.push((file.cache().clone(), pages));
+        }
+        start = end;
+    }
+    // Writing back must not happen with the address space locked, since the
+    // page cache may need to update the mappings.
+    drop(aspace);
+
+    // The page cache is shared by all mappings, so there is nothing to
+    // invalidate.
+    for (cache, pages) in files {
+        if flags.contains(MsyncFlags::SYNC) {
+            sync_pages(&cache, pages)?;
+        } else {
+            mark_cache_dirty(&cache, pages);
+        }
+    }
     Ok(0)
 }
 
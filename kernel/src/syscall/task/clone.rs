@@ -256,6 +256,10 @@ impl CloneArgs {
         new_proc_data.proc.add_thread(tid);
 
         let thr = Thread::new(tid, new_proc_data.clone());
+        *thr.seccomp.write() = curr.as_thread().seccomp.read().clone();
+        if curr.as_thread().no_new_privs() {
+            thr.set_no_new_privs();
+        }
         if flags.contains(CloneFlags::CHILD_CLEARTID) {
             thr.set_clear_child_tid(child_tid);
         }
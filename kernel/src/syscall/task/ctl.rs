@@ -2,9 +2,13 @@ use core::ffi::c_char;
 
 use axerrno::{AxError, AxResult};
 use axtask::current;
-use linux_raw_sys::general::{__user_cap_data_struct, __user_cap_header_struct};
+use linux_raw_sys::{
+    general::{__user_cap_data_struct, __user_cap_header_struct},
+    ptrace::{SECCOMP_MODE_FILTER, SECCOMP_MODE_STRICT},
+};
 use starry_vm::{VmMutPtr, VmPtr, vm_write_slice};
 
+use super::{seccomp_set_filter, seccomp_set_strict};
 use crate::{
     mm::vm_load_string,
     task::{AsThread, get_process_data},
@@ -82,6 +86,9 @@ pub fn sys_get_mempolicy(
 /// - PR_SET_NAME: set the name of the calling thread, using the value pointed to by `arg2`
 /// - PR_GET_NAME: get the name of the calling
 /// - PR_SET_SECCOMP: enable seccomp mode, with the mode specified in `arg2`
+/// - PR_GET_SECCOMP: get the seccomp mode of the calling thread
+/// - PR_SET_NO_NEW_PRIVS / PR_GET_NO_NEW_PRIVS: set or get the no_new_privs
+///   flag of the calling thread
 /// - PR_MCE_KILL: set the machine check exception policy
 /// - PR_SET_MM options: set various memory management options (start/end code/data/brk/stack)
 pub fn sys_prctl(
@@ -107,7 +114,28 @@ pub fn sys_prctl(
             buf[..len].copy_from_slice(&name.as_bytes()[..len]);
             vm_write_slice(arg2 as _, &buf)?;
         }
-        PR_SET_SECCOMP => {}
+        PR_SET_SECCOMP => {
+            return match arg2 as u32 {
+                SECCOMP_MODE_STRICT => seccomp_set_strict(),
+                SECCOMP_MODE_FILTER => seccomp_set_filter(0, arg3 as _),
+                _ => Err(AxError::InvalidInput),
+            };
+        }
+        PR_GET_SECCOMP => {
+            return Ok(current().as_thread().seccomp.read().mode as _);
+        }
+        PR_SET_NO_NEW_PRIVS => {
+            if arg2 != 1 || arg3 != 0 || arg4 != 0 || arg5 != 0 {
+                return Err(AxError::InvalidInput);
+            }
+            current().as_thread().set_no_new_privs();
+        }
+        PR_GET_NO_NEW_PRIVS => {
+            if arg2 != 0 || arg3 != 0 || arg4 != 0 || arg5 != 0 {
+                return Err(AxError::InvalidInput);
+            }
+            return Ok(current().as_thread().no_new_privs() as _);
+        }
         PR_MCE_KILL => {}
         PR_SET_MM => {
             // not implemented; but avoid annoying warnings
@@ -9,7 +9,7 @@ use starry_vm::vm_load_until_nul;
 
 use crate::{
     config::USER_HEAP_BASE,
-    file::FD_TABLE,
+    file::{FD_TABLE, lock::release_posix_locks},
     mm::{load_user_app, vm_load_string},
     task::AsThread,
 };
@@ -67,6 +67,7 @@ pub fn sys_execve(
     proc_data.set_heap_top(USER_HEAP_BASE);
 
     *proc_data.signal.actions.lock() = Default::default();
+    proc_data.posix_timers.clear();
 
     // Clear set_child_tid after exec since the original address is no longer valid
     curr.as_thread().set_clear_child_tid(0);
@@ -78,7 +79,9 @@ pub fn sys_execve(
         .filter(|it| fd_table.get(*it).unwrap().cloexec)
         .collect::<Vec<_>>();
     for fd in cloexec_fds {
-        fd_table.remove(fd);
+        if let Some(f) = fd_table.remove(fd) {
+            release_posix_locks(&f.inner);
+        }
     }
     drop(fd_table);
 
@@ -5,9 +5,11 @@ mod execve;
 mod exit;
 mod job;
 mod schedule;
+mod seccomp;
 mod thread;
 mod wait;
 
 pub use self::{
-    clone::*, clone3::*, ctl::*, execve::*, exit::*, job::*, schedule::*, thread::*, wait::*,
+    clone::*, clone3::*, ctl::*, execve::*, exit::*, job::*, schedule::*, seccomp::*, thread::*,
+    wait::*,
 };
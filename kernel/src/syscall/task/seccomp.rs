@@ -0,0 +1,156 @@
+use alloc::{sync::Arc, vec::Vec};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axtask::current;
+use bitflags::bitflags;
+use linux_raw_sys::ptrace::{
+    BPF_MAXINSNS, SECCOMP_FILTER_FLAG_LOG, SECCOMP_FILTER_FLAG_NEW_LISTENER,
+    SECCOMP_FILTER_FLAG_SPEC_ALLOW, SECCOMP_FILTER_FLAG_TSYNC, SECCOMP_FILTER_FLAG_TSYNC_ESRCH,
+    SECCOMP_FILTER_FLAG_WAIT_KILLABLE_RECV, SECCOMP_GET_ACTION_AVAIL, SECCOMP_SET_MODE_FILTER,
+    SECCOMP_SET_MODE_STRICT, sock_filter, sock_fprog,
+};
+use spin::Mutex;
+use starry_vm::{VmPtr, vm_load_any};
+
+use crate::{
+    syscall::sys::sys_geteuid,
+    task::{AsThread, SeccompFilter, SeccompMode, get_task, is_action_available},
+};
+
+bitflags! {
+    /// Flags for `SECCOMP_SET_MODE_FILTER`.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct SeccompFilterFlags: u32 {
+        /// Install the filter on all threads of the process.
+        const TSYNC = SECCOMP_FILTER_FLAG_TSYNC;
+        /// Log the actions taken by the filter, except `ALLOW`.
+        const LOG = SECCOMP_FILTER_FLAG_LOG;
+        /// Do not enable speculative store bypass mitigation.
+        const SPEC_ALLOW = SECCOMP_FILTER_FLAG_SPEC_ALLOW;
+        /// Create a listener for user notifications.
+        const NEW_LISTENER = SECCOMP_FILTER_FLAG_NEW_LISTENER;
+        /// Fail `TSYNC` with `ESRCH` instead of a thread ID.
+        const TSYNC_ESRCH = SECCOMP_FILTER_FLAG_TSYNC_ESRCH;
+        /// Wait for notifications killably.
+        const WAIT_KILLABLE_RECV = SECCOMP_FILTER_FLAG_WAIT_KILLABLE_RECV;
+    }
+}
+
+/// Serializes installing filters, since `TSYNC` changes the filters of other
+/// threads.
+static INSTALL_LOCK: Mutex<()> = Mutex::new(());
+
+/// Puts the current thread in strict mode.
+pub fn seccomp_set_strict() -> AxResult<isize> {
+    let _guard = INSTALL_LOCK.lock();
+    let curr = current();
+    let mut seccomp = curr.as_thread().seccomp.write();
+    if seccomp.mode == SeccompMode::Filter {
+        return Err(AxError::InvalidInput);
+    }
+    seccomp.mode = SeccompMode::Strict;
+    Ok(0)
+}
+
+/// Installs the filter `fprog` on the current thread, or on all threads of
+/// the process with `TSYNC`.
+pub fn seccomp_set_filter(flags: u32, fprog: *const sock_fprog) -> AxResult<isize> {
+    let flags = SeccompFilterFlags::from_bits(flags).ok_or(AxError::InvalidInput)?;
+    // User notifications are not supported.
+    if flags.intersects(SeccompFilterFlags::NEW_LISTENER | SeccompFilterFlags::WAIT_KILLABLE_RECV) {
+        return Err(AxError::InvalidInput);
+    }
+
+    // FIXME: AnyBitPattern
+    let fprog = unsafe { fprog.vm_read_uninit()?.assume_init() };
+    if fprog.len == 0 || fprog.len as u32 > BPF_MAXINSNS {
+        return Err(AxError::InvalidInput);
+    }
+    // FIXME: AnyBitPattern
+    let prog = unsafe { vm_load_any(fprog.filter as *const sock_filter, fprog.len as usize)? };
+
+    let curr = current();
+    let thr = curr.as_thread();
+    // Filters could otherwise be used to fool privileged programs run by
+    // `execve`. Every process runs as root, which holds `CAP_SYS_ADMIN`.
+    if !thr.no_new_privs() && sys_geteuid()? != 0 {
+        return Err(AxError::from(LinuxError::EACCES));
+    }
+
+    let _guard = INSTALL_LOCK.lock();
+    let seccomp = thr.seccomp.read();
+    if seccomp.mode == SeccompMode::Strict {
+        return Err(AxError::InvalidInput);
+    }
+    let filter = SeccompFilter::new(
+        prog.into_boxed_slice(),
+        flags.contains(SeccompFilterFlags::LOG),
+        seccomp.filter.clone(),
+    )?;
+    drop(seccomp);
+
+    if flags.contains(SeccompFilterFlags::TSYNC) {
+        let tid = curr.id().as_u64() as u32;
+        let threads = thr
+            .proc_data
+            .proc
+            .threads()
+            .into_iter()
+            .filter(|it| *it != tid)
+            .filter_map(|it| get_task(it).ok())
+            .collect::<Vec<_>>();
+        // Every other thread must be able to take the filter of the current
+        // thread without dropping any of its own.
+        for task in &threads {
+            let other = task.as_thread().seccomp.read();
+            let synced = match (&other.mode, &other.filter) {
+                (SeccompMode::Disabled, _) => true,
+                (SeccompMode::Filter, Some(it)) => filter.is_descendant_of(it),
+                _ => false,
+            };
+            if !synced {
+                if flags.contains(SeccompFilterFlags::TSYNC_ESRCH) {
+                    return Err(AxError::from(LinuxError::ESRCH));
+                }
+                return Ok(task.id().as_u64() as _);
+            }
+        }
+        for task in &threads {
+            let other = task.as_thread();
+            let mut other_seccomp = other.seccomp.write();
+            other_seccomp.mode = SeccompMode::Filter;
+            other_seccomp.filter = Some(Arc::clone(&filter));
+            other.set_no_new_privs();
+        }
+    }
+
+    let mut seccomp = thr.seccomp.write();
+    seccomp.mode = SeccompMode::Filter;
+    seccomp.filter = Some(filter);
+    Ok(0)
+}
+
+pub fn sys_seccomp(op: u32, flags: u32, args: usize) -> AxResult<isize> {
+    debug!("sys_seccomp <= op: {op}, flags: {flags:#x}, args: {args:#x}");
+
+    match op {
+        SECCOMP_SET_MODE_STRICT => {
+            if flags != 0 || args != 0 {
+                return Err(AxError::InvalidInput);
+            }
+            seccomp_set_strict()
+        }
+        SECCOMP_SET_MODE_FILTER => seccomp_set_filter(flags, args as _),
+        SECCOMP_GET_ACTION_AVAIL => {
+            if flags != 0 {
+                return Err(AxError::InvalidInput);
+            }
+            if is_action_available((args as *const u32).vm_read()?) {
+                Ok(0)
+            } else {
+                Err(AxError::OperationNotSupported)
+            }
+        }
+        _ => Err(AxError::InvalidInput),
+    }
+}
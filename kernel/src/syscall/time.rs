@@ -2,15 +2,18 @@ use axerrno::{AxError, AxResult};
 use axhal::time::{TimeValue, monotonic_time, monotonic_time_nanos, nanos_to_ticks, wall_time};
 use axtask::current;
 use linux_raw_sys::general::{
-    __kernel_clockid_t, CLOCK_BOOTTIME, CLOCK_MONOTONIC, CLOCK_MONOTONIC_COARSE,
+    __kernel_clockid_t, __kernel_timer_t, CLOCK_BOOTTIME, CLOCK_MONOTONIC, CLOCK_MONOTONIC_COARSE,
     CLOCK_MONOTONIC_RAW, CLOCK_PROCESS_CPUTIME_ID, CLOCK_REALTIME, CLOCK_REALTIME_COARSE,
-    CLOCK_THREAD_CPUTIME_ID, itimerval, timespec, timeval,
+    CLOCK_THREAD_CPUTIME_ID, SIGEV_NONE, SIGEV_SIGNAL, SIGEV_THREAD, SIGEV_THREAD_ID,
+    TIMER_ABSTIME, itimerspec, itimerval, sigevent, timespec, timeval,
 };
+use starry_process::Pid;
+use starry_signal::Signo;
 use starry_vm::{VmMutPtr, VmPtr};
 
 use crate::{
-    task::{AsThread, ITimerType},
-    time::TimeValueLike,
+    task::{AsThread, ITimerType, TimerClock, TimerNotify, get_task},
+    time::{Clock, TimeValueLike},
 };
 
 pub fn sys_clock_gettime(clock_id: __kernel_clockid_t, ts: *mut timespec) -> AxResult<isize> {
@@ -120,3 +123,133 @@ pub fn sys_setitimer(
     }
     Ok(0)
 }
+
+pub fn sys_timer_create(
+    clock_id: __kernel_clockid_t,
+    sevp: *const sigevent,
+    timerid: *mut __kernel_timer_t,
+) -> AxResult<isize> {
+    let curr = current();
+    let proc_data = &curr.as_thread().proc_data;
+    let pid = proc_data.proc.pid();
+
+    let clock = match clock_id as u32 {
+        CLOCK_PROCESS_CPUTIME_ID => TimerClock::Process,
+        CLOCK_THREAD_CPUTIME_ID => TimerClock::Thread(curr.id().as_u64() as Pid),
+        clock_id => TimerClock::System(Clock::from_id(clock_id)?),
+    };
+
+    let notify = match sevp.nullable() {
+        None => None,
+        Some(sevp) => {
+            // FIXME: AnyBitPattern
+            let sev = unsafe { sevp.vm_read_uninit()?.assume_init() };
+            let notify = match sev.sigev_notify as u32 {
+                SIGEV_NONE => TimerNotify::None,
+                SIGEV_SIGNAL | SIGEV_THREAD | SIGEV_THREAD_ID => {
+                    let signo = u8::try_from(sev.sigev_signo)
+                        .ok()
+                        .and_then(Signo::from_repr)
+                        .ok_or(AxError::InvalidInput)?;
+                    let tid = if sev.sigev_notify as u32 == SIGEV_THREAD_ID {
+                        let tid = unsafe { sev._sigev_un._tid } as Pid;
+                        let task = get_task(tid).map_err(|_| AxError::InvalidInput)?;
+                        if task
+                            .try_as_thread()
+                            .is_none_or(|thr| thr.proc_data.proc.pid() != pid)
+                        {
+                            return Err(AxError::InvalidInput);
+                        }
+                        Some(tid)
+                    } else {
+                        None
+                    };
+                    TimerNotify::Signal {
+                        signo,
+                        value: unsafe { sev.sigev_value.sival_ptr } as usize,
+                        pid,
+                        tid,
+                    }
+                }
+                _ => return Err(AxError::InvalidInput),
+            };
+            Some(notify)
+        }
+    };
+
+    // By default, the timer sends `SIGALRM` carrying its ID.
+    let id = proc_data.posix_timers.create(clock, |id| {
+        notify.unwrap_or(TimerNotify::Signal {
+            signo: Signo::SIGALRM,
+            value: id as usize,
+            pid,
+            tid: None,
+        })
+    })?;
+    debug!("sys_timer_create <= clock: {clock:?} => id: {id}");
+    if let Err(err) = timerid.vm_write(id) {
+        proc_data.posix_timers.delete(id)?;
+        return Err(err.into());
+    }
+    Ok(0)
+}
+
+pub fn sys_timer_settime(
+    timerid: __kernel_timer_t,
+    flags: u32,
+    new_value: *const itimerspec,
+    old_value: *mut itimerspec,
+) -> AxResult<isize> {
+    // FIXME: AnyBitPattern
+    let new_value = unsafe { new_value.vm_read_uninit()?.assume_init() };
+    let value = new_value.it_value.try_into_time_value()?;
+    let interval = new_value.it_interval.try_into_time_value()?;
+    debug!(
+        "sys_timer_settime <= id: {timerid}, flags: {flags:#x}, value: {value:?}, interval: \
+         {interval:?}"
+    );
+
+    let timer = current().as_thread().proc_data.posix_timers.get(timerid)?;
+    let old = timer.set(value, flags & TIMER_ABSTIME != 0, interval);
+
+    if let Some(old_value) = old_value.nullable() {
+        old_value.vm_write(itimerspec {
+            it_interval: timespec::from_time_value(old.0),
+            it_value: timespec::from_time_value(old.1),
+        })?;
+    }
+    Ok(0)
+}
+
+pub fn sys_timer_gettime(
+    timerid: __kernel_timer_t,
+    curr_value: *mut itimerspec,
+) -> AxResult<isize> {
+    debug!("sys_timer_gettime <= id: {timerid}");
+
+    let timer = current().as_thread().proc_data.posix_timers.get(timerid)?;
+    let (interval, value) = timer.get();
+    curr_value.vm_write(itimerspec {
+        it_interval: timespec::from_time_value(interval),
+        it_value: timespec::from_time_value(value),
+    })?;
+    Ok(0)
+}
+
+pub fn sys_timer_getoverrun(timerid: __kernel_timer_t) -> AxResult<isize> {
+    debug!("sys_timer_getoverrun <= id: {timerid}");
+
+    let timer = current().as_thread().proc_data.posix_timers.get(timerid)?;
+    Ok(timer.overrun() as _)
+}
+
+pub fn sys_timer_delete(timerid: __kernel_timer_t) -> AxResult<isize> {
+    debug!("sys_timer_delete <= id: {timerid}");
+
+    current()
+        .as_thread()
+        .proc_data
+        .posix_timers
+        .delete(timerid)?;
+    Ok(0)
+}
@@ -2,7 +2,9 @@
 
 mod futex;
 mod ops;
+mod posix_timer;
 mod resources;
+mod seccomp;
 mod signal;
 mod stat;
 mod timer;
@@ -27,7 +29,10 @@ use starry_signal::{
     api::{ProcessSignalManager, SignalActions, ThreadSignalManager},
 };
 
-pub use self::{futex::*, ops::*, resources::*, signal::*, stat::*, timer::*, user::*};
+pub use self::{
+    futex::*, ops::*, posix_timer::*, resources::*, seccomp::*, signal::*, stat::*, timer::*,
+    user::*,
+};
 use crate::mm::AddrSpace;
 
 ///  A wrapper type that assumes the inner type is `Sync`.
@@ -80,6 +85,12 @@ pub struct Thread {
 
     /// Self exit event
     pub exit_event: Arc<PollSet>,
+
+    /// The seccomp state.
+    pub seccomp: RwLock<Seccomp>,
+
+    /// Whether `execve` is prevented from granting privileges.
+    no_new_privs: AtomicBool,
 }
 
 impl Thread {
@@ -95,6 +106,8 @@ impl Thread {
             oom_score_adj: AtomicI32::new(200),
             accessing_user_memory: AtomicBool::new(false),
             exit_event: Arc::default(),
+            seccomp: RwLock::default(),
+            no_new_privs: AtomicBool::new(false),
         })
     }
 
@@ -145,6 +158,16 @@ impl Thread {
         self.accessing_user_memory.load(Ordering::Acquire)
     }
 
+    /// Check if the no_new_privs flag is set.
+    pub fn no_new_privs(&self) -> bool {
+        self.no_new_privs.load(Ordering::Acquire)
+    }
+
+    /// Set the no_new_privs flag, which cannot be unset.
+    pub fn set_no_new_privs(&self) {
+        self.no_new_privs.store(true, Ordering::Release);
+    }
+
     /// Set the accessing user memory flag.
     pub fn set_accessing_user_memory(&self, accessing: bool) {
         self.accessing_user_memory
@@ -216,6 +239,9 @@ pub struct ProcessData {
     /// The futex table.
     futex_table: Arc<FutexTable>,
 
+    /// The POSIX timers.
+    pub posix_timers: PosixTimers,
+
     /// The default mask for file permissions.
     umask: AtomicU32,
 }
@@ -251,6 +277,8 @@ impl ProcessData {
 
             futex_table: Arc::new(FutexTable::new()),
 
+            posix_timers: PosixTimers::default(),
+
             umask: AtomicU32::new(0o022),
         })
     }
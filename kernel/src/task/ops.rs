@@ -15,9 +15,10 @@ use starry_vm::{VmMutPtr, VmPtr};
 use weak_map::WeakMap;
 
 use super::{
-    AsThread, FutexKey, ProcessData, TimerState, futex_table_for, send_signal_thread_inner,
-    send_signal_to_process, send_signal_to_thread,
+    AsThread, FutexKey, ProcessData, Thread, TimeManager, TimerState, futex_table_for,
+    send_signal_thread_inner, send_signal_to_process, send_signal_to_thread,
 };
+use crate::file::{FD_TABLE, lock::release_posix_locks};
 
 static TASK_TABLE: RwLock<WeakMap<Pid, WeakAxTaskRef>> = RwLock::new(WeakMap::new());
 
@@ -109,6 +110,15 @@ pub fn get_session(sid: Pid) -> AxResult<Arc<Session>> {
     SESSION_TABLE.read().get(&sid).ok_or(AxError::NoSuchProcess)
 }
 
+fn poll_time(task: &TaskInner, thr: &Thread, time: &mut TimeManager) {
+    let cpu_time = time.poll(|signo| {
+        send_signal_thread_inner(task, thr, SignalInfo::new_kernel(signo));
+    });
+    thr.proc_data
+        .posix_timers
+        .charge_cpu_time(task.id().as_u64() as Pid, cpu_time);
+}
+
 /// Poll the timer
 pub fn poll_timer(task: &TaskInner) {
     let Some(thr) = task.try_as_thread() else {
@@ -118,9 +128,7 @@ pub fn poll_timer(task: &TaskInner) {
         // reentrant borrow, likely IRQ
         return;
     };
-    time.poll(|signo| {
-        send_signal_thread_inner(task, thr, SignalInfo::new_kernel(signo));
-    });
+    poll_time(task, thr, &mut time);
 }
 
 /// Sets the timer state.
@@ -132,9 +140,7 @@ pub fn set_timer_state(task: &TaskInner, state: TimerState) {
         // reentrant borrow, likely IRQ
         return;
     };
-    time.poll(|signo| {
-        send_signal_thread_inner(task, thr, SignalInfo::new_kernel(signo));
-    });
+    poll_time(task, thr, &mut time);
     time.set_state(state);
 }
 
@@ -236,6 +242,12 @@ pub fn do_exit(exit_code: i32, group_exit: bool) {
         crate::syscall::SHM_MANAGER
             .lock()
             .clear_proc_shm(process.pid());
+        thr.proc_data.posix_timers.clear();
+
+        let fd_table = FD_TABLE.read();
+        for fd in fd_table.ids() {
+            release_posix_locks(&fd_table.get(fd).unwrap().inner);
+        }
     }
     thr.exit_event.wake();
 
@@ -0,0 +1,368 @@
+//! POSIX per-process timers.
+
+use alloc::{collections::btree_map::BTreeMap, sync::Arc, vec::Vec};
+use core::{
+    mem,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use axerrno::{AxError, AxResult};
+use axhal::time::TimeValue;
+use linux_raw_sys::general::{__sifields__bindgen_ty_2, SI_TIMER, sigval_t};
+use spin::Mutex;
+use starry_process::Pid;
+use starry_signal::{SignalInfo, Signo};
+
+use super::{
+    AlarmKey, AsThread, call_at, cancel_alarm, get_process_data, get_task, send_signal_to_process,
+    send_signal_to_thread,
+};
+use crate::time::Clock;
+
+/// The clock a POSIX timer runs on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerClock {
+    /// A system-wide clock.
+    System(Clock),
+    /// The CPU time consumed by the process.
+    Process,
+    /// The CPU time consumed by a thread.
+    Thread(Pid),
+}
+
+/// How a POSIX timer notifies its expirations.
+#[derive(Debug, Clone, Copy)]
+pub enum TimerNotify {
+    /// No notification (`SIGEV_NONE`).
+    None,
+    /// A signal carrying `value`, sent to the process `pid` (`SIGEV_SIGNAL`),
+    /// or to its thread `tid` if set (`SIGEV_THREAD_ID`).
+    Signal {
+        signo: Signo,
+        value: usize,
+        pid: Pid,
+        tid: Option<Pid>,
+    },
+}
+
+#[derive(Default)]
+struct TimerState {
+    /// When the timer expires next: a time of the clock for system clocks, or
+    /// the CPU time left until then for CPU-time clocks.
+    expiry: Option<TimeValue>,
+    interval: Duration,
+    /// Expirations that have not been notified yet.
+    pending_overrun: u64,
+    /// The overrun count of the last notification.
+    overrun: i32,
+    /// The alarm that goes off at `expiry`, for system clocks.
+    alarm: Option<AlarmKey>,
+}
+
+/// A POSIX timer, as created by `timer_create`.
+pub struct PosixTimer {
+    id: i32,
+    clock: TimerClock,
+    notify: TimerNotify,
+    state: Mutex<TimerState>,
+}
+
+impl PosixTimer {
+    /// Returns the current time of the clock of the timer.
+    fn now(&self) -> TimeValue {
+        let tid = match self.clock {
+            TimerClock::System(clock) => return clock.now(),
+            // The CPU time of a process is approximated by that of the calling
+            // thread, as `clock_gettime` does.
+            TimerClock::Process => None,
+            TimerClock::Thread(tid) => Some(tid),
+        };
+        let task = match tid {
+            Some(tid) => get_task(tid).ok(),
+            None => Some(axtask::current().clone()),
+        };
+        task.and_then(|task| {
+            let (utime, stime) = task.try_as_thread()?.time.try_borrow().ok()?.output();
+            Some(utime + stime)
+        })
+        .unwrap_or_default()
+    }
+
+    /// Returns the interval of the timer and the time until its next
+    /// expiration, which is zero if the timer is disarmed.
+    pub fn get(self: &Arc<Self>) -> (Duration, Duration) {
+        let mut state = self.state.lock();
+        let notification = self.update(&mut state);
+        let result = (state.interval, self.remaining(&state));
+        drop(state);
+        self.send(notification);
+        result
+    }
+
+    /// Notifies the expirations of a timer on a system clock up to now.
+    fn poll(self: &Arc<Self>) {
+        let notification = self.update(&mut self.state.lock());
+        self.send(notification);
+    }
+
+    /// Arms the timer to expire after `value`, or at `value` if `absolute` is
+    /// set, and then every `interval` if it is not zero. The timer is
+    /// disarmed if `value` is zero.
+    ///
+    /// Returns the previous setting as [`PosixTimer::get`] does.
+    pub fn set(
+        self: &Arc<Self>,
+        value: TimeValue,
+        absolute: bool,
+        interval: Duration,
+    ) -> (Duration, Duration) {
+        let mut state = self.state.lock();
+        let notification = self.update(&mut state);
+        let old = (state.interval, self.remaining(&state));
+
+        state.expiry = if value.is_zero() {
+            None
+        } else {
+            Some(match (self.clock, absolute) {
+                (TimerClock::System(_), true) => value,
+                (TimerClock::System(clock), false) => clock.now() + value,
+                // An expiration already passed fires on the next charge.
+                (_, true) => value
+                    .saturating_sub(self.now())
+                    .max(Duration::from_nanos(1)),
+                (_, false) => value,
+            })
+        };
+        state.interval = interval;
+        state.pending_overrun = 0;
+        self.arm(&mut state);
+        drop(state);
+        self.send(notification);
+        old
+    }
+
+    /// Returns the overrun count of the last notification.
+    pub fn overrun(&self) -> i32 {
+        self.state.lock().overrun
+    }
+
+    fn remaining(&self, state: &TimerState) -> Duration {
+        match (self.clock, state.expiry) {
+            (_, None) => Duration::ZERO,
+            (TimerClock::System(clock), Some(deadline)) => deadline.saturating_sub(clock.now()),
+            (_, Some(remaining)) => remaining,
+        }
+    }
+
+    /// Accounts for the expirations of a timer on a system clock up to now.
+    fn update(self: &Arc<Self>, state: &mut TimerState) -> Option<SignalInfo> {
+        let TimerClock::System(clock) = self.clock else {
+            return None;
+        };
+        let deadline = state.expiry?;
+        let now = clock.now();
+        if deadline > now {
+            return None;
+        }
+        let count = if state.interval.is_zero() {
+            state.expiry = None;
+            1
+        } else {
+            let interval = state.interval.as_nanos();
+            let count = (now - deadline).as_nanos() / interval + 1;
+            state.expiry = Some(deadline + Duration::from_nanos((count * interval) as u64));
+            count as u64
+        };
+        self.arm(state);
+        self.expire(state, count)
+    }
+
+    /// Charges CPU time to a timer on a CPU-time clock.
+    fn charge(&self, cpu_time: Duration) {
+        let mut state = self.state.lock();
+        let Some(remaining) = state.expiry else {
+            return;
+        };
+        if remaining > cpu_time {
+            state.expiry = Some(remaining - cpu_time);
+            return;
+        }
+        let overshoot = cpu_time - remaining;
+        let count = if state.interval.is_zero() {
+            state.expiry = None;
+            1
+        } else {
+            let interval = state.interval.as_nanos();
+            let elapsed = overshoot.as_nanos();
+            state.expiry = Some(Duration::from_nanos((interval - elapsed % interval) as u64));
+            (elapsed / interval + 1) as u64
+        };
+        let notification = self.expire(&mut state, count);
+        drop(state);
+        self.send(notification);
+    }
+
+    /// Sets up the alarm for the next expiration of a timer on a system
+    /// clock, replacing the old one.
+    fn arm(self: &Arc<Self>, state: &mut TimerState) {
+        if let Some(alarm) = state.alarm.take() {
+            cancel_alarm(alarm);
+        }
+        if let (TimerClock::System(clock), Some(deadline)) = (self.clock, state.expiry) {
+            let timer = Arc::downgrade(self);
+            state.alarm = Some(call_at(clock.to_wall_time(deadline), move || {
+                if let Some(timer) = timer.upgrade() {
+                    timer.poll();
+                }
+            }));
+        }
+    }
+
+    /// Records `count` expirations, and returns the signal to notify them
+    /// with unless the previous one is still pending, in which case they
+    /// are overruns.
+    fn expire(&self, state: &mut TimerState, count: u64) -> Option<SignalInfo> {
+        let TimerNotify::Signal {
+            signo,
+            value,
+            pid,
+            tid,
+        } = self.notify
+        else {
+            return None;
+        };
+        let pending = match tid {
+            Some(tid) => get_task(tid)
+                .ok()
+                .and_then(|task| Some(task.try_as_thread()?.signal.pending())),
+            None => get_process_data(pid).ok().map(|it| it.signal.pending()),
+        };
+        if pending.is_none_or(|it| it.has(signo)) {
+            state.pending_overrun = state.pending_overrun.saturating_add(count);
+            return None;
+        }
+
+        let overrun = state.pending_overrun.saturating_add(count - 1);
+        state.pending_overrun = 0;
+        state.overrun = overrun.min(i32::MAX as u64) as i32;
+
+        let mut sig = SignalInfo::new_kernel(signo);
+        sig.set_code(SI_TIMER);
+        sig.0.__bindgen_anon_1.__bindgen_anon_1._sifields._timer = __sifields__bindgen_ty_2 {
+            _tid: self.id,
+            _overrun: state.overrun,
+            _sigval: sigval_t {
+                sival_ptr: value as _,
+            },
+            _sys_private: 0,
+        };
+        Some(sig)
+    }
+
+    fn send(&self, sig: Option<SignalInfo>) {
+        let (Some(sig), TimerNotify::Signal { pid, tid, .. }) = (sig, self.notify) else {
+            return;
+        };
+        let _ = match tid {
+            Some(tid) => send_signal_to_thread(Some(pid), tid, Some(sig)),
+            None => send_signal_to_process(pid, Some(sig)),
+        };
+    }
+}
+
+impl Drop for PosixTimer {
+    fn drop(&mut self) {
+        if let Some(alarm) = self.state.get_mut().alarm.take() {
+            cancel_alarm(alarm);
+        }
+    }
+}
+
+/// The POSIX timers of a process.
+#[derive(Default)]
+pub struct PosixTimers {
+    timers: Mutex<BTreeMap<i32, Arc<PosixTimer>>>,
+    /// Number of timers on CPU-time clocks, which have to be charged as
+    /// threads run.
+    cpu_timers: AtomicUsize,
+}
+
+impl PosixTimers {
+    /// Creates a timer on `clock`, whose notification is given by `notify`
+    /// from the ID of the timer, and returns the ID.
+    pub fn create(
+        &self,
+        clock: TimerClock,
+        notify: impl FnOnce(i32) -> TimerNotify,
+    ) -> AxResult<i32> {
+        let mut timers = self.timers.lock();
+        let id = (0..i32::MAX)
+            .find(|id| !timers.contains_key(id))
+            .ok_or(AxError::WouldBlock)?;
+        timers.insert(
+            id,
+            Arc::new(PosixTimer {
+                id,
+                clock,
+                notify: notify(id),
+                state: Mutex::default(),
+            }),
+        );
+        if !matches!(clock, TimerClock::System(_)) {
+            self.cpu_timers.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(id)
+    }
+
+    /// Returns the timer `id`.
+    pub fn get(&self, id: i32) -> AxResult<Arc<PosixTimer>> {
+        self.timers
+            .lock()
+            .get(&id)
+            .cloned()
+            .ok_or(AxError::InvalidInput)
+    }
+
+    /// Deletes the timer `id`.
+    pub fn delete(&self, id: i32) -> AxResult<()> {
+        let timer = self
+            .timers
+            .lock()
+            .remove(&id)
+            .ok_or(AxError::InvalidInput)?;
+        if !matches!(timer.clock, TimerClock::System(_)) {
+            self.cpu_timers.fetch_sub(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Deletes all timers, as on `exec` and exit.
+    pub fn clear(&self) {
+        let timers = mem::take(&mut *self.timers.lock());
+        self.cpu_timers.store(0, Ordering::Relaxed);
+        drop(timers);
+    }
+
+    /// Charges `cpu_time` consumed by the thread `tid` to the timers on
+    /// CPU-time clocks.
+    pub fn charge_cpu_time(&self, tid: Pid, cpu_time: Duration) {
+        if cpu_time.is_zero() || self.cpu_timers.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let timers: Vec<_> = self
+            .timers
+            .lock()
+            .values()
+            .filter(|it| match it.clock {
+                TimerClock::System(_) => false,
+                TimerClock::Process => true,
+                TimerClock::Thread(it) => it == tid,
+            })
+            .cloned()
+            .collect();
+        for timer in timers {
+            timer.charge(cpu_time);
+        }
+    }
+}
@@ -0,0 +1,378 @@
+//! Secure computing mode.
+
+use alloc::{boxed::Box, sync::Arc};
+use core::iter;
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axhal::uspace::UserContext;
+use axtask::current;
+use linux_raw_sys::{
+    general::{__sifields__bindgen_ty_7, SYS_SECCOMP},
+    ptrace::{
+        BPF_A, BPF_ABS, BPF_ADD, BPF_ALU, BPF_AND, BPF_DIV, BPF_IMM, BPF_JA, BPF_JEQ, BPF_JGE,
+        BPF_JGT, BPF_JMP, BPF_JSET, BPF_K, BPF_LD, BPF_LDX, BPF_LEN, BPF_LSH, BPF_MAXINSNS,
+        BPF_MEM, BPF_MEMWORDS, BPF_MISC, BPF_MOD, BPF_MUL, BPF_NEG, BPF_OR, BPF_RET, BPF_RSH,
+        BPF_ST, BPF_STX, BPF_SUB, BPF_TAX, BPF_TXA, BPF_W, BPF_X, BPF_XOR, SECCOMP_RET_ACTION_FULL,
+        SECCOMP_RET_ALLOW, SECCOMP_RET_DATA, SECCOMP_RET_ERRNO, SECCOMP_RET_KILL_PROCESS,
+        SECCOMP_RET_KILL_THREAD, SECCOMP_RET_LOG, SECCOMP_RET_TRACE, SECCOMP_RET_TRAP,
+        SECCOMP_RET_USER_NOTIF, seccomp_data, sock_filter,
+    },
+};
+use starry_signal::{SignalAction, SignalDisposition, SignalInfo, Signo};
+use syscalls::Sysno;
+
+use super::{AsThread, do_exit};
+
+/// The size of `seccomp_data` in 32-bit words, as loaded by filters.
+const DATA_WORDS: usize = size_of::<seccomp_data>() / size_of::<u32>();
+
+/// The limit on the total number of instructions of the filters run for a
+/// syscall, counting 4 extra instructions per filter.
+const MAX_INSNS_PER_PATH: usize = 32768;
+
+const MAX_ERRNO: u32 = 4095;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = linux_raw_sys::ptrace::AUDIT_ARCH_X86_64;
+#[cfg(target_arch = "riscv64")]
+const AUDIT_ARCH: u32 = linux_raw_sys::ptrace::AUDIT_ARCH_RISCV64;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = linux_raw_sys::ptrace::AUDIT_ARCH_AARCH64;
+#[cfg(target_arch = "loongarch64")]
+const AUDIT_ARCH: u32 = linux_raw_sys::ptrace::AUDIT_ARCH_LOONGARCH64;
+
+/// Syscalls allowed in strict mode.
+const STRICT_SYSCALLS: [Sysno; 4] = [Sysno::read, Sysno::write, Sysno::exit, Sysno::rt_sigreturn];
+
+/// The seccomp mode of a thread.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum SeccompMode {
+    /// Syscalls are not restricted.
+    #[default]
+    Disabled = 0,
+    /// Only `read`, `write`, `exit` and `rt_sigreturn` are allowed.
+    Strict   = 1,
+    /// Syscalls are checked by the filters.
+    Filter   = 2,
+}
+
+/// A classic BPF program checking syscalls, attached on top of the filters
+/// installed before it.
+pub struct SeccompFilter {
+    prog: Box<[sock_filter]>,
+    /// Whether the actions taken by the filter are logged.
+    log: bool,
+    prev: Option<Arc<SeccompFilter>>,
+}
+
+impl SeccompFilter {
+    /// Verifies `prog` and creates a filter from it on top of `prev`.
+    pub fn new(
+        prog: Box<[sock_filter]>,
+        log: bool,
+        prev: Option<Arc<SeccompFilter>>,
+    ) -> AxResult<Arc<Self>> {
+        check_prog(&prog)?;
+        let filter = Self { prog, log, prev };
+        let path_len: usize = filter.iter().map(|it| it.prog.len() + 4).sum();
+        if path_len > MAX_INSNS_PER_PATH {
+            return Err(AxError::NoMemory);
+        }
+        Ok(Arc::new(filter))
+    }
+
+    /// Returns an iterator over the filter and those below it.
+    fn iter(&self) -> impl Iterator<Item = &SeccompFilter> {
+        iter::successors(Some(self), |it| it.prev.as_deref())
+    }
+
+    /// Checks whether `other` is the filter or one below it.
+    pub fn is_descendant_of(&self, other: &SeccompFilter) -> bool {
+        self.iter().any(|it| core::ptr::eq(it, other))
+    }
+
+    /// Runs all the filters, and returns the result with the highest
+    /// precedence along with whether it is to be logged.
+    fn evaluate(&self, data: &[u32; DATA_WORDS]) -> (u32, bool) {
+        let action = |ret: u32| (ret & SECCOMP_RET_ACTION_FULL) as i32;
+        let mut result = (SECCOMP_RET_ALLOW, false);
+        for filter in self.iter() {
+            let ret = filter.run(data);
+            if action(ret) < action(result.0) {
+                result = (ret, filter.log);
+            }
+        }
+        result
+    }
+
+    /// Runs the program, which has been verified by [`check_prog`].
+    fn run(&self, data: &[u32; DATA_WORDS]) -> u32 {
+        let (mut a, mut x) = (0u32, 0u32);
+        let mut mem = [0u32; BPF_MEMWORDS as usize];
+        let mut pc = 0;
+        loop {
+            let insn = &self.prog[pc];
+            let code = insn.code as u32;
+            let k = insn.k;
+            pc += 1;
+            match class(code) {
+                BPF_LD | BPF_LDX => {
+                    let value = match code & 0xe0 {
+                        BPF_ABS => data[k as usize / 4],
+                        BPF_LEN => size_of::<seccomp_data>() as u32,
+                        BPF_MEM => mem[k as usize],
+                        _ => k,
+                    };
+                    if class(code) == BPF_LD {
+                        a = value;
+                    } else {
+                        x = value;
+                    }
+                }
+                BPF_ST => mem[k as usize] = a,
+                BPF_STX => mem[k as usize] = x,
+                BPF_ALU => {
+                    let src = if code & BPF_X != 0 { x } else { k };
+                    a = match code & 0xf0 {
+                        BPF_ADD => a.wrapping_add(src),
+                        BPF_SUB => a.wrapping_sub(src),
+                        BPF_MUL => a.wrapping_mul(src),
+                        BPF_DIV | BPF_MOD if src == 0 => return 0,
+                        BPF_DIV => a / src,
+                        BPF_MOD => a % src,
+                        BPF_OR => a | src,
+                        BPF_AND => a & src,
+                        BPF_XOR => a ^ src,
+                        BPF_LSH => a.checked_shl(src).unwrap_or(0),
+                        BPF_RSH => a.checked_shr(src).unwrap_or(0),
+                        _ => a.wrapping_neg(),
+                    };
+                }
+                BPF_JMP => {
+                    let src = if code & BPF_X != 0 { x } else { k };
+                    let taken = match code & 0xf0 {
+                        BPF_JA => {
+                            pc += k as usize;
+                            continue;
+                        }
+                        BPF_JEQ => a == src,
+                        BPF_JGT => a > src,
+                        BPF_JGE => a >= src,
+                        _ => a & src != 0,
+                    };
+                    pc += if taken { insn.jt } else { insn.jf } as usize;
+                }
+                BPF_RET => return if code & 0x18 == BPF_A { a } else { k },
+                _ => {
+                    if code & 0xf8 == BPF_TXA {
+                        a = x;
+                    } else {
+                        x = a;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn class(code: u32) -> u32 {
+    code & 0x07
+}
+
+/// Checks that a program only uses the instructions allowed for seccomp, and
+/// always terminates with a return within bounds.
+fn check_prog(prog: &[sock_filter]) -> AxResult {
+    if prog.is_empty() || prog.len() > BPF_MAXINSNS as usize {
+        return Err(AxError::InvalidInput);
+    }
+    let in_bounds = |pc: usize, offset: u32| pc + 1 + (offset as usize) < prog.len();
+    for (pc, insn) in prog.iter().enumerate() {
+        let code = insn.code as u32;
+        let k = insn.k;
+        let valid = match code {
+            // Loads from `seccomp_data` must be aligned.
+            _ if code == BPF_LD | BPF_W | BPF_ABS => {
+                k % 4 == 0 && (k as usize) < size_of::<seccomp_data>()
+            }
+            _ if code == BPF_LD | BPF_W | BPF_LEN
+                || code == BPF_LDX | BPF_W | BPF_LEN
+                || code == BPF_LD | BPF_IMM
+                || code == BPF_LDX | BPF_IMM
+                || code == BPF_RET | BPF_K
+                || code == BPF_RET | BPF_A
+                || code == BPF_ALU | BPF_NEG
+                || code == BPF_MISC | BPF_TAX
+                || code == BPF_MISC | BPF_TXA =>
+            {
+                true
+            }
+            _ if code == BPF_LD | BPF_MEM
+                || code == BPF_LDX | BPF_MEM
+                || code == BPF_ST
+                || code == BPF_STX =>
+            {
+                k < BPF_MEMWORDS
+            }
+            _ if class(code) == BPF_ALU && code & !(0xf0 | BPF_X) == BPF_ALU => match code & 0xf0 {
+                BPF_ADD | BPF_SUB | BPF_MUL | BPF_OR | BPF_AND | BPF_XOR => true,
+                BPF_DIV | BPF_MOD => code & BPF_X != 0 || k != 0,
+                BPF_LSH | BPF_RSH => code & BPF_X != 0 || k < 32,
+                _ => false,
+            },
+            _ if code == BPF_JMP | BPF_JA => in_bounds(pc, k),
+            _ if class(code) == BPF_JMP && code & !(0xf0 | BPF_X) == BPF_JMP => {
+                matches!(code & 0xf0, BPF_JEQ | BPF_JGT | BPF_JGE | BPF_JSET)
+                    && in_bounds(pc, insn.jt as u32)
+                    && in_bounds(pc, insn.jf as u32)
+            }
+            _ => false,
+        };
+        if !valid {
+            return Err(AxError::InvalidInput);
+        }
+    }
+    if class(prog[prog.len() - 1].code as u32) != BPF_RET {
+        return Err(AxError::InvalidInput);
+    }
+    Ok(())
+}
+
+/// Checks whether `action` is a supported filter return action.
+pub fn is_action_available(action: u32) -> bool {
+    matches!(
+        action,
+        SECCOMP_RET_KILL_PROCESS
+            | SECCOMP_RET_KILL_THREAD
+            | SECCOMP_RET_TRAP
+            | SECCOMP_RET_ERRNO
+            | SECCOMP_RET_TRACE
+            | SECCOMP_RET_LOG
+            | SECCOMP_RET_ALLOW
+    )
+}
+
+/// The seccomp state of a thread, which is inherited by its children.
+#[derive(Default, Clone)]
+pub struct Seccomp {
+    pub mode: SeccompMode,
+    /// The topmost filter, in filter mode.
+    pub filter: Option<Arc<SeccompFilter>>,
+}
+
+/// Checks the syscall in `uctx` against the seccomp state of the current
+/// thread, taking the action given by it.
+///
+/// Returns whether the syscall is to be executed. If not, its return value
+/// is set, or the thread is exiting.
+pub fn check_seccomp(uctx: &mut UserContext) -> bool {
+    let curr = current();
+    let thr = curr.as_thread();
+    let seccomp = thr.seccomp.read();
+    let sysno = uctx.sysno();
+    let filter = match seccomp.mode {
+        SeccompMode::Disabled => return true,
+        SeccompMode::Strict => {
+            drop(seccomp);
+            if STRICT_SYSCALLS.iter().any(|it| it.id() as usize == sysno) {
+                return true;
+            }
+            info!("{}: syscall {sysno} denied in strict mode", curr.id_name());
+            do_exit(Signo::SIGKILL as i32, false);
+            return false;
+        }
+        SeccompMode::Filter => seccomp.filter.clone().expect("no seccomp filter"),
+    };
+    drop(seccomp);
+
+    // All supported architectures are little-endian, so the lower half of a
+    // 64-bit field comes first.
+    let mut data = [0; DATA_WORDS];
+    data[0] = sysno as u32;
+    data[1] = AUDIT_ARCH;
+    let args = [
+        uctx.ip(),
+        uctx.arg0(),
+        uctx.arg1(),
+        uctx.arg2(),
+        uctx.arg3(),
+        uctx.arg4(),
+        uctx.arg5(),
+    ];
+    for (i, value) in args.into_iter().enumerate() {
+        data[2 + i * 2] = value as u32;
+        data[3 + i * 2] = (value as u64 >> 32) as u32;
+    }
+
+    let (ret, log) = filter.evaluate(&data);
+    let action = ret & SECCOMP_RET_ACTION_FULL;
+    let value = ret & SECCOMP_RET_DATA;
+    if log
+        || matches!(
+            action,
+            SECCOMP_RET_LOG | SECCOMP_RET_KILL_PROCESS | SECCOMP_RET_KILL_THREAD
+        )
+    {
+        info!(
+            "{}: seccomp action {action:#x} on syscall {sysno}",
+            curr.id_name()
+        );
+    }
+    match action {
+        SECCOMP_RET_ALLOW | SECCOMP_RET_LOG => true,
+        SECCOMP_RET_ERRNO => {
+            uctx.set_retval(-(value.min(MAX_ERRNO) as isize) as usize);
+            false
+        }
+        SECCOMP_RET_TRAP => {
+            uctx.set_retval(-LinuxError::ENOSYS.code() as usize);
+            let mut sig = SignalInfo::new_kernel(Signo::SIGSYS);
+            sig.set_code(SYS_SECCOMP as _);
+            sig.0.__bindgen_anon_1.__bindgen_anon_1.si_errno = value as _;
+            sig.0.__bindgen_anon_1.__bindgen_anon_1._sifields._sigsys = __sifields__bindgen_ty_7 {
+                _call_addr: uctx.ip() as _,
+                _syscall: sysno as _,
+                _arch: AUDIT_ARCH,
+            };
+            force_sigsys(sig);
+            false
+        }
+        // There are no tracers or listeners, in which case the syscall fails.
+        SECCOMP_RET_TRACE | SECCOMP_RET_USER_NOTIF => {
+            uctx.set_retval(-LinuxError::ENOSYS.code() as usize);
+            false
+        }
+        SECCOMP_RET_KILL_THREAD if thr.proc_data.proc.threads().len() > 1 => {
+            do_exit(Signo::SIGSYS as i32, false);
+            false
+        }
+        // Killing the last thread kills the process, with a core dump.
+        _ => {
+            do_exit(128 + Signo::SIGSYS as i32, true);
+            false
+        }
+    }
+}
+
+/// Sends `SIGSYS` to the current thread, which cannot be blocked or ignored.
+fn force_sigsys(sig: SignalInfo) {
+    let curr = current();
+    let thr = curr.as_thread();
+    let blocked = thr.signal.signal_blocked(Signo::SIGSYS);
+    let mut actions = thr.proc_data.signal.actions.lock();
+    if blocked
+        || matches!(
+            actions[Signo::SIGSYS].disposition,
+            SignalDisposition::Ignore
+        )
+    {
+        actions[Signo::SIGSYS] = SignalAction::default();
+        drop(actions);
+        let mut set = thr.signal.blocked();
+        set.remove(Signo::SIGSYS);
+        thr.signal.set_blocked(set);
+    } else {
+        drop(actions);
+    }
+    let _ = thr.signal.send_signal(sig);
+}
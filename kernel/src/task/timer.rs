@@ -1,7 +1,11 @@
 //! Time management module.
 
-use alloc::{borrow::ToOwned, collections::binary_heap::BinaryHeap, sync::Arc};
-use core::{mem, time::Duration};
+use alloc::{borrow::ToOwned, boxed::Box, collections::btree_map::BTreeMap, sync::Arc};
+use core::{
+    mem,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
 
 use axhal::time::{NANOS_PER_SEC, TimeValue, monotonic_time_nanos, wall_time};
 use axtask::{
@@ -22,29 +26,23 @@ fn time_value_from_nanos(nanos: usize) -> TimeValue {
     TimeValue::new(secs, nsecs as u32)
 }
 
-struct Entry {
-    deadline: Duration,
-    task: WeakAxTaskRef,
-}
-impl PartialEq for Entry {
-    fn eq(&self, other: &Self) -> bool {
-        self.deadline == other.deadline
-    }
-}
-impl Eq for Entry {}
-impl PartialOrd for Entry {
-    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
-        Some(self.cmp(other))
-    }
+/// What to do when an alarm goes off.
+enum Action {
+    /// Polls the interval timers of a task.
+    PollTimer(WeakAxTaskRef),
+    /// Calls a function.
+    Call(Box<dyn FnOnce() + Send>),
 }
-impl Ord for Entry {
-    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
-        other.deadline.cmp(&self.deadline)
-    }
+
+/// Identifies a pending alarm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AlarmKey {
+    deadline: Duration,
+    id: u64,
 }
 
 lazy_static! {
-    static ref ALARM_LIST: Mutex<BinaryHeap<Entry>> = Mutex::new(BinaryHeap::new());
+    static ref ALARM_LIST: Mutex<BTreeMap<AlarmKey, Action>> = Mutex::new(BTreeMap::new());
     static ref EVENT_NEW_TIMER: Event = Event::new();
 }
 
@@ -105,16 +103,7 @@ impl ITimer {
     pub fn renew_timer(&self) {
         if self.remained_ns > 0 {
             let deadline = wall_time() + Duration::from_nanos(self.remained_ns as u64);
-            let mut guard = ALARM_LIST.lock();
-            let should_wake = guard.peek().is_none_or(|it| it.deadline > deadline);
-            guard.push(Entry {
-                deadline,
-                task: Arc::downgrade(&current()),
-            });
-            drop(guard);
-            if should_wake {
-                EVENT_NEW_TIMER.notify(1);
-            }
+            add_alarm(deadline, Action::PollTimer(Arc::downgrade(&current())));
         }
     }
 }
@@ -166,23 +155,28 @@ impl TimeManager {
 
     /// Polls the time manager to update the timers and emit signals if
     /// necessary.
-    pub fn poll(&mut self, emitter: impl Fn(Signo)) {
+    ///
+    /// Returns the CPU time consumed since the last poll.
+    pub fn poll(&mut self, emitter: impl Fn(Signo)) -> TimeValue {
         let now_ns = monotonic_time_nanos() as usize;
         let delta = now_ns - self.last_wall_ns;
-        match self.state {
+        let cpu_time = match self.state {
             TimerState::User => {
                 self.utime_ns += delta;
                 self.update_itimer(ITimerType::Virtual, delta, &emitter);
                 self.update_itimer(ITimerType::Prof, delta, &emitter);
+                delta
             }
             TimerState::Kernel => {
                 self.stime_ns += delta;
                 self.update_itimer(ITimerType::Prof, delta, &emitter);
+                delta
             }
-            TimerState::None => {}
-        }
+            TimerState::None => 0,
+        };
         self.update_itimer(ITimerType::Real, delta, &emitter);
         self.last_wall_ns = now_ns;
+        time_value_from_nanos(cpu_time)
     }
 
     /// Updates the timer state.
@@ -224,10 +218,39 @@ impl TimeManager {
     }
 }
 
+fn add_alarm(deadline: TimeValue, action: Action) -> AlarmKey {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+    let key = AlarmKey {
+        deadline,
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+    };
+    let mut guard = ALARM_LIST.lock();
+    let should_wake = guard.first_key_value().is_none_or(|(it, _)| *it > key);
+    guard.insert(key, action);
+    drop(guard);
+    if should_wake {
+        EVENT_NEW_TIMER.notify(1);
+    }
+    key
+}
+
+/// Calls `f` from the alarm task once the wall clock reaches `deadline`.
+///
+/// `f` must not block.
+pub fn call_at(deadline: TimeValue, f: impl FnOnce() + Send + 'static) -> AlarmKey {
+    add_alarm(deadline, Action::Call(Box::new(f)))
+}
+
+/// Cancels a pending alarm. Does nothing if it has already gone off.
+pub fn cancel_alarm(key: AlarmKey) {
+    ALARM_LIST.lock().remove(&key);
+}
+
 async fn alarm_task() {
     loop {
-        let guard = ALARM_LIST.lock();
-        let Some(entry) = guard.peek() else {
+        let mut guard = ALARM_LIST.lock();
+        let Some(key) = guard.first_key_value().map(|(key, _)| *key) else {
             drop(guard);
             listener!(EVENT_NEW_TIMER => listener);
 
@@ -240,28 +263,28 @@ async fn alarm_task() {
         };
 
         let now = wall_time();
-        if entry.deadline <= now {
-            let entry_deadline = entry.deadline;
-            if let Some(task) = entry.task.upgrade() {
-                drop(guard);
-                poll_timer(&task);
-            } else {
-                drop(guard);
+        if key.deadline <= now {
+            let action = guard.remove(&key).unwrap();
+            drop(guard);
+            match action {
+                Action::PollTimer(task) => {
+                    if let Some(task) = task.upgrade() {
+                        poll_timer(&task);
+                    }
+                }
+                Action::Call(f) => f(),
             }
-            let mut guard = ALARM_LIST.lock();
-            assert!(guard.pop().is_some_and(|it| it.deadline == entry_deadline));
         } else {
-            let deadline = entry.deadline;
             drop(guard);
             listener!(EVENT_NEW_TIMER => listener);
             if ALARM_LIST
                 .lock()
-                .peek()
-                .is_none_or(|it| it.deadline != deadline)
+                .first_key_value()
+                .is_none_or(|(it, _)| *it != key)
             {
                 continue;
             }
-            let _ = timeout_at(Some(deadline), listener).await;
+            let _ = timeout_at(Some(key.deadline), listener).await;
         }
     }
 }
@@ -7,7 +7,7 @@ use starry_vm::{VmMutPtr, VmPtr};
 use super::{
     AsThread, TimerState, check_signals, raise_signal_fatal, set_timer_state, unblock_next_signal,
 };
-use crate::syscall::handle_syscall;
+use crate::{mm::PageFaultResult, syscall::handle_syscall};
 
 /// Create a new user task.
 pub fn new_user_task(name: &str, mut uctx: UserContext, set_child_tid: usize) -> TaskInner {
@@ -30,13 +30,22 @@ pub fn new_user_task(name: &str, mut uctx: UserContext, set_child_tid: usize) ->
                 match reason {
                     ReturnReason::Syscall => handle_syscall(&mut uctx),
                     ReturnReason::PageFault(addr, flags) => {
-                        if !thr.proc_data.aspace.lock().handle_page_fault(addr, flags) {
-                            info!(
-                                "{:?}: segmentation fault at {:#x} {:?}",
-                                thr.proc_data.proc, addr, flags
-                            );
-                            raise_signal_fatal(SignalInfo::new_kernel(Signo::SIGSEGV))
-                                .expect("Failed to send SIGSEGV");
+                        let result = thr.proc_data.aspace.lock().handle_page_fault(addr, flags);
+                        match result {
+                            PageFaultResult::Handled => {}
+                            // The access is retried once the fault is resolved,
+                            // or after the signal interrupting the wait.
+                            PageFaultResult::UserFault(uffd, uffd_flags) => {
+                                uffd.handle_fault(addr, uffd_flags);
+                            }
+                            PageFaultResult::Invalid => {
+                                info!(
+                                    "{:?}: segmentation fault at {:#x} {:?}",
+                                    thr.proc_data.proc, addr, flags
+                                );
+                                raise_signal_fatal(SignalInfo::new_kernel(Signo::SIGSEGV))
+                                    .expect("Failed to send SIGSEGV");
+                            }
                         }
                     }
                     ReturnReason::Interrupt => {}
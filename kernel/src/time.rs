@@ -1,10 +1,47 @@
 use axerrno::{AxError, AxResult};
-use axhal::time::TimeValue;
+use axhal::time::{TimeValue, epochoffset_nanos, monotonic_time, wall_time};
 use linux_raw_sys::general::{
     __kernel_old_timespec, __kernel_old_timeval, __kernel_sock_timeval, __kernel_timespec,
-    timespec, timeval,
+    CLOCK_BOOTTIME, CLOCK_MONOTONIC, CLOCK_REALTIME, timespec, timeval,
 };
 
+/// A clock that timers can be set on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clock {
+    /// `CLOCK_REALTIME`.
+    Realtime,
+    /// `CLOCK_MONOTONIC`, which also serves as `CLOCK_BOOTTIME` since the
+    /// system never suspends.
+    Monotonic,
+}
+
+impl Clock {
+    /// Returns the clock identified by `clock_id`.
+    pub fn from_id(clock_id: u32) -> AxResult<Self> {
+        match clock_id {
+            CLOCK_REALTIME => Ok(Self::Realtime),
+            CLOCK_MONOTONIC | CLOCK_BOOTTIME => Ok(Self::Monotonic),
+            _ => Err(AxError::InvalidInput),
+        }
+    }
+
+    /// Returns the current time of the clock.
+    pub fn now(self) -> TimeValue {
+        match self {
+            Self::Realtime => wall_time(),
+            Self::Monotonic => monotonic_time(),
+        }
+    }
+
+    /// Converts a time of the clock into wall time, which alarms are set in.
+    pub fn to_wall_time(self, time: TimeValue) -> TimeValue {
+        match self {
+            Self::Realtime => time,
+            Self::Monotonic => time + TimeValue::from_nanos(epochoffset_nanos()),
+        }
+    }
+}
+
 /// A helper trait for converting from and to `TimeValue`.
 pub trait TimeValueLike {
     /// Converts from `TimeValue`.
@@ -92,6 +92,10 @@ endif
 
 qemu_args-$(ICOUNT) += -icount shift=1
 
+ifneq ($(INITRD),)
+  qemu_args-y += -initrd $(INITRD)
+endif
+
 qemu_args-y += $(QEMU_ARGS)
 
 qemu_args-debug := $(qemu_args-y) -s -S
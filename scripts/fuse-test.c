@@ -0,0 +1,504 @@
+// StarryOS FUSE test daemon.
+//
+// Mounts a flat in-memory filesystem served through /dev/fuse, then exercises
+// it from a child process with create, write, read, lookup, setattr, readdir,
+// rename, unlink and release. Exits with 0 once every check passed and the
+// daemon has served each of these operations.
+//
+// Usage (inside StarryOS):
+//   apk add gcc musl-dev linux-headers
+//   gcc -O2 -o fuse-test fuse-test.c
+//   ./fuse-test [MOUNTPOINT]          # defaults to /tmp/fuse-test
+
+#define _GNU_SOURCE
+#include <dirent.h>
+#include <errno.h>
+#include <fcntl.h>
+#include <linux/fuse.h>
+#include <signal.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/mount.h>
+#include <sys/stat.h>
+#include <sys/uio.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+#define MAX_NODES 64
+#define MAX_WRITE (128 * 1024)
+#define BUF_SIZE (MAX_WRITE + 4096)
+
+// Regular files of the root directory; node `i` has the inode number `i + 2`.
+struct node {
+    int used;
+    char name[256];
+    uint32_t mode;
+    char *data;
+    uint64_t size;
+    uint64_t mtime;
+};
+
+static struct node nodes[MAX_NODES];
+static int seen[64];
+static char buf[BUF_SIZE];
+static volatile sig_atomic_t child_done;
+
+static struct node *get_node(uint64_t ino)
+{
+    if (ino < 2 || ino - 2 >= MAX_NODES || !nodes[ino - 2].used)
+        return NULL;
+    return &nodes[ino - 2];
+}
+
+static int find_node(const char *name)
+{
+    for (int i = 0; i < MAX_NODES; i++)
+        if (nodes[i].used && strcmp(nodes[i].name, name) == 0)
+            return i;
+    return -1;
+}
+
+static int fill_attr(uint64_t ino, struct fuse_attr *attr)
+{
+    memset(attr, 0, sizeof(*attr));
+    attr->ino = ino;
+    attr->blksize = 4096;
+    if (ino == FUSE_ROOT_ID) {
+        attr->mode = S_IFDIR | 0755;
+        attr->nlink = 2;
+        return 0;
+    }
+    struct node *node = get_node(ino);
+    if (!node)
+        return -ENOENT;
+    attr->mode = node->mode;
+    attr->nlink = 1;
+    attr->size = node->size;
+    attr->blocks = (node->size + 511) / 512;
+    attr->atime = attr->mtime = attr->ctime = node->mtime;
+    return 0;
+}
+
+static void reply(int fd, uint64_t unique, int error, const void *data, size_t len)
+{
+    struct fuse_out_header out = {
+        .len = sizeof(out) + (error ? 0 : len),
+        .error = error,
+        .unique = unique,
+    };
+    struct iovec iov[2] = {
+        { &out, sizeof(out) },
+        { (void *)data, error ? 0 : len },
+    };
+    if (writev(fd, iov, 2) < 0)
+        perror("fuse-test: reply");
+}
+
+static int fill_entry(uint64_t ino, struct fuse_entry_out *entry)
+{
+    memset(entry, 0, sizeof(*entry));
+    entry->nodeid = ino;
+    return fill_attr(ino, &entry->attr);
+}
+
+static int resize(struct node *node, uint64_t size)
+{
+    if (size > node->size) {
+        char *data = realloc(node->data, size);
+        if (!data)
+            return -ENOMEM;
+        memset(data + node->size, 0, size - node->size);
+        node->data = data;
+    }
+    node->size = size;
+    return 0;
+}
+
+static void do_setattr(int fd, struct fuse_in_header *in, struct fuse_setattr_in *arg)
+{
+    struct fuse_attr_out out = { 0 };
+    struct node *node = get_node(in->nodeid);
+    int err = 0;
+    if (node) {
+        if (arg->valid & FATTR_SIZE)
+            err = resize(node, arg->size);
+        if (!err && (arg->valid & FATTR_MODE))
+            node->mode = (node->mode & S_IFMT) | (arg->mode & 07777);
+        if (!err && (arg->valid & FATTR_MTIME))
+            node->mtime = arg->mtime;
+    } else if (in->nodeid != FUSE_ROOT_ID) {
+        err = -ENOENT;
+    }
+    if (!err)
+        err = fill_attr(in->nodeid, &out.attr);
+    reply(fd, in->unique, err, &out, sizeof(out));
+}
+
+static void do_create(int fd, struct fuse_in_header *in, struct fuse_create_in *arg)
+{
+    struct {
+        struct fuse_entry_out entry;
+        struct fuse_open_out open;
+    } out = { 0 };
+    const char *name = (const char *)(arg + 1);
+    int err = 0;
+
+    if (in->nodeid != FUSE_ROOT_ID)
+        err = -ENOTDIR;
+    else if (find_node(name) >= 0)
+        err = -EEXIST;
+    else if (strlen(name) >= sizeof(nodes[0].name))
+        err = -ENAMETOOLONG;
+    if (!err) {
+        int i = 0;
+        while (i < MAX_NODES && nodes[i].used)
+            i++;
+        if (i == MAX_NODES) {
+            err = -ENOSPC;
+        } else {
+            nodes[i] = (struct node) {
+                .used = 1,
+                .mode = S_IFREG | (arg->mode & ~arg->umask & 07777),
+            };
+            strcpy(nodes[i].name, name);
+            fill_entry(i + 2, &out.entry);
+            out.open.fh = i + 2;
+        }
+    }
+    reply(fd, in->unique, err, &out, sizeof(out));
+}
+
+static void do_read(int fd, struct fuse_in_header *in, struct fuse_read_in *arg)
+{
+    struct node *node = get_node(in->nodeid);
+    if (!node) {
+        reply(fd, in->unique, -EBADF, NULL, 0);
+        return;
+    }
+    size_t len = 0;
+    if (arg->offset < node->size) {
+        len = node->size - arg->offset;
+        if (len > arg->size)
+            len = arg->size;
+    }
+    reply(fd, in->unique, 0, node->data + arg->offset, len);
+}
+
+static void do_write(int fd, struct fuse_in_header *in, struct fuse_write_in *arg)
+{
+    struct fuse_write_out out = { .size = arg->size };
+    struct node *node = get_node(in->nodeid);
+    int err = node ? 0 : -EBADF;
+    if (!err && arg->offset + arg->size > node->size)
+        err = resize(node, arg->offset + arg->size);
+    if (!err)
+        memcpy(node->data + arg->offset, arg + 1, arg->size);
+    reply(fd, in->unique, err, &out, sizeof(out));
+}
+
+static void do_readdir(int fd, struct fuse_in_header *in, struct fuse_read_in *arg)
+{
+    static char out[MAX_WRITE];
+    size_t len = 0;
+    uint64_t index = 0;
+
+    if (in->nodeid != FUSE_ROOT_ID) {
+        reply(fd, in->unique, -ENOTDIR, NULL, 0);
+        return;
+    }
+    for (int i = -2; i < MAX_NODES; i++) {
+        const char *name;
+        uint64_t ino;
+        uint32_t type;
+        if (i == -2) {
+            name = ".", ino = FUSE_ROOT_ID, type = DT_DIR;
+        } else if (i == -1) {
+            name = "..", ino = FUSE_ROOT_ID, type = DT_DIR;
+        } else if (nodes[i].used) {
+            name = nodes[i].name, ino = i + 2, type = DT_REG;
+        } else {
+            continue;
+        }
+        if (index++ < arg->offset)
+            continue;
+        size_t size = FUSE_DIRENT_SIZE(&(struct fuse_dirent) { .namelen = strlen(name) });
+        if (len + size > arg->size || len + size > sizeof(out))
+            break;
+        struct fuse_dirent *dirent = (struct fuse_dirent *)(out + len);
+        memset(dirent, 0, size);
+        dirent->ino = ino;
+        dirent->off = index;
+        dirent->namelen = strlen(name);
+        dirent->type = type;
+        memcpy(dirent->name, name, dirent->namelen);
+        len += size;
+    }
+    reply(fd, in->unique, 0, out, len);
+}
+
+static void do_rename(int fd, struct fuse_in_header *in, struct fuse_rename_in *arg)
+{
+    const char *old_name = (const char *)(arg + 1);
+    const char *new_name = old_name + strlen(old_name) + 1;
+    int err = 0;
+    int i = find_node(old_name);
+
+    if (in->nodeid != FUSE_ROOT_ID || arg->newdir != FUSE_ROOT_ID)
+        err = -ENOTDIR;
+    else if (i < 0)
+        err = -ENOENT;
+    else if (strlen(new_name) >= sizeof(nodes[0].name))
+        err = -ENAMETOOLONG;
+    if (!err) {
+        int j = find_node(new_name);
+        if (j >= 0 && j != i) {
+            free(nodes[j].data);
+            nodes[j] = (struct node) { 0 };
+        }
+        strcpy(nodes[i].name, new_name);
+    }
+    reply(fd, in->unique, err, NULL, 0);
+}
+
+static void serve(int fd, struct fuse_in_header *in)
+{
+    void *arg = in + 1;
+    if (in->opcode < sizeof(seen) / sizeof(seen[0]))
+        seen[in->opcode] = 1;
+
+    switch (in->opcode) {
+    case FUSE_INIT: {
+        struct fuse_init_in *init = arg;
+        struct fuse_init_out out = {
+            .major = FUSE_KERNEL_VERSION,
+            .minor = FUSE_KERNEL_MINOR_VERSION,
+            .max_readahead = init->max_readahead,
+            .flags = FUSE_BIG_WRITES,
+            .max_write = MAX_WRITE,
+        };
+        reply(fd, in->unique, 0, &out, sizeof(out));
+        break;
+    }
+    case FUSE_LOOKUP: {
+        struct fuse_entry_out out;
+        int i = in->nodeid == FUSE_ROOT_ID ? find_node(arg) : -1;
+        int err = i < 0 ? -ENOENT : fill_entry(i + 2, &out);
+        reply(fd, in->unique, err, &out, sizeof(out));
+        break;
+    }
+    case FUSE_FORGET:
+        break;
+    case FUSE_GETATTR: {
+        struct fuse_attr_out out = { 0 };
+        int err = fill_attr(in->nodeid, &out.attr);
+        reply(fd, in->unique, err, &out, sizeof(out));
+        break;
+    }
+    case FUSE_SETATTR:
+        do_setattr(fd, in, arg);
+        break;
+    case FUSE_OPEN:
+    case FUSE_OPENDIR: {
+        struct fuse_open_out out = { .fh = in->nodeid };
+        struct fuse_attr attr;
+        int err = fill_attr(in->nodeid, &attr);
+        reply(fd, in->unique, err, &out, sizeof(out));
+        break;
+    }
+    case FUSE_CREATE:
+        do_create(fd, in, arg);
+        break;
+    case FUSE_READ:
+        do_read(fd, in, arg);
+        break;
+    case FUSE_WRITE:
+        do_write(fd, in, arg);
+        break;
+    case FUSE_READDIR:
+        do_readdir(fd, in, arg);
+        break;
+    case FUSE_UNLINK: {
+        int i = in->nodeid == FUSE_ROOT_ID ? find_node(arg) : -1;
+        if (i >= 0) {
+            free(nodes[i].data);
+            nodes[i] = (struct node) { 0 };
+        }
+        reply(fd, in->unique, i < 0 ? -ENOENT : 0, NULL, 0);
+        break;
+    }
+    case FUSE_RENAME:
+        do_rename(fd, in, arg);
+        break;
+    case FUSE_STATFS: {
+        struct fuse_statfs_out out = {
+            .st = { .bsize = 4096, .frsize = 4096, .namelen = 255 },
+        };
+        reply(fd, in->unique, 0, &out, sizeof(out));
+        break;
+    }
+    case FUSE_FLUSH:
+    case FUSE_FSYNC:
+    case FUSE_RELEASE:
+    case FUSE_RELEASEDIR:
+        reply(fd, in->unique, 0, NULL, 0);
+        break;
+    default:
+        reply(fd, in->unique, -ENOSYS, NULL, 0);
+        break;
+    }
+}
+
+#define CHECK(cond, what)                                                      \
+    do {                                                                       \
+        if (!(cond)) {                                                         \
+            fprintf(stderr, "fuse-test: %s failed: %s\n", what,                \
+                    strerror(errno));                                          \
+            return 1;                                                          \
+        }                                                                      \
+    } while (0)
+
+static int dir_contains(const char *dir, const char *name)
+{
+    DIR *d = opendir(dir);
+    struct dirent *ent;
+    int found = 0;
+    if (!d)
+        return -1;
+    while ((ent = readdir(d)))
+        if (strcmp(ent->d_name, name) == 0)
+            found = 1;
+    closedir(d);
+    return found;
+}
+
+static int run_tests(const char *mnt)
+{
+    static const char msg[] = "Hello, FUSE!\n";
+    char path[4096], new_path[4096], data[64];
+    struct stat st;
+    int fd;
+
+    snprintf(path, sizeof(path), "%s/hello", mnt);
+    snprintf(new_path, sizeof(new_path), "%s/world", mnt);
+
+    fd = open(path, O_CREAT | O_EXCL | O_RDWR, 0644);
+    CHECK(fd >= 0, "create");
+    CHECK(write(fd, msg, sizeof(msg) - 1) == sizeof(msg) - 1, "write");
+    CHECK(pread(fd, data, sizeof(data), 0) == sizeof(msg) - 1, "read");
+    CHECK(memcmp(data, msg, sizeof(msg) - 1) == 0, "read back");
+    CHECK(close(fd) == 0, "close");
+
+    CHECK(stat(path, &st) == 0, "stat");
+    CHECK(S_ISREG(st.st_mode) && st.st_size == sizeof(msg) - 1, "stat attributes");
+
+    CHECK(truncate(path, 5) == 0, "truncate");
+    CHECK(chmod(path, 0600) == 0, "chmod");
+    CHECK(stat(path, &st) == 0, "stat after setattr");
+    CHECK(st.st_size == 5 && (st.st_mode & 07777) == 0600, "setattr attributes");
+
+    fd = open(path, O_RDONLY);
+    CHECK(fd >= 0, "open");
+    CHECK(read(fd, data, sizeof(data)) == 5, "read after truncate");
+    CHECK(memcmp(data, "Hello", 5) == 0, "truncated data");
+    CHECK(close(fd) == 0, "close");
+
+    CHECK(dir_contains(mnt, "hello") == 1, "readdir");
+
+    CHECK(rename(path, new_path) == 0, "rename");
+    CHECK(stat(path, &st) < 0 && errno == ENOENT, "stat of renamed file");
+    CHECK(stat(new_path, &st) == 0 && st.st_size == 5, "stat of new name");
+
+    CHECK(unlink(new_path) == 0, "unlink");
+    CHECK(stat(new_path, &st) < 0 && errno == ENOENT, "stat of unlinked file");
+    CHECK(dir_contains(mnt, "world") == 0, "readdir after unlink");
+    return 0;
+}
+
+static void on_child(int sig)
+{
+    (void)sig;
+    child_done = 1;
+}
+
+int main(int argc, char **argv)
+{
+    static const struct {
+        uint32_t opcode;
+        const char *name;
+    } required[] = {
+        { FUSE_LOOKUP, "lookup" },   { FUSE_READ, "read" },
+        { FUSE_WRITE, "write" },     { FUSE_READDIR, "readdir" },
+        { FUSE_CREATE, "create" },   { FUSE_UNLINK, "unlink" },
+        { FUSE_RENAME, "rename" },   { FUSE_SETATTR, "setattr" },
+        { FUSE_RELEASE, "release" },
+    };
+    const char *mnt = argc > 1 ? argv[1] : "/tmp/fuse-test";
+    char opts[128];
+    int fd, status, ok = 1;
+    pid_t pid;
+
+    if (mkdir(mnt, 0755) < 0 && errno != EEXIST) {
+        perror("fuse-test: mkdir");
+        return 1;
+    }
+    fd = open("/dev/fuse", O_RDWR | O_CLOEXEC);
+    if (fd < 0) {
+        perror("fuse-test: open /dev/fuse");
+        return 1;
+    }
+    snprintf(opts, sizeof(opts), "fd=%d,rootmode=40000,user_id=%d,group_id=%d",
+             fd, getuid(), getgid());
+    if (mount("fuse-test", mnt, "fuse", 0, opts) < 0) {
+        perror("fuse-test: mount");
+        return 1;
+    }
+
+    // Without SA_RESTART the read below is interrupted once the child exits,
+    // in case its unmount did not abort the connection.
+    sigaction(SIGCHLD, &(struct sigaction) { .sa_handler = on_child }, NULL);
+
+    pid = fork();
+    if (pid < 0) {
+        perror("fuse-test: fork");
+        return 1;
+    }
+    if (pid == 0) {
+        close(fd);
+        status = run_tests(mnt);
+        if (umount2(mnt, MNT_DETACH) < 0)
+            perror("fuse-test: umount");
+        _exit(status);
+    }
+
+    while (!child_done) {
+        ssize_t n = read(fd, buf, sizeof(buf));
+        if (n < 0) {
+            if (errno == EINTR)
+                continue;
+            if (errno != ENODEV)
+                perror("fuse-test: read /dev/fuse");
+            break;
+        }
+        if ((size_t)n < sizeof(struct fuse_in_header)) {
+            fprintf(stderr, "fuse-test: short request of %zd bytes\n", n);
+            break;
+        }
+        serve(fd, (struct fuse_in_header *)buf);
+    }
+
+    if (waitpid(pid, &status, 0) < 0 || !WIFEXITED(status) || WEXITSTATUS(status))
+        ok = 0;
+    umount2(mnt, MNT_DETACH);
+    close(fd);
+
+    for (size_t i = 0; i < sizeof(required) / sizeof(required[0]); i++) {
+        if (!seen[required[i].opcode]) {
+            fprintf(stderr, "fuse-test: no %s request was served\n", required[i].name);
+            ok = 0;
+        }
+    }
+    puts(ok ? "fuse-test: all operations passed" : "fuse-test: FAILED");
+    return ok ? 0 : 1;
+}
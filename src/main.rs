@@ -8,6 +8,12 @@ use alloc::{borrow::ToOwned, vec::Vec};
 
 pub const CMDLINE: &[&str] = &["/bin/sh", "-c", include_str!("init.sh")];
 
+/// The initramfs image built into the kernel.
+#[cfg(feature = "initramfs")]
+pub const INITRAMFS: &[u8] = include_bytes!(env!("STARRY_INITRAMFS"));
+#[cfg(not(feature = "initramfs"))]
+pub const INITRAMFS: &[u8] = &[];
+
 #[unsafe(no_mangle)]
 fn main() {
     let args = CMDLINE
@@ -17,7 +23,7 @@ fn main() {
         .collect::<Vec<_>>();
     let envs = [];
 
-    starry_kernel::entry::init(&args, &envs);
+    starry_kernel::entry::init(&args, &envs, INITRAMFS);
 }
 
 #[cfg(feature = "vf2")]